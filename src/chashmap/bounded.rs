@@ -0,0 +1,424 @@
+use super::iter::Iter;
+use super::HashMap;
+use crate::memory::MemoryUsage;
+use crossbeam_epoch::Guard;
+use rand::{thread_rng, Rng};
+use std::borrow::Borrow;
+use std::fmt::{self, Debug, Formatter};
+use std::hash::{BuildHasher, Hash};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+/// Number of entries inspected per eviction when no explicit sample size is given.
+pub const DEFAULT_EVICTION_SAMPLES: usize = 5;
+
+/// How a [`BoundedHashMap`] picks a victim once an insert pushes it over its capacity.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum EvictionPolicy {
+    /// Inspect up to `n` entries starting at a random bin and evict the least recently read one.
+    Sampled(usize),
+    /// Second-chance CLOCK: a hand sweeps the bins, clearing reference bits until it finds an
+    /// entry that has not been read since the last pass.
+    Clock,
+}
+
+impl Default for EvictionPolicy {
+    fn default() -> Self {
+        EvictionPolicy::Sampled(DEFAULT_EVICTION_SAMPLES)
+    }
+}
+
+pub(crate) struct Slot<V> {
+    pub(crate) value: V,
+    referenced: AtomicBool,
+    last_access: AtomicU64,
+}
+
+impl<V> Slot<V> {
+    fn new(value: V, tick: u64) -> Self {
+        Self {
+            value,
+            referenced: AtomicBool::new(true),
+            last_access: AtomicU64::new(tick),
+        }
+    }
+
+    #[inline]
+    fn touch(&self, tick: u64) {
+        // Plain stores keep the read path free of read-modify-write contention.
+        if !self.referenced.load(Ordering::Relaxed) {
+            self.referenced.store(true, Ordering::Relaxed);
+        }
+        self.last_access.store(tick, Ordering::Relaxed);
+    }
+}
+
+type EvictionListener<K, V> = Box<dyn Fn(&K, &V) + Send + Sync>;
+
+/// A [`HashMap`] that keeps at most `capacity` entries.
+///
+/// Reads stay lock-free; they only record an access stamp on the entry. Writers that push the
+/// map past its bound evict entries according to the configured [`EvictionPolicy`] and report
+/// every victim to the eviction listener, if one is installed.
+pub struct BoundedHashMap<K, V, S = super::DefaultHashBuilder> {
+    map: HashMap<K, Slot<V>, S>,
+    capacity: usize,
+    policy: EvictionPolicy,
+    hand: AtomicUsize,
+    tick: AtomicU64,
+    evictions: AtomicU64,
+    listener: Option<EvictionListener<K, V>>,
+}
+
+impl<K, V> BoundedHashMap<K, V, super::DefaultHashBuilder> {
+    pub fn new(capacity: usize) -> Self {
+        Self::with_policy(capacity, EvictionPolicy::default())
+    }
+
+    pub fn with_policy(capacity: usize, policy: EvictionPolicy) -> Self {
+        Self::with_policy_and_hasher(capacity, policy, super::DefaultHashBuilder::default())
+    }
+}
+
+impl<K, V, S> BoundedHashMap<K, V, S> {
+    pub fn with_policy_and_hasher(capacity: usize, policy: EvictionPolicy, hash_builder: S) -> Self {
+        assert_ne!(capacity, 0);
+        if let EvictionPolicy::Sampled(samples) = policy {
+            assert_ne!(samples, 0);
+        }
+        Self {
+            map: HashMap::with_capacity_and_hasher(capacity, hash_builder),
+            capacity,
+            policy,
+            hand: AtomicUsize::new(0),
+            tick: AtomicU64::new(1),
+            evictions: AtomicU64::new(0),
+            listener: None,
+        }
+    }
+
+    pub fn with_eviction_listener<F>(mut self, listener: F) -> Self
+    where
+        F: Fn(&K, &V) + Send + Sync + 'static,
+    {
+        self.listener = Some(Box::new(listener));
+        self
+    }
+
+    pub fn guard(&self) -> crossbeam_epoch::Guard {
+        self.map.guard()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn policy(&self) -> EvictionPolicy {
+        self.policy
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    pub fn room_left(&self) -> usize {
+        self.capacity.saturating_sub(self.len())
+    }
+
+    /// Total number of entries evicted to enforce the bound.
+    pub fn evictions(&self) -> u64 {
+        self.evictions.load(Ordering::Relaxed)
+    }
+
+    pub fn iter<'g>(&'g self, guard: &'g Guard) -> BoundedIter<'g, K, V> {
+        BoundedIter {
+            inner: self.map.iter(guard),
+        }
+    }
+}
+
+impl<K, V, S> BoundedHashMap<K, V, S>
+where
+    K: Hash + Ord,
+    S: BuildHasher,
+{
+    pub fn contains_key<Q>(&self, key: &Q, guard: &Guard) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Ord,
+    {
+        self.map.contains_key(key, guard)
+    }
+
+    #[inline]
+    pub fn get<'g, Q>(&'g self, key: &Q, guard: &'g Guard) -> Option<&'g V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Ord,
+    {
+        let slot = self.map.get(key, guard)?;
+        slot.touch(self.tick.load(Ordering::Relaxed));
+        Some(&slot.value)
+    }
+
+    /// Like [`get`](Self::get), but does not count as an access for eviction purposes.
+    #[inline]
+    pub fn peek<'g, Q>(&'g self, key: &Q, guard: &'g Guard) -> Option<&'g V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Ord,
+    {
+        self.map.get(key, guard).map(|slot| &slot.value)
+    }
+}
+
+impl<K, V, S> BoundedHashMap<K, V, S>
+where
+    K: 'static + Sync + Send + Clone + Hash + Ord,
+    V: 'static + Sync + Send,
+    S: BuildHasher,
+{
+    pub fn insert<'g>(&'g self, key: K, value: V, guard: &'g Guard) -> Option<&'g V> {
+        let tick = self.tick.fetch_add(1, Ordering::Relaxed);
+        // only kept when the insert is likely to evict
+        let fresh = (self.map.len() >= self.capacity).then(|| key.clone());
+        let old = self.map.insert(key, Slot::new(value, tick), guard);
+        if old.is_none() {
+            self.enforce_capacity(fresh.as_ref(), guard);
+        }
+        old.map(|slot| &slot.value)
+    }
+
+    pub fn remove<'g, Q>(&'g self, key: &Q, guard: &'g Guard) -> Option<&'g V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Ord,
+    {
+        self.map.remove(key, guard).map(|slot| &slot.value)
+    }
+
+    pub fn clear(&self, guard: &Guard) {
+        self.map.clear(guard);
+    }
+
+    /// Evicts a single entry regardless of the current size. Returns `false` if the map had
+    /// nothing left to evict.
+    pub fn evict_one(&self, guard: &Guard) -> bool {
+        self.evict(None, guard)
+    }
+
+    /// Evicts an entry other than `spared`
+    fn evict(&self, spared: Option<&K>, guard: &Guard) -> bool {
+        match self.policy {
+            EvictionPolicy::Sampled(samples) => self.evict_sampled(samples, spared, guard),
+            EvictionPolicy::Clock => self.evict_clock(spared, guard),
+        }
+    }
+
+    /// Evicts entries until the map fits its capacity, sparing the entry `fresh` just inserted
+    fn enforce_capacity(&self, fresh: Option<&K>, guard: &Guard) {
+        while self.map.len() > self.capacity {
+            if !self.evict(fresh, guard) {
+                break;
+            }
+        }
+    }
+
+    fn evict_sampled(&self, samples: usize, spared: Option<&K>, guard: &Guard) -> bool {
+        let bins = self.map.bin_count(guard);
+        if bins == 0 {
+            return false;
+        }
+
+        let start = thread_rng().gen_range(0..bins);
+        let mut victim: Option<(&K, &Slot<V>)> = None;
+        let mut seen = 0;
+        'scan: for step in 0..bins {
+            let bin = (start + step) % bins;
+            for (k, slot) in self.map.iter_bins(bin..bin + 1, guard) {
+                if spared == Some(k) {
+                    continue;
+                }
+                let stamp = slot.last_access.load(Ordering::Relaxed);
+                match victim {
+                    Some((_, current)) if current.last_access.load(Ordering::Relaxed) <= stamp => {}
+                    _ => victim = Some((k, slot)),
+                }
+                seen += 1;
+                if seen >= samples {
+                    break 'scan;
+                }
+            }
+        }
+
+        match victim {
+            Some((k, slot)) => self.evict_entry(k, slot, guard),
+            None => false,
+        }
+    }
+
+    fn evict_clock(&self, spared: Option<&K>, guard: &Guard) -> bool {
+        let bins = self.map.bin_count(guard);
+        if bins == 0 {
+            return false;
+        }
+
+        // Two full sweeps are enough: the first one clears every reference bit it passes.
+        for _ in 0..bins * 2 {
+            let bin = self.hand.fetch_add(1, Ordering::Relaxed) % bins;
+            for (k, slot) in self.map.iter_bins(bin..bin + 1, guard) {
+                if spared == Some(k) || slot.referenced.swap(false, Ordering::Relaxed) {
+                    continue;
+                }
+                if self.evict_entry(k, slot, guard) {
+                    return true;
+                }
+            }
+        }
+        false
+    }
+
+    fn evict_entry(&self, key: &K, slot: &Slot<V>, guard: &Guard) -> bool {
+        match self.map.remove_if_current(key, slot, guard) {
+            Some((k, removed)) => {
+                self.evictions.fetch_add(1, Ordering::Relaxed);
+                if let Some(listener) = &self.listener {
+                    listener(k, &removed.value);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl<K, V, S> Debug for BoundedHashMap<K, V, S>
+where
+    K: Debug,
+    V: Debug,
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let guard = self.map.guard();
+        f.debug_map().entries(self.iter(&guard)).finish()
+    }
+}
+
+#[derive(Debug)]
+pub struct BoundedIter<'g, K, V> {
+    inner: Iter<'g, K, Slot<V>>,
+}
+
+impl<'g, K, V> Iterator for BoundedIter<'g, K, V> {
+    type Item = (&'g K, &'g V);
+    fn next(&mut self) -> Option<Self::Item> {
+        let (k, slot) = self.inner.next()?;
+        Some((k, &slot.value))
+    }
+}
+
+impl<V: Debug> Debug for Slot<V> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Slot")
+            .field("value", &self.value)
+            .field("referenced", &self.referenced.load(Ordering::Relaxed))
+            .finish()
+    }
+}
+
+impl<V: MemoryUsage> MemoryUsage for Slot<V> {
+    fn heap_usage(&self, samples: usize) -> usize {
+        self.value.heap_usage(samples)
+    }
+}
+
+impl<K, V, S> MemoryUsage for BoundedHashMap<K, V, S>
+where
+    K: MemoryUsage,
+    V: MemoryUsage,
+{
+    fn heap_usage(&self, samples: usize) -> usize {
+        self.map.heap_usage(samples)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn sampled_respects_capacity() {
+        let map = BoundedHashMap::new(16);
+        let guard = map.guard();
+        for i in 0..100 {
+            map.insert(i, i, &guard);
+        }
+        assert!(map.len() <= 16);
+        assert_eq!(map.evictions(), 100 - map.len() as u64);
+    }
+
+    #[test]
+    fn clock_spares_fresh_insert() {
+        let evicted = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let seen = evicted.clone();
+        let map = BoundedHashMap::with_policy(4, EvictionPolicy::Clock)
+            .with_eviction_listener(move |k: &usize, _: &usize| seen.lock().unwrap().push(*k));
+        let guard = map.guard();
+        for i in 0..4 {
+            map.insert(i, i, &guard);
+        }
+        // the first sweep clears every reference bit, the second one evicts
+        assert!(map.evict_one(&guard));
+        let first = evicted.lock().unwrap()[0];
+        assert!(!map.contains_key(&first, &guard));
+        map.insert(10, 10, &guard);
+        let read = (0..4).find(|k| *k != first).unwrap();
+        assert_eq!(map.get(&read, &guard), Some(&read));
+
+        // the entries read or inserted since the sweep are spared, as is the fresh insert
+        map.insert(11, 11, &guard);
+        assert_eq!(map.len(), 4);
+        let second = evicted.lock().unwrap()[1];
+        assert!(![first, read, 10, 11].contains(&second));
+        for k in [read, 10, 11] {
+            assert!(map.contains_key(&k, &guard));
+        }
+    }
+
+    #[test]
+    fn sampled_spares_fresh_insert() {
+        for _ in 0..100 {
+            let map = BoundedHashMap::with_policy(4, EvictionPolicy::Sampled(usize::MAX));
+            let guard = map.guard();
+            for i in 0..4 {
+                map.insert(i, i, &guard);
+            }
+            // the entries read since the last insert have the stamp of the next one
+            for i in 0..4 {
+                map.get(&i, &guard);
+            }
+            map.insert(10, 10, &guard);
+            assert_eq!(map.len(), 4);
+            assert!(map.contains_key(&10, &guard));
+        }
+    }
+
+    #[test]
+    fn listener_sees_every_eviction() {
+        let evicted = Arc::new(AtomicUsize::new(0));
+        let seen = evicted.clone();
+        let map = BoundedHashMap::with_policy(8, EvictionPolicy::Clock)
+            .with_eviction_listener(move |_: &usize, _: &usize| {
+                seen.fetch_add(1, Ordering::SeqCst);
+            });
+        let guard = map.guard();
+        for i in 0..64 {
+            map.insert(i, i, &guard);
+        }
+        assert_eq!(evicted.load(Ordering::SeqCst) as u64, map.evictions());
+        assert_eq!(map.len(), 8);
+    }
+}
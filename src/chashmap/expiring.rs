@@ -0,0 +1,433 @@
+use super::iter::{Iter, ScanCursor};
+use super::HashMap;
+use crate::memory::MemoryUsage;
+use crossbeam_epoch::Guard;
+use parking_lot::Mutex;
+use std::borrow::Borrow;
+use std::fmt::{self, Debug, Formatter};
+use std::hash::{BuildHasher, Hash};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Number of bins the background sweeper inspects per tick unless told otherwise.
+pub const DEFAULT_SWEEP_BINS: usize = 64;
+
+const NO_DEADLINE: u64 = 0;
+
+pub(crate) struct Expiring<V> {
+    pub(crate) value: V,
+    // Milliseconds since the map's origin plus one, or `NO_DEADLINE`.
+    deadline: AtomicU64,
+}
+
+impl<V> Expiring<V> {
+    fn new(value: V, deadline: u64) -> Self {
+        Self {
+            value,
+            deadline: AtomicU64::new(deadline),
+        }
+    }
+
+    #[inline]
+    fn is_expired(&self, now: u64) -> bool {
+        let deadline = self.deadline.load(Ordering::Acquire);
+        deadline != NO_DEADLINE && deadline <= now
+    }
+}
+
+type RemovalListener<K, V> = Box<dyn Fn(&K, &V) + Send + Sync>;
+
+/// A [`HashMap`] whose entries may carry a deadline.
+///
+/// Expired entries are treated as absent by every read. They are physically removed by
+/// [`sweep`](ExpiringHashMap::sweep), which walks the table a few bins at a time, either when
+/// called directly or from a [`Sweeper`] thread. Every swept entry is reported to the removal
+/// listener, if one is installed.
+pub struct ExpiringHashMap<K, V, S = super::DefaultHashBuilder> {
+    map: HashMap<K, Expiring<V>, S>,
+    origin: Instant,
+    cursor: Mutex<Option<ScanCursor>>,
+    listener: Option<RemovalListener<K, V>>,
+}
+
+impl<K, V> ExpiringHashMap<K, V, super::DefaultHashBuilder> {
+    pub fn new() -> Self {
+        Self::with_hasher(super::DefaultHashBuilder::default())
+    }
+
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self::with_capacity_and_hasher(capacity, super::DefaultHashBuilder::default())
+    }
+}
+
+impl<K, V, S> Default for ExpiringHashMap<K, V, S>
+where
+    S: Default,
+{
+    fn default() -> Self {
+        Self::with_hasher(S::default())
+    }
+}
+
+impl<K, V, S> ExpiringHashMap<K, V, S> {
+    pub fn with_hasher(hash_builder: S) -> Self {
+        Self::from_map(HashMap::with_hasher(hash_builder))
+    }
+
+    pub fn with_capacity_and_hasher(capacity: usize, hash_builder: S) -> Self {
+        Self::from_map(HashMap::with_capacity_and_hasher(capacity, hash_builder))
+    }
+
+    fn from_map(map: HashMap<K, Expiring<V>, S>) -> Self {
+        Self {
+            map,
+            origin: Instant::now(),
+            cursor: Mutex::new(None),
+            listener: None,
+        }
+    }
+
+    pub fn with_removal_listener<F>(mut self, listener: F) -> Self
+    where
+        F: Fn(&K, &V) + Send + Sync + 'static,
+    {
+        self.listener = Some(Box::new(listener));
+        self
+    }
+
+    pub fn guard(&self) -> crossbeam_epoch::Guard {
+        self.map.guard()
+    }
+
+    /// Number of entries in the map, including expired entries that have not been swept yet.
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    pub fn iter<'g>(&'g self, guard: &'g Guard) -> ExpiringIter<'g, K, V> {
+        ExpiringIter {
+            inner: self.map.iter(guard),
+            now: self.now(),
+        }
+    }
+
+    #[inline]
+    fn now(&self) -> u64 {
+        self.origin.elapsed().as_millis() as u64 + 1
+    }
+
+    #[inline]
+    fn deadline_after(&self, ttl: Duration) -> u64 {
+        self.now().saturating_add(ttl.as_millis() as u64)
+    }
+}
+
+impl<K, V, S> ExpiringHashMap<K, V, S>
+where
+    K: Hash + Ord,
+    S: BuildHasher,
+{
+    fn get_live<'g, Q>(&'g self, key: &Q, guard: &'g Guard) -> Option<&'g Expiring<V>>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Ord,
+    {
+        self.map
+            .get(key, guard)
+            .filter(|entry| !entry.is_expired(self.now()))
+    }
+
+    pub fn contains_key<Q>(&self, key: &Q, guard: &Guard) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Ord,
+    {
+        self.get_live(key, guard).is_some()
+    }
+
+    #[inline]
+    pub fn get<'g, Q>(&'g self, key: &Q, guard: &'g Guard) -> Option<&'g V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Ord,
+    {
+        self.get_live(key, guard).map(|entry| &entry.value)
+    }
+
+    /// Remaining time to live of `key`. Returns `None` if the key is absent or has no deadline.
+    pub fn ttl<Q>(&self, key: &Q, guard: &Guard) -> Option<Duration>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Ord,
+    {
+        let entry = self.get_live(key, guard)?;
+        match entry.deadline.load(Ordering::Acquire) {
+            NO_DEADLINE => None,
+            deadline => Some(Duration::from_millis(deadline.saturating_sub(self.now()))),
+        }
+    }
+
+    /// Sets a deadline `ttl` from now on a live key. Returns `false` if the key is absent.
+    pub fn expire<Q>(&self, key: &Q, ttl: Duration, guard: &Guard) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Ord,
+    {
+        match self.get_live(key, guard) {
+            Some(entry) => {
+                entry.deadline.store(self.deadline_after(ttl), Ordering::Release);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Clears the deadline of a live key. Returns `false` if the key is absent.
+    pub fn persist<Q>(&self, key: &Q, guard: &Guard) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Ord,
+    {
+        match self.get_live(key, guard) {
+            Some(entry) => {
+                entry.deadline.store(NO_DEADLINE, Ordering::Release);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl<K, V, S> ExpiringHashMap<K, V, S>
+where
+    K: 'static + Sync + Send + Clone + Hash + Ord,
+    V: 'static + Sync + Send,
+    S: BuildHasher,
+{
+    pub fn insert<'g>(&'g self, key: K, value: V, guard: &'g Guard) -> Option<&'g V> {
+        self.put(key, Expiring::new(value, NO_DEADLINE), guard)
+    }
+
+    pub fn insert_with_ttl<'g>(
+        &'g self,
+        key: K,
+        value: V,
+        ttl: Duration,
+        guard: &'g Guard,
+    ) -> Option<&'g V> {
+        self.put(key, Expiring::new(value, self.deadline_after(ttl)), guard)
+    }
+
+    fn put<'g>(&'g self, key: K, entry: Expiring<V>, guard: &'g Guard) -> Option<&'g V> {
+        let now = self.now();
+        self.map
+            .insert(key, entry, guard)
+            .filter(|old| !old.is_expired(now))
+            .map(|old| &old.value)
+    }
+
+    pub fn remove<'g, Q>(&'g self, key: &Q, guard: &'g Guard) -> Option<&'g V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Ord,
+    {
+        let now = self.now();
+        self.map
+            .remove(key, guard)
+            .filter(|old| !old.is_expired(now))
+            .map(|old| &old.value)
+    }
+
+    pub fn clear(&self, guard: &Guard) {
+        self.map.clear(guard);
+    }
+
+    /// Removes expired entries from the next `bins` bins of the table and returns how many
+    /// were removed. Successive calls resume where the previous one stopped and wrap around
+    /// once the whole table has been visited.
+    pub fn sweep(&self, bins: usize, guard: &Guard) -> usize {
+        let mut cursor = self.cursor.lock();
+        let cursor = match &mut *cursor {
+            Some(cursor) if !cursor.is_finished() => cursor,
+            slot => slot.insert(self.map.cursor(guard)),
+        };
+
+        let now = self.now();
+        let mut removed = 0;
+        for (k, entry) in self.map.scan(cursor, bins, guard) {
+            if !entry.is_expired(now) {
+                continue;
+            }
+            if let Some((k, entry)) = self.map.remove_if_current(k, entry, guard) {
+                removed += 1;
+                if let Some(listener) = &self.listener {
+                    listener(k, &entry.value);
+                }
+            }
+        }
+        removed
+    }
+}
+
+impl<K, V, S> Debug for ExpiringHashMap<K, V, S>
+where
+    K: Debug,
+    V: Debug,
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let guard = self.map.guard();
+        f.debug_map().entries(self.iter(&guard)).finish()
+    }
+}
+
+#[derive(Debug)]
+pub struct ExpiringIter<'g, K, V> {
+    inner: Iter<'g, K, Expiring<V>>,
+    now: u64,
+}
+
+impl<'g, K, V> Iterator for ExpiringIter<'g, K, V> {
+    type Item = (&'g K, &'g V);
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (k, entry) = self.inner.next()?;
+            if !entry.is_expired(self.now) {
+                return Some((k, &entry.value));
+            }
+        }
+    }
+}
+
+impl<V: Debug> Debug for Expiring<V> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Expiring")
+            .field("value", &self.value)
+            .field("deadline", &self.deadline.load(Ordering::Relaxed))
+            .finish()
+    }
+}
+
+/// Background thread that periodically sweeps an [`ExpiringHashMap`]. The thread is stopped
+/// and joined when the handle is dropped.
+pub struct Sweeper {
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Sweeper {
+    pub fn start<K, V, S>(map: Arc<ExpiringHashMap<K, V, S>>, interval: Duration, bins: usize) -> Self
+    where
+        K: 'static + Sync + Send + Clone + Hash + Ord,
+        V: 'static + Sync + Send,
+        S: 'static + BuildHasher + Send + Sync,
+    {
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        let handle = thread::spawn(move || {
+            while !stopped.load(Ordering::Relaxed) {
+                let guard = map.guard();
+                map.sweep(bins, &guard);
+                drop(guard);
+                thread::park_timeout(interval);
+            }
+        });
+        Self {
+            stop,
+            handle: Some(handle),
+        }
+    }
+
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            handle.thread().unpark();
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Drop for Sweeper {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+impl<V: MemoryUsage> MemoryUsage for Expiring<V> {
+    fn heap_usage(&self, samples: usize) -> usize {
+        self.value.heap_usage(samples)
+    }
+}
+
+impl<K, V, S> MemoryUsage for ExpiringHashMap<K, V, S>
+where
+    K: MemoryUsage,
+    V: MemoryUsage,
+{
+    fn heap_usage(&self, samples: usize) -> usize {
+        self.map.heap_usage(samples)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    #[test]
+    fn expired_entries_are_absent() {
+        let map = ExpiringHashMap::new();
+        let guard = map.guard();
+        map.insert_with_ttl(1, 1, Duration::from_millis(0), &guard);
+        map.insert(2, 2, &guard);
+        assert_eq!(map.get(&1, &guard), None);
+        assert_eq!(map.get(&2, &guard), Some(&2));
+        assert_eq!(map.iter(&guard).count(), 1);
+    }
+
+    #[test]
+    fn persist_and_expire() {
+        let map = ExpiringHashMap::new();
+        let guard = map.guard();
+        map.insert_with_ttl(1, 1, Duration::from_secs(60), &guard);
+        assert!(map.ttl(&1, &guard).is_some());
+        assert!(map.persist(&1, &guard));
+        assert_eq!(map.ttl(&1, &guard), None);
+        assert!(map.expire(&1, Duration::from_millis(0), &guard));
+        assert!(!map.contains_key(&1, &guard));
+    }
+
+    #[test]
+    fn sweep_notifies_listener() {
+        let removed = Arc::new(AtomicUsize::new(0));
+        let seen = removed.clone();
+        let map = ExpiringHashMap::new().with_removal_listener(move |_: &u32, _: &u32| {
+            seen.fetch_add(1, Ordering::SeqCst);
+        });
+        let guard = map.guard();
+        for i in 0..100 {
+            if i % 2 == 0 {
+                map.insert_with_ttl(i, i, Duration::from_millis(0), &guard);
+            } else {
+                map.insert(i, i, &guard);
+            }
+        }
+
+        let mut swept = 0;
+        for _ in 0..map.map.bin_count(&guard) {
+            swept += map.sweep(1, &guard);
+        }
+        assert_eq!(swept, 50);
+        assert_eq!(removed.load(Ordering::SeqCst), 50);
+        assert_eq!(map.len(), 50);
+    }
+}
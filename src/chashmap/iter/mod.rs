@@ -47,4 +47,94 @@ impl<'g, K, V> Iterator for Values<'g, K, V> {
         let value = unsafe { value.deref() };
         Some(value)
     }
-}
\ No newline at end of file
+}
+
+/// A resumable position in a bin-by-bin scan of a [`HashMap`](crate::chashmap::HashMap).
+///
+/// A cursor remembers the table size observed when the scan started, so it can be carried
+/// across guard re-pins and table resizes: every entry that is present for the whole scan is
+/// returned exactly once, while entries inserted or removed concurrently may or may not be seen.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ScanCursor {
+    pub(crate) next: usize,
+    pub(crate) end: usize,
+    pub(crate) base_size: usize,
+}
+
+impl ScanCursor {
+    pub(crate) fn new(start: usize, end: usize, base_size: usize) -> Self {
+        Self {
+            next: start,
+            end,
+            base_size,
+        }
+    }
+
+    /// Index of the next bin (relative to the table size the scan started with).
+    pub fn position(&self) -> usize {
+        self.next
+    }
+
+    /// Number of bins left to visit.
+    pub fn remaining(&self) -> usize {
+        self.end - self.next
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.next >= self.end
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::chashmap::HashMap;
+    use std::collections::HashSet;
+
+    #[test]
+    fn cursor_survives_resize() {
+        let map = HashMap::with_capacity(16);
+        for i in 0..16 {
+            map.insert(i, i, &map.guard());
+        }
+
+        let mut cursor = map.cursor(&map.guard());
+        let mut seen = HashSet::new();
+        {
+            let guard = map.guard();
+            seen.extend(map.scan(&mut cursor, 4, &guard).map(|(k, _)| *k));
+        }
+
+        // force several resizes between two batches
+        for i in 16..1024 {
+            map.insert(i, i, &map.guard());
+        }
+
+        while !cursor.is_finished() {
+            let guard = map.guard();
+            seen.extend(map.scan(&mut cursor, 4, &guard).map(|(k, _)| *k));
+        }
+        for i in 0..16 {
+            assert!(seen.contains(&i));
+        }
+    }
+
+    #[test]
+    fn split_scan_is_disjoint() {
+        let map = HashMap::new();
+        let guard = map.guard();
+        for i in 0..500 {
+            map.insert(i, i, &guard);
+        }
+
+        let mut total = 0;
+        let mut seen = HashSet::new();
+        for cursor in map.split_scan(5, &guard) {
+            for (k, _) in map.iter_cursor(&cursor, &guard) {
+                total += 1;
+                seen.insert(*k);
+            }
+        }
+        assert_eq!(total, 500);
+        assert_eq!(seen.len(), 500);
+    }
+}
@@ -45,6 +45,30 @@ impl<'g, K, V> NodeIter<'g, K, V> {
         }
     }
 
+    /// Visits only the bins whose index modulo `base_size` falls in `start..end`.
+    ///
+    /// Tables only ever grow by doubling, so a bin `i` of a table with `base_size` bins is split
+    /// into bins `i`, `i + base_size`, ... of every later table. Iterating with the `base_size`
+    /// observed when a scan started therefore covers the same keys even after a resize.
+    pub(crate) fn with_range(
+        table: Shared<'g, Table<K, V>>,
+        start: usize,
+        end: usize,
+        base_size: usize,
+        guard: &'g Guard,
+    ) -> Self {
+        let mut iter = Self::new(table, guard);
+        if iter.table.is_none() {
+            return iter;
+        }
+        debug_assert!(base_size <= iter.base_size);
+        iter.base_size = base_size;
+        iter.base_limit = std::cmp::min(end, base_size);
+        iter.base_index = std::cmp::min(start, iter.base_limit);
+        iter.index = iter.base_index;
+        iter
+    }
+
     fn push_state(&mut self, t: &'g Table<K, V>, i: usize, n: usize) {
         let mut s = self.spare.take();
         if let Some(ref mut s) = s {
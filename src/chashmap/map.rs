@@ -3,12 +3,14 @@
 use super::iter::*;
 use super::node::*;
 use super::raw::*;
+use crate::memory::{sampled_usage, MemoryUsage};
 use crossbeam_epoch::{self as epoch, Atomic, Guard, Owned, Shared};
 use std::borrow::Borrow;
 use std::error::Error;
 use std::fmt::{self, Debug, Display, Formatter};
 use std::hash::{BuildHasher, Hash, Hasher};
 use std::iter::FromIterator;
+use std::ops::Range;
 use std::sync::atomic::{AtomicIsize, Ordering};
 
 
@@ -226,6 +228,74 @@ impl<K, V, S> HashMap<K, V, S> {
         Values { node_iter, guard }
     }
 
+    pub(crate) fn bin_count(&self, guard: &Guard) -> usize {
+        let table = self.table.load(Ordering::SeqCst, guard);
+        if table.is_null() {
+            0
+        } else {
+            unsafe { table.deref() }.len()
+        }
+    }
+
+    pub(crate) fn iter_bins<'g>(&'g self, bins: Range<usize>, guard: &'g Guard) -> Iter<'g, K, V> {
+        self.check_guard(guard);
+        let table = self.table.load(Ordering::SeqCst, guard);
+        let base_size = if table.is_null() {
+            0
+        } else {
+            unsafe { table.deref() }.len()
+        };
+        let node_iter = NodeIter::with_range(table, bins.start, bins.end, base_size, guard);
+        Iter { node_iter, guard }
+    }
+
+    pub fn cursor(&self, guard: &Guard) -> ScanCursor {
+        self.check_guard(guard);
+        let base_size = self.bin_count(guard);
+        ScanCursor::new(0, base_size, base_size)
+    }
+
+    pub fn split_scan(&self, parts: usize, guard: &Guard) -> Vec<ScanCursor> {
+        assert_ne!(parts, 0);
+        self.check_guard(guard);
+        let base_size = self.bin_count(guard);
+        let parts = std::cmp::max(1, std::cmp::min(parts, base_size));
+        let stride = base_size / parts;
+        let extra = base_size % parts;
+        let mut start = 0;
+        (0..parts)
+            .map(|i| {
+                let len = stride + if i < extra { 1 } else { 0 };
+                let cursor = ScanCursor::new(start, start + len, base_size);
+                start += len;
+                cursor
+            })
+            .collect()
+    }
+
+    pub fn scan<'g>(&'g self, cursor: &mut ScanCursor, bins: usize, guard: &'g Guard) -> Iter<'g, K, V> {
+        let start = cursor.next;
+        cursor.next = std::cmp::min(cursor.end, start.saturating_add(bins));
+        self.scan_range(start, cursor.next, cursor.base_size, guard)
+    }
+
+    pub fn iter_cursor<'g>(&'g self, cursor: &ScanCursor, guard: &'g Guard) -> Iter<'g, K, V> {
+        self.scan_range(cursor.next, cursor.end, cursor.base_size, guard)
+    }
+
+    fn scan_range<'g>(
+        &'g self,
+        start: usize,
+        end: usize,
+        base_size: usize,
+        guard: &'g Guard,
+    ) -> Iter<'g, K, V> {
+        self.check_guard(guard);
+        let table = self.table.load(Ordering::SeqCst, guard);
+        let node_iter = NodeIter::with_range(table, start, end, base_size, guard);
+        Iter { node_iter, guard }
+    }
+
     fn init_table<'g>(&'g self, guard: &'g Guard) -> Shared<'g, Table<K, V>> {
         loop {
             let table = self.table.load(Ordering::SeqCst, guard);
@@ -1446,6 +1516,17 @@ where
         None
     }
 
+    pub(crate) fn remove_if_current<'g>(
+        &'g self,
+        key: &K,
+        current: &'g V,
+        guard: &'g Guard,
+    ) -> Option<(&'g K, &'g V)> {
+        self.check_guard(guard);
+        let observed: Shared<'_, V> = Shared::from(current as *const V);
+        self.replace_node(key, None, Some(observed), guard)
+    }
+
     pub fn retain<F>(&self, mut f: F, guard: &Guard)
     where
         F: FnMut(&K, &V) -> bool,
@@ -1720,9 +1801,70 @@ where
     }
 }
 
+impl<K, V, S> MemoryUsage for HashMap<K, V, S>
+where
+    K: MemoryUsage,
+    V: MemoryUsage,
+{
+    fn heap_usage(&self, samples: usize) -> usize {
+        let guard = self.guard();
+        let entry_size = std::mem::size_of::<BinEntry<K, V>>();
+        let mut usage = 0;
+        let mut bins = 0;
+
+        for table in [
+            self.table.load(Ordering::SeqCst, &guard),
+            self.next_table.load(Ordering::SeqCst, &guard),
+        ] {
+            if table.is_null() {
+                continue;
+            }
+            let t = unsafe { table.deref() };
+            // the table itself, its bin array and its shared `Moved` entry
+            usage += std::mem::size_of::<Table<K, V>>()
+                + t.len() * std::mem::size_of::<Atomic<BinEntry<K, V>>>()
+                + entry_size;
+            for i in 0..t.len() {
+                let bin = t.bin(i, &guard);
+                if !bin.is_null() {
+                    if let BinEntry::Tree(_) = unsafe { bin.deref() } {
+                        bins += 1;
+                    }
+                }
+            }
+        }
+
+        // every entry is a `BinEntry` plus a separately allocated value; tree bins add a head
+        let len = self.len();
+        usage += (len + bins) * entry_size + len * std::mem::size_of::<V>();
+        usage
+            + sampled_usage(
+                len,
+                samples,
+                self.iter(&guard)
+                    .map(|(k, v)| k.heap_usage(samples) + v.heap_usage(samples)),
+            )
+    }
+}
+
 #[cfg(not(miri))]
 #[inline]
 fn num_cpus() -> usize {
     NCPU_INITIALIZER.call_once(|| NCPU.store(num_cpus::get_physical(), Ordering::Relaxed));
     NCPU.load(Ordering::Relaxed)
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::memory::testing::usage_growth;
+
+    #[test]
+    fn usage_grows_with_entries() {
+        let mut map: HashMap<u64, String> = HashMap::new();
+        let (empty, ten, _) = usage_growth(&mut map, |map, i| {
+            map.insert(i as u64, "v".repeat(32), &map.guard());
+        });
+        assert!(ten >= empty + 10 * 32);
+    }
+}
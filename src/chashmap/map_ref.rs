@@ -49,6 +49,22 @@ impl<K, V, S> HashMapRef<'_, K, V, S> {
     pub fn values(&self) -> Values<'_, K, V> {
         self.map.values(&self.guard)
     }
+
+    pub fn cursor(&self) -> ScanCursor {
+        self.map.cursor(&self.guard)
+    }
+
+    pub fn split_scan(&self, parts: usize) -> Vec<ScanCursor> {
+        self.map.split_scan(parts, &self.guard)
+    }
+
+    pub fn scan(&self, cursor: &mut ScanCursor, bins: usize) -> Iter<'_, K, V> {
+        self.map.scan(cursor, bins, &self.guard)
+    }
+
+    pub fn iter_cursor(&self, cursor: &ScanCursor) -> Iter<'_, K, V> {
+        self.map.iter_cursor(cursor, &self.guard)
+    }
 }
 
 impl<K, V, S> HashMapRef<'_, K, V, S>
@@ -3,6 +3,8 @@
 use crossbeam_epoch::Guard;
 use std::ops::Deref;
 
+pub mod bounded;
+pub mod expiring;
 pub mod map;
 pub mod map_ref;
 pub mod node;
@@ -12,7 +14,7 @@ pub mod set_ref;
 
 
 #[cfg(feature = "rayon")]
-mod rayon_impls;
+mod rayon_impl;
 
 #[cfg(feature = "serde")]
 mod serde_impls;
@@ -21,6 +23,8 @@ mod serde_impls;
 /// Iterator types.
 pub mod iter;
 
+pub use bounded::{BoundedHashMap, EvictionPolicy};
+pub use expiring::{ExpiringHashMap, Sweeper};
 pub use map::{HashMap, TryInsertError};
 pub use map_ref::HashMapRef;
 pub use set::HashSet;
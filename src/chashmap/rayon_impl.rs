@@ -120,9 +120,35 @@ where
     }
 }
 
+impl<K, V, S> HashMap<K, V, S>
+where
+    K: Send + Sync,
+    V: Send + Sync,
+    S: Sync,
+{
+    /// Calls `f` for every entry, scanning `parts` disjoint bin ranges on the rayon pool.
+    pub fn par_for_each<F>(&self, parts: usize, f: F)
+    where
+        F: Fn(&K, &V) + Send + Sync,
+    {
+        let guard = self.guard();
+        let cursors = self.split_scan(parts, &guard);
+        drop(guard);
+
+        cursors.into_par_iter().for_each_init(
+            || self.guard(),
+            |guard, cursor| {
+                for (k, v) in self.iter_cursor(&cursor, guard) {
+                    f(k, v);
+                }
+            },
+        );
+    }
+}
+
 #[cfg(test)]
 mod test {
-    use crate::{HashMap, HashSet};
+    use crate::chashmap::{HashMap, HashSet};
     use rayon::iter::{FromParallelIterator, IntoParallelIterator, ParallelExtend};
 
     #[test]
@@ -317,4 +343,21 @@ mod test {
         assert!(set_ref.contains(&(199, 990)));
         assert!(!set_ref.contains(&(199, 167)));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn hm_par_for_each_visits_every_entry() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let map = HashMap::new();
+        let guard = map.guard();
+        for i in 0..1000 {
+            map.insert(i, i, &guard);
+        }
+
+        let seen = AtomicUsize::new(0);
+        map.par_for_each(7, |_, _| {
+            seen.fetch_add(1, Ordering::Relaxed);
+        });
+        assert_eq!(seen.load(Ordering::Relaxed), 1000);
+    }
+}
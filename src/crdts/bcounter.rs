@@ -0,0 +1,288 @@
+//! Bounded counter, a counter that never goes below zero across replicas.
+//!
+//! Increments create rights and decrements spend them. An actor can only spend the rights it
+//! holds: the ones its own increments created plus the ones other actors transferred to it,
+//! minus what it already spent or gave away. No right is ever spent twice, so the value stays
+//! non-negative however replicas interleave, without any coordination; an actor running low
+//! gets another one to transfer it rights.
+use core::convert::Infallible;
+use core::fmt::{self, Debug};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::mem;
+
+use num::bigint::BigInt;
+use serde::{Deserialize, Serialize};
+
+use super::traits::{CmRDT, CvRDT, ResetRemove};
+use super::dot::Dot;
+use super::vclock::VClock;
+use super::gcounter::GCounter;
+use crate::memory::{sampled_usage, MemoryUsage};
+
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct BCounter<A: Ord> {
+    p: GCounter<A>,
+    n: GCounter<A>,
+    /// Rights given so far, by giving and receiving actor
+    transfers: BTreeMap<(A, A), u64>,
+}
+
+/// An Op which is produced through from mutating the counter
+/// Ship these ops to other replicas to have them sync up.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Op<A: Ord> {
+    /// Raise the increments of the dot's actor to the dot's counter
+    Inc(Dot<A>),
+    /// Raise the decrements of the dot's actor to the dot's counter
+    Dec(Dot<A>),
+    /// Raise the rights `from` gave to `to` to `total`
+    Transfer {
+        /// actor giving the rights
+        from: A,
+        /// actor receiving the rights
+        to: A,
+        /// rights given by `from` to `to` since the counter was created
+        total: u64,
+    },
+}
+
+/// An actor tried to spend more rights than it holds.
+#[derive(Debug, PartialEq, Eq)]
+pub struct InsufficientRights {
+    /// rights the actor holds
+    pub available: u64,
+    /// rights the operation needs
+    pub requested: u64,
+}
+
+impl fmt::Display for InsufficientRights {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} rights requested, {} available", self.requested, self.available)
+    }
+}
+
+impl Error for InsufficientRights {}
+
+impl<A: Ord> Default for BCounter<A> {
+    fn default() -> Self {
+        Self {
+            p: Default::default(),
+            n: Default::default(),
+            transfers: Default::default(),
+        }
+    }
+}
+
+impl<A: Ord + Clone + Debug> CmRDT for BCounter<A> {
+    type Op = Op<A>;
+    type Validation = InsufficientRights;
+
+    /// Checks the actor of a decrement or a transfer held the rights it spends, which only
+    /// holds once the ops it had seen were applied.
+    fn validate_op(&self, op: &Self::Op) -> Result<(), Self::Validation> {
+        match op {
+            Op::Inc(_) => Ok(()),
+            Op::Dec(dot) => self.spend(&dot.actor, dot.counter.saturating_sub(self.n.get(&dot.actor))),
+            Op::Transfer { from, to, total } => self.spend(from, total.saturating_sub(self.transferred(from, to))),
+        }
+    }
+
+    fn apply(&mut self, op: Self::Op) {
+        match op {
+            Op::Inc(dot) => self.p.apply(dot),
+            Op::Dec(dot) => self.n.apply(dot),
+            Op::Transfer { from, to, total } => {
+                let given = self.transfers.entry((from, to)).or_insert(0);
+                *given = total.max(*given);
+            }
+        }
+    }
+}
+
+impl<A: Ord + Clone + Debug> CvRDT for BCounter<A> {
+    type Validation = Infallible;
+
+    fn validate_merge(&self, _other: &Self) -> Result<(), Self::Validation> {
+        Ok(())
+    }
+
+    fn merge(&mut self, other: Self) {
+        self.p.merge(other.p);
+        self.n.merge(other.n);
+        for ((from, to), total) in other.transfers {
+            self.apply(Op::Transfer { from, to, total });
+        }
+    }
+}
+
+impl<A: Ord> ResetRemove<A> for BCounter<A> {
+    fn reset_remove(&mut self, clock: &VClock<A>) {
+        self.p.reset_remove(clock);
+        self.n.reset_remove(clock);
+        // transfers are versioned by their total, like the increments of a GCounter
+        self.transfers.retain(|(from, _), total| *total > clock.get(from));
+    }
+}
+
+impl<A: Ord + MemoryUsage> MemoryUsage for BCounter<A> {
+    fn heap_usage(&self, samples: usize) -> usize {
+        self.p.heap_usage(samples)
+            + self.n.heap_usage(samples)
+            + self.transfers.len() * mem::size_of::<((A, A), u64)>()
+            + sampled_usage(
+                self.transfers.len(),
+                samples,
+                self.transfers.keys().map(|(from, to)| from.heap_usage(samples) + to.heap_usage(samples)),
+            )
+    }
+}
+
+impl<A: Ord + Clone> BCounter<A> {
+    /// Produce a new `BCounter`.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Generate an Op to increment the counter.
+    pub fn inc(&self, actor: A) -> Op<A> {
+        self.inc_many(actor, 1)
+    }
+
+    /// Generate an Op to increment the counter by a number of steps.
+    pub fn inc_many(&self, actor: A, steps: u64) -> Op<A> {
+        Op::Inc(self.p.inc_many(actor, steps))
+    }
+
+    /// Generate an Op to decrement the counter, if the actor holds a right.
+    pub fn dec(&self, actor: A) -> Result<Op<A>, InsufficientRights> {
+        self.dec_many(actor, 1)
+    }
+
+    /// Generate an Op to decrement the counter by a number of steps, if the actor holds as
+    /// many rights.
+    pub fn dec_many(&self, actor: A, steps: u64) -> Result<Op<A>, InsufficientRights> {
+        self.spend(&actor, steps)?;
+        Ok(Op::Dec(self.n.inc_many(actor, steps)))
+    }
+
+    /// Generate an Op giving `amount` rights of `from` to `to`, if `from` holds them.
+    pub fn transfer(&self, from: A, to: A, amount: u64) -> Result<Op<A>, InsufficientRights> {
+        self.spend(&from, amount)?;
+        let total = self.transferred(&from, &to) + amount;
+        Ok(Op::Transfer { from, to, total })
+    }
+
+    /// Return the rights `actor` can still spend.
+    pub fn rights(&self, actor: &A) -> u64 {
+        let (mut received, mut given) = (0, 0);
+        for ((from, to), total) in self.transfers.iter() {
+            if to == actor {
+                received += total;
+            }
+            if from == actor {
+                given += total;
+            }
+        }
+        (self.p.get(actor) + received).saturating_sub(self.n.get(actor) + given)
+    }
+
+    /// Return the current value of this counter (P-N).
+    pub fn read(&self) -> BigInt {
+        let p: BigInt = self.p.read().into();
+        let n: BigInt = self.n.read().into();
+        p - n
+    }
+
+    fn transferred(&self, from: &A, to: &A) -> u64 {
+        self.transfers.get(&(from.clone(), to.clone())).cloned().unwrap_or(0)
+    }
+
+    fn spend(&self, actor: &A, requested: u64) -> Result<(), InsufficientRights> {
+        let available = self.rights(actor);
+        match requested <= available {
+            true => Ok(()),
+            false => Err(InsufficientRights { available, requested }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use quickcheck::quickcheck;
+
+    #[test]
+    fn rights_bound_decrements() {
+        let mut a = BCounter::new();
+        a.apply(a.inc_many(1u8, 3));
+        let mut b = a.clone();
+
+        // both replicas hold the 3 rights of actor 1, only actor 1 can spend them
+        assert_eq!(b.dec(2), Err(InsufficientRights { available: 0, requested: 1 }));
+        let op = a.transfer(1, 2, 2).unwrap();
+        a.apply(op.clone());
+        b.apply(op);
+        assert_eq!(a.rights(&1), 1);
+        assert_eq!(b.rights(&2), 2);
+
+        a.apply(a.dec(1).unwrap());
+        b.apply(b.dec_many(2, 2).unwrap());
+        assert!(a.dec(1).is_err());
+        assert!(b.dec(2).is_err());
+        a.merge(b.clone());
+        b.merge(a.clone());
+        assert_eq!(a, b);
+        assert_eq!(a.read(), BigInt::from(0));
+    }
+
+    quickcheck! {
+        fn prop_value_never_negative_and_converges(steps: Vec<(u8, u8, u8)>) -> bool {
+            let mut replicas = vec![BCounter::new(); 3];
+            let mut ops = vec![];
+            let mut expected = BigInt::from(0);
+            for (replica, action, amount) in steps {
+                let r = replica as usize % 3;
+                let actor = r as u8;
+                let amount = amount as u64 % 5 + 1;
+                let op = match action % 4 {
+                    0 => Ok(replicas[r].inc_many(actor, amount)),
+                    1 => replicas[r].dec_many(actor, amount),
+                    2 => replicas[r].transfer(actor, (actor + 1) % 3, amount),
+                    _ => {
+                        let state = replicas[r].clone();
+                        replicas[(r + amount as usize) % 3].merge(state);
+                        continue;
+                    }
+                };
+                if let Ok(op) = op {
+                    match &op {
+                        Op::Inc(_) => expected += amount,
+                        Op::Dec(_) => expected -= amount,
+                        Op::Transfer { .. } => (),
+                    }
+                    replicas[r].apply(op.clone());
+                    ops.push(op);
+                }
+                if replicas.iter().any(|c| c.read() < BigInt::from(0)) {
+                    return false;
+                }
+            }
+
+            let mut merged = BCounter::new();
+            for replica in replicas.iter() {
+                merged.merge(replica.clone());
+            }
+            let mut replayed = BCounter::new();
+            for op in ops {
+                if replayed.validate_op(&op).is_err() {
+                    return false;
+                }
+                replayed.apply(op);
+            }
+            // transfers only move rights around, all of them add up to the value
+            let rights: u64 = (0..3).map(|a| merged.rights(&a)).sum();
+            merged == replayed && merged.read() == expected && BigInt::from(rights) == expected
+        }
+    }
+}
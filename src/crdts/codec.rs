@@ -0,0 +1,594 @@
+//! Compact binary encoding of CRDT states and ops.
+//!
+//! The serde derives of the CRDTs write every actor in full wherever it appears, and a
+//! `VClock` in an op often repeats the same handful of actors over and over. This codec
+//! writes each distinct actor once, in a dictionary placed after a small header, and refers
+//! to it by index in the body. Counters, lengths and indexes are LEB128 varints.
+//!
+//! An encoded value is laid out as:
+//!
+//! ```text
+//! version: u8 | kind: u8 | actor count: varint | actors... | body
+//! ```
+//!
+//! `kind` tells which type the body holds, decoding into another type fails instead of
+//! producing garbage.
+use std::collections::BTreeMap;
+use std::error;
+use std::fmt;
+use std::iter::FromIterator;
+
+use num::{BigInt, BigRational, Zero};
+
+use super::dot::{Dot, OrdDot};
+use super::vclock::VClock;
+
+/// Version written in the header; decoding rejects values written by a newer codec.
+pub const CODEC_VERSION: u8 = 1;
+
+/// Kinds of the values the codec knows about.
+pub mod kind {
+    pub const VCLOCK: u8 = 1;
+    pub const DOT: u8 = 2;
+    pub const ORSWOT: u8 = 3;
+    pub const ORSWOT_OP: u8 = 4;
+    pub const MAP: u8 = 5;
+    pub const MAP_OP: u8 = 6;
+    pub const LIST: u8 = 7;
+    pub const LIST_OP: u8 = 8;
+    pub const MVREG: u8 = 9;
+    pub const MVREG_OP: u8 = 10;
+    pub const IDENTIFIER: u8 = 11;
+}
+
+/// The ways decoding can fail.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CodecError {
+    /// The input ended in the middle of a value
+    UnexpectedEof,
+    /// The header carries a version this codec does not understand
+    UnsupportedVersion(u8),
+    /// The header announces another type than the one requested
+    WrongKind { expected: u8, found: u8 },
+    /// A varint does not fit in 64 bits
+    VarintOverflow,
+    /// The body refers to an actor missing from the dictionary
+    UnknownActor(u64),
+    /// An enum tag that matches no variant
+    InvalidTag(u8),
+    /// A value that cannot be represented, like a string with invalid UTF-8
+    InvalidValue,
+    /// Bytes left after the value
+    TrailingBytes(usize),
+}
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&self, f)
+    }
+}
+
+impl error::Error for CodecError {}
+
+pub fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+pub fn read_varint(input: &mut &[u8]) -> Result<u64, CodecError> {
+    let mut value = 0u64;
+    let mut shift = 0;
+    loop {
+        let byte = read_byte(input)?;
+        if shift == 63 && byte > 1 {
+            return Err(CodecError::VarintOverflow);
+        }
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+        shift += 7;
+        if shift > 63 {
+            return Err(CodecError::VarintOverflow);
+        }
+    }
+}
+
+fn read_byte(input: &mut &[u8]) -> Result<u8, CodecError> {
+    let (&byte, rest) = input.split_first().ok_or(CodecError::UnexpectedEof)?;
+    *input = rest;
+    Ok(byte)
+}
+
+fn read_slice<'a>(input: &mut &'a [u8], len: usize) -> Result<&'a [u8], CodecError> {
+    if input.len() < len {
+        return Err(CodecError::UnexpectedEof);
+    }
+    let (bytes, rest) = input.split_at(len);
+    *input = rest;
+    Ok(bytes)
+}
+
+/// A length read from the input, checked against what is left of it so that a corrupted
+/// length cannot make the decoder preallocate gigabytes.
+fn read_len(input: &mut &[u8]) -> Result<usize, CodecError> {
+    let len = read_varint(input)?;
+    if len > input.len() as u64 {
+        return Err(CodecError::UnexpectedEof);
+    }
+    Ok(len as usize)
+}
+
+/// A plain value written as is: actors, set members, map keys, register values.
+pub trait Atom: Sized {
+    fn write(&self, out: &mut Vec<u8>);
+    fn read(input: &mut &[u8]) -> Result<Self, CodecError>;
+}
+
+impl Atom for u8 {
+    fn write(&self, out: &mut Vec<u8>) {
+        out.push(*self);
+    }
+
+    fn read(input: &mut &[u8]) -> Result<Self, CodecError> {
+        read_byte(input)
+    }
+}
+
+macro_rules! varint_atom {
+    ($($t:ty),*) => {
+        $(
+            impl Atom for $t {
+                fn write(&self, out: &mut Vec<u8>) {
+                    write_varint(out, *self as u64);
+                }
+
+                fn read(input: &mut &[u8]) -> Result<Self, CodecError> {
+                    let value = read_varint(input)?;
+                    if value > <$t>::MAX as u64 {
+                        return Err(CodecError::InvalidValue);
+                    }
+                    Ok(value as $t)
+                }
+            }
+        )*
+    };
+}
+
+varint_atom!(u16, u32, u64, usize);
+
+impl Atom for i64 {
+    fn write(&self, out: &mut Vec<u8>) {
+        // zigzag, so that small negative numbers stay short
+        write_varint(out, ((*self << 1) ^ (*self >> 63)) as u64);
+    }
+
+    fn read(input: &mut &[u8]) -> Result<Self, CodecError> {
+        let value = read_varint(input)?;
+        Ok((value >> 1) as i64 ^ -((value & 1) as i64))
+    }
+}
+
+impl Atom for char {
+    fn write(&self, out: &mut Vec<u8>) {
+        write_varint(out, *self as u64);
+    }
+
+    fn read(input: &mut &[u8]) -> Result<Self, CodecError> {
+        let value = read_varint(input)?;
+        std::char::from_u32(value as u32)
+            .filter(|_| value <= u32::MAX as u64)
+            .ok_or(CodecError::InvalidValue)
+    }
+}
+
+impl Atom for Vec<u8> {
+    fn write(&self, out: &mut Vec<u8>) {
+        write_varint(out, self.len() as u64);
+        out.extend_from_slice(self);
+    }
+
+    fn read(input: &mut &[u8]) -> Result<Self, CodecError> {
+        let len = read_len(input)?;
+        Ok(read_slice(input, len)?.to_vec())
+    }
+}
+
+impl Atom for String {
+    fn write(&self, out: &mut Vec<u8>) {
+        write_varint(out, self.len() as u64);
+        out.extend_from_slice(self.as_bytes());
+    }
+
+    fn read(input: &mut &[u8]) -> Result<Self, CodecError> {
+        String::from_utf8(Vec::<u8>::read(input)?).map_err(|_| CodecError::InvalidValue)
+    }
+}
+
+impl Atom for BigInt {
+    fn write(&self, out: &mut Vec<u8>) {
+        self.to_signed_bytes_le().write(out);
+    }
+
+    fn read(input: &mut &[u8]) -> Result<Self, CodecError> {
+        Ok(BigInt::from_signed_bytes_le(&Vec::<u8>::read(input)?))
+    }
+}
+
+impl Atom for BigRational {
+    fn write(&self, out: &mut Vec<u8>) {
+        self.numer().write(out);
+        self.denom().write(out);
+    }
+
+    fn read(input: &mut &[u8]) -> Result<Self, CodecError> {
+        let numer = BigInt::read(input)?;
+        let denom = BigInt::read(input)?;
+        if denom.is_zero() {
+            return Err(CodecError::InvalidValue);
+        }
+        Ok(BigRational::new(numer, denom))
+    }
+}
+
+/// Writes the body of a value, collecting the actors it mentions into the dictionary.
+pub struct Encoder<A: Ord> {
+    actors: Vec<A>,
+    index: BTreeMap<A, u64>,
+    body: Vec<u8>,
+}
+
+impl<A: Ord + Clone + Atom> Encoder<A> {
+    pub fn new() -> Self {
+        Encoder {
+            actors: vec![],
+            index: BTreeMap::new(),
+            body: vec![],
+        }
+    }
+
+    pub fn varint(&mut self, value: u64) {
+        write_varint(&mut self.body, value);
+    }
+
+    pub fn tag(&mut self, tag: u8) {
+        self.body.push(tag);
+    }
+
+    pub fn atom<T: Atom>(&mut self, value: &T) {
+        value.write(&mut self.body);
+    }
+
+    /// Writes a sequence of atoms preceded by its length.
+    pub fn atoms<'b, T: Atom + 'b, I>(&mut self, items: I)
+    where
+        I: IntoIterator<Item = &'b T>,
+        I::IntoIter: ExactSizeIterator,
+    {
+        let items = items.into_iter();
+        self.varint(items.len() as u64);
+        for item in items {
+            item.write(&mut self.body);
+        }
+    }
+
+    /// Writes the dictionary index of `actor`, adding it on its first appearance.
+    pub fn actor(&mut self, actor: &A) {
+        let index = match self.index.get(actor) {
+            Some(index) => *index,
+            None => {
+                let index = self.actors.len() as u64;
+                self.actors.push(actor.clone());
+                self.index.insert(actor.clone(), index);
+                index
+            }
+        };
+        self.varint(index);
+    }
+
+    /// Prepends the header and the actor dictionary to the body.
+    pub fn finish(self, kind: u8) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.body.len() + 2 + self.actors.len() * 2);
+        out.push(CODEC_VERSION);
+        out.push(kind);
+        write_varint(&mut out, self.actors.len() as u64);
+        for actor in self.actors.iter() {
+            actor.write(&mut out);
+        }
+        out.extend_from_slice(&self.body);
+        out
+    }
+}
+
+impl<A: Ord + Clone + Atom> Default for Encoder<A> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Reads the body of a value, resolving actors through the dictionary of its header.
+pub struct Decoder<'a, A> {
+    actors: Vec<A>,
+    input: &'a [u8],
+}
+
+impl<'a, A: Clone + Atom> Decoder<'a, A> {
+    /// Checks the header of `input` and reads its actor dictionary.
+    pub fn new(mut input: &'a [u8], kind: u8) -> Result<Self, CodecError> {
+        let version = read_byte(&mut input)?;
+        if version > CODEC_VERSION {
+            return Err(CodecError::UnsupportedVersion(version));
+        }
+        let found = read_byte(&mut input)?;
+        if found != kind {
+            return Err(CodecError::WrongKind { expected: kind, found });
+        }
+        let count = read_len(&mut input)?;
+        let mut actors = Vec::with_capacity(count);
+        for _ in 0..count {
+            actors.push(A::read(&mut input)?);
+        }
+        Ok(Decoder { actors, input })
+    }
+
+    pub fn varint(&mut self) -> Result<u64, CodecError> {
+        read_varint(&mut self.input)
+    }
+
+    /// A count of items about to be read, each taking at least a byte.
+    pub fn count(&mut self) -> Result<usize, CodecError> {
+        read_len(&mut self.input)
+    }
+
+    pub fn tag(&mut self) -> Result<u8, CodecError> {
+        read_byte(&mut self.input)
+    }
+
+    pub fn atom<T: Atom>(&mut self) -> Result<T, CodecError> {
+        T::read(&mut self.input)
+    }
+
+    /// Reads a sequence written by [Encoder::atoms].
+    pub fn atoms<T: Atom, C: FromIterator<T>>(&mut self) -> Result<C, CodecError> {
+        let count = self.count()?;
+        (0..count).map(|_| self.atom()).collect()
+    }
+
+    pub fn actor(&mut self) -> Result<A, CodecError> {
+        let index = self.varint()?;
+        self.actors.get(index as usize).cloned().ok_or(CodecError::UnknownActor(index))
+    }
+
+    /// Fails if bytes are left once the value was read.
+    pub fn finish(self) -> Result<(), CodecError> {
+        match self.input.len() {
+            0 => Ok(()),
+            left => Err(CodecError::TrailingBytes(left)),
+        }
+    }
+}
+
+/// A CRDT state or op the codec can write, its actors going through the dictionary.
+pub trait Compact<A: Ord>: Sized {
+    /// Written in the header, see [kind].
+    const KIND: u8;
+
+    fn encode_body(&self, enc: &mut Encoder<A>);
+
+    fn decode_body(dec: &mut Decoder<A>) -> Result<Self, CodecError>;
+}
+
+/// Encodes `value` along with its header and actor dictionary.
+pub fn to_compact<A: Ord + Clone + Atom, T: Compact<A>>(value: &T) -> Vec<u8> {
+    let mut enc = Encoder::new();
+    value.encode_body(&mut enc);
+    enc.finish(T::KIND)
+}
+
+/// Decodes a value written by [to_compact], all of `bytes` must be used.
+pub fn from_compact<A: Ord + Clone + Atom, T: Compact<A>>(bytes: &[u8]) -> Result<T, CodecError> {
+    let mut dec = Decoder::new(bytes, T::KIND)?;
+    let value = T::decode_body(&mut dec)?;
+    dec.finish()?;
+    Ok(value)
+}
+
+impl<A: Ord + Clone + Atom> Compact<A> for VClock<A> {
+    const KIND: u8 = kind::VCLOCK;
+
+    fn encode_body(&self, enc: &mut Encoder<A>) {
+        enc.varint(self.dots.len() as u64);
+        for (actor, counter) in self.dots.iter() {
+            enc.actor(actor);
+            enc.varint(*counter);
+        }
+    }
+
+    fn decode_body(dec: &mut Decoder<A>) -> Result<Self, CodecError> {
+        let mut clock = VClock::new();
+        for _ in 0..dec.count()? {
+            let actor = dec.actor()?;
+            let counter = dec.varint()?;
+            clock.dots.insert(actor, counter);
+        }
+        Ok(clock)
+    }
+}
+
+impl<A: Ord + Clone + Atom> Compact<A> for Dot<A> {
+    const KIND: u8 = kind::DOT;
+
+    fn encode_body(&self, enc: &mut Encoder<A>) {
+        enc.actor(&self.actor);
+        enc.varint(self.counter);
+    }
+
+    fn decode_body(dec: &mut Decoder<A>) -> Result<Self, CodecError> {
+        let actor = dec.actor()?;
+        Ok(Dot::new(actor, dec.varint()?))
+    }
+}
+
+impl<A: Ord + Clone + Atom> Compact<A> for OrdDot<A> {
+    const KIND: u8 = kind::DOT;
+
+    fn encode_body(&self, enc: &mut Encoder<A>) {
+        enc.actor(&self.actor);
+        enc.varint(self.counter);
+    }
+
+    fn decode_body(dec: &mut Decoder<A>) -> Result<Self, CodecError> {
+        Ok(Dot::decode_body(dec)?.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crdts::list::{self, List};
+    use crate::crdts::map::{self, Map};
+    use crate::crdts::mvreg::MVReg;
+    use crate::crdts::orswot::{self, Orswot};
+    use crate::crdts::traits::CmRDT;
+    use quickcheck::quickcheck;
+
+    fn round_trip<A, T>(value: &T) -> T
+    where
+        A: Ord + Clone + Atom,
+        T: Compact<A>,
+    {
+        from_compact(&to_compact(value)).unwrap()
+    }
+
+    /// Builds a list out of `(index, char, delete)` edits of actors 0 to 2.
+    fn edit_list(edits: &[(u8, char, bool)]) -> (List<char, u64>, Vec<list::Op<char, u64>>) {
+        let mut list = List::new();
+        let mut ops = vec![];
+        for (i, (ix, c, delete)) in edits.iter().enumerate() {
+            let actor = i as u64 % 3;
+            let op = match delete {
+                true => list.delete_index(*ix as usize, actor),
+                false => Some(list.insert_index(*ix as usize, *c, actor)),
+            };
+            if let Some(op) = op {
+                list.apply(op.clone());
+                ops.push(op);
+            }
+        }
+        (list, ops)
+    }
+
+    type RegisterMap = Map<u8, MVReg<u8, u64>, u64>;
+    type RegisterMapOp = map::Op<u8, MVReg<u8, u64>, u64>;
+
+    /// Builds a map of registers out of `(key, value, remove)` edits of actors 0 to 2.
+    fn edit_map(edits: &[(u8, u8, bool)]) -> (RegisterMap, Vec<RegisterMapOp>) {
+        let mut map = Map::new();
+        let mut ops = vec![];
+        for (i, (key, value, remove)) in edits.iter().enumerate() {
+            let actor = i as u64 % 3;
+            let op = match remove {
+                true => map.rm(*key % 8, map.get(&(*key % 8)).derive_rm_ctx()),
+                false => {
+                    let ctx = map.read_ctx().derive_add_ctx(actor);
+                    map.update(*key % 8, ctx, |reg: &MVReg<u8, u64>, ctx| reg.write(*value, ctx))
+                }
+            };
+            map.apply(op.clone());
+            ops.push(op);
+        }
+        (map, ops)
+    }
+
+    quickcheck! {
+        fn prop_vclock_round_trips(clock: VClock<u8>) -> bool {
+            round_trip(&clock) == clock
+        }
+
+        fn prop_dot_round_trips(dot: Dot<u64>) -> bool {
+            round_trip(&dot) == dot
+        }
+
+        fn prop_orswot_op_round_trips(op: orswot::Op<u8, u8>) -> bool {
+            round_trip(&op) == op
+        }
+
+        fn prop_orswot_round_trips(ops: Vec<orswot::Op<u8, u8>>) -> bool {
+            let mut set = Orswot::new();
+            for op in ops {
+                set.apply(op);
+            }
+            round_trip(&set) == set
+        }
+
+        fn prop_map_round_trips(edits: Vec<(u8, u8, bool)>) -> bool {
+            let (map, ops) = edit_map(&edits);
+            round_trip(&map) == map && ops.iter().all(|op| &round_trip(op) == op)
+        }
+
+        fn prop_list_round_trips(edits: Vec<(u8, char, bool)>) -> bool {
+            let (list, ops) = edit_list(&edits);
+            round_trip(&list) == list && ops.iter().all(|op| &round_trip(op) == op)
+        }
+
+        fn prop_atoms_round_trip(n: i64, s: String, b: Vec<u8>) -> bool {
+            let mut out = vec![];
+            n.write(&mut out);
+            s.write(&mut out);
+            b.write(&mut out);
+            let mut input = &out[..];
+            i64::read(&mut input) == Ok(n)
+                && String::read(&mut input) == Ok(s)
+                && Vec::<u8>::read(&mut input) == Ok(b)
+                && input.is_empty()
+        }
+    }
+
+    #[test]
+    fn decoding_checks_the_header() {
+        let bytes = to_compact(&VClock::from(Dot::new(7u64, 3)));
+        assert_eq!(
+            from_compact::<u64, Dot<u64>>(&bytes),
+            Err(CodecError::WrongKind { expected: kind::DOT, found: kind::VCLOCK })
+        );
+
+        let mut newer = bytes.clone();
+        newer[0] = CODEC_VERSION + 1;
+        assert_eq!(
+            from_compact::<u64, VClock<u64>>(&newer),
+            Err(CodecError::UnsupportedVersion(CODEC_VERSION + 1))
+        );
+
+        assert_eq!(from_compact::<u64, VClock<u64>>(&bytes[..bytes.len() - 1]), Err(CodecError::UnexpectedEof));
+        let mut longer = bytes;
+        longer.push(0);
+        assert_eq!(from_compact::<u64, VClock<u64>>(&longer), Err(CodecError::TrailingBytes(1)));
+    }
+
+    #[test]
+    fn smaller_than_cbor() {
+        // node actors are random u64s, which CBOR writes in 9 bytes each time
+        let actors: Vec<u64> = (1..=5).map(|i| u64::MAX / i).collect();
+        let mut set: Orswot<Vec<u8>, u64> = Orswot::new();
+        for (i, actor) in actors.iter().cycle().take(40).enumerate() {
+            let op = set.add(format!("member-{}", i).into_bytes(), set.read_ctx().derive_add_ctx(*actor));
+            set.apply(op);
+        }
+        let (compact, cbor) = (to_compact(&set).len(), serde_cbor::to_vec(&set).unwrap().len());
+        assert!(compact * 2 < cbor, "compact {} bytes, cbor {} bytes", compact, cbor);
+
+        // a single op mentions each actor about once, the dictionary has less to save there
+        let members: Vec<Vec<u8>> = (0..10).map(|i| format!("member-{}", i).into_bytes()).collect();
+        let rm = set.rm_all(members, set.read_ctx().derive_rm_ctx());
+        assert!(to_compact(&rm).len() < serde_cbor::to_vec(&rm).unwrap().len());
+
+        let (list, ops) = edit_list(&(0..50).map(|i| (i, 'x', i % 7 == 0)).collect::<Vec<_>>());
+        assert!(to_compact(&list).len() < serde_cbor::to_vec(&list).unwrap().len());
+        let compact: usize = ops.iter().map(|op| to_compact(op).len()).sum();
+        let cbor: usize = ops.iter().map(|op| serde_cbor::to_vec(op).unwrap().len()).sum();
+        assert!(compact < cbor, "compact {} bytes, cbor {} bytes", compact, cbor);
+    }
+}
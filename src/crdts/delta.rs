@@ -0,0 +1,372 @@
+//! Anti-entropy for delta-state CRDT's.
+//!
+//! Every replica pushes the deltas it produces (and the delta-groups it
+//! receives) into a `DeltaBuffer`. For each peer the buffer joins the deltas
+//! the peer has not acknowledged yet into a single `DeltaGroup`; once every
+//! peer acknowledged a delta it is garbage collected.
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+
+use serde::{Deserialize, Serialize};
+
+use super::traits::{CmRDT, CvRDT};
+use super::dot::Dot;
+use super::vclock::VClock;
+
+/// Joins the dots of a single member (or key) held by two replicas.
+///
+/// A dot survives if the other side holds it too, or if the other side's
+/// context has not observed it; otherwise the other side removed it. Returns
+/// the surviving dots together with our dots that were dropped.
+pub(crate) fn join_dots<A: Ord + Clone + Debug>(
+    ours: &VClock<A>,
+    our_ctx: &VClock<A>,
+    theirs: &VClock<A>,
+    their_ctx: &VClock<A>,
+) -> (VClock<A>, VClock<A>) {
+    let mut joined = VClock::new();
+    let mut dropped = VClock::new();
+
+    for Dot { actor, counter } in ours.iter() {
+        let dot = Dot::new(actor.clone(), counter);
+        if their_ctx.get(actor) < counter || theirs.get(actor) == counter {
+            joined.apply(dot);
+        } else {
+            dropped.apply(dot);
+        }
+    }
+
+    for Dot { actor, counter } in theirs.iter() {
+        if our_ctx.get(actor) < counter || ours.get(actor) == counter {
+            joined.apply(Dot::new(actor.clone(), counter));
+        }
+    }
+
+    (joined, dropped)
+}
+
+/// The join of every delta a peer has not acknowledged yet.
+///
+/// Once merged, the receiver acknowledges `seq` back to the sender.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeltaGroup<D> {
+    /// Sequence number of the newest delta in this group
+    pub seq: u64,
+    /// The joined deltas
+    pub delta: D,
+}
+
+/// Buffers deltas until every peer has acknowledged them.
+///
+/// Peers are expected to receive the full state when they are added; from then
+/// on `delta_group` yields everything they are missing, in causal order.
+#[derive(Debug, Clone)]
+pub struct DeltaBuffer<D, P: Ord> {
+    seq: u64,
+    deltas: BTreeMap<u64, D>,
+    acks: BTreeMap<P, u64>,
+}
+
+impl<D, P: Ord> Default for DeltaBuffer<D, P> {
+    fn default() -> Self {
+        Self {
+            seq: 0,
+            deltas: BTreeMap::new(),
+            acks: BTreeMap::new(),
+        }
+    }
+}
+
+impl<D: CvRDT + Default + Clone, P: Ord> DeltaBuffer<D, P> {
+    /// Returns a new, empty `DeltaBuffer` without any peers.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Sequence number of the newest delta pushed into the buffer.
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    /// Number of deltas still waiting for an acknowledgement.
+    pub fn len(&self) -> usize {
+        self.deltas.len()
+    }
+
+    /// Returns `true` if every delta has been acknowledged by every peer.
+    pub fn is_empty(&self) -> bool {
+        self.deltas.is_empty()
+    }
+
+    /// Start tracking a peer. The peer is considered to have seen everything
+    /// pushed so far, so it must be bootstrapped with the full state.
+    pub fn add_peer(&mut self, peer: P) {
+        let seq = self.seq;
+        self.acks.entry(peer).or_insert(seq);
+    }
+
+    /// Stop tracking a peer, releasing the deltas only it was missing.
+    pub fn remove_peer(&mut self, peer: &P) {
+        self.acks.remove(peer);
+        self.gc();
+    }
+
+    /// The last sequence number acknowledged by a peer.
+    pub fn acked(&self, peer: &P) -> Option<u64> {
+        self.acks.get(peer).cloned()
+    }
+
+    /// Buffer a delta produced locally or received from another replica.
+    pub fn push(&mut self, delta: D) -> u64 {
+        self.seq += 1;
+        if self.acks.is_empty() {
+            // nobody to ship it to
+            return self.seq;
+        }
+        self.deltas.insert(self.seq, delta);
+        self.seq
+    }
+
+    /// Join the deltas the peer has not acknowledged yet into a delta-group.
+    ///
+    /// Returns `None` if the peer is unknown or already up to date.
+    pub fn delta_group(&self, peer: &P) -> Option<DeltaGroup<D>> {
+        let acked = *self.acks.get(peer)?;
+        let mut pending = self.deltas.range(acked + 1..).peekable();
+        pending.peek()?;
+
+        let mut group = DeltaGroup {
+            seq: acked,
+            delta: D::default(),
+        };
+        for (seq, delta) in pending {
+            group.seq = *seq;
+            group.delta.merge(delta.clone());
+        }
+        Some(group)
+    }
+
+    /// Record that the peer merged every delta up to `seq`, garbage collecting
+    /// the deltas acknowledged by all peers.
+    pub fn ack(&mut self, peer: &P, seq: u64) {
+        if let Some(acked) = self.acks.get_mut(peer) {
+            if seq > *acked {
+                *acked = seq;
+                self.gc();
+            }
+        }
+    }
+
+    fn gc(&mut self) {
+        let floor = self.acks.values().min().cloned().unwrap_or(self.seq);
+        self.deltas = self.deltas.split_off(&(floor + 1));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::crdts::gcounter::GCounter;
+    use crate::crdts::map::Map;
+    use crate::crdts::mvreg::MVReg;
+    use crate::crdts::orswot::Orswot;
+    use crate::crdts::pncounter::PNCounter;
+    use crate::crdts::traits::DeltaCRDT;
+    use quickcheck::quickcheck;
+
+    const REPLICAS: u8 = 3;
+
+    struct Replica<T: DeltaCRDT> {
+        state: T,
+        buffer: DeltaBuffer<T::Delta, u8>,
+    }
+
+    fn sync<T: DeltaCRDT + Clone + PartialEq>(replicas: &mut [Replica<T>], from: usize, to: usize) {
+        if let Some(group) = replicas[from].buffer.delta_group(&(to as u8)) {
+            let receiver = &mut replicas[to];
+            let before = receiver.state.clone();
+            receiver.state.merge_delta(group.delta.clone());
+            if receiver.state != before {
+                // only forward what was news to us
+                receiver.buffer.push(group.delta);
+            }
+            replicas[from].buffer.ack(&(to as u8), group.seq);
+        }
+    }
+
+    /// Replays `ops` over a few replicas exchanging delta-groups, then returns
+    /// the replicas after a final round of anti-entropy together with the full
+    /// state merge of the replicas taken before that round.
+    fn simulate<T, F>(ops: &[(u8, u8, u8)], mutate: F) -> (Vec<Replica<T>>, T)
+    where
+        T: DeltaCRDT + Default + Clone + PartialEq,
+        F: Fn(&T, u8, u8) -> T::Delta,
+    {
+        let mut replicas: Vec<Replica<T>> = (0..REPLICAS)
+            .map(|i| {
+                let mut buffer = DeltaBuffer::new();
+                (0..REPLICAS).filter(|p| *p != i).for_each(|p| buffer.add_peer(p));
+                Replica {
+                    state: T::default(),
+                    buffer,
+                }
+            })
+            .collect();
+
+        for (kind, a, b) in ops.iter().cloned() {
+            let from = (a % REPLICAS) as usize;
+            let to = (b % REPLICAS) as usize;
+            if kind % 3 == 0 {
+                if from != to {
+                    sync(&mut replicas, from, to);
+                }
+            } else {
+                let replica = &mut replicas[from];
+                let delta = mutate(&replica.state, from as u8, b);
+                replica.state.merge_delta(delta.clone());
+                replica.buffer.push(delta);
+            }
+        }
+
+        let mut full = T::default();
+        for replica in replicas.iter() {
+            full.merge(replica.state.clone());
+        }
+
+        // forwarded delta-groups need more than one round to settle
+        for _ in 0..REPLICAS {
+            for from in 0..REPLICAS as usize {
+                for to in 0..REPLICAS as usize {
+                    if from != to {
+                        sync(&mut replicas, from, to);
+                    }
+                }
+            }
+        }
+
+        (replicas, full)
+    }
+
+    fn mutate_orswot(set: &Orswot<u8, u8>, actor: u8, arg: u8) -> <Orswot<u8, u8> as DeltaCRDT>::Delta {
+        let member = arg % 8;
+        if arg.is_multiple_of(2) {
+            set.add_delta(member, set.read_ctx().derive_add_ctx(actor))
+        } else {
+            set.rm_delta(member, set.contains(&member).derive_rm_ctx())
+        }
+    }
+
+    type TestMap = Map<u8, Orswot<u8, u8>, u8>;
+
+    fn mutate_map(map: &TestMap, actor: u8, arg: u8) -> <TestMap as DeltaCRDT>::Delta {
+        let key = arg % 4;
+        if arg.is_multiple_of(3) {
+            map.rm_delta(key, map.get(&key).derive_rm_ctx())
+        } else {
+            let ctx = map.read_ctx().derive_add_ctx(actor);
+            map.update_delta(key, ctx, |set, ctx| set.add_delta(arg % 8, ctx))
+        }
+    }
+
+    quickcheck! {
+        fn prop_gcounter_delta_merge_converges(ops: Vec<(u8, u8, u8)>) -> bool {
+            let (replicas, full) = simulate(&ops, |c: &GCounter<u8>, actor, arg| {
+                c.inc_many_delta(actor, arg as u64 % 4 + 1)
+            });
+            replicas.iter().all(|r| r.state == full && r.buffer.is_empty())
+        }
+
+        fn prop_pncounter_delta_merge_converges(ops: Vec<(u8, u8, u8)>) -> bool {
+            let (replicas, full) = simulate(&ops, |c: &PNCounter<u8>, actor, arg| {
+                if arg.is_multiple_of(2) {
+                    c.inc_delta(actor)
+                } else {
+                    c.dec_delta(actor)
+                }
+            });
+            replicas.iter().all(|r| r.state == full && r.buffer.is_empty())
+        }
+
+        fn prop_orswot_delta_merge_converges(ops: Vec<(u8, u8, u8)>) -> bool {
+            let (replicas, full) = simulate(&ops, mutate_orswot);
+            replicas.iter().all(|r| r.state == full && r.buffer.is_empty())
+        }
+
+        fn prop_mvreg_delta_merge_converges(ops: Vec<(u8, u8, u8)>) -> bool {
+            let (replicas, full) = simulate(&ops, |reg: &MVReg<u8, u8>, actor, arg| {
+                reg.write_delta(arg, reg.read_ctx().derive_add_ctx(actor))
+            });
+            replicas.iter().all(|r| r.state == full)
+        }
+
+        fn prop_map_delta_merge_converges(ops: Vec<(u8, u8, u8)>) -> bool {
+            let (replicas, full) = simulate(&ops, mutate_map);
+            replicas.iter().all(|r| r.state == full && r.buffer.is_empty())
+        }
+
+        fn prop_orswot_delta_group_matches_deltas(ops: Vec<u8>) -> bool {
+            let mut sequential = Orswot::new();
+            let mut group = <Orswot<u8, u8> as DeltaCRDT>::Delta::default();
+            for (i, arg) in ops.into_iter().enumerate() {
+                let delta = mutate_orswot(&sequential, i as u8 % REPLICAS, arg);
+                sequential.merge_delta(delta.clone());
+                group.merge(delta);
+            }
+
+            let mut grouped = Orswot::new();
+            grouped.merge_delta(group);
+            grouped == sequential
+        }
+
+        fn prop_map_delta_group_matches_deltas(ops: Vec<u8>) -> bool {
+            let mut sequential = TestMap::new();
+            let mut group = <TestMap as DeltaCRDT>::Delta::default();
+            for (i, arg) in ops.into_iter().enumerate() {
+                let delta = mutate_map(&sequential, i as u8 % REPLICAS, arg);
+                sequential.merge_delta(delta.clone());
+                group.merge(delta);
+            }
+
+            let mut grouped = TestMap::new();
+            grouped.merge_delta(group);
+            grouped == sequential
+        }
+    }
+
+    #[test]
+    fn test_buffer_gc_waits_for_every_ack() {
+        let mut buffer: DeltaBuffer<GCounter<u8>, u8> = DeltaBuffer::new();
+        buffer.add_peer(1);
+        buffer.add_peer(2);
+
+        let counter = GCounter::new();
+        buffer.push(counter.inc_delta(0));
+        buffer.push(counter.inc_many_delta(0, 2));
+        assert_eq!(buffer.len(), 2);
+
+        let group = buffer.delta_group(&1).unwrap();
+        assert_eq!(group.seq, 2);
+        assert_eq!(group.delta.read(), 2u8.into());
+
+        buffer.ack(&1, group.seq);
+        assert_eq!(buffer.len(), 2);
+        assert_eq!(buffer.delta_group(&1), None);
+
+        buffer.ack(&2, 1);
+        assert_eq!(buffer.len(), 1);
+        buffer.remove_peer(&2);
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn test_late_peer_starts_from_current_seq() {
+        let mut buffer: DeltaBuffer<GCounter<u8>, u8> = DeltaBuffer::new();
+        buffer.add_peer(1);
+        buffer.push(GCounter::new().inc_delta(0));
+
+        buffer.add_peer(2);
+        assert_eq!(buffer.acked(&2), Some(1));
+        assert_eq!(buffer.delta_group(&2), None);
+        assert!(buffer.delta_group(&1).is_some());
+    }
+}
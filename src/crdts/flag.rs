@@ -0,0 +1,386 @@
+//! Enable-wins and disable-wins flags.
+//!
+//! Both flags keep, for every actor, the dot of its latest enable (and, for the disable-wins
+//! flag, of its latest disable) together with the clock of everything the flag has seen.
+//! An operation removes the dots it observed, so what stays are the dots concurrent with it,
+//! and the flag resolves them in favour of enabling or disabling.
+use core::convert::Infallible;
+use core::fmt::Debug;
+
+use serde::{Deserialize, Serialize};
+
+use super::ctx::{AddCtx, ReadCtx, RmCtx};
+use super::delta::join_dots;
+use super::dot::Dot;
+use super::traits::{CmRDT, CvRDT, ResetRemove};
+use super::vclock::VClock;
+use crate::memory::MemoryUsage;
+
+/// `EWFlag` is a flag where an enable concurrent with a disable wins.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EWFlag<A: Ord> {
+    clock: VClock<A>,
+    enables: VClock<A>,
+}
+
+/// An Op which is produced through from mutating an `EWFlag`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EWOp<A: Ord> {
+    /// Enable the flag
+    Enable {
+        /// witnessing dot
+        dot: Dot<A>,
+    },
+    /// Disable the flag
+    Disable {
+        /// the enables observed by the disabling replica
+        clock: VClock<A>,
+    },
+}
+
+impl<A: Ord> Default for EWFlag<A> {
+    fn default() -> Self {
+        Self {
+            clock: Default::default(),
+            enables: Default::default(),
+        }
+    }
+}
+
+impl<A: Ord + Clone + Debug> CmRDT for EWFlag<A> {
+    type Op = EWOp<A>;
+    type Validation = Infallible;
+
+    fn validate_op(&self, _op: &Self::Op) -> Result<(), Self::Validation> {
+        Ok(())
+    }
+
+    fn apply(&mut self, op: Self::Op) {
+        match op {
+            EWOp::Enable { dot } => {
+                if self.clock.get(&dot.actor) < dot.counter {
+                    self.enables.apply(dot.clone());
+                    self.clock.apply(dot);
+                }
+            }
+            EWOp::Disable { clock } => {
+                self.enables.reset_remove(&clock);
+                // enables the disable observed but we have yet to see are already disabled
+                self.clock.merge(clock);
+            }
+        }
+    }
+}
+
+impl<A: Ord + Clone + Debug> CvRDT for EWFlag<A> {
+    type Validation = Infallible;
+
+    fn validate_merge(&self, _other: &Self) -> Result<(), Self::Validation> {
+        Ok(())
+    }
+
+    fn merge(&mut self, other: Self) {
+        let (enables, _) = join_dots(&self.enables, &self.clock, &other.enables, &other.clock);
+        self.enables = enables;
+        self.clock.merge(other.clock);
+    }
+}
+
+impl<A: Ord> ResetRemove<A> for EWFlag<A> {
+    fn reset_remove(&mut self, clock: &VClock<A>) {
+        self.clock.reset_remove(clock);
+        self.enables.reset_remove(clock);
+    }
+}
+
+impl<A: Ord + MemoryUsage> MemoryUsage for EWFlag<A> {
+    fn heap_usage(&self, samples: usize) -> usize {
+        self.clock.heap_usage(samples) + self.enables.heap_usage(samples)
+    }
+}
+
+impl<A: Ord + Clone> EWFlag<A> {
+    /// Produce a new, disabled, `EWFlag`.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Generate an Op to enable the flag.
+    pub fn enable(&self, ctx: AddCtx<A>) -> EWOp<A> {
+        EWOp::Enable { dot: ctx.dot }
+    }
+
+    /// Generate an Op to disable the flag, concurrent enables win over it.
+    pub fn disable(&self, ctx: RmCtx<A>) -> EWOp<A> {
+        EWOp::Disable { clock: ctx.clock }
+    }
+
+    /// Return whether the flag is enabled.
+    pub fn read(&self) -> ReadCtx<bool, A> {
+        ReadCtx {
+            add_clock: self.clock.clone(),
+            rm_clock: self.clock.clone(),
+            val: !self.enables.is_empty(),
+        }
+    }
+
+    /// Retrieve the current read context
+    pub fn read_ctx(&self) -> ReadCtx<(), A> {
+        ReadCtx {
+            add_clock: self.clock.clone(),
+            rm_clock: self.clock.clone(),
+            val: (),
+        }
+    }
+}
+
+/// `DWFlag` is a flag where a disable concurrent with an enable wins.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DWFlag<A: Ord> {
+    clock: VClock<A>,
+    enables: VClock<A>,
+    disables: VClock<A>,
+}
+
+/// An Op which is produced through from mutating a `DWFlag`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DWOp<A: Ord> {
+    /// Enable the flag
+    Enable {
+        /// witnessing dot
+        dot: Dot<A>,
+        /// the disables observed by the enabling replica
+        clock: VClock<A>,
+    },
+    /// Disable the flag
+    Disable {
+        /// witnessing dot
+        dot: Dot<A>,
+        /// the enables observed by the disabling replica
+        clock: VClock<A>,
+    },
+}
+
+impl<A: Ord> Default for DWFlag<A> {
+    fn default() -> Self {
+        Self {
+            clock: Default::default(),
+            enables: Default::default(),
+            disables: Default::default(),
+        }
+    }
+}
+
+impl<A: Ord + Clone + Debug> CmRDT for DWFlag<A> {
+    type Op = DWOp<A>;
+    type Validation = Infallible;
+
+    fn validate_op(&self, _op: &Self::Op) -> Result<(), Self::Validation> {
+        Ok(())
+    }
+
+    fn apply(&mut self, op: Self::Op) {
+        let (dot, clock, enable) = match op {
+            DWOp::Enable { dot, clock } => (dot, clock, true),
+            DWOp::Disable { dot, clock } => (dot, clock, false),
+        };
+        if self.clock.get(&dot.actor) >= dot.counter {
+            // we've seen this op already
+            return;
+        }
+        self.enables.reset_remove(&clock);
+        self.disables.reset_remove(&clock);
+        match enable {
+            true => self.enables.apply(dot.clone()),
+            false => self.disables.apply(dot.clone()),
+        }
+        self.clock.merge(clock);
+        self.clock.apply(dot);
+    }
+}
+
+impl<A: Ord + Clone + Debug> CvRDT for DWFlag<A> {
+    type Validation = Infallible;
+
+    fn validate_merge(&self, _other: &Self) -> Result<(), Self::Validation> {
+        Ok(())
+    }
+
+    fn merge(&mut self, other: Self) {
+        let (enables, _) = join_dots(&self.enables, &self.clock, &other.enables, &other.clock);
+        let (disables, _) = join_dots(&self.disables, &self.clock, &other.disables, &other.clock);
+        self.enables = enables;
+        self.disables = disables;
+        self.clock.merge(other.clock);
+    }
+}
+
+impl<A: Ord> ResetRemove<A> for DWFlag<A> {
+    fn reset_remove(&mut self, clock: &VClock<A>) {
+        self.clock.reset_remove(clock);
+        self.enables.reset_remove(clock);
+        self.disables.reset_remove(clock);
+    }
+}
+
+impl<A: Ord + MemoryUsage> MemoryUsage for DWFlag<A> {
+    fn heap_usage(&self, samples: usize) -> usize {
+        self.clock.heap_usage(samples) + self.enables.heap_usage(samples) + self.disables.heap_usage(samples)
+    }
+}
+
+impl<A: Ord + Clone> DWFlag<A> {
+    /// Produce a new, disabled, `DWFlag`.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Generate an Op to enable the flag, concurrent disables win over it.
+    pub fn enable(&self, ctx: AddCtx<A>) -> DWOp<A> {
+        DWOp::Enable {
+            dot: ctx.dot,
+            clock: ctx.clock,
+        }
+    }
+
+    /// Generate an Op to disable the flag.
+    pub fn disable(&self, ctx: AddCtx<A>) -> DWOp<A> {
+        DWOp::Disable {
+            dot: ctx.dot,
+            clock: ctx.clock,
+        }
+    }
+
+    /// Return whether the flag is enabled: an enable was seen and no disable is left
+    /// concurrent with it.
+    pub fn read(&self) -> ReadCtx<bool, A> {
+        ReadCtx {
+            add_clock: self.clock.clone(),
+            rm_clock: self.clock.clone(),
+            val: !self.enables.is_empty() && self.disables.is_empty(),
+        }
+    }
+
+    /// Retrieve the current read context
+    pub fn read_ctx(&self) -> ReadCtx<(), A> {
+        ReadCtx {
+            add_clock: self.clock.clone(),
+            rm_clock: self.clock.clone(),
+            val: (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::crdts::map::Map;
+    use quickcheck::quickcheck;
+
+    #[test]
+    fn concurrent_enable_and_disable() {
+        let mut ew = EWFlag::new();
+        ew.apply(ew.enable(ew.read_ctx().derive_add_ctx(1u8)));
+        let mut dw = DWFlag::new();
+        dw.apply(dw.enable(dw.read_ctx().derive_add_ctx(1u8)));
+        let (mut ew2, mut dw2) = (ew.clone(), dw.clone());
+
+        ew.apply(ew.disable(ew.read_ctx().derive_rm_ctx()));
+        ew2.apply(ew2.enable(ew2.read_ctx().derive_add_ctx(2)));
+        dw.apply(dw.disable(dw.read_ctx().derive_add_ctx(1)));
+        dw2.apply(dw2.enable(dw2.read_ctx().derive_add_ctx(2)));
+        assert!(!ew.read().val);
+        assert!(!dw.read().val);
+
+        ew.merge(ew2.clone());
+        ew2.merge(ew.clone());
+        dw.merge(dw2.clone());
+        dw2.merge(dw.clone());
+        assert_eq!(ew, ew2);
+        assert_eq!(dw, dw2);
+        assert!(ew.read().val);
+        assert!(!dw.read().val);
+
+        // an enable that saw the disable turns the disable-wins flag back on
+        dw.apply(dw.enable(dw.read_ctx().derive_add_ctx(2)));
+        assert!(dw.read().val);
+    }
+
+    #[test]
+    fn disable_before_the_enables_it_observed() {
+        let mut a = EWFlag::new();
+        let enable = a.enable(a.read_ctx().derive_add_ctx(1u8));
+        a.apply(enable.clone());
+        let disable = a.disable(a.read_ctx().derive_rm_ctx());
+
+        let mut b = EWFlag::new();
+        b.apply(disable);
+        b.apply(enable);
+        assert!(!b.read().val);
+    }
+
+    #[test]
+    fn nested_in_map() {
+        let mut m: Map<u8, EWFlag<u8>, u8> = Map::new();
+        let op = m.update(1, m.read_ctx().derive_add_ctx(1), |f, ctx| f.enable(ctx));
+        m.apply(op);
+        assert_eq!(m.get(&1).val.map(|f| f.read().val), Some(true));
+
+        let op = m.rm(1, m.get(&1).derive_rm_ctx());
+        m.apply(op);
+        assert_eq!(m.get(&1).val, None);
+
+        let mut m: Map<u8, DWFlag<u8>, u8> = Map::new();
+        let op = m.update(1, m.read_ctx().derive_add_ctx(1), |f, ctx| f.disable(ctx));
+        m.apply(op);
+        assert_eq!(m.get(&1).val.map(|f| f.read().val), Some(false));
+        let op = m.update(1, m.read_ctx().derive_add_ctx(1), |f, ctx| f.enable(ctx));
+        m.apply(op);
+        assert_eq!(m.get(&1).val.map(|f| f.read().val), Some(true));
+    }
+
+    quickcheck! {
+        fn prop_merge_and_ops_converge(steps: Vec<(u8, u8, u8)>) -> bool {
+            let mut ews = vec![EWFlag::new(); 3];
+            let mut dws = vec![DWFlag::new(); 3];
+            let (mut ew_ops, mut dw_ops) = (vec![], vec![]);
+            for (replica, action, other) in steps {
+                let r = replica as usize % 3;
+                let actor = r as u8;
+                match action % 3 {
+                    0 => {
+                        ew_ops.push(ews[r].enable(ews[r].read_ctx().derive_add_ctx(actor)));
+                        dw_ops.push(dws[r].enable(dws[r].read_ctx().derive_add_ctx(actor)));
+                    }
+                    1 => {
+                        ew_ops.push(ews[r].disable(ews[r].read_ctx().derive_rm_ctx()));
+                        dw_ops.push(dws[r].disable(dws[r].read_ctx().derive_add_ctx(actor)));
+                    }
+                    _ => {
+                        let o = (r + other as usize) % 3;
+                        let (ew, dw) = (ews[r].clone(), dws[r].clone());
+                        ews[o].merge(ew);
+                        dws[o].merge(dw);
+                        continue;
+                    }
+                }
+                ews[r].apply(ew_ops.last().cloned().unwrap());
+                dws[r].apply(dw_ops.last().cloned().unwrap());
+            }
+
+            let (mut ew, mut dw) = (EWFlag::new(), DWFlag::new());
+            for (e, d) in ews.into_iter().zip(dws) {
+                ew.merge(e);
+                dw.merge(d);
+            }
+            let (mut ew_replayed, mut dw_replayed) = (EWFlag::new(), DWFlag::new());
+            for op in ew_ops {
+                ew_replayed.apply(op);
+            }
+            for op in dw_ops {
+                dw_replayed.apply(op);
+            }
+            ew.read().val == ew_replayed.read().val && dw.read().val == dw_replayed.read().val
+        }
+    }
+}
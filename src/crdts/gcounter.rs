@@ -5,9 +5,10 @@ use num::bigint::BigUint;
 use serde::{Deserialize, Serialize};
 
 //use crate::{CmRDT, CvRDT, Dot, ResetRemove, VClock};
-use super::traits::{CmRDT, CvRDT, ResetRemove};
+use super::traits::{CmRDT, CvRDT, DeltaCRDT, ResetRemove};
 use super::dot::Dot;
 use super::vclock::VClock;
+use crate::memory::MemoryUsage;
 
 #[derive(Debug, PartialEq, Eq, Clone, Hash, Serialize, Deserialize)]
 pub struct GCounter<A: Ord> {
@@ -47,12 +48,26 @@ impl<A: Ord + Clone + Debug> CvRDT for GCounter<A> {
     }
 }
 
+impl<A: Ord + Clone + Debug> DeltaCRDT for GCounter<A> {
+    type Delta = Self;
+
+    fn merge_delta(&mut self, delta: Self::Delta) {
+        self.merge(delta);
+    }
+}
+
 impl<A: Ord> ResetRemove<A> for GCounter<A> {
     fn reset_remove(&mut self, clock: &VClock<A>) {
         self.inner.reset_remove(&clock);
     }
 }
 
+impl<A: Ord + MemoryUsage> MemoryUsage for GCounter<A> {
+    fn heap_usage(&self, samples: usize) -> usize {
+        self.inner.heap_usage(samples)
+    }
+}
+
 impl<A: Ord + Clone> GCounter<A> {
     /// Produce a new `GCounter`.
     pub fn new() -> Self {
@@ -70,8 +85,29 @@ impl<A: Ord + Clone> GCounter<A> {
         Dot::new(actor, steps)
     }
 
+    /// Return the increments of a single actor.
+    pub fn get(&self, actor: &A) -> u64 {
+        self.inner.get(actor)
+    }
+
     /// Return the current sum of this counter.
     pub fn read(&self) -> BigUint {
         self.inner.iter().map(|dot| dot.counter).sum()
     }
-}
\ No newline at end of file
+}
+
+impl<A: Ord + Clone + Debug> GCounter<A> {
+    /// Generate the delta of incrementing the counter.
+    pub fn inc_delta(&self, actor: A) -> Self {
+        let mut delta = Self::new();
+        delta.apply(self.inc(actor));
+        delta
+    }
+
+    /// Generate the delta of incrementing the counter by a number of steps.
+    pub fn inc_many_delta(&self, actor: A, steps: u64) -> Self {
+        let mut delta = Self::new();
+        delta.apply(self.inc_many(actor, steps));
+        delta
+    }
+}
@@ -0,0 +1,191 @@
+//! Hybrid logical clock, timestamps close to wall-clock time that still order causally.
+//!
+//! A timestamp pairs the greatest physical time (in milliseconds) a node has seen with a
+//! logical counter that grows while the physical time stands still, so timestamps a node
+//! produces strictly increase even when its wall clock goes backwards. Receiving a remote
+//! timestamp moves the clock past it, unless it is further ahead of the local wall clock
+//! than the drift we tolerate, which would drag every later timestamp into the future.
+//! The node id breaks ties, making timestamps of distinct nodes globally unique.
+use core::fmt;
+use std::error::Error;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::memory::MemoryUsage;
+
+/// Remote timestamps further ahead of the local wall clock are rejected by default.
+pub const DEFAULT_MAX_DRIFT: Duration = Duration::from_secs(1);
+
+/// A hybrid logical clock timestamp, ordered by physical time, then logical counter,
+/// then node id.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct Hlc {
+    /// milliseconds since the unix epoch
+    pub physical: u64,
+    /// events seen since the physical time last moved
+    pub logical: u32,
+    /// node the timestamp was produced on
+    pub node: u64,
+}
+
+impl Hlc {
+    pub fn new(physical: u64, logical: u32, node: u64) -> Self {
+        Hlc { physical, logical, node }
+    }
+}
+
+impl fmt::Display for Hlc {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}@{}", self.physical, self.logical, self.node)
+    }
+}
+
+impl MemoryUsage for Hlc {
+    fn heap_usage(&self, _samples: usize) -> usize {
+        0
+    }
+}
+
+/// A remote timestamp was too far ahead of the local wall clock.
+#[derive(Debug, PartialEq, Eq)]
+pub struct ClockDrift {
+    /// physical time of the remote timestamp, in milliseconds
+    pub remote: u64,
+    /// local wall clock, in milliseconds
+    pub local: u64,
+    /// drift tolerated, in milliseconds
+    pub max_drift: u64,
+}
+
+impl fmt::Display for ClockDrift {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "remote timestamp {}ms ahead of the local clock, at most {}ms tolerated",
+            self.remote - self.local,
+            self.max_drift
+        )
+    }
+}
+
+impl Error for ClockDrift {}
+
+/// Produces the timestamps of a node.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HlcClock {
+    last: Hlc,
+    max_drift: u64,
+}
+
+impl HlcClock {
+    /// A clock for `node` tolerating `DEFAULT_MAX_DRIFT`.
+    pub fn new(node: u64) -> Self {
+        Self::with_max_drift(node, DEFAULT_MAX_DRIFT)
+    }
+
+    pub fn with_max_drift(node: u64, max_drift: Duration) -> Self {
+        HlcClock {
+            last: Hlc::new(0, 0, node),
+            max_drift: max_drift.as_millis() as u64,
+        }
+    }
+
+    /// The latest timestamp produced.
+    pub fn last(&self) -> Hlc {
+        self.last
+    }
+
+    /// Timestamp for a local event, greater than every timestamp produced or received before.
+    pub fn now(&mut self) -> Hlc {
+        self.tick(wall_clock())
+    }
+
+    /// Moves the clock past a received timestamp and returns the timestamp of the receive
+    /// event, or fails leaving the clock untouched if `remote` is too far in the future.
+    pub fn update(&mut self, remote: &Hlc) -> Result<Hlc, ClockDrift> {
+        self.observe(remote, wall_clock())
+    }
+
+    /// `now`, with the wall clock reading `wall` milliseconds.
+    pub fn tick(&mut self, wall: u64) -> Hlc {
+        if wall > self.last.physical {
+            self.last.physical = wall;
+            self.last.logical = 0;
+        } else {
+            self.last.logical += 1;
+        }
+        self.last
+    }
+
+    /// `update`, with the wall clock reading `wall` milliseconds.
+    pub fn observe(&mut self, remote: &Hlc, wall: u64) -> Result<Hlc, ClockDrift> {
+        if remote.physical > wall.saturating_add(self.max_drift) {
+            return Err(ClockDrift {
+                remote: remote.physical,
+                local: wall,
+                max_drift: self.max_drift,
+            });
+        }
+
+        let last = self.last;
+        let physical = wall.max(last.physical).max(remote.physical);
+        self.last.logical = match (physical == last.physical, physical == remote.physical) {
+            (true, true) => last.logical.max(remote.logical) + 1,
+            (true, false) => last.logical + 1,
+            (false, true) => remote.logical + 1,
+            (false, false) => 0,
+        };
+        self.last.physical = physical;
+        Ok(self.last)
+    }
+}
+
+fn wall_clock() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn timestamps_increase_when_the_wall_clock_goes_back() {
+        let mut clock = HlcClock::new(1);
+        let a = clock.tick(100);
+        let b = clock.tick(90);
+        let c = clock.tick(100);
+        let d = clock.tick(101);
+        assert!(a < b && b < c && c < d);
+        assert_eq!(c, Hlc::new(100, 2, 1));
+        assert_eq!(d, Hlc::new(101, 0, 1));
+    }
+
+    #[test]
+    fn update_moves_past_remote_within_drift() {
+        let mut clock = HlcClock::with_max_drift(1, Duration::from_millis(50));
+        clock.tick(100);
+        let remote = Hlc::new(140, 7, 2);
+        let received = clock.observe(&remote, 100).unwrap();
+        assert_eq!(received, Hlc::new(140, 8, 1));
+        assert!(clock.tick(120) > remote);
+
+        let far = Hlc::new(200, 0, 2);
+        assert_eq!(
+            clock.observe(&far, 100),
+            Err(ClockDrift { remote: 200, local: 100, max_drift: 50 })
+        );
+        assert_eq!(clock.last(), Hlc::new(140, 9, 1));
+    }
+
+    #[test]
+    fn serde_round_trip() {
+        let mut clock = HlcClock::new(3);
+        let t = clock.now();
+        let bytes = serde_cbor::to_vec(&t).unwrap();
+        assert_eq!(serde_cbor::from_slice::<Hlc>(&bytes).unwrap(), t);
+    }
+}
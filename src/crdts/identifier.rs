@@ -4,6 +4,8 @@ use num::{BigRational, One, Zero};
 use quickcheck::{Arbitrary, Gen};
 use serde::{Deserialize, Serialize};
 
+use super::codec::{kind, Atom, CodecError, Compact, Decoder, Encoder};
+
 fn rational_between(low: Option<&BigRational>, high: Option<&BigRational>) -> BigRational {
     match (low, high) {
         (None, None) => BigRational::zero(),
@@ -104,4 +106,24 @@ impl<T: Arbitrary> Arbitrary for Identifier<T> {
         }
         Self(path)
     }
-}
\ No newline at end of file
+}
+
+impl<A: Ord + Clone + Atom, T: Compact<A>> Compact<A> for Identifier<T> {
+    const KIND: u8 = kind::IDENTIFIER;
+
+    fn encode_body(&self, enc: &mut Encoder<A>) {
+        enc.varint(self.0.len() as u64);
+        for (rational, value) in self.0.iter() {
+            enc.atom(rational);
+            value.encode_body(enc);
+        }
+    }
+
+    fn decode_body(dec: &mut Decoder<A>) -> Result<Self, CodecError> {
+        let mut path = Vec::new();
+        for _ in 0..dec.count()? {
+            path.push((dec.atom()?, T::decode_body(dec)?));
+        }
+        Ok(Identifier(path))
+    }
+}
@@ -5,6 +5,7 @@ use std::collections::BTreeMap;
 use serde::{Deserialize, Serialize};
 
 //use crate::{CmRDT, Dot, Identifier, OrdDot, VClock};
+use super::codec::{kind, Atom, CodecError, Compact, Decoder, Encoder};
 use super::traits::CmRDT;
 use super::dot::{Dot, OrdDot};
 use super::identifier::Identifier;
@@ -204,3 +205,53 @@ impl<T, A: Ord + Clone + fmt::Debug> CmRDT for List<T, A> {
         }
     }
 }
+
+impl<T: Atom, A: Ord + Clone + Atom> Compact<A> for List<T, A> {
+    const KIND: u8 = kind::LIST;
+
+    fn encode_body(&self, enc: &mut Encoder<A>) {
+        enc.varint(self.seq.len() as u64);
+        for (id, val) in self.seq.iter() {
+            id.encode_body(enc);
+            enc.atom(val);
+        }
+        self.clock.encode_body(enc);
+    }
+
+    fn decode_body(dec: &mut Decoder<A>) -> Result<Self, CodecError> {
+        let mut seq = BTreeMap::new();
+        for _ in 0..dec.count()? {
+            let id = Identifier::decode_body(dec)?;
+            seq.insert(id, dec.atom()?);
+        }
+        let clock = VClock::decode_body(dec)?;
+        Ok(List { seq, clock })
+    }
+}
+
+impl<T: Atom, A: Ord + Clone + Atom> Compact<A> for Op<T, A> {
+    const KIND: u8 = kind::LIST_OP;
+
+    fn encode_body(&self, enc: &mut Encoder<A>) {
+        match self {
+            Op::Insert { id, val } => {
+                enc.tag(0);
+                id.encode_body(enc);
+                enc.atom(val);
+            }
+            Op::Delete { id, dot } => {
+                enc.tag(1);
+                id.encode_body(enc);
+                dot.encode_body(enc);
+            }
+        }
+    }
+
+    fn decode_body(dec: &mut Decoder<A>) -> Result<Self, CodecError> {
+        match dec.tag()? {
+            0 => Ok(Op::Insert { id: Identifier::decode_body(dec)?, val: dec.atom()? }),
+            1 => Ok(Op::Delete { id: Identifier::decode_body(dec)?, dot: Dot::decode_body(dec)? }),
+            tag => Err(CodecError::InvalidTag(tag)),
+        }
+    }
+}
@@ -0,0 +1,321 @@
+//! Last-writer-wins element map.
+//!
+//! Every key holds an `LWWReg` and the greatest marker written under a key wins, whether it
+//! sets a value or removes it. Removes leave the register behind with no value so that a
+//! write with a smaller marker arriving later stays overwritten. Each register also keeps the
+//! dot that wrote it, which lets the map forget what a containing `Map` removed.
+use core::fmt::Debug;
+use std::collections::BTreeMap;
+use std::mem;
+
+use serde::{Deserialize, Serialize};
+
+use super::ctx::{AddCtx, ReadCtx};
+use super::dot::Dot;
+use super::hlc::Hlc;
+use super::lwwreg::{LWWReg, Validation};
+use super::traits::{CmRDT, CvRDT, ResetRemove};
+use super::vclock::VClock;
+use crate::memory::{sampled_usage, MemoryUsage};
+
+/// `LWWMap` maps keys of type `K` to values of type `V`, resolving concurrent writes to a
+/// key with their markers of type `M`.
+///
+/// `M` follows the rules of `LWWReg` markers: it must grow monotonically *and* be globally
+/// unique. It defaults to hybrid logical clock timestamps.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LWWMap<K: Ord, V, A: Ord, M = Hlc> {
+    clock: VClock<A>,
+    entries: BTreeMap<K, Entry<V, A, M>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Entry<V, A: Ord, M> {
+    /// the winning write, a value of `None` is a remove
+    reg: LWWReg<Option<V>, M>,
+    /// the dot of the winning write
+    dot: Dot<A>,
+}
+
+/// An Op which is produced through from mutating the map
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Op<K, V, A: Ord, M = Hlc> {
+    /// Set the value of a key
+    Insert {
+        /// witnessing dot
+        dot: Dot<A>,
+        /// key to set
+        key: K,
+        /// value to set
+        val: V,
+        /// marker of the write
+        marker: M,
+    },
+    /// Remove a key
+    Rm {
+        /// witnessing dot
+        dot: Dot<A>,
+        /// key to remove
+        key: K,
+        /// marker of the remove
+        marker: M,
+    },
+}
+
+impl<K: Ord, V, A: Ord, M> Default for LWWMap<K, V, A, M> {
+    fn default() -> Self {
+        Self {
+            clock: Default::default(),
+            entries: Default::default(),
+        }
+    }
+}
+
+impl<K, V, A, M> CmRDT for LWWMap<K, V, A, M>
+where
+    K: Ord,
+    V: PartialEq,
+    A: Ord + Clone + Debug,
+    M: Ord,
+{
+    type Op = Op<K, V, A, M>;
+    type Validation = Validation;
+
+    fn validate_op(&self, op: &Self::Op) -> Result<(), Self::Validation> {
+        let (key, val, marker) = match op {
+            Op::Insert { key, val, marker, .. } => (key, Some(val), marker),
+            Op::Rm { key, marker, .. } => (key, None, marker),
+        };
+        match self.entries.get(key) {
+            Some(entry) if &entry.reg.marker == marker && entry.reg.val.as_ref() != val => {
+                Err(Validation::ConflictingMarker)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn apply(&mut self, op: Self::Op) {
+        let (dot, key, val, marker) = match op {
+            Op::Insert { dot, key, val, marker } => (dot, key, Some(val), marker),
+            Op::Rm { dot, key, marker } => (dot, key, None, marker),
+        };
+        self.clock.apply(dot.clone());
+        self.write(key, Entry { reg: LWWReg { val, marker }, dot });
+    }
+}
+
+impl<K, V, A, M> CvRDT for LWWMap<K, V, A, M>
+where
+    K: Ord,
+    V: PartialEq,
+    A: Ord + Clone + Debug,
+    M: Ord,
+{
+    type Validation = Validation;
+
+    fn validate_merge(&self, other: &Self) -> Result<(), Self::Validation> {
+        for (key, entry) in other.entries.iter() {
+            if let Some(ours) = self.entries.get(key) {
+                ours.reg.validate_update(&entry.reg.val, &entry.reg.marker)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn merge(&mut self, other: Self) {
+        for (key, entry) in other.entries {
+            self.write(key, entry);
+        }
+        self.clock.merge(other.clock);
+    }
+}
+
+impl<K: Ord, V, A: Ord, M> ResetRemove<A> for LWWMap<K, V, A, M> {
+    fn reset_remove(&mut self, clock: &VClock<A>) {
+        self.clock.reset_remove(clock);
+        self.entries.retain(|_, entry| clock.get(&entry.dot.actor) < entry.dot.counter);
+    }
+}
+
+impl<K, V, A, M> MemoryUsage for LWWMap<K, V, A, M>
+where
+    K: Ord + MemoryUsage,
+    V: MemoryUsage,
+    A: Ord + MemoryUsage,
+    M: MemoryUsage,
+{
+    fn heap_usage(&self, samples: usize) -> usize {
+        self.clock.heap_usage(samples)
+            + self.entries.len() * mem::size_of::<(K, Entry<V, A, M>)>()
+            + sampled_usage(
+                self.entries.len(),
+                samples,
+                self.entries.iter().map(|(k, entry)| {
+                    k.heap_usage(samples)
+                        + entry.reg.val.heap_usage(samples)
+                        + entry.reg.marker.heap_usage(samples)
+                        + entry.dot.actor.heap_usage(samples)
+                }),
+            )
+    }
+}
+
+impl<K: Ord, V, A: Ord + Clone, M> LWWMap<K, V, A, M> {
+    /// Produce a new, empty, `LWWMap`.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Generate an Op to set the value of a key.
+    pub fn insert(&self, key: impl Into<K>, val: V, marker: M, ctx: AddCtx<A>) -> Op<K, V, A, M> {
+        Op::Insert {
+            dot: ctx.dot,
+            key: key.into(),
+            val,
+            marker,
+        }
+    }
+
+    /// Generate an Op to remove a key.
+    pub fn rm(&self, key: impl Into<K>, marker: M, ctx: AddCtx<A>) -> Op<K, V, A, M> {
+        Op::Rm {
+            dot: ctx.dot,
+            key: key.into(),
+            marker,
+        }
+    }
+
+    /// Retrieve the value of a key.
+    pub fn get(&self, key: &K) -> Option<&V> {
+        self.entries.get(key).and_then(|entry| entry.reg.val.as_ref())
+    }
+
+    /// Retrieve the marker of the latest write to a key, removes included. A new write
+    /// must use a greater marker to take effect.
+    pub fn marker(&self, key: &K) -> Option<&M> {
+        self.entries.get(key).map(|entry| &entry.reg.marker)
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// Number of keys holding a value.
+    pub fn len(&self) -> usize {
+        self.iter().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.iter().next().is_none()
+    }
+
+    /// Iterate over the keys holding a value, in key order.
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.entries
+            .iter()
+            .filter_map(|(key, entry)| entry.reg.val.as_ref().map(|val| (key, val)))
+    }
+
+    /// Retrieve the current read context
+    pub fn read_ctx(&self) -> ReadCtx<(), A> {
+        ReadCtx {
+            add_clock: self.clock.clone(),
+            rm_clock: self.clock.clone(),
+            val: (),
+        }
+    }
+
+    fn write(&mut self, key: K, entry: Entry<V, A, M>)
+    where
+        M: Ord,
+    {
+        match self.entries.get_mut(&key) {
+            Some(ours) if ours.reg.marker < entry.reg.marker => *ours = entry,
+            Some(_) => (),
+            None => {
+                self.entries.insert(key, entry);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::crdts::map::Map;
+    use quickcheck::quickcheck;
+
+    type Marker = (u64, u8);
+
+    #[test]
+    fn latest_marker_wins() {
+        let mut a: LWWMap<u8, String, u8, Marker> = LWWMap::new();
+        a.apply(a.insert(1, "x".to_string(), (1, 1), a.read_ctx().derive_add_ctx(1)));
+        let mut b = a.clone();
+
+        let rm = a.rm(1, (3, 1), a.read_ctx().derive_add_ctx(1));
+        a.apply(rm.clone());
+        b.apply(b.insert(1, "y".to_string(), (2, 2), b.read_ctx().derive_add_ctx(2)));
+        b.apply(b.insert(2, "z".to_string(), (2, 2), b.read_ctx().derive_add_ctx(2)));
+        assert_eq!(b.get(&1), Some(&"y".to_string()));
+
+        b.apply(rm);
+        a.merge(b.clone());
+        assert_eq!(a.get(&1), None);
+        assert_eq!(a.marker(&1), Some(&(3, 1)));
+        assert_eq!(a.iter().collect::<Vec<_>>(), vec![(&2, &"z".to_string())]);
+        assert_eq!(a.iter().collect::<Vec<_>>(), b.iter().collect::<Vec<_>>());
+
+        let conflict = a.insert(2, "w".to_string(), (2, 2), a.read_ctx().derive_add_ctx(1));
+        assert_eq!(a.validate_op(&conflict), Err(Validation::ConflictingMarker));
+    }
+
+    #[test]
+    fn nested_in_map() {
+        let mut m: Map<u8, LWWMap<u8, u8, u8, Marker>, u8> = Map::new();
+        let op = m.update(1, m.read_ctx().derive_add_ctx(1), |s, ctx| s.insert(7, 42, (1, 1), ctx));
+        m.apply(op);
+        assert_eq!(m.get(&1).val.and_then(|s| s.get(&7).cloned()), Some(42));
+
+        let op = m.rm(1, m.get(&1).derive_rm_ctx());
+        m.apply(op);
+        assert_eq!(m.get(&1).val, None);
+    }
+
+    quickcheck! {
+        fn prop_merge_and_ops_converge(steps: Vec<(u8, u8, u8, u8)>) -> bool {
+            let mut replicas: Vec<LWWMap<u8, u8, u8, Marker>> = vec![LWWMap::new(); 3];
+            let mut ops = vec![];
+            for (time, (replica, action, key, val)) in steps.into_iter().enumerate() {
+                let r = replica as usize % 3;
+                let actor = r as u8;
+                let marker = ((time as u64 * 7919) % 1009, actor);
+                let ctx = replicas[r].read_ctx().derive_add_ctx(actor);
+                let op = match action % 3 {
+                    0 => replicas[r].insert(key % 4, val, marker, ctx),
+                    1 => replicas[r].rm(key % 4, marker, ctx),
+                    _ => {
+                        let state = replicas[r].clone();
+                        replicas[(r + val as usize) % 3].merge(state);
+                        continue;
+                    }
+                };
+                if replicas[r].validate_op(&op).is_err() {
+                    continue;
+                }
+                replicas[r].apply(op.clone());
+                ops.push(op);
+            }
+
+            let mut merged = LWWMap::new();
+            for replica in replicas {
+                merged.merge(replica);
+            }
+            let mut replayed = LWWMap::new();
+            for op in ops.into_iter().rev() {
+                replayed.apply(op);
+            }
+            merged.iter().eq(replayed.iter())
+        }
+    }
+}
@@ -2,6 +2,7 @@ use std::{error, fmt};
 
 use serde::{Deserialize, Serialize};
 
+use super::hlc::Hlc;
 use super::traits::{CmRDT, CvRDT};
 
 /// `LWWReg` is a simple CRDT that contains an arbitrary value
@@ -10,9 +11,10 @@ use super::traits::{CmRDT, CvRDT};
 /// is monotonic. Don't use timestamps unless you are comfortable
 /// with divergence.
 ///
-/// `M` is a marker. It must grow monotonically *and* must be globally unique
+/// `M` is a marker. It must grow monotonically *and* must be globally unique,
+/// which the hybrid logical clock timestamps of `HlcClock` are.
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
-pub struct LWWReg<V, M> {
+pub struct LWWReg<V, M = Hlc> {
     /// `val` is the opaque element contained within this CRDT
     pub val: V,
     /// `marker` should be a monotonic value associated with this val
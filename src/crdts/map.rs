@@ -3,16 +3,20 @@ use std::collections::HashMap;
 use std::collections::{BTreeMap, BTreeSet};
 use std::fmt::{self, Debug, Display};
 use std::hash::Hash;
+use std::convert::Infallible;
 use std::mem;
 
 use serde::{Deserialize, Serialize};
 
 use super::ctx::{AddCtx, ReadCtx, RmCtx};
 //use crate::{CmRDT, CvRDT, Dot, ResetRemove, VClock};
-use super::traits::{CmRDT, CvRDT};
+use super::traits::{CmRDT, CvRDT, DeltaCRDT};
+use super::codec::{kind, Atom, CodecError, Compact, Decoder, Encoder};
+use super::delta::join_dots;
 use super::dot::{Dot};
 use super::vclock::VClock;
 use super::traits::ResetRemove;
+use crate::memory::{sampled_usage, MemoryUsage};
 
 /// Val Trait alias to reduce redundancy in type decl.
 pub trait Val<A: Ord>: Clone + Default + ResetRemove<A> + CmRDT {}
@@ -62,6 +66,67 @@ pub enum Op<K: Ord, V: Val<A>, A: Ord> {
     },
 }
 
+/// A delta-state of a `Map`, produced by its delta-mutators.
+///
+/// Each key carries the dots it holds, the causal context the mutation observed
+/// for it and a delta of the nested CRDT. Keys absent from the delta are left
+/// untouched when it is merged.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Delta<K: Ord, D, A: Ord> {
+    clock: VClock<A>,
+    entries: BTreeMap<K, DeltaEntry<D, A>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct DeltaEntry<D, A: Ord> {
+    // The dots this key holds.
+    dots: VClock<A>,
+
+    // The dots the mutation had observed for this key.
+    ctx: VClock<A>,
+
+    // The delta of the nested CRDT
+    val: D,
+}
+
+impl<K: Ord, D, A: Ord> Default for Delta<K, D, A> {
+    fn default() -> Self {
+        Self {
+            clock: Default::default(),
+            entries: Default::default(),
+        }
+    }
+}
+
+impl<K: Ord, D: CvRDT + ResetRemove<A>, A: Ord + Clone + Debug> CvRDT for Delta<K, D, A> {
+    type Validation = Infallible;
+
+    fn validate_merge(&self, _other: &Self) -> Result<(), Self::Validation> {
+        Ok(())
+    }
+
+    /// Joins two deltas into a delta-group.
+    fn merge(&mut self, other: Self) {
+        for (key, mut entry) in other.entries {
+            if let Some(our_entry) = self.entries.get_mut(&key) {
+                let (joined, removed) =
+                    join_dots(&our_entry.dots, &our_entry.ctx, &entry.dots, &entry.ctx);
+                let (_, stale) = join_dots(&entry.dots, &entry.ctx, &our_entry.dots, &our_entry.ctx);
+
+                // drop the nested updates each side made under dots the other removed
+                our_entry.val.reset_remove(&removed);
+                entry.val.reset_remove(&stale);
+                our_entry.val.merge(entry.val);
+                our_entry.dots = joined;
+                our_entry.ctx.merge(entry.ctx);
+            } else {
+                self.entries.insert(key, entry);
+            }
+        }
+        self.clock.merge(other.clock);
+    }
+}
+
 impl<V: Val<A>, A: Ord> Default for Entry<V, A> {
     fn default() -> Self {
         Self {
@@ -309,6 +374,146 @@ impl<K: Ord + Clone + Debug, V: Val<A> + CvRDT + Debug, A: Ord + Hash + Clone +
     }
 }
 
+impl<K, V, A> DeltaCRDT for Map<K, V, A>
+where
+    K: Ord + Clone + Debug,
+    V: Val<A> + DeltaCRDT + Debug,
+    V::Delta: ResetRemove<A>,
+    A: Ord + Hash + Clone + Debug,
+{
+    type Delta = Delta<K, V::Delta, A>;
+
+    fn merge_delta(&mut self, delta: Self::Delta) {
+        for (key, DeltaEntry { dots, ctx, mut val }) in delta.entries {
+            let mut entry = self.entries.remove(&key).unwrap_or_default();
+            let (joined, removed) = join_dots(&entry.clock, &self.clock, &dots, &ctx);
+            if joined.is_empty() {
+                // every dot of this entry has been removed
+                continue;
+            }
+
+            // forget the nested updates made under removed dots, on both sides
+            let (_, stale) = join_dots(&dots, &ctx, &entry.clock, &self.clock);
+            entry.val.reset_remove(&removed);
+            val.reset_remove(&stale);
+            entry.val.merge_delta(val);
+            entry.clock = joined;
+            self.entries.insert(key, entry);
+        }
+
+        self.clock.merge(delta.clock);
+        self.apply_deferred();
+    }
+}
+
+impl<K, V, A> MemoryUsage for Map<K, V, A>
+where
+    K: Ord + MemoryUsage,
+    V: Val<A> + MemoryUsage,
+    A: Ord + Hash + MemoryUsage,
+{
+    fn heap_usage(&self, samples: usize) -> usize {
+        let entries = self.entries.len() * mem::size_of::<(K, Entry<V, A>)>()
+            + sampled_usage(
+                self.entries.len(),
+                samples,
+                self.entries.iter().map(|(k, entry)| {
+                    k.heap_usage(samples) + entry.clock.heap_usage(samples) + entry.val.heap_usage(samples)
+                }),
+            );
+        let deferred = self.deferred.capacity() * mem::size_of::<(VClock<A>, BTreeSet<K>)>()
+            + sampled_usage(
+                self.deferred.len(),
+                samples,
+                self.deferred.iter().map(|(clock, keys)| {
+                    clock.heap_usage(samples)
+                        + keys.len() * mem::size_of::<K>()
+                        + sampled_usage(keys.len(), samples, keys.iter().map(|k| k.heap_usage(samples)))
+                }),
+            );
+        self.clock.heap_usage(samples) + entries + deferred
+    }
+}
+
+impl<K, V, A> Compact<A> for Map<K, V, A>
+where
+    K: Ord + Atom,
+    V: Val<A> + Compact<A>,
+    A: Ord + Hash + Clone + Atom,
+{
+    const KIND: u8 = kind::MAP;
+
+    fn encode_body(&self, enc: &mut Encoder<A>) {
+        self.clock.encode_body(enc);
+        enc.varint(self.entries.len() as u64);
+        for (key, entry) in self.entries.iter() {
+            enc.atom(key);
+            entry.clock.encode_body(enc);
+            entry.val.encode_body(enc);
+        }
+        enc.varint(self.deferred.len() as u64);
+        for (clock, keyset) in self.deferred.iter() {
+            clock.encode_body(enc);
+            enc.atoms(keyset);
+        }
+    }
+
+    fn decode_body(dec: &mut Decoder<A>) -> Result<Self, CodecError> {
+        let clock = VClock::decode_body(dec)?;
+        let mut entries = BTreeMap::new();
+        for _ in 0..dec.count()? {
+            let key = dec.atom()?;
+            let clock = VClock::decode_body(dec)?;
+            let val = V::decode_body(dec)?;
+            entries.insert(key, Entry { clock, val });
+        }
+        let mut deferred = HashMap::new();
+        for _ in 0..dec.count()? {
+            let clock = VClock::decode_body(dec)?;
+            deferred.insert(clock, dec.atoms()?);
+        }
+        Ok(Map { clock, entries, deferred })
+    }
+}
+
+impl<K, V, A> Compact<A> for Op<K, V, A>
+where
+    K: Ord + Atom,
+    V: Val<A>,
+    V::Op: Compact<A>,
+    A: Ord + Clone + Atom,
+{
+    const KIND: u8 = kind::MAP_OP;
+
+    fn encode_body(&self, enc: &mut Encoder<A>) {
+        match self {
+            Op::Rm { clock, keyset } => {
+                enc.tag(0);
+                clock.encode_body(enc);
+                enc.atoms(keyset);
+            }
+            Op::Up { dot, key, op } => {
+                enc.tag(1);
+                dot.encode_body(enc);
+                enc.atom(key);
+                op.encode_body(enc);
+            }
+        }
+    }
+
+    fn decode_body(dec: &mut Decoder<A>) -> Result<Self, CodecError> {
+        match dec.tag()? {
+            0 => Ok(Op::Rm { clock: VClock::decode_body(dec)?, keyset: dec.atoms()? }),
+            1 => Ok(Op::Up {
+                dot: Dot::decode_body(dec)?,
+                key: dec.atom()?,
+                op: V::Op::decode_body(dec)?,
+            }),
+            tag => Err(CodecError::InvalidTag(tag)),
+        }
+    }
+}
+
 impl<K: Ord, V: Val<A>, A: Ord + Hash + Clone> Map<K, V, A> {
     /// Constructs an empty Map
     pub fn new() -> Self {
@@ -383,6 +588,58 @@ impl<K: Ord, V: Val<A>, A: Ord + Hash + Clone> Map<K, V, A> {
         }
     }
 
+    /// Generate the delta of updating a value under some key.
+    ///
+    /// Works like `update`, except that the updater returns a delta of the
+    /// nested CRDT instead of an Op.
+    pub fn update_delta<F>(&self, key: impl Into<K>, ctx: AddCtx<A>, f: F) -> Delta<K, V::Delta, A>
+    where
+        V: DeltaCRDT,
+        A: Debug,
+        F: FnOnce(&V, AddCtx<A>) -> V::Delta,
+    {
+        let key = key.into();
+        let dot = VClock::from(ctx.dot.clone());
+        let val = match self.entries.get(&key).map(|e| &e.val) {
+            Some(data) => f(data, ctx),
+            None => f(&V::default(), ctx),
+        };
+
+        let mut entries = BTreeMap::new();
+        entries.insert(
+            key,
+            DeltaEntry {
+                dots: dot.clone(),
+                ctx: dot.clone(),
+                val,
+            },
+        );
+        Delta {
+            clock: dot,
+            entries,
+        }
+    }
+
+    /// Generate the delta of removing an entry from the Map
+    pub fn rm_delta(&self, key: impl Into<K>, ctx: RmCtx<A>) -> Delta<K, V::Delta, A>
+    where
+        V: DeltaCRDT,
+    {
+        let mut entries = BTreeMap::new();
+        entries.insert(
+            key.into(),
+            DeltaEntry {
+                dots: VClock::new(),
+                ctx: ctx.clock,
+                val: Default::default(),
+            },
+        );
+        Delta {
+            clock: VClock::new(),
+            entries,
+        }
+    }
+
     /// Retrieve the current read context
     pub fn read_ctx(&self) -> ReadCtx<(), A> {
         ReadCtx {
@@ -400,6 +657,24 @@ impl<K: Ord, V: Val<A>, A: Ord + Hash + Clone> Map<K, V, A> {
         }
     }
 
+    /// Number of key removals waiting for causal context.
+    pub fn deferred_len(&self) -> usize {
+        self.deferred.values().map(|keys| keys.len()).sum()
+    }
+
+    /// Garbage-collects the deferred removes given `stable`, the causally stable cut of the
+    /// replicas. Same as [Orswot::gc](super::orswot::Orswot::gc), for keys.
+    pub fn gc(&mut self, stable: &VClock<A>) -> usize {
+        let before = self.deferred_len();
+        let mut seen = stable.clone();
+        seen.glb(&self.clock);
+        for (mut clock, keys) in mem::take(&mut self.deferred) {
+            clock.dots.retain(|actor, counter| seen.get(actor) < *counter);
+            self.apply_keyset_rm(keys, clock);
+        }
+        before - self.deferred_len()
+    }
+
     /// Apply a set of key removals given a clock.
     fn apply_keyset_rm(&mut self, mut keyset: BTreeSet<K>, clock: VClock<A>) {
         for key in keyset.iter() {
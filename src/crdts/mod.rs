@@ -1,4 +1,6 @@
 pub mod lwwreg;
+pub mod lwwmap;
+pub mod hlc;
 pub mod mvreg;
 pub mod vclock;
 pub mod dot;
@@ -8,8 +10,15 @@ pub mod gcounter;
 pub mod gset;
 pub mod glist;
 pub mod pncounter;
+pub mod bcounter;
+pub mod rcounter;
+pub mod flag;
 pub mod map;
 pub mod ctx;
 pub mod list;
+pub mod rga;
 pub mod vvwe;
+pub mod delta;
+pub mod stability;
+pub mod codec;
 pub mod traits;
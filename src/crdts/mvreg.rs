@@ -8,8 +8,10 @@ use serde::{Deserialize, Serialize};
 
 use super::ctx::{AddCtx, ReadCtx};
 //use crate::{CmRDT, CvRDT, ResetRemove, VClock};
-use super::traits::{CvRDT,CmRDT, ResetRemove};
+use super::codec::{kind, Atom, CodecError, Compact, Decoder, Encoder};
+use super::traits::{CvRDT, CmRDT, DeltaCRDT, ResetRemove};
 use super::vclock::VClock;
+use crate::memory::{sampled_usage, MemoryUsage};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MVReg<V, A: Ord> {
@@ -113,6 +115,14 @@ impl<V, A: Ord> CvRDT for MVReg<V, A> {
     }
 }
 
+impl<V: Clone, A: Ord + Clone> DeltaCRDT for MVReg<V, A> {
+    type Delta = Self;
+
+    fn merge_delta(&mut self, delta: Self::Delta) {
+        self.merge(delta);
+    }
+}
+
 impl<V, A: Ord> CmRDT for MVReg<V, A> {
     type Op = Op<V, A>;
     type Validation = Infallible;
@@ -129,15 +139,10 @@ impl<V, A: Ord> CmRDT for MVReg<V, A> {
                 }
                 // first filter out all values that are dominated by the Op clock
                 self.vals.retain(|(val_clock, _)| {
-                    // matches!(
-                    //     val_clock.partial_cmp(&clock),
-                    //     None | Some(Ordering::Greater)
-                    // )
-                    let res = val_clock.partial_cmp(&clock);
-                    match res {
-                        Some(Ordering::Greater) => true,
-                        _ => false
-                    }
+                    matches!(
+                        val_clock.partial_cmp(&clock),
+                        None | Some(Ordering::Greater)
+                    )
                 });
 
                 let mut should_add = true;
@@ -156,6 +161,55 @@ impl<V, A: Ord> CmRDT for MVReg<V, A> {
     }
 }
 
+impl<V: MemoryUsage, A: Ord + MemoryUsage> MemoryUsage for MVReg<V, A> {
+    fn heap_usage(&self, samples: usize) -> usize {
+        self.vals.capacity() * mem::size_of::<(VClock<A>, V)>()
+            + sampled_usage(
+                self.vals.len(),
+                samples,
+                self.vals.iter().map(|(clock, val)| clock.heap_usage(samples) + val.heap_usage(samples)),
+            )
+    }
+}
+
+impl<V: Atom, A: Ord + Clone + Atom> Compact<A> for MVReg<V, A> {
+    const KIND: u8 = kind::MVREG;
+
+    fn encode_body(&self, enc: &mut Encoder<A>) {
+        enc.varint(self.vals.len() as u64);
+        for (clock, val) in self.vals.iter() {
+            clock.encode_body(enc);
+            enc.atom(val);
+        }
+    }
+
+    fn decode_body(dec: &mut Decoder<A>) -> Result<Self, CodecError> {
+        let mut vals = Vec::new();
+        for _ in 0..dec.count()? {
+            vals.push((VClock::decode_body(dec)?, dec.atom()?));
+        }
+        Ok(MVReg { vals })
+    }
+}
+
+impl<V: Atom, A: Ord + Clone + Atom> Compact<A> for Op<V, A> {
+    const KIND: u8 = kind::MVREG_OP;
+
+    fn encode_body(&self, enc: &mut Encoder<A>) {
+        let Op::Put { clock, val } = self;
+        enc.tag(0);
+        clock.encode_body(enc);
+        enc.atom(val);
+    }
+
+    fn decode_body(dec: &mut Decoder<A>) -> Result<Self, CodecError> {
+        match dec.tag()? {
+            0 => Ok(Op::Put { clock: VClock::decode_body(dec)?, val: dec.atom()? }),
+            tag => Err(CodecError::InvalidTag(tag)),
+        }
+    }
+}
+
 impl<V, A: Ord + Clone + Debug> MVReg<V, A> {
     /// Construct a new empty MVReg
     pub fn new() -> Self {
@@ -170,6 +224,13 @@ impl<V, A: Ord + Clone + Debug> MVReg<V, A> {
         }
     }
 
+    /// Generate the delta of setting the value of the register
+    pub fn write_delta(&self, val: V, ctx: AddCtx<A>) -> Self {
+        Self {
+            vals: vec![(ctx.clock, val)],
+        }
+    }
+
     /// Consumes the register and returns the values
     pub fn read(&self) -> ReadCtx<Vec<V>, A>
     where
@@ -3,6 +3,7 @@ use std::cmp::Ordering;
 use std::collections::{HashMap, HashSet};
 use std::fmt::{Debug, Display};
 use std::hash::Hash;
+use std::convert::Infallible;
 use std::mem;
 
 use serde::{Deserialize, Serialize};
@@ -10,9 +11,12 @@ use serde::{Deserialize, Serialize};
 use super::ctx::{AddCtx, ReadCtx, RmCtx};
 use quickcheck::{Arbitrary, Gen};
 //use crate::{CmRDT, CvRDT, Dot, ResetRemove, VClock};
-use super::traits::{CmRDT, CvRDT, ResetRemove};
+use super::traits::{CmRDT, CvRDT, DeltaCRDT, ResetRemove};
+use super::codec::{kind, Atom, CodecError, Compact, Decoder, Encoder};
+use super::delta::join_dots;
 use super::dot::Dot;
 use super::vclock::VClock;
+use crate::memory::{sampled_usage, MemoryUsage};
 
 /// `Orswot` is an add-biased or-set without tombstones ported from
 /// the riak_dt CRDT library.
@@ -45,6 +49,56 @@ pub enum Op<M, A: Ord> {
     },
 }
 
+/// A delta-state of an `Orswot`, produced by its delta-mutators.
+///
+/// Unlike a full `Orswot`, a delta only speaks for the members it carries: each
+/// member comes with the dots it holds and the causal context the mutation
+/// observed for it, so members absent from the delta are left untouched.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Delta<M: Hash + Eq, A: Ord + Hash> {
+    clock: VClock<A>,
+    entries: HashMap<M, (VClock<A>, VClock<A>)>,
+}
+
+impl<M: Hash + Eq, A: Ord + Hash> Default for Delta<M, A> {
+    fn default() -> Self {
+        Delta {
+            clock: Default::default(),
+            entries: Default::default(),
+        }
+    }
+}
+
+impl<M: Hash + Eq, A: Ord + Hash + Clone + Debug> CvRDT for Delta<M, A> {
+    type Validation = Infallible;
+
+    fn validate_merge(&self, _other: &Self) -> Result<(), Self::Validation> {
+        Ok(())
+    }
+
+    /// Joins two deltas into a delta-group.
+    fn merge(&mut self, other: Self) {
+        for (member, (dots, ctx)) in other.entries {
+            if let Some((our_dots, our_ctx)) = self.entries.get_mut(&member) {
+                *our_dots = join_dots(our_dots, our_ctx, &dots, &ctx).0;
+                our_ctx.merge(ctx);
+            } else {
+                self.entries.insert(member, (dots, ctx));
+            }
+        }
+        self.clock.merge(other.clock);
+    }
+}
+
+impl<M: Hash + Eq, A: Ord + Hash> ResetRemove<A> for Delta<M, A> {
+    fn reset_remove(&mut self, clock: &VClock<A>) {
+        self.clock.reset_remove(clock);
+        for (dots, _) in self.entries.values_mut() {
+            dots.reset_remove(clock);
+        }
+    }
+}
+
 impl<M: Hash + Eq, A: Ord + Hash> Default for Orswot<M, A> {
     fn default() -> Self {
         Orswot {
@@ -202,6 +256,23 @@ impl<M: Hash + Eq + Clone + Debug, A: Ord + Hash + Clone + Debug> CvRDT for Orsw
     }
 }
 
+impl<M: Hash + Eq + Clone + Debug, A: Ord + Hash + Clone + Debug> DeltaCRDT for Orswot<M, A> {
+    type Delta = Delta<M, A>;
+
+    fn merge_delta(&mut self, delta: Self::Delta) {
+        for (member, (dots, ctx)) in delta.entries {
+            let ours = self.entries.remove(&member).unwrap_or_default();
+            let (joined, _) = join_dots(&ours, &self.clock, &dots, &ctx);
+            if !joined.is_empty() {
+                self.entries.insert(member, joined);
+            }
+        }
+
+        self.clock.merge(delta.clock);
+        self.apply_deferred();
+    }
+}
+
 impl<M: Hash + Clone + Eq, A: Ord + Hash> ResetRemove<A> for Orswot<M, A> {
     fn reset_remove(&mut self, clock: &VClock<A>) {
         self.clock.reset_remove(&clock);
@@ -232,6 +303,96 @@ impl<M: Hash + Clone + Eq, A: Ord + Hash> ResetRemove<A> for Orswot<M, A> {
     }
 }
 
+impl<M, A> MemoryUsage for Orswot<M, A>
+where
+    M: Hash + Eq + MemoryUsage,
+    A: Ord + Hash + MemoryUsage,
+{
+    fn heap_usage(&self, samples: usize) -> usize {
+        let entries = self.entries.capacity() * mem::size_of::<(M, VClock<A>)>()
+            + sampled_usage(
+                self.entries.len(),
+                samples,
+                self.entries.iter().map(|(m, clock)| m.heap_usage(samples) + clock.heap_usage(samples)),
+            );
+        let deferred = self.deferred.capacity() * mem::size_of::<(VClock<A>, HashSet<M>)>()
+            + sampled_usage(
+                self.deferred.len(),
+                samples,
+                self.deferred.iter().map(|(clock, members)| {
+                    clock.heap_usage(samples)
+                        + members.capacity() * mem::size_of::<M>()
+                        + sampled_usage(members.len(), samples, members.iter().map(|m| m.heap_usage(samples)))
+                }),
+            );
+        self.clock.heap_usage(samples) + entries + deferred
+    }
+}
+
+impl<M, A> Compact<A> for Orswot<M, A>
+where
+    M: Hash + Eq + Atom,
+    A: Ord + Hash + Clone + Atom,
+{
+    const KIND: u8 = kind::ORSWOT;
+
+    fn encode_body(&self, enc: &mut Encoder<A>) {
+        self.clock.encode_body(enc);
+        enc.varint(self.entries.len() as u64);
+        for (member, clock) in self.entries.iter() {
+            enc.atom(member);
+            clock.encode_body(enc);
+        }
+        enc.varint(self.deferred.len() as u64);
+        for (clock, members) in self.deferred.iter() {
+            clock.encode_body(enc);
+            enc.atoms(members);
+        }
+    }
+
+    fn decode_body(dec: &mut Decoder<A>) -> Result<Self, CodecError> {
+        let clock = VClock::decode_body(dec)?;
+        let mut entries = HashMap::new();
+        for _ in 0..dec.count()? {
+            let member = dec.atom()?;
+            entries.insert(member, VClock::decode_body(dec)?);
+        }
+        let mut deferred = HashMap::new();
+        for _ in 0..dec.count()? {
+            let clock = VClock::decode_body(dec)?;
+            deferred.insert(clock, dec.atoms()?);
+        }
+        Ok(Orswot { clock, entries, deferred })
+    }
+}
+
+impl<M: Atom, A: Ord + Clone + Atom> Compact<A> for Op<M, A> {
+    const KIND: u8 = kind::ORSWOT_OP;
+
+    fn encode_body(&self, enc: &mut Encoder<A>) {
+        match self {
+            Op::Add { dot, members } => {
+                enc.tag(0);
+                dot.encode_body(enc);
+                enc.atoms(members);
+            }
+            Op::Rm { clock, members } => {
+                enc.tag(1);
+                clock.encode_body(enc);
+                enc.atoms(members);
+            }
+        }
+    }
+
+    fn decode_body(dec: &mut Decoder<A>) -> Result<Self, CodecError> {
+        match dec.tag()? {
+            0 => Ok(Op::Add { dot: Dot::decode_body(dec)?, members: dec.atoms()? }),
+            1 => Ok(Op::Rm { clock: VClock::decode_body(dec)?, members: dec.atoms()? }),
+            tag => Err(CodecError::InvalidTag(tag)),
+        }
+    }
+}
+
 impl<M: Hash + Clone + Eq, A: Ord + Hash + Clone> Orswot<M, A> {
     /// Returns a new `Orswot` instance.
     pub fn new() -> Self {
@@ -298,6 +459,46 @@ impl<M: Hash + Clone + Eq, A: Ord + Hash + Clone> Orswot<M, A> {
         }
     }
 
+    /// Generate the delta of adding a single element.
+    pub fn add_delta(&self, member: M, ctx: AddCtx<A>) -> Delta<M, A>
+    where
+        A: Debug,
+    {
+        self.add_all_delta(std::iter::once(member), ctx)
+    }
+
+    /// Generate the delta of adding multiple elements.
+    pub fn add_all_delta<I>(&self, members: I, ctx: AddCtx<A>) -> Delta<M, A>
+    where
+        I: IntoIterator<Item = M>,
+        A: Debug,
+    {
+        let dot = VClock::from(ctx.dot);
+        Delta {
+            entries: members
+                .into_iter()
+                .map(|member| (member, (dot.clone(), dot.clone())))
+                .collect(),
+            clock: dot,
+        }
+    }
+
+    /// Generate the delta of removing a member with a witnessing ctx.
+    pub fn rm_delta(&self, member: M, ctx: RmCtx<A>) -> Delta<M, A> {
+        self.rm_all_delta(std::iter::once(member), ctx)
+    }
+
+    /// Generate the delta of removing members with a witnessing ctx.
+    pub fn rm_all_delta<I: IntoIterator<Item = M>>(&self, members: I, ctx: RmCtx<A>) -> Delta<M, A> {
+        Delta {
+            clock: VClock::new(),
+            entries: members
+                .into_iter()
+                .map(|member| (member, (VClock::new(), ctx.clock.clone())))
+                .collect(),
+        }
+    }
+
     /// Check if the set contains a member
     pub fn contains(&self, member: &M) -> ReadCtx<bool, A> {
         let member_clock_opt = self.entries.get(&member);
@@ -341,6 +542,30 @@ impl<M: Hash + Clone + Eq, A: Ord + Hash + Clone> Orswot<M, A> {
             self.apply_rm(entries, clock)
         }
     }
+
+    /// Number of member removals waiting for causal context.
+    pub fn deferred_len(&self) -> usize {
+        self.deferred.values().map(|members| members.len()).sum()
+    }
+
+    /// Garbage-collects the deferred removes given `stable`, the causally stable cut of the
+    /// replicas (see [StableCut](super::stability::StableCut)).
+    ///
+    /// A deferred remove waits for the dots of its context this replica has not seen yet.
+    /// Dots of actors every replica has seen up to the context can no longer show up, so they
+    /// are pruned from the contexts; removes whose whole context has been seen are applied and
+    /// removes left with the same context are coalesced. Returns the number of member removals
+    /// dropped.
+    pub fn gc(&mut self, stable: &VClock<A>) -> usize {
+        let before = self.deferred_len();
+        let mut seen = stable.clone();
+        seen.glb(&self.clock);
+        for (mut clock, members) in mem::take(&mut self.deferred) {
+            clock.dots.retain(|actor, counter| seen.get(actor) < *counter);
+            self.apply_rm(members, clock);
+        }
+        before - self.deferred_len()
+    }
 }
 
 impl<A: Ord + Hash + Arbitrary + Debug, M: Hash + Eq + Arbitrary> Arbitrary for Op<M, A> {
@@ -2,11 +2,12 @@ use num::bigint::BigInt;
 use serde::{Deserialize, Serialize};
 use std::fmt::Debug;
 
-use super::traits::{CmRDT, CvRDT, ResetRemove};
+use super::traits::{CmRDT, CvRDT, DeltaCRDT, ResetRemove};
 //use crate::{Dot, GCounter, VClock};
 use super::dot::Dot;
 use super::vclock::VClock;
 use super::gcounter::GCounter;
+use crate::memory::MemoryUsage;
 
 #[derive(Debug, PartialEq, Eq, Clone, Hash, Serialize, Deserialize)]
 pub struct PNCounter<A: Ord> {
@@ -75,6 +76,14 @@ impl<A: Ord + Clone + Debug> CvRDT for PNCounter<A> {
     }
 }
 
+impl<A: Ord + Clone + Debug> DeltaCRDT for PNCounter<A> {
+    type Delta = Self;
+
+    fn merge_delta(&mut self, delta: Self::Delta) {
+        self.merge(delta);
+    }
+}
+
 impl<A: Ord> ResetRemove<A> for PNCounter<A> {
     fn reset_remove(&mut self, clock: &VClock<A>) {
         self.p.reset_remove(&clock);
@@ -82,6 +91,12 @@ impl<A: Ord> ResetRemove<A> for PNCounter<A> {
     }
 }
 
+impl<A: Ord + MemoryUsage> MemoryUsage for PNCounter<A> {
+    fn heap_usage(&self, samples: usize) -> usize {
+        self.p.heap_usage(samples) + self.n.heap_usage(samples)
+    }
+}
+
 impl<A: Ord + Clone> PNCounter<A> {
     /// Produce a new `PNCounter`.
     pub fn new() -> Self {
@@ -126,4 +141,38 @@ impl<A: Ord + Clone> PNCounter<A> {
         let n: BigInt = self.n.read().into();
         p - n
     }
-}
\ No newline at end of file
+}
+
+impl<A: Ord + Clone + Debug> PNCounter<A> {
+    /// Generate the delta of incrementing the counter.
+    pub fn inc_delta(&self, actor: A) -> Self {
+        Self {
+            p: self.p.inc_delta(actor),
+            n: GCounter::new(),
+        }
+    }
+
+    /// Generate the delta of decrementing the counter.
+    pub fn dec_delta(&self, actor: A) -> Self {
+        Self {
+            p: GCounter::new(),
+            n: self.n.inc_delta(actor),
+        }
+    }
+
+    /// Generate the delta of incrementing the counter by a number of steps.
+    pub fn inc_many_delta(&self, actor: A, steps: u64) -> Self {
+        Self {
+            p: self.p.inc_many_delta(actor, steps),
+            n: GCounter::new(),
+        }
+    }
+
+    /// Generate the delta of decrementing the counter by a number of steps.
+    pub fn dec_many_delta(&self, actor: A, steps: u64) -> Self {
+        Self {
+            p: GCounter::new(),
+            n: self.n.inc_many_delta(actor, steps),
+        }
+    }
+}
@@ -0,0 +1,216 @@
+//! Observed-reset counter, a PN-counter that can be brought back to zero.
+//!
+//! A reset does not erase the counter, it records how far it had seen every actor increment
+//! and decrement, and the value only counts what goes past those marks. Increments concurrent
+//! with a reset are therefore kept, only what the resetting replica observed is discarded.
+//! Marks are merged like the increments themselves, so the state stays one entry per actor
+//! however many resets happen.
+use core::convert::Infallible;
+use core::fmt::Debug;
+
+use num::bigint::BigInt;
+use serde::{Deserialize, Serialize};
+
+use super::traits::{CmRDT, CvRDT, ResetRemove};
+use super::dot::Dot;
+use super::vclock::VClock;
+use crate::memory::MemoryUsage;
+
+#[derive(Debug, PartialEq, Eq, Clone, Hash, Serialize, Deserialize)]
+pub struct RCounter<A: Ord> {
+    p: VClock<A>,
+    n: VClock<A>,
+    /// Increments of every actor observed by the latest resets
+    reset_p: VClock<A>,
+    /// Decrements of every actor observed by the latest resets
+    reset_n: VClock<A>,
+}
+
+/// An Op which is produced through from mutating the counter
+/// Ship these ops to other replicas to have them sync up.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Op<A: Ord> {
+    /// Raise the increments of the dot's actor to the dot's counter
+    Inc(Dot<A>),
+    /// Raise the decrements of the dot's actor to the dot's counter
+    Dec(Dot<A>),
+    /// Discard the increments and decrements observed by the resetting replica
+    Reset {
+        /// increments observed
+        p: VClock<A>,
+        /// decrements observed
+        n: VClock<A>,
+    },
+}
+
+impl<A: Ord> Default for RCounter<A> {
+    fn default() -> Self {
+        Self {
+            p: Default::default(),
+            n: Default::default(),
+            reset_p: Default::default(),
+            reset_n: Default::default(),
+        }
+    }
+}
+
+impl<A: Ord + Clone + Debug> CmRDT for RCounter<A> {
+    type Op = Op<A>;
+    type Validation = Infallible;
+
+    fn validate_op(&self, _op: &Self::Op) -> Result<(), Self::Validation> {
+        Ok(())
+    }
+
+    fn apply(&mut self, op: Self::Op) {
+        match op {
+            Op::Inc(dot) => self.p.apply(dot),
+            Op::Dec(dot) => self.n.apply(dot),
+            Op::Reset { p, n } => {
+                self.reset_p.merge(p);
+                self.reset_n.merge(n);
+            }
+        }
+    }
+}
+
+impl<A: Ord + Clone + Debug> CvRDT for RCounter<A> {
+    type Validation = Infallible;
+
+    fn validate_merge(&self, _other: &Self) -> Result<(), Self::Validation> {
+        Ok(())
+    }
+
+    fn merge(&mut self, other: Self) {
+        self.p.merge(other.p);
+        self.n.merge(other.n);
+        self.reset_p.merge(other.reset_p);
+        self.reset_n.merge(other.reset_n);
+    }
+}
+
+impl<A: Ord> ResetRemove<A> for RCounter<A> {
+    fn reset_remove(&mut self, clock: &VClock<A>) {
+        self.p.reset_remove(clock);
+        self.n.reset_remove(clock);
+        self.reset_p.reset_remove(clock);
+        self.reset_n.reset_remove(clock);
+    }
+}
+
+impl<A: Ord + MemoryUsage> MemoryUsage for RCounter<A> {
+    fn heap_usage(&self, samples: usize) -> usize {
+        self.p.heap_usage(samples)
+            + self.n.heap_usage(samples)
+            + self.reset_p.heap_usage(samples)
+            + self.reset_n.heap_usage(samples)
+    }
+}
+
+impl<A: Ord + Clone> RCounter<A> {
+    /// Produce a new `RCounter`.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Generate an Op to increment the counter.
+    pub fn inc(&self, actor: A) -> Op<A> {
+        self.inc_many(actor, 1)
+    }
+
+    /// Generate an Op to decrement the counter.
+    pub fn dec(&self, actor: A) -> Op<A> {
+        self.dec_many(actor, 1)
+    }
+
+    /// Generate an Op to increment the counter by a number of steps.
+    pub fn inc_many(&self, actor: A, steps: u64) -> Op<A> {
+        let steps = steps + self.p.get(&actor);
+        Op::Inc(Dot::new(actor, steps))
+    }
+
+    /// Generate an Op to decrement the counter by a number of steps.
+    pub fn dec_many(&self, actor: A, steps: u64) -> Op<A> {
+        let steps = steps + self.n.get(&actor);
+        Op::Dec(Dot::new(actor, steps))
+    }
+
+    /// Generate an Op bringing the counter back to zero, as far as this replica can see.
+    pub fn reset(&self) -> Op<A> {
+        Op::Reset {
+            p: self.p.clone(),
+            n: self.n.clone(),
+        }
+    }
+
+    /// Return the current value of this counter, what was added since the resets minus what
+    /// was removed since.
+    pub fn read(&self) -> BigInt {
+        let since = |counts: &VClock<A>, reset: &VClock<A>| -> BigInt {
+            counts.iter().map(|dot| BigInt::from(dot.counter.saturating_sub(reset.get(dot.actor)))).sum()
+        };
+        since(&self.p, &self.reset_p) - since(&self.n, &self.reset_n)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use quickcheck::quickcheck;
+
+    #[test]
+    fn reset_keeps_concurrent_increments() {
+        let mut a = RCounter::new();
+        a.apply(a.inc_many(1u8, 5));
+        a.apply(a.dec(1));
+        let mut b = a.clone();
+
+        a.apply(a.inc_many(1, 2));
+        b.apply(b.inc(2));
+        let reset = b.reset();
+        b.apply(reset.clone());
+        assert_eq!(b.read(), BigInt::from(0));
+
+        a.apply(reset);
+        assert_eq!(a.read(), BigInt::from(2));
+        a.merge(b.clone());
+        b.merge(a.clone());
+        assert_eq!(a, b);
+        // the increment of actor 2 was observed by the reset, the last two of actor 1 were not
+        assert_eq!(a.read(), BigInt::from(2));
+    }
+
+    quickcheck! {
+        fn prop_merge_and_ops_converge(steps: Vec<(u8, u8, u8)>) -> bool {
+            let mut replicas = vec![RCounter::new(); 3];
+            let mut ops = vec![];
+            for (replica, action, amount) in steps {
+                let r = replica as usize % 3;
+                let op = match action % 4 {
+                    0 => replicas[r].inc_many(r as u8, amount as u64 % 5 + 1),
+                    1 => replicas[r].dec_many(r as u8, amount as u64 % 5 + 1),
+                    2 => replicas[r].reset(),
+                    _ => {
+                        let state = replicas[r].clone();
+                        replicas[(r + amount as usize) % 3].merge(state);
+                        continue;
+                    }
+                };
+                replicas[r].apply(op.clone());
+                ops.push(op);
+            }
+
+            let mut merged = RCounter::new();
+            for replica in replicas {
+                merged.merge(replica);
+            }
+            let mut replayed = RCounter::new();
+            for op in ops.into_iter().rev() {
+                replayed.apply(op);
+            }
+            let converged = merged == replayed && merged.read() == replayed.read();
+            merged.apply(merged.reset());
+            converged && merged.read() == BigInt::from(0)
+        }
+    }
+}
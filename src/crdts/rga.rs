@@ -0,0 +1,782 @@
+//! Replicated Growable Array, a sequence CRDT tuned for text editing.
+//!
+//! Every element is identified by the dot that inserted it and placed right after the element
+//! it was inserted after; concurrent inserts after the same element are ordered by decreasing
+//! lamport timestamp. Unlike the identifiers of [List](super::list::List), dots do not grow
+//! as the document gets edited.
+//!
+//! Elements inserted in one go, or typed one after the other, are kept together as a block of
+//! consecutive dots. Blocks, tombstones included, are held in a treap ordered by position that
+//! counts the visible elements of every subtree, which turns index lookups into O(log n)
+//! walks instead of the linear scan of `List`.
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::iter::FromIterator;
+use std::ops::Range;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use super::dot::{Dot, DotRange};
+use super::traits::CmRDT;
+use super::vclock::VClock;
+
+const NIL: usize = usize::MAX;
+
+/// Consecutive elements inserted by one op, or what is left of them after splits.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Block<T, A> {
+    /// Dot of the first element, the following ones take the next counters
+    id: Dot<A>,
+    /// Lamport timestamp of the first element, the following ones take the next timestamps
+    lamport: u64,
+    len: u64,
+    /// The elements, dropped once the block is deleted
+    vals: Vec<T>,
+    deleted: bool,
+}
+
+impl<T, A: Clone> Block<T, A> {
+    fn visible(&self) -> usize {
+        match self.deleted {
+            true => 0,
+            false => self.len as usize,
+        }
+    }
+
+    fn dot(&self, offset: u64) -> Dot<A> {
+        Dot::new(self.id.actor.clone(), self.id.counter + offset)
+    }
+
+    fn last(&self) -> Dot<A> {
+        self.dot(self.len - 1)
+    }
+
+    /// Cuts the block at `offset` and returns the elements from there on.
+    fn split_off(&mut self, offset: u64) -> Self {
+        let vals = match self.deleted {
+            true => vec![],
+            false => self.vals.split_off(offset as usize),
+        };
+        let rest = Block {
+            id: self.dot(offset),
+            lamport: self.lamport + offset,
+            len: self.len - offset,
+            vals,
+            deleted: self.deleted,
+        };
+        self.len = offset;
+        rest
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Node<T, A> {
+    block: Block<T, A>,
+    priority: u64,
+    parent: usize,
+    left: usize,
+    right: usize,
+    /// Blocks in the subtree
+    blocks: usize,
+    /// Visible elements in the subtree
+    len: usize,
+}
+
+/// A sequence of `T` edited by actors `A`.
+#[derive(Debug, Clone)]
+pub struct Rga<T, A: Ord> {
+    nodes: Vec<Node<T, A>>,
+    root: usize,
+    /// Node of every block, by actor and counter of its first element
+    index: BTreeMap<A, BTreeMap<u64, usize>>,
+    clock: VClock<A>,
+    lamport: u64,
+    seed: u64,
+}
+
+/// Operations that can be performed on a Rga
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Op<T, A: Ord> {
+    /// Insert a run of elements
+    Insert {
+        /// Element the run goes after, `None` for the front of the sequence
+        after: Option<Dot<A>>,
+        /// Dot of the first element of the run
+        id: Dot<A>,
+        /// Lamport timestamp of the first element of the run
+        lamport: u64,
+        /// Elements to insert
+        vals: Vec<T>,
+    },
+    /// Delete ranges of elements
+    Delete {
+        /// First element and length of every range, the elements of a range having
+        /// consecutive dots of the same actor
+        ranges: Vec<(Dot<A>, u64)>,
+        /// id of site that issued delete
+        dot: Dot<A>,
+    },
+}
+
+impl<T, A: Ord + Clone> Op<T, A> {
+    /// Return the Dot originating the operation, the one of the first inserted element for
+    /// an insert.
+    pub fn dot(&self) -> Dot<A> {
+        match self {
+            Op::Insert { id, .. } => id.clone(),
+            Op::Delete { dot, .. } => dot.clone(),
+        }
+    }
+}
+
+impl<T, A: Ord> Default for Rga<T, A> {
+    fn default() -> Self {
+        Self {
+            nodes: vec![],
+            root: NIL,
+            index: BTreeMap::new(),
+            clock: VClock::new(),
+            lamport: 0,
+            seed: 0x2545_f491_4f6c_dd1d,
+        }
+    }
+}
+
+impl<T, A: Ord + Clone> Rga<T, A> {
+    /// Create an empty Rga
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Generate an op to insert the given element at the given index.
+    /// If `ix` is greater than the length of the Rga then it is appended to the end.
+    pub fn insert_index(&self, ix: usize, val: T, actor: A) -> Op<T, A> {
+        self.insert_all_index(ix, vec![val], actor)
+    }
+
+    /// Generate an op to insert the given elements, in order, at the given index.
+    pub fn insert_all_index<I: IntoIterator<Item = T>>(&self, ix: usize, vals: I, actor: A) -> Op<T, A> {
+        let after = ix
+            .min(self.len())
+            .checked_sub(1)
+            .and_then(|prev| self.find_index(prev))
+            .map(|(n, offset)| self.nodes[n].block.dot(offset as u64));
+        Op::Insert {
+            after,
+            id: self.clock.inc(actor),
+            lamport: self.lamport + 1,
+            vals: vals.into_iter().collect(),
+        }
+    }
+
+    /// Create an op to insert an element at the end of the sequence.
+    pub fn append(&self, val: T, actor: A) -> Op<T, A> {
+        self.insert_index(self.len(), val, actor)
+    }
+
+    /// Create an op to delete the element at the given index.
+    ///
+    /// Returns None if `ix` is out of bounds, i.e. `ix >= self.len()`.
+    pub fn delete_index(&self, ix: usize, actor: A) -> Option<Op<T, A>> {
+        self.delete_range(ix..ix + 1, actor)
+    }
+
+    /// Create an op to delete the elements in `range`, clamped to the sequence.
+    ///
+    /// Returns None if no element falls in the range.
+    pub fn delete_range(&self, range: Range<usize>, actor: A) -> Option<Op<T, A>> {
+        let end = range.end.min(self.len());
+        if range.start >= end {
+            return None;
+        }
+        let (mut n, mut offset) = self.find_index(range.start)?;
+        let mut rank = self.rank(n);
+        let mut left = end - range.start;
+        let mut ranges: Vec<(Dot<A>, u64)> = vec![];
+        loop {
+            let block = &self.nodes[n].block;
+            if !block.deleted {
+                let take = (block.len as usize - offset).min(left);
+                let first = block.dot(offset as u64);
+                match ranges.last_mut() {
+                    Some((dot, count)) if dot.actor == first.actor && dot.counter + *count == first.counter => {
+                        *count += take as u64
+                    }
+                    _ => ranges.push((first, take as u64)),
+                }
+                left -= take;
+            }
+            if left == 0 {
+                break;
+            }
+            offset = 0;
+            rank += 1;
+            n = self.node_at(rank);
+        }
+        Some(Op::Delete {
+            ranges,
+            dot: self.clock.inc(actor),
+        })
+    }
+
+    /// Get the length of the Rga.
+    pub fn len(&self) -> usize {
+        self.len_of(self.root)
+    }
+
+    /// Check if the Rga is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Number of blocks the elements are stored in, deleted ones included.
+    pub fn block_count(&self) -> usize {
+        self.blocks_of(self.root)
+    }
+
+    pub fn read<'a, C: FromIterator<&'a T>>(&'a self) -> C {
+        self.iter().collect()
+    }
+
+    /// Get the elements represented by the Rga.
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.in_order().into_iter().flat_map(move |n| self.nodes[n].block.vals.iter())
+    }
+
+    /// Get an element at a position in the sequence represented by the Rga.
+    pub fn position(&self, ix: usize) -> Option<&T> {
+        let (n, offset) = self.find_index(ix)?;
+        self.nodes[n].block.vals.get(offset)
+    }
+
+    /// Every element ever inserted, in order, with its value unless it was deleted.
+    fn elements(&self) -> impl Iterator<Item = (Dot<A>, Option<&T>)> {
+        self.in_order().into_iter().flat_map(move |n| {
+            let block = &self.nodes[n].block;
+            (0..block.len).map(move |i| (block.dot(i), block.vals.get(i as usize)))
+        })
+    }
+
+    fn in_order(&self) -> Vec<usize> {
+        let mut order = Vec::with_capacity(self.nodes.len());
+        let mut stack = vec![];
+        let mut n = self.root;
+        while n != NIL || !stack.is_empty() {
+            while n != NIL {
+                stack.push(n);
+                n = self.nodes[n].left;
+            }
+            n = stack.pop().unwrap();
+            order.push(n);
+            n = self.nodes[n].right;
+        }
+        order
+    }
+
+    /// Places a run inserted after `after`, skipping the concurrent runs with a greater
+    /// timestamp along with everything inserted after them.
+    fn integrate(&mut self, after: Option<Dot<A>>, id: Dot<A>, lamport: u64, vals: Vec<T>) {
+        let mut rank = match &after {
+            None => 0,
+            Some(after) => match self.find_dot(after) {
+                Some((n, offset)) => {
+                    if offset + 1 < self.nodes[n].block.len {
+                        self.split_block(n, offset + 1);
+                    }
+                    self.rank(n) + 1
+                }
+                // ops were not delivered in causal order
+                None => return,
+            },
+        };
+        let total = self.block_count();
+        while rank < total {
+            let next = &self.nodes[self.node_at(rank)].block;
+            if (next.lamport, &next.id.actor) < (lamport, &id.actor) {
+                break;
+            }
+            rank += 1;
+        }
+
+        // keep typing in the block being typed in
+        if rank > 0 {
+            let prev = self.node_at(rank - 1);
+            let block = &mut self.nodes[prev].block;
+            if !block.deleted
+                && block.id.actor == id.actor
+                && block.id.counter + block.len == id.counter
+                && block.lamport + block.len == lamport
+                && after.as_ref() == Some(&block.last())
+            {
+                block.len += vals.len() as u64;
+                block.vals.extend(vals);
+                self.refresh(prev);
+                return;
+            }
+        }
+        let len = vals.len() as u64;
+        self.insert_node(rank, Block { id, lamport, len, vals, deleted: false });
+    }
+
+    fn delete(&mut self, start: &Dot<A>, count: u64) {
+        let mut dot = start.clone();
+        let end = start.counter + count;
+        while dot.counter < end {
+            let (mut n, offset) = match self.find_dot(&dot) {
+                Some(found) => found,
+                None => return,
+            };
+            if offset > 0 {
+                n = self.split_block(n, offset);
+            }
+            if self.nodes[n].block.len > end - dot.counter {
+                self.split_block(n, end - dot.counter);
+            }
+            let block = &mut self.nodes[n].block;
+            block.deleted = true;
+            block.vals = vec![];
+            dot.counter += block.len;
+            self.refresh(n);
+        }
+    }
+
+    /// Node of the block holding `dot` and the offset of the element in the block.
+    fn find_dot(&self, dot: &Dot<A>) -> Option<(usize, u64)> {
+        let (start, n) = self.index.get(&dot.actor)?.range(..=dot.counter).next_back()?;
+        let offset = dot.counter - start;
+        match offset < self.nodes[*n].block.len {
+            true => Some((*n, offset)),
+            false => None,
+        }
+    }
+
+    /// Node holding the visible element at `ix` and the offset of the element in its block.
+    fn find_index(&self, mut ix: usize) -> Option<(usize, usize)> {
+        if ix >= self.len() {
+            return None;
+        }
+        let mut n = self.root;
+        loop {
+            let left = self.nodes[n].left;
+            if ix < self.len_of(left) {
+                n = left;
+                continue;
+            }
+            ix -= self.len_of(left);
+            let visible = self.nodes[n].block.visible();
+            if ix < visible {
+                return Some((n, ix));
+            }
+            ix -= visible;
+            n = self.nodes[n].right;
+        }
+    }
+
+    /// Node of the block at position `rank`, which must be less than the block count.
+    fn node_at(&self, mut rank: usize) -> usize {
+        let mut n = self.root;
+        loop {
+            let left = self.nodes[n].left;
+            let before = self.blocks_of(left);
+            if rank < before {
+                n = left;
+            } else if rank == before {
+                return n;
+            } else {
+                rank -= before + 1;
+                n = self.nodes[n].right;
+            }
+        }
+    }
+
+    /// Position of the block of node `n`.
+    fn rank(&self, mut n: usize) -> usize {
+        let mut rank = self.blocks_of(self.nodes[n].left);
+        while self.nodes[n].parent != NIL {
+            let parent = self.nodes[n].parent;
+            if self.nodes[parent].right == n {
+                rank += self.blocks_of(self.nodes[parent].left) + 1;
+            }
+            n = parent;
+        }
+        rank
+    }
+
+    /// Splits the block of node `n` at `offset` and returns the node of the second part.
+    fn split_block(&mut self, n: usize, offset: u64) -> usize {
+        let rest = self.nodes[n].block.split_off(offset);
+        self.refresh(n);
+        let rank = self.rank(n) + 1;
+        self.insert_node(rank, rest)
+    }
+
+    fn insert_node(&mut self, rank: usize, block: Block<T, A>) -> usize {
+        // xorshift, the shape of the treap does not need better randomness
+        self.seed ^= self.seed << 13;
+        self.seed ^= self.seed >> 7;
+        self.seed ^= self.seed << 17;
+        let n = self.nodes.len();
+        self.index
+            .entry(block.id.actor.clone())
+            .or_default()
+            .insert(block.id.counter, n);
+        self.nodes.push(Node {
+            block,
+            priority: self.seed,
+            parent: NIL,
+            left: NIL,
+            right: NIL,
+            blocks: 0,
+            len: 0,
+        });
+        self.update(n);
+        let (left, right) = self.split(self.root, rank);
+        let left = self.merge(left, n);
+        self.root = self.merge(left, right);
+        self.nodes[self.root].parent = NIL;
+        n
+    }
+
+    fn blocks_of(&self, n: usize) -> usize {
+        match n {
+            NIL => 0,
+            n => self.nodes[n].blocks,
+        }
+    }
+
+    fn len_of(&self, n: usize) -> usize {
+        match n {
+            NIL => 0,
+            n => self.nodes[n].len,
+        }
+    }
+
+    /// Recomputes the counts of `n` from its children and adopts them.
+    fn update(&mut self, n: usize) {
+        let (left, right) = (self.nodes[n].left, self.nodes[n].right);
+        self.nodes[n].blocks = 1 + self.blocks_of(left) + self.blocks_of(right);
+        self.nodes[n].len = self.nodes[n].block.visible() + self.len_of(left) + self.len_of(right);
+        for child in [left, right] {
+            if child != NIL {
+                self.nodes[child].parent = n;
+            }
+        }
+    }
+
+    /// Recomputes the counts from `n` up to the root after the block of `n` changed.
+    fn refresh(&mut self, mut n: usize) {
+        while n != NIL {
+            self.update(n);
+            n = self.nodes[n].parent;
+        }
+    }
+
+    fn merge(&mut self, a: usize, b: usize) -> usize {
+        if a == NIL {
+            return b;
+        }
+        if b == NIL {
+            return a;
+        }
+        if self.nodes[a].priority > self.nodes[b].priority {
+            let right = self.merge(self.nodes[a].right, b);
+            self.nodes[a].right = right;
+            self.update(a);
+            a
+        } else {
+            let left = self.merge(a, self.nodes[b].left);
+            self.nodes[b].left = left;
+            self.update(b);
+            b
+        }
+    }
+
+    /// Splits the subtree of `n` into its first `rank` blocks and the others.
+    fn split(&mut self, n: usize, rank: usize) -> (usize, usize) {
+        if n == NIL {
+            return (NIL, NIL);
+        }
+        let left = self.nodes[n].left;
+        if rank <= self.blocks_of(left) {
+            let (a, b) = self.split(left, rank);
+            self.nodes[n].left = b;
+            self.update(n);
+            (a, n)
+        } else {
+            let right = self.nodes[n].right;
+            let (a, b) = self.split(right, rank - self.blocks_of(left) - 1);
+            self.nodes[n].right = a;
+            self.update(n);
+            (n, b)
+        }
+    }
+}
+
+impl<T: PartialEq, A: Ord + Clone> PartialEq for Rga<T, A> {
+    /// Replicas can cut their blocks differently, only the elements matter.
+    fn eq(&self, other: &Self) -> bool {
+        self.clock == other.clock && self.elements().eq(other.elements())
+    }
+}
+
+impl<T: Eq, A: Ord + Clone> Eq for Rga<T, A> {}
+
+impl<T, A: Ord + Clone + Debug> CmRDT for Rga<T, A> {
+    type Op = Op<T, A>;
+    type Validation = DotRange<A>;
+
+    fn validate_op(&self, op: &Self::Op) -> Result<(), Self::Validation> {
+        self.clock.validate_op(&op.dot())
+    }
+
+    /// Ops must be applied in causal order, an insert after an element or a delete of an
+    /// element that is not there yet is dropped.
+    fn apply(&mut self, op: Self::Op) {
+        let op_dot = op.dot();
+
+        if op_dot.counter <= self.clock.get(&op_dot.actor) {
+            return;
+        }
+
+        match op {
+            Op::Insert { after, id, lamport, vals } => {
+                if vals.is_empty() {
+                    return;
+                }
+                let last = vals.len() as u64 - 1;
+                self.clock.apply(Dot::new(id.actor.clone(), id.counter + last));
+                self.lamport = self.lamport.max(lamport + last);
+                self.integrate(after, id, lamport, vals);
+            }
+            Op::Delete { ranges, dot } => {
+                self.clock.apply(dot);
+                for (start, count) in ranges {
+                    self.delete(&start, count);
+                }
+            }
+        }
+    }
+}
+
+/// What a Rga is serialized to: its blocks in order, without the treap.
+#[derive(Serialize, Deserialize)]
+struct State<B, C> {
+    blocks: Vec<B>,
+    clock: C,
+    lamport: u64,
+}
+
+impl<T: Serialize, A: Ord + Clone + Serialize> Serialize for Rga<T, A> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        State {
+            blocks: self.in_order().into_iter().map(|n| &self.nodes[n].block).collect(),
+            clock: &self.clock,
+            lamport: self.lamport,
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de, T: Deserialize<'de>, A: Ord + Clone + Deserialize<'de>> Deserialize<'de> for Rga<T, A> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let state: State<Block<T, A>, VClock<A>> = State::deserialize(deserializer)?;
+        let mut rga = Rga {
+            clock: state.clock,
+            lamport: state.lamport,
+            ..Rga::default()
+        };
+        for block in state.blocks {
+            let rank = rga.block_count();
+            rga.insert_node(rank, block);
+        }
+        Ok(rga)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::crdts::list::List;
+    use quickcheck::quickcheck;
+    use std::time::Instant;
+
+    fn text(rga: &Rga<char, u8>) -> String {
+        rga.read()
+    }
+
+    /// A deterministic editing session: mostly typing at a cursor that sometimes jumps, with
+    /// backspaces in between.
+    fn trace(edits: usize) -> Vec<(usize, Option<char>)> {
+        let mut seed = 0x9e37_79b9_7f4a_7c15u64;
+        let mut next = move |bound: usize| {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            (seed % bound as u64) as usize
+        };
+        let (mut len, mut cursor) = (0, 0);
+        let mut trace = Vec::with_capacity(edits);
+        for _ in 0..edits {
+            if next(50) == 0 {
+                cursor = next(len + 1);
+            }
+            if cursor > 0 && next(8) == 0 {
+                cursor -= 1;
+                len -= 1;
+                trace.push((cursor, None));
+            } else {
+                trace.push((cursor, Some((b'a' + next(26) as u8) as char)));
+                cursor += 1;
+                len += 1;
+            }
+        }
+        trace
+    }
+
+    #[test]
+    fn typing_is_run_length_compressed() {
+        let mut rga = Rga::new();
+        for c in "hello world".chars() {
+            let op = rga.append(c, 1u8);
+            rga.apply(op);
+        }
+        assert_eq!(text(&rga), "hello world");
+        assert_eq!(rga.block_count(), 1);
+
+        let op = rga.delete_range(4..7, 1).unwrap();
+        rga.apply(op);
+        assert_eq!(text(&rga), "hellorld");
+        assert_eq!(rga.block_count(), 3);
+        assert_eq!(rga.position(4), Some(&'o'));
+        assert_eq!(rga.len(), 8);
+    }
+
+    #[test]
+    fn concurrent_runs_do_not_interleave() {
+        let mut a = Rga::new();
+        let op = a.insert_all_index(0, "[]".chars(), 1u8);
+        a.apply(op);
+        let mut b = a.clone();
+
+        let mut a_ops = vec![];
+        let mut b_ops = vec![];
+        for (i, (x, y)) in "abc".chars().zip("123".chars()).enumerate() {
+            let op = a.insert_index(1 + i, x, 1);
+            a.apply(op.clone());
+            a_ops.push(op);
+            let op = b.insert_index(1 + i, y, 2);
+            b.apply(op.clone());
+            b_ops.push(op);
+        }
+        let op = b.delete_index(3, 2).unwrap();
+        b.apply(op.clone());
+        b_ops.push(op);
+
+        for op in b_ops {
+            a.apply(op);
+        }
+        for op in a_ops {
+            b.apply(op);
+        }
+        assert_eq!(a, b);
+        assert_eq!(text(&a), "[12abc]");
+    }
+
+    #[test]
+    fn matches_list_on_text_trace() {
+        let mut list: List<char, u8> = List::new();
+        let mut rga = Rga::new();
+        for (ix, edit) in trace(2_000) {
+            match edit {
+                Some(c) => {
+                    list.apply(list.insert_index(ix, c, 1));
+                    rga.apply(rga.insert_index(ix, c, 1));
+                }
+                None => {
+                    list.apply(list.delete_index(ix, 1).unwrap());
+                    rga.apply(rga.delete_index(ix, 1).unwrap());
+                }
+            }
+        }
+        assert_eq!(list.read::<String>(), text(&rga));
+        // one block per run typed between two backspaces or cursor jumps, not one per char
+        assert!(rga.block_count() < rga.len() / 2);
+
+        let copy: Rga<char, u8> = serde_cbor::from_slice(&serde_cbor::to_vec(&rga).unwrap()).unwrap();
+        assert_eq!(copy, rga);
+        assert_eq!(text(&copy), text(&rga));
+    }
+
+    /// Run with `cargo test --release -- --ignored --nocapture bench_text_trace`.
+    #[test]
+    #[ignore]
+    fn bench_text_trace() {
+        let trace = trace(50_000);
+
+        let start = Instant::now();
+        let mut list: List<char, u8> = List::new();
+        for (ix, edit) in trace.iter().cloned() {
+            match edit {
+                Some(c) => list.apply(list.insert_index(ix, c, 1)),
+                None => list.apply(list.delete_index(ix, 1).unwrap()),
+            }
+        }
+        let list_time = start.elapsed();
+
+        let start = Instant::now();
+        let mut rga = Rga::new();
+        for (ix, edit) in trace.iter().cloned() {
+            match edit {
+                Some(c) => rga.apply(rga.insert_index(ix, c, 1)),
+                None => rga.apply(rga.delete_index(ix, 1).unwrap()),
+            }
+        }
+        let rga_time = start.elapsed();
+
+        let list_size = serde_cbor::to_vec(&list).unwrap().len();
+        let rga_size = serde_cbor::to_vec(&rga).unwrap().len();
+        println!("{} edits, {} chars left", trace.len(), rga.len());
+        println!("List: {:?}, {} bytes", list_time, list_size);
+        println!("Rga:  {:?}, {} bytes in {} blocks", rga_time, rga_size, rga.block_count());
+        assert_eq!(list.read::<String>(), text(&rga));
+    }
+
+    quickcheck! {
+        fn prop_concurrent_edits_converge(edits: Vec<(u8, u8, u8, bool)>) -> bool {
+            let mut base = Rga::new();
+            base.apply(base.insert_all_index(0, "base".chars(), 0u8));
+            let mut replicas = [base.clone(), base.clone(), base];
+            let mut ops: Vec<Vec<Op<char, u8>>> = vec![vec![]; 3];
+
+            for (actor, ix, len, delete) in edits {
+                let r = actor as usize % 3;
+                let replica = &mut replicas[r];
+                let ix = ix as usize % (replica.len() + 1);
+                let op = match delete {
+                    true => replica.delete_range(ix..ix + len as usize % 4 + 1, r as u8 + 1),
+                    false => {
+                        let vals = (0..len % 4 + 1).map(|i| (b'a' + (actor + i) % 26) as char);
+                        Some(replica.insert_all_index(ix, vals, r as u8 + 1))
+                    }
+                };
+                if let Some(op) = op {
+                    replica.apply(op.clone());
+                    ops[r].push(op);
+                }
+            }
+
+            // every replica gets the ops of the others, origin by origin in a different order
+            for (r, replica) in replicas.iter_mut().enumerate() {
+                for origin in [(r + 1) % 3, (r + 2) % 3] {
+                    for op in ops[origin].iter() {
+                        replica.apply(op.clone());
+                    }
+                }
+            }
+            replicas[0] == replicas[1]
+                && replicas[1] == replicas[2]
+                && text(&replicas[0]) == text(&replicas[1])
+                && text(&replicas[1]) == text(&replicas[2])
+        }
+    }
+}
@@ -0,0 +1,133 @@
+//! Causal stability: the dots every replica of a CRDT has seen.
+//!
+//! A dot is causally stable once all replicas have observed it; no replica can later produce
+//! or deliver an operation concurrent with it. Metadata kept only to order operations around
+//! stable dots, such as the contexts of deferred removes, can then be dropped.
+use std::collections::BTreeMap;
+
+use super::vclock::VClock;
+
+/// Tracks the clocks a fixed set of replicas reported for a CRDT.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StableCut<P: Ord, A: Ord> {
+    clocks: BTreeMap<P, Option<VClock<A>>>,
+}
+
+impl<P: Ord, A: Ord + Clone> StableCut<P, A> {
+    /// Tracks the given replicas, none of which has reported a clock yet.
+    pub fn new<I: IntoIterator<Item = P>>(replicas: I) -> Self {
+        StableCut {
+            clocks: replicas.into_iter().map(|p| (p, None)).collect(),
+        }
+    }
+
+    pub fn add_replica(&mut self, replica: P) {
+        self.clocks.entry(replica).or_insert(None);
+    }
+
+    /// Stops waiting on a replica that left, which may make more dots stable.
+    pub fn remove_replica(&mut self, replica: &P) {
+        self.clocks.remove(replica);
+    }
+
+    pub fn replicas(&self) -> impl Iterator<Item = &P> {
+        self.clocks.keys()
+    }
+
+    /// Records that `replica` has seen `clock`. Clocks of unknown replicas are ignored.
+    pub fn observe(&mut self, replica: &P, clock: &VClock<A>) {
+        if let Some(seen) = self.clocks.get_mut(replica) {
+            match seen {
+                Some(seen) => {
+                    for (actor, counter) in clock.dots.iter() {
+                        if seen.get(actor) < *counter {
+                            seen.dots.insert(actor.clone(), *counter);
+                        }
+                    }
+                }
+                None => *seen = Some(clock.clone()),
+            }
+        }
+    }
+
+    /// The greatest lower bound of the clocks of all replicas, empty until every replica has
+    /// reported one.
+    pub fn cut(&self) -> VClock<A> {
+        let mut clocks = self.clocks.values();
+        let mut cut = match clocks.next() {
+            Some(Some(clock)) => clock.clone(),
+            _ => return VClock::new(),
+        };
+        for clock in clocks {
+            match clock {
+                Some(clock) => cut.glb(clock),
+                None => return VClock::new(),
+            }
+        }
+        cut
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::crdts::dot::Dot;
+    use crate::crdts::orswot::Orswot;
+    use crate::crdts::traits::CmRDT;
+
+    fn clock(dots: &[(u8, u64)]) -> VClock<u8> {
+        dots.iter().map(|(a, c)| Dot::new(*a, *c)).collect()
+    }
+
+    #[test]
+    fn cut_waits_for_every_replica() {
+        let mut cut = StableCut::new(vec![1u8, 2, 3]);
+        cut.observe(&1, &clock(&[(1, 4), (2, 2)]));
+        cut.observe(&2, &clock(&[(1, 3), (2, 5)]));
+        assert_eq!(cut.cut(), VClock::new());
+
+        cut.observe(&3, &clock(&[(1, 6), (2, 1), (3, 1)]));
+        assert_eq!(cut.cut(), clock(&[(1, 3), (2, 1)]));
+
+        cut.remove_replica(&3);
+        assert_eq!(cut.cut(), clock(&[(1, 3), (2, 2)]));
+    }
+
+    #[test]
+    fn gc_prunes_stable_dots_of_deferred_removes() {
+        let mut a: Orswot<u8, u8> = Orswot::new();
+        let mut b: Orswot<u8, u8> = Orswot::new();
+        let mut c: Orswot<u8, u8> = Orswot::new();
+
+        let add_x = a.add(1, a.read_ctx().derive_add_ctx(1));
+        a.apply(add_x.clone());
+        b.apply(add_x.clone());
+        let add_y = b.add(2, b.read_ctx().derive_add_ctx(2));
+        b.apply(add_y.clone());
+        let rm_x = b.rm(1, b.read_ctx().derive_rm_ctx());
+        b.apply(rm_x.clone());
+
+        // c sees the remove before the adds it depends on
+        let mut reference = c.clone();
+        for o in [&mut c, &mut reference] {
+            o.apply(rm_x.clone());
+            o.apply(add_x.clone());
+        }
+        assert_eq!(c.deferred_len(), 1);
+
+        let mut cut = StableCut::new(vec![1u8, 2, 3]);
+        cut.observe(&1, &a.clock());
+        cut.observe(&2, &b.clock());
+        cut.observe(&3, &c.clock());
+        assert_eq!(c.gc(&cut.cut()), 0);
+        let contexts: Vec<_> = c.deferred.keys().cloned().collect();
+        assert_eq!(contexts, vec![clock(&[(2, 1)])]);
+
+        for o in [&mut c, &mut reference] {
+            o.apply(add_y.clone());
+        }
+        assert_eq!(c.deferred_len(), 0);
+        assert_eq!(c.read().val, reference.read().val);
+        assert_eq!(c.read().val, b.read().val);
+    }
+}
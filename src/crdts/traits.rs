@@ -73,3 +73,22 @@ pub trait ResetRemove<A: Ord> {
     /// Remove data that is strictly smaller than this clock
     fn reset_remove(&mut self, clock: &VClock<A>);
 }
+
+/// Delta-state CRDT's replicate by transmitting only the part of the state
+/// touched by recent mutations.
+///
+/// A delta-mutator returns a `Delta` instead of mutating the CRDT. Deltas are
+/// states themselves: several deltas can be joined into a delta-group with
+/// `CvRDT::merge`, and merging a delta-group has the same effect as merging
+/// each of its deltas in turn.
+///
+/// Deltas must be merged in causal order, i.e. a replica must merge every delta
+/// an actor produced before merging a later delta from that same actor. The
+/// acknowledgement protocol of `DeltaBuffer` provides this ordering.
+pub trait DeltaCRDT: CvRDT {
+    /// The delta-state produced by this CRDT's delta-mutators.
+    type Delta: CvRDT + Default + Clone;
+
+    /// Merge a delta or a delta-group into the current CRDT.
+    fn merge_delta(&mut self, delta: Self::Delta);
+}
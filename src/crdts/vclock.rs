@@ -10,6 +10,7 @@ use quickcheck::{Arbitrary, Gen};
 //use crate::{CmRDT, CvRDT, Dot, DotRange, ResetRemove};
 use super::traits::{CmRDT, CvRDT, ResetRemove};
 use super::dot::{Dot, DotRange};
+use crate::memory::{sampled_usage, MemoryUsage};
 
 /// A `VClock` is a standard vector clock.
 /// It contains a set of "actors" and associated counters.
@@ -79,6 +80,13 @@ impl<A: Ord> ResetRemove<A> for VClock<A> {
     }
 }
 
+impl<A: Ord + MemoryUsage> MemoryUsage for VClock<A> {
+    fn heap_usage(&self, samples: usize) -> usize {
+        self.dots.len() * (mem::size_of::<A>() + mem::size_of::<u64>())
+            + sampled_usage(self.dots.len(), samples, self.dots.keys().map(|a| a.heap_usage(samples)))
+    }
+}
+
 impl<A: Ord + Clone + Debug> CmRDT for VClock<A> {
     type Op = Dot<A>;
     type Validation = DotRange<A>;
@@ -53,6 +53,12 @@ impl VectorEntry {
         };
     }
 
+    /// Marks every version up to `clk` as seen, whether it was or not.
+    pub fn skip_to(&mut self, clk: LogTime) {
+        self.next_version = self.next_version.max(clk + 1);
+        self.exceptions.retain(|exception| *exception > clk);
+    }
+
     pub fn is_ready(&self, clk: LogTime) -> bool {
         clk < self.next_version && self.no_exceptions(clk)
     }
@@ -135,6 +141,42 @@ impl<A: Hash + Clone + Eq, T: CausalOp<A>> CausalityBarrier<A, T> {
         }
     }
 
+    /// Like `ingest`, but an op is only integrated once the op it happens after was, and
+    /// integrating it releases the buffered ops that were waiting on it. Returns every op that
+    /// became deliverable, in causal order.
+    pub fn ingest_all(&mut self, op: T) -> Vec<T> {
+        if self.saw_site_dot(&op.dot()) {
+            return Vec::new();
+        }
+        if let Some(dot) = op.happens_after() {
+            if !self.saw_site_dot(&dot) {
+                self.buffer.insert(dot, op);
+                return Vec::new();
+            }
+        }
+        self.release(Some(op))
+    }
+
+    /// Gives up on the ops up to `dot` that never arrived, and integrates the buffered ops that
+    /// were waiting on them. Returns every op that became deliverable, in causal order.
+    pub fn skip_to(&mut self, dot: &Dot<A>) -> Vec<T> {
+        self.peers.entry(dot.actor.clone()).or_default().skip_to(dot.counter);
+        let next = self.buffer.remove(dot);
+        self.release(next)
+    }
+
+    /// Integrates `next` and the chain of buffered ops waiting on it.
+    fn release(&mut self, mut next: Option<T>) -> Vec<T> {
+        let mut ready = Vec::new();
+        while let Some(op) = next.take() {
+            let dot = op.dot();
+            self.peers.entry(dot.actor.clone()).or_default().increment(dot.counter);
+            next = self.buffer.remove(&dot);
+            ready.push(op);
+        }
+        ready
+    }
+
     fn saw_site_dot(&self, dot: &Dot<A>) -> bool {
         // TODO: shouldn't need to deconstruct a dot like this
         match self.peers.get(&dot.actor) {
@@ -286,6 +328,48 @@ mod test {
         assert_eq!(barrier.ingest(ins), None);
     }
 
+    #[test]
+    fn ingest_all_releases_buffered_ops() {
+        let mut barrier = CausalityBarrier::new();
+
+        let ins = CausalMessage {
+            time: 0,
+            local_id: 1,
+            op: Op::Insert(0),
+        };
+        let del = CausalMessage {
+            time: 0,
+            local_id: 2,
+            op: Op::Delete(1, 0),
+        };
+
+        assert_eq!(barrier.ingest_all(del.clone()), vec![]);
+        assert_eq!(barrier.ingest_all(ins.clone()), vec![ins.clone(), del.clone()]);
+        assert_eq!(barrier.ingest_all(del), vec![]);
+        assert_eq!(barrier.ingest_all(ins), vec![]);
+    }
+
+    #[test]
+    fn skip_to_releases_ops_waiting_on_lost_ones() {
+        let mut barrier = CausalityBarrier::new();
+
+        let lost = CausalMessage {
+            time: 0,
+            local_id: 1,
+            op: Op::Insert(0),
+        };
+        let del = CausalMessage {
+            time: 0,
+            local_id: 2,
+            op: Op::Delete(1, 0),
+        };
+
+        assert_eq!(barrier.ingest_all(del.clone()), vec![]);
+        assert_eq!(barrier.skip_to(&Dot::new(1, 0)), vec![del.clone()]);
+        assert_eq!(barrier.ingest_all(lost), vec![]);
+        assert_eq!(barrier.ingest_all(del), vec![]);
+    }
+
     #[test]
     fn entry_diff_new_entries() {
         let a = VectorEntry::new();
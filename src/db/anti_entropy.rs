@@ -0,0 +1,975 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, Sender};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::crdts::hlc::Hlc;
+use crate::svalue::hash::murmur_hash64a;
+use crate::svalue::list::ListWhere;
+use crate::svalue::object::{Robj, RobjEncoding, RobjPtr, RobjType};
+use crate::svalue::replicated::ReplicatedState;
+
+use super::db::DB;
+
+pub type Digest = [u8; 32];
+
+/// Depth of the Merkle trees compared by anti-entropy, the keyspace is cut in
+/// `2^MERKLE_DEPTH` key-hash ranges.
+pub const MERKLE_DEPTH: u8 = 10;
+
+/// How long the tree a round compares is reused for its next levels, see [RoundTrees]
+pub const ROUND_TREE_TTL: Duration = Duration::from_secs(5);
+
+/// Milliseconds deletes are remembered for. A replica missing a delete for longer may bring
+/// the deleted key back.
+pub const TOMBSTONE_RETENTION: u64 = 24 * 60 * 60 * 1000;
+
+/// Longest a peer may take to send a message. Every connection is read by its own thread, a
+/// stalled one only holds that thread until then.
+pub const MESSAGE_READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Largest message read from a peer, twice the largest value a key may hold
+pub const MAX_MESSAGE_SIZE: usize = 1 << 30;
+
+const EMPTY_DIGEST: Digest = [0; 32];
+
+/// Milliseconds since the unix epoch
+fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64)
+}
+
+/// Key-hash range, i.e. Merkle leaf, `key` falls into.
+pub fn key_leaf(key: &[u8], depth: u8) -> u64 {
+    if depth == 0 {
+        return 0;
+    }
+    murmur_hash64a(key, 0) >> (64 - depth as u32)
+}
+
+pub(crate) fn object_bytes(o: &RobjPtr) -> Vec<u8> {
+    let o = o.borrow();
+    match o.encoding() {
+        RobjEncoding::Int => o.integer().to_string().into_bytes(),
+        _ => o.string().to_vec(),
+    }
+}
+
+/// A plain value as transferred between replicas. Set members, hash fields and sorted set
+/// members are sorted so that equal values encode to the same bytes, whatever their encoding.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PlainValue {
+    String(Vec<u8>),
+    List(Vec<Vec<u8>>),
+    Set(Vec<Vec<u8>>),
+    Hash(Vec<(Vec<u8>, Vec<u8>)>),
+    /// Members with the bits of their score, which keep the value comparable
+    Zset(Vec<(Vec<u8>, u64)>),
+}
+
+impl PlainValue {
+    /// `None` for the replicated types, which are transferred as their state, see
+    /// [KeyValue::Replicated].
+    pub fn from_object(o: &Robj) -> Option<PlainValue> {
+        let value = match o.object_type() {
+            RobjType::String => PlainValue::String(match o.encoding() {
+                RobjEncoding::Int => o.integer().to_string().into_bytes(),
+                _ => o.string().to_vec(),
+            }),
+            RobjType::List => PlainValue::List(o.list_iter().map(|e| object_bytes(&e)).collect()),
+            RobjType::Set => {
+                let mut members: Vec<Vec<u8>> = o.set_iter().map(|e| object_bytes(&e)).collect();
+                members.sort();
+                PlainValue::Set(members)
+            }
+            RobjType::Hash => {
+                let mut fields: Vec<(Vec<u8>, Vec<u8>)> = o.hash_iter()
+                    .map(|(f, v)| (object_bytes(&f), object_bytes(&v)))
+                    .collect();
+                fields.sort();
+                PlainValue::Hash(fields)
+            }
+            RobjType::Zset => {
+                let mut members: Vec<(Vec<u8>, u64)> = o.zset_iter()
+                    .map(|(m, score)| (object_bytes(&m), score.to_bits()))
+                    .collect();
+                members.sort();
+                PlainValue::Zset(members)
+            }
+            _ => return None,
+        };
+        Some(value)
+    }
+
+    pub fn into_object(self) -> RobjPtr {
+        match self {
+            PlainValue::String(s) => Robj::from_bytes(s),
+            PlainValue::List(elements) => {
+                let o = Robj::create_list_object();
+                for e in elements {
+                    o.borrow_mut().list_push(Robj::from_bytes(e), ListWhere::Tail);
+                }
+                o
+            }
+            PlainValue::Set(members) => {
+                let o = Robj::create_set_object();
+                for m in members {
+                    let _ = o.borrow_mut().set_add(Robj::from_bytes(m));
+                }
+                o
+            }
+            PlainValue::Hash(fields) => {
+                let o = Robj::create_hash_object();
+                for (f, v) in fields {
+                    let _ = o.borrow_mut().hash_table_mut().add(Robj::from_bytes(f), Robj::from_bytes(v));
+                }
+                o
+            }
+            PlainValue::Zset(members) => {
+                let o = Robj::create_zset_object();
+                for (m, score) in members {
+                    let _ = o.borrow_mut().zset_add(Robj::from_bytes(m), f64::from_bits(score));
+                }
+                o
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum KeyValue {
+    /// Merged with the local state of the key
+    Replicated(ReplicatedState),
+    /// The most recent write wins
+    Plain { written: Hlc, value: PlainValue },
+    /// A plain key deleted or expired at the given time, which competes with the writes
+    Deleted(Hlc),
+}
+
+impl KeyValue {
+    /// Hash of the value, equal on replicas holding the same value whatever the order their
+    /// writes were applied in.
+    pub fn digest(&self) -> Digest {
+        let canonical = match self {
+            KeyValue::Plain { value, .. } => serde_cbor::to_vec(value),
+            KeyValue::Deleted(when) => serde_cbor::to_vec(when),
+            KeyValue::Replicated(ReplicatedState::Counter(c)) => serde_cbor::to_vec(c),
+            KeyValue::Replicated(ReplicatedState::Set(s)) => {
+                let mut members: Vec<Vec<u8>> = s.read().val.into_iter().collect();
+                members.sort();
+                serde_cbor::to_vec(&(s.clock(), members))
+            }
+            KeyValue::Replicated(ReplicatedState::Register(r)) => {
+                let mut vals = r.read().val;
+                vals.sort();
+                serde_cbor::to_vec(&(r.read_ctx().add_clock, vals))
+            }
+            KeyValue::Replicated(ReplicatedState::Map(m)) => {
+                let entries: Vec<_> = m.keys()
+                    .map(|k| {
+                        let entry = m.get(k.val);
+                        let mut vals = entry.val.map(|r| r.read().val).unwrap_or_default();
+                        vals.sort();
+                        (k.val.clone(), entry.rm_clock, vals)
+                    })
+                    .collect();
+                serde_cbor::to_vec(&(m.read_ctx().add_clock, entries))
+            }
+        };
+        *blake3::hash(&canonical.expect("Values always encode")).as_bytes()
+    }
+}
+
+/// A key and its value as transferred between replicas.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KeyEntry {
+    key: Vec<u8>,
+    value: KeyValue,
+    /// When the key expires, in milliseconds since the unix epoch
+    #[serde(default)]
+    expires: Option<u64>,
+}
+
+/// When `key` expires, in milliseconds since the unix epoch
+fn expires_at(db: &DB, key: &RobjPtr) -> Option<u64> {
+    db.expires.find(key).map(|(_, when)| unix_millis(*when))
+}
+
+/// The entries of the keys and tombstones of `db` that `keep` accepts, see [KeyEntry::from_db]
+fn db_entries<'a, F>(db: &'a DB, keep: &'a F) -> impl Iterator<Item = KeyEntry> + 'a
+where
+    F: Fn(&[u8]) -> bool,
+{
+    let values = db.dict.iter()
+        .filter(move |(key, _)| keep(&object_bytes(key)))
+        .filter_map(move |(key, value)| KeyEntry::from_db(db, key, value));
+    let deletes = db.tombstones.iter()
+        .filter(move |(key, _)| db.dict.find(key).is_none() && keep(&object_bytes(key)))
+        .map(|(key, when)| KeyEntry::deleted(object_bytes(key), *when));
+    values.chain(deletes)
+}
+
+impl KeyEntry {
+    /// `None` for the values anti-entropy does not transfer and the keys that expired.
+    pub fn from_db(db: &DB, key: &RobjPtr, value: &RobjPtr) -> Option<KeyEntry> {
+        let expires = expires_at(db, key);
+        if expires.is_some_and(|expires| expires <= unix_millis(SystemTime::now())) {
+            return None;
+        }
+        let o = value.borrow();
+        let value = if o.is_replicated() {
+            KeyValue::Replicated(o.replicated_state())
+        } else {
+            KeyValue::Plain {
+                written: db.write_time(key).unwrap_or_default(),
+                value: PlainValue::from_object(&o)?,
+            }
+        };
+        Some(KeyEntry {
+            key: object_bytes(key),
+            value,
+            expires,
+        })
+    }
+
+    fn deleted(key: Vec<u8>, when: Hlc) -> KeyEntry {
+        KeyEntry {
+            key,
+            value: KeyValue::Deleted(when),
+            expires: None,
+        }
+    }
+
+    pub fn key(&self) -> &[u8] {
+        &self.key
+    }
+
+    fn digest(&self) -> Digest {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(&(self.key.len() as u64).to_le_bytes());
+        hasher.update(&self.key);
+        hasher.update(&self.value.digest());
+        if let Some(expires) = self.expires {
+            hasher.update(&expires.to_le_bytes());
+        }
+        *hasher.finalize().as_bytes()
+    }
+
+    /// Merges the entry into `db`: replicated values are merged and keep the earliest expiry,
+    /// plain values and deletes replace the local value or delete if they happened later. Fails
+    /// if the key holds a value of another kind, or if the value was written too far ahead of
+    /// the local clock.
+    pub fn merge_into(self, db: &mut DB) -> Result<(), ()> {
+        let key = Robj::from_bytes(self.key.clone());
+        let local = db.look_up_key_read(&key);
+        let (written, value) = match self.value {
+            KeyValue::Replicated(state) => {
+                match local {
+                    Some(o) => o.borrow_mut().replicated_merge(state)?,
+                    None => db.dict.add(Rc::clone(&key), Robj::from_replicated_state(state))?,
+                }
+                let local_expires = expires_at(db, &key);
+                let expires = match (local_expires, self.expires) {
+                    (Some(local), Some(remote)) => Some(std::cmp::min(local, remote)),
+                    (local, remote) => local.or(remote),
+                };
+                if expires != local_expires {
+                    set_expires(db, key, expires)?;
+                }
+                return Ok(());
+            }
+            KeyValue::Plain { ref written, ref value } => (*written, Some(value.clone())),
+            KeyValue::Deleted(when) => (when, None),
+        };
+        db.observe_write_time(&written).map_err(|_| ())?;
+        let local_entry = match &local {
+            Some(o) if o.borrow().is_replicated() => return if value.is_some() { Err(()) } else { Ok(()) },
+            Some(o) => Some((db.write_time(&key).unwrap_or_default(), KeyEntry::from_db(db, &key, o))),
+            None => db.tombstone(&key).map(|when| (when, Some(KeyEntry::deleted(self.key.clone(), when)))),
+        };
+        if let Some((local_written, local_entry)) = local_entry {
+            if local_written > written {
+                return Ok(());
+            }
+            // concurrent writes, break the tie the same way on every replica
+            if local_written == written && local_entry.map_or(EMPTY_DIGEST, |entry| entry.digest()) >= self.digest() {
+                return Ok(());
+            }
+        }
+        match value {
+            Some(value) => {
+                db.set_key_at(Rc::clone(&key), value.into_object(), written);
+                set_expires(db, key, self.expires)
+            }
+            None => {
+                if local.is_some() {
+                    db.evict_key(&key)?;
+                }
+                db.bury_at(key, written);
+                Ok(())
+            }
+        }
+    }
+}
+
+fn set_expires(db: &mut DB, key: RobjPtr, expires: Option<u64>) -> Result<(), ()> {
+    let _ = db.remove_expire(&key);
+    match expires {
+        Some(expires) => db.set_expire(key, UNIX_EPOCH + Duration::from_millis(expires)),
+        None => Ok(()),
+    }
+}
+
+/// Hashes of a database keyspace: leaves cover key-hash ranges and hold the XOR of the
+/// digests of their keys, inner nodes hash their two children.
+pub struct MerkleTree {
+    levels: Vec<Vec<Digest>>,
+}
+
+impl MerkleTree {
+    pub fn build(db: &DB, depth: u8) -> MerkleTree {
+        MerkleTree::build_where(db, depth, |_| true)
+    }
+
+    /// Same as [MerkleTree::build], covering only the keys `keep` accepts, such as the keys of
+    /// the slots this node of the cluster holds.
+    pub fn build_where<F>(db: &DB, depth: u8, keep: F) -> MerkleTree
+    where
+        F: Fn(&[u8]) -> bool,
+    {
+        let mut leaves = vec![EMPTY_DIGEST; 1 << depth];
+        for entry in db_entries(db, &keep) {
+            let leaf = &mut leaves[key_leaf(&entry.key, depth) as usize];
+            for (l, d) in leaf.iter_mut().zip(entry.digest().iter()) {
+                *l ^= d;
+            }
+        }
+
+        let mut levels = vec![leaves];
+        while levels[0].len() > 1 {
+            let parents = levels[0]
+                .chunks(2)
+                .map(|pair| {
+                    let mut hasher = blake3::Hasher::new();
+                    hasher.update(&pair[0]);
+                    hasher.update(&pair[1]);
+                    *hasher.finalize().as_bytes()
+                })
+                .collect();
+            levels.insert(0, parents);
+        }
+        MerkleTree { levels }
+    }
+
+    pub fn depth(&self) -> u8 {
+        (self.levels.len() - 1) as u8
+    }
+
+    pub fn root(&self) -> Digest {
+        self.levels[0][0]
+    }
+
+    /// Hash of the `index`th node of `level`, the root being the only node of level 0.
+    pub fn node(&self, level: u8, index: u64) -> Option<Digest> {
+        self.levels.get(level as usize)?.get(index as usize).copied()
+    }
+}
+
+/// Merkle trees of the rounds in progress, by database, so that the levels of a round are
+/// compared against one tree instead of a tree rebuilt for each of them. A tree is dropped
+/// once keys were merged into its database or after [ROUND_TREE_TTL].
+#[derive(Default)]
+pub struct RoundTrees {
+    trees: HashMap<usize, (MerkleTree, Instant)>,
+}
+
+impl RoundTrees {
+    pub fn new() -> RoundTrees {
+        RoundTrees::default()
+    }
+
+    /// Tree of database `index`, built from `db` over the keys `keep` accepts unless a recent
+    /// one is known
+    pub fn get_or_build<F>(&mut self, index: usize, db: &DB, keep: F) -> &MerkleTree
+    where
+        F: Fn(&[u8]) -> bool,
+    {
+        let stale = self.trees.get(&index).is_none_or(|(_, built)| built.elapsed() >= ROUND_TREE_TTL);
+        if stale {
+            self.trees.insert(index, (MerkleTree::build_where(db, MERKLE_DEPTH, keep), Instant::now()));
+        }
+        &self.trees[&index].0
+    }
+
+    /// Forgets the tree of database `index`, whose keys changed
+    pub fn invalidate(&mut self, index: usize) {
+        self.trees.remove(&index);
+    }
+}
+
+/// Messages exchanged by two replicas repairing a database.
+///
+/// The initiator sends its root; each side answers the nodes that differ from its own with
+/// the hashes of their children, until the differing leaves are reached. The keys of those
+/// leaves are then sent both ways and merged.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum AntiEntropyMessage {
+    Hashes {
+        sender: String,
+        db: usize,
+        depth: u8,
+        level: u8,
+        nodes: Vec<(u64, Digest)>,
+    },
+    Keys {
+        sender: String,
+        db: usize,
+        depth: u8,
+        leaves: Vec<u64>,
+        entries: Vec<KeyEntry>,
+        /// Whether the receiver should answer with its own keys of `leaves`
+        reply: bool,
+    },
+}
+
+impl AntiEntropyMessage {
+    /// Starts repairing database `db` with a peer, `sender` being the address answers go to.
+    pub fn start(sender: String, db: usize, tree: &MerkleTree) -> AntiEntropyMessage {
+        AntiEntropyMessage::Hashes {
+            sender,
+            db,
+            depth: tree.depth(),
+            level: 0,
+            nodes: vec![(0, tree.root())],
+        }
+    }
+
+    pub fn sender(&self) -> &str {
+        match self {
+            AntiEntropyMessage::Hashes { sender, .. } => sender,
+            AntiEntropyMessage::Keys { sender, .. } => sender,
+        }
+    }
+
+    pub fn db(&self) -> usize {
+        match self {
+            AntiEntropyMessage::Hashes { db, .. } => *db,
+            AntiEntropyMessage::Keys { db, .. } => *db,
+        }
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+        Ok(serde_cbor::to_vec(self)?)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Box<dyn Error>> {
+        Ok(serde_cbor::from_slice(bytes)?)
+    }
+
+    /// Whether the message is about trees of [MERKLE_DEPTH] and a level they have. Peers
+    /// sending anything else are not answered.
+    fn is_well_formed(&self) -> bool {
+        match self {
+            AntiEntropyMessage::Hashes { depth, level, .. } => *depth == MERKLE_DEPTH && level <= depth,
+            AntiEntropyMessage::Keys { depth, .. } => *depth == MERKLE_DEPTH,
+        }
+    }
+
+    /// Processes the message against `db` and returns the answer to send back, if any.
+    /// `address` is where the peer should send its own answer.
+    pub fn handle(self, db: &mut DB, address: &str) -> Option<AntiEntropyMessage> {
+        self.handle_where(db, address, |_| true, &mut RoundTrees::new())
+    }
+
+    /// Same as [AntiEntropyMessage::handle], leaving out of the repair the keys `keep` refuses:
+    /// they are neither compared, sent nor merged. The trees compared come from `trees`.
+    pub fn handle_where<F>(self, db: &mut DB, address: &str, keep: F, trees: &mut RoundTrees) -> Option<AntiEntropyMessage>
+    where
+        F: Fn(&[u8]) -> bool,
+    {
+        if !self.is_well_formed() {
+            log::warn!("Ignoring malformed anti-entropy message of {}", self.sender());
+            return None;
+        }
+        match self {
+            AntiEntropyMessage::Hashes { db: index, depth, level, nodes, .. } => {
+                let tree = trees.get_or_build(index, db, &keep);
+                let differing: Vec<u64> = nodes.into_iter()
+                    .filter(|(i, digest)| tree.node(level, *i).is_some_and(|d| d != *digest))
+                    .map(|(i, _)| i)
+                    .collect();
+                if differing.is_empty() {
+                    return None;
+                }
+                if level == depth {
+                    return Some(AntiEntropyMessage::Keys {
+                        sender: address.to_string(),
+                        db: index,
+                        depth,
+                        entries: leaf_entries(db, &differing, depth, &keep),
+                        leaves: differing,
+                        reply: true,
+                    });
+                }
+                let nodes = differing.iter()
+                    .flat_map(|i| vec![2 * i, 2 * i + 1])
+                    .map(|i| (i, tree.node(level + 1, i).unwrap()))
+                    .collect();
+                Some(AntiEntropyMessage::Hashes {
+                    sender: address.to_string(),
+                    db: index,
+                    depth,
+                    level: level + 1,
+                    nodes,
+                })
+            }
+            AntiEntropyMessage::Keys { db: index, depth, leaves, entries, reply, .. } => {
+                // answer with the keys we had before merging, the peer already has its own
+                let answer = if reply {
+                    Some(AntiEntropyMessage::Keys {
+                        sender: address.to_string(),
+                        db: index,
+                        depth,
+                        entries: leaf_entries(db, &leaves, depth, &keep),
+                        leaves,
+                        reply: false,
+                    })
+                } else {
+                    None
+                };
+                for entry in entries.into_iter().filter(|entry| keep(&entry.key)) {
+                    let key = entry.key.clone();
+                    if entry.merge_into(db).is_err() {
+                        log::warn!("Key {:?} holds another kind of value on the peer", key);
+                    }
+                }
+                trees.invalidate(index);
+                answer
+            }
+        }
+    }
+}
+
+fn leaf_entries<F>(db: &DB, leaves: &[u64], depth: u8, keep: &F) -> Vec<KeyEntry>
+where
+    F: Fn(&[u8]) -> bool,
+{
+    db_entries(db, keep)
+        .filter(|entry| leaves.contains(&key_leaf(&entry.key, depth)))
+        .collect()
+}
+
+/// Reads the message a peer sends on `stream` before closing it, failing when the peer is too
+/// slow or the message too large.
+fn read_message(stream: &mut TcpStream) -> io::Result<Vec<u8>> {
+    let started = Instant::now();
+    stream.set_read_timeout(Some(MESSAGE_READ_TIMEOUT))?;
+    let mut message = Vec::new();
+    let mut chunk = [0u8; 64 * 1024];
+    loop {
+        let read = stream.read(&mut chunk)?;
+        if read == 0 {
+            return Ok(message);
+        }
+        message.extend_from_slice(&chunk[..read]);
+        if message.len() > MAX_MESSAGE_SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Message too large"));
+        }
+        if started.elapsed() > MESSAGE_READ_TIMEOUT {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "Message took too long to arrive"));
+        }
+    }
+}
+
+/// Whether `sender`, the address a message claims to come from, is on the host the message
+/// came from
+pub(crate) fn is_sent_from(sender: &str, peer: &SocketAddr) -> bool {
+    sender.to_socket_addrs().is_ok_and(|mut addresses| addresses.any(|address| address.ip() == peer.ip()))
+}
+
+/// Accepts the connections of peers sending one message each, every connection read by its
+/// own thread. `on_message` gets the messages with the address they came from and returns
+/// `false` once no more are wanted.
+pub(crate) fn start_message_listener<F>(name: &'static str, address: SocketAddr, shutdown: Arc<AtomicBool>, on_message: F) -> Result<JoinHandle<()>, Box<dyn Error>>
+    where F: Fn(SocketAddr, Vec<u8>) -> bool + Clone + Send + 'static
+{
+    let listener = TcpListener::bind(address)?;
+    let handle = std::thread::Builder::new().name(format!("{} - {} listener", address, name)).spawn(move || {
+        log::info!("{} listener started at {}", name, address);
+        let mut readers: Vec<JoinHandle<()>> = Vec::new();
+        let done = Arc::new(AtomicBool::new(false));
+        for incoming_stream in listener.incoming() {
+            if shutdown.load(Ordering::SeqCst) || done.load(Ordering::SeqCst) {
+                break;
+            }
+            let (mut stream, peer) = match incoming_stream.and_then(|stream| stream.peer_addr().map(|peer| (stream, peer))) {
+                Ok(connection) => connection,
+                Err(e) => {
+                    log::warn!("Connection failed: {}", e);
+                    continue;
+                }
+            };
+            readers.retain(|reader| !reader.is_finished());
+            let on_message = on_message.clone();
+            let done = Arc::clone(&done);
+            let reader = std::thread::Builder::new().name(format!("{} - {} from {}", address, name, peer)).spawn(move || {
+                match read_message(&mut stream) {
+                    Ok(message) => {
+                        if !on_message(peer, message) {
+                            done.store(true, Ordering::SeqCst);
+                        }
+                    }
+                    Err(e) => log::warn!("Dropping {} connection from {}: {}", name, peer, e),
+                }
+            });
+            match reader {
+                Ok(reader) => readers.push(reader),
+                Err(e) => log::error!("Could not read connection from {}: {}", peer, e),
+            }
+        }
+        for reader in readers {
+            if let Err(e) = reader.join() {
+                log::error!("Error joining thread: {:?}", e);
+            }
+        }
+        log::info!("{} listener exiting", name);
+    })?;
+    Ok(handle)
+}
+
+pub enum AntiEntropyEvent {
+    /// Time to start repairing every database with this peer
+    Round(SocketAddr),
+    /// A message sent by a peer
+    Message(AntiEntropyMessage),
+}
+
+/// Background part of anti-entropy: listens for peer messages and periodically picks a peer
+/// to repair with. The databases are only touched by the thread calling
+/// [AntiEntropyService::try_recv], see [super::server::Server::poll_anti_entropy].
+pub struct AntiEntropyService {
+    address: SocketAddr,
+    shutdown: Arc<AtomicBool>,
+    activities: Vec<JoinHandle<()>>,
+    receiver: Receiver<AntiEntropyEvent>,
+}
+
+impl AntiEntropyService {
+    /// Starts the service
+    ///
+    /// # Arguments
+    ///
+    /// * `address` - Address to listen on for anti-entropy messages
+    /// * `period` - Milliseconds between two repair rounds
+    /// * `peers` - Anti-entropy addresses of the other replicas
+    pub fn start(address: SocketAddr, period: u64, peers: Vec<SocketAddr>) -> Result<AntiEntropyService, Box<dyn Error>> {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let listener = Self::start_listener(address, peers.clone(), Arc::clone(&shutdown), sender.clone())?;
+        let ticker = Self::start_ticker(address, period, peers, Arc::clone(&shutdown), sender)?;
+        Ok(AntiEntropyService {
+            address,
+            shutdown,
+            activities: vec![listener, ticker],
+            receiver,
+        })
+    }
+
+    /// Only the messages of `peers` are accepted, coming from the host of the peer they name
+    fn start_listener(address: SocketAddr, peers: Vec<SocketAddr>, shutdown: Arc<AtomicBool>, sender: Sender<AntiEntropyEvent>) -> Result<JoinHandle<()>, Box<dyn Error>> {
+        start_message_listener("anti-entropy", address, shutdown, move |peer, bytes| {
+            let message = match AntiEntropyMessage::from_bytes(&bytes) {
+                Ok(message) => message,
+                Err(e) => {
+                    log::error!("Could not decode anti-entropy message: {:?}", e);
+                    return true;
+                }
+            };
+            let known = message.sender().parse::<SocketAddr>()
+                .is_ok_and(|sender| sender.ip() == peer.ip() && peers.contains(&sender));
+            if !known {
+                log::warn!("Rejected anti-entropy message claiming to come from {} sent from {}", message.sender(), peer);
+                return true;
+            }
+            sender.send(AntiEntropyEvent::Message(message)).is_ok()
+        })
+    }
+
+    fn start_ticker(address: SocketAddr, period: u64, peers: Vec<SocketAddr>, shutdown: Arc<AtomicBool>, sender: Sender<AntiEntropyEvent>) -> Result<JoinHandle<()>, Box<dyn Error>> {
+        let handle = std::thread::Builder::new().name(format!("{} - anti-entropy rounds", address)).spawn(move || {
+            while !shutdown.load(Ordering::SeqCst) {
+                std::thread::sleep(Duration::from_millis(period));
+                if peers.is_empty() {
+                    continue;
+                }
+                let peer = peers[rand::thread_rng().gen_range(0..peers.len())];
+                if sender.send(AntiEntropyEvent::Round(peer)).is_err() {
+                    break;
+                }
+            }
+        })?;
+        Ok(handle)
+    }
+
+    pub fn address(&self) -> &SocketAddr {
+        &self.address
+    }
+
+    /// Next pending event, without blocking.
+    pub fn try_recv(&self) -> Option<AntiEntropyEvent> {
+        self.receiver.try_recv().ok()
+    }
+
+    pub fn send(&self, peer: &SocketAddr, message: &AntiEntropyMessage) -> Result<usize, Box<dyn Error>> {
+        let bytes = message.to_bytes()?;
+        TcpStream::connect(peer)?.write_all(&bytes)?;
+        Ok(bytes.len())
+    }
+
+    pub fn shutdown(&mut self) -> Result<(), Box<dyn Error>> {
+        self.shutdown.store(true, Ordering::SeqCst);
+        // wake up the listener
+        let _ = TcpStream::connect(self.address);
+        let mut error = false;
+        for handle in self.activities.drain(..) {
+            if let Err(e) = handle.join() {
+                log::error!("Error during thread join: {:?}", e);
+                error = true;
+            }
+        }
+        if error {
+            Err("Error occurred during shutdown")?
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::svalue::zip_list::ZipList;
+
+    /// Runs the exchange between two databases until no message is left.
+    fn repair(a: &mut DB, b: &mut DB) {
+        repair_where(a, b, |_| true)
+    }
+
+    /// Same as [repair], both sides only repairing the keys `keep` accepts.
+    fn repair_where(a: &mut DB, b: &mut DB, keep: impl Fn(&[u8]) -> bool) {
+        let (mut trees_a, mut trees_b) = (RoundTrees::new(), RoundTrees::new());
+        let start = AntiEntropyMessage::start("a".to_string(), 0, trees_a.get_or_build(0, a, &keep));
+        let mut pending = vec![(true, start)];
+        while let Some((to_b, message)) = pending.pop() {
+            let (db, address, trees) = if to_b { (&mut *b, "b", &mut trees_b) } else { (&mut *a, "a", &mut trees_a) };
+            if let Some(answer) = message.handle_where(db, address, &keep, trees) {
+                pending.push((!to_b, answer));
+            }
+        }
+    }
+
+    #[test]
+    fn malformed_messages_are_ignored() {
+        let mut db = DB::with_actor(0, 1);
+        db.set_key(Robj::create_string_object("key"), Robj::create_string_object("v"));
+        let hashes = |depth, level| AntiEntropyMessage::Hashes {
+            sender: "a".to_string(),
+            db: 0,
+            depth,
+            level,
+            nodes: vec![(0, EMPTY_DIGEST)],
+        };
+        for (depth, level) in [(0, 0), (64, 0), (200, 0), (MERKLE_DEPTH, MERKLE_DEPTH + 1)] {
+            assert!(hashes(depth, level).handle(&mut db, "b").is_none());
+        }
+        let keys = AntiEntropyMessage::Keys {
+            sender: "a".to_string(),
+            db: 0,
+            depth: 200,
+            leaves: vec![0],
+            entries: vec![],
+            reply: true,
+        };
+        assert!(keys.handle(&mut db, "b").is_none());
+        assert!(hashes(MERKLE_DEPTH, 0).handle(&mut db, "b").is_some());
+    }
+
+    #[test]
+    fn diverged_replicas_converge() {
+        let mut a = DB::with_actor(0, 1);
+        let mut b = DB::with_actor(0, 2);
+        for i in 0..50 {
+            let key = Robj::create_string_object(&format!("key:{}", i));
+            a.set_key_at(key.clone(), Robj::create_string_object("a"), Hlc::new(1000 * i, 0, 1));
+            b.set_key_at(key, Robj::create_string_object("b"), Hlc::new(1000 * (50 - i), 0, 2));
+        }
+        let only_b = Robj::create_string_object("only-b");
+        b.set_key(only_b.clone(), Robj::create_string_object("x"));
+        let counter = Robj::create_string_object("counter");
+        a.counter_incr_by(&counter, 3).unwrap();
+        b.counter_incr_by(&counter, 4).unwrap();
+        assert_ne!(MerkleTree::build(&a, MERKLE_DEPTH).root(), MerkleTree::build(&b, MERKLE_DEPTH).root());
+
+        repair(&mut a, &mut b);
+
+        assert_eq!(MerkleTree::build(&a, MERKLE_DEPTH).root(), MerkleTree::build(&b, MERKLE_DEPTH).root());
+        assert_eq!(a.counter_get(&counter).unwrap(), Some(7.into()));
+        assert!(a.look_up_key(&only_b).is_some());
+        let early = a.look_up_key(&Robj::create_string_object("key:10")).unwrap();
+        let late = a.look_up_key(&Robj::create_string_object("key:40")).unwrap();
+        assert_eq!(early.borrow().string(), b"b");
+        assert_eq!(late.borrow().string(), b"a");
+    }
+
+    #[test]
+    fn sorted_sets_and_zip_list_hashes_are_repaired() {
+        let mut a = DB::with_actor(0, 1);
+        let mut b = DB::with_actor(0, 2);
+        let zset = Robj::create_zset_object();
+        zset.borrow_mut().zset_add(Robj::create_string_object("low"), -1.5).unwrap();
+        zset.borrow_mut().zset_add(Robj::create_string_object("high"), 42.0).unwrap();
+        let zip_list = |entries: &[&str]| {
+            let mut list = ZipList::new();
+            for entry in entries {
+                list.push(entry.as_bytes());
+            }
+            Box::new(list)
+        };
+        let hash = zip_list(&["field", "value", "count", "7"]);
+        let small_zset = zip_list(&["member", "2.5"]);
+        a.set_key(Robj::create_string_object("zset"), zset);
+        a.set_key(Robj::create_string_object("hash"), Robj::create_object(RobjType::Hash, RobjEncoding::ZipList, hash));
+        b.set_key(Robj::create_string_object("small-zset"), Robj::create_object(RobjType::Zset, RobjEncoding::ZipList, small_zset));
+
+        repair(&mut a, &mut b);
+
+        assert_eq!(MerkleTree::build(&a, MERKLE_DEPTH).root(), MerkleTree::build(&b, MERKLE_DEPTH).root());
+        let zset = b.look_up_key(&Robj::create_string_object("zset")).unwrap();
+        let members: Vec<(Vec<u8>, f64)> = zset.borrow().zset_iter()
+            .map(|(m, score)| (m.borrow().string().to_vec(), score))
+            .collect();
+        assert_eq!(members, vec![(b"low".to_vec(), -1.5), (b"high".to_vec(), 42.0)]);
+        let hash = b.look_up_key(&Robj::create_string_object("hash")).unwrap();
+        assert_eq!(
+            PlainValue::from_object(&hash.borrow()),
+            Some(PlainValue::Hash(vec![
+                (b"count".to_vec(), b"7".to_vec()),
+                (b"field".to_vec(), b"value".to_vec()),
+            ])),
+        );
+        let small_zset = a.look_up_key(&Robj::create_string_object("small-zset")).unwrap();
+        assert_eq!(
+            PlainValue::from_object(&small_zset.borrow()),
+            Some(PlainValue::Zset(vec![(b"member".to_vec(), 2.5f64.to_bits())])),
+        );
+    }
+
+    #[test]
+    fn deletes_and_ttls_are_repaired() {
+        let mut a = DB::with_actor(0, 1);
+        let mut b = DB::with_actor(0, 2);
+        let gone = Robj::create_string_object("gone");
+        let expired = Robj::create_string_object("expired");
+        let volatile = Robj::create_string_object("volatile");
+        for (db, node) in [(&mut a, 1), (&mut b, 2)] {
+            db.set_key_at(gone.clone(), Robj::create_string_object("v"), Hlc::new(1000, 0, node));
+            db.set_key_at(expired.clone(), Robj::create_string_object("v"), Hlc::new(1000, 0, node));
+        }
+        a.delete_key(&gone).unwrap();
+        a.set_expire(expired.clone(), SystemTime::now() - Duration::from_secs(1)).unwrap();
+        let deadline = SystemTime::now() + Duration::from_secs(60);
+        a.set_key(volatile.clone(), Robj::create_string_object("v"));
+        a.set_expire(volatile.clone(), deadline).unwrap();
+
+        repair(&mut a, &mut b);
+        repair(&mut a, &mut b);
+
+        assert_eq!(MerkleTree::build(&a, MERKLE_DEPTH).root(), MerkleTree::build(&b, MERKLE_DEPTH).root());
+        for db in [&mut a, &mut b] {
+            assert!(db.look_up_key_read(&gone).is_none());
+            assert!(db.look_up_key_read(&expired).is_none());
+        }
+        assert_eq!(unix_millis(*b.get_expire(&volatile).unwrap()), unix_millis(deadline));
+
+        // a write after the delete brings the key back
+        b.set_key(gone.clone(), Robj::create_string_object("again"));
+        repair(&mut a, &mut b);
+        assert_eq!(a.look_up_key(&gone).unwrap().borrow().string(), b"again");
+    }
+
+    #[test]
+    fn keys_refused_are_left_out_of_the_repair() {
+        let mut a = DB::with_actor(0, 1);
+        let mut b = DB::with_actor(0, 2);
+        let kept = Robj::create_string_object("kept");
+        let foreign = Robj::create_string_object("foreign");
+        a.set_key(kept.clone(), Robj::create_string_object("a"));
+        a.set_key(foreign.clone(), Robj::create_string_object("a"));
+        b.set_key(foreign.clone(), Robj::create_string_object("b"));
+        b.delete_key(&foreign).unwrap();
+        let keep = |key: &[u8]| key != b"foreign";
+
+        repair_where(&mut a, &mut b, keep);
+
+        assert_eq!(MerkleTree::build_where(&a, MERKLE_DEPTH, keep).root(), MerkleTree::build_where(&b, MERKLE_DEPTH, keep).root());
+        assert!(b.look_up_key_read(&kept).is_some());
+        assert!(b.look_up_key_read(&foreign).is_none());
+        assert_eq!(a.look_up_key(&foreign).unwrap().borrow().string(), b"a");
+    }
+
+    #[test]
+    fn plain_writes_order_by_hybrid_clock() {
+        let mut a = DB::with_actor(0, 1);
+        let key = Robj::create_string_object("key");
+        let entry = |written, value: &str| KeyEntry {
+            key: b"key".to_vec(),
+            value: KeyValue::Plain { written, value: PlainValue::String(value.as_bytes().to_vec()) },
+            expires: None,
+        };
+
+        // a remote write slightly ahead of our clock wins, and our next write goes past it
+        let ahead = Hlc::new(a.clock.now().physical + 500, 3, 2);
+        entry(ahead, "remote").merge_into(&mut a).unwrap();
+        assert_eq!(a.look_up_key(&key).unwrap().borrow().string(), b"remote");
+        a.set_key(key.clone(), Robj::create_string_object("local"));
+        assert!(a.write_time(&key).unwrap() > ahead);
+
+        let far = Hlc::new(ahead.physical + 3_600_000, 0, 2);
+        assert!(entry(far, "future").merge_into(&mut a).is_err());
+        assert_eq!(a.look_up_key(&key).unwrap().borrow().string(), b"local");
+    }
+
+    #[test]
+    fn service_only_accepts_messages_of_its_peers() {
+        let address: SocketAddr = "127.0.0.1:47220".parse().unwrap();
+        let peer: SocketAddr = "127.0.0.1:47221".parse().unwrap();
+        let mut service = AntiEntropyService::start(address, 50, vec![peer]).unwrap();
+        let tree = MerkleTree::build(&DB::with_actor(0, 1), MERKLE_DEPTH);
+        service.send(&address, &AntiEntropyMessage::start("127.0.0.1:47222".to_string(), 0, &tree)).unwrap();
+        service.send(&address, &AntiEntropyMessage::start(peer.to_string(), 0, &tree)).unwrap();
+
+        let receive = |senders: &mut Vec<String>| while let Some(event) = service.try_recv() {
+            if let AntiEntropyEvent::Message(message) = event {
+                senders.push(message.sender().to_owned());
+            }
+        };
+        let mut senders = Vec::new();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while senders.is_empty() && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(10));
+            receive(&mut senders);
+        }
+        // the rejected message is read concurrently, leave it time to arrive as well
+        std::thread::sleep(Duration::from_millis(200));
+        receive(&mut senders);
+        assert_eq!(senders, vec![peer.to_string()]);
+        service.shutdown().unwrap();
+    }
+}
@@ -0,0 +1,493 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::sync::Mutex;
+use std::sync::mpsc::{Receiver, Sender};
+
+use serde::{Deserialize, Serialize};
+
+use crate::gossip::update::{Update, UpdateHandler};
+use crate::svalue::replicated::Actor;
+
+/// Number of hash slots the keyspace is partitioned into
+pub const CLUSTER_SLOTS: u16 = 16384;
+/// Gossip topic the slot map travels on
+pub const CLUSTER_TOPIC: &str = "cluster";
+
+/// Start slot, end slot, address and id of the node of an assigned range, see [Cluster::slots]
+pub type SlotInfo = (u16, u16, String, String);
+
+/// CRC16 as used by Redis Cluster (XMODEM: polynomial 0x1021, no reflection, initial value 0).
+pub fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for byte in bytes {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+/// Returns the slot of `key`. When the key contains a non empty `{hashtag}`, only the tag is
+/// hashed, so that keys sharing it land in the same slot.
+pub fn key_hash_slot(key: &[u8]) -> u16 {
+    let tag = key.iter().position(|byte| *byte == b'{')
+        .and_then(|open| {
+            key[open + 1..].iter().position(|byte| *byte == b'}')
+                .filter(|length| *length > 0)
+                .map(|length| &key[open + 1..open + 1 + length])
+        });
+    crc16(tag.unwrap_or(key)) & (CLUSTER_SLOTS - 1)
+}
+
+/// Slots `start` to `end`, both included, served by `node`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SlotRange {
+    pub start: u16,
+    pub end: u16,
+    pub node: Actor,
+}
+
+/// A slot moving from `source` to `target`. The source keeps serving the keys it still holds
+/// while the others are sent to the target, see [super::migration::Migrator].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Migration {
+    pub slot: u16,
+    pub source: Actor,
+    pub target: Actor,
+    /// Where the target receives the migrated keys
+    pub address: String,
+}
+
+/// State of a slot as seen by a node
+#[derive(Debug, Clone, PartialEq)]
+pub enum SlotState {
+    Stable,
+    /// The node serves the slot and moves its keys to `target`
+    Migrating { target: Actor },
+    /// The node receives the keys of the slot from `source`
+    Importing { source: Actor },
+}
+
+/// Which node serves which slots, as agreed on by the cluster.
+///
+/// Every change bumps the epoch and is gossiped as a whole map. A node adopts the maps with a
+/// higher epoch than its own, the actor of the node that made the change breaking ties, so
+/// that concurrent changes converge to the same map everywhere. Migrations are part of the
+/// map, a node restarting mid-migration learns them back from its peers.
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct SlotMap {
+    epoch: u64,
+    /// Node that made the last change
+    author: Actor,
+    /// Client address of every node
+    nodes: BTreeMap<Actor, String>,
+    /// Assigned slots, sorted and disjoint
+    ranges: Vec<SlotRange>,
+    /// Slots being moved to another node
+    #[serde(default)]
+    migrations: Vec<Migration>,
+    /// Times the node re-announced the map, each announcement is then a new gossip update
+    #[serde(default)]
+    announcement: u64,
+}
+
+impl SlotMap {
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+        Ok(serde_cbor::to_vec(self)?)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Box<dyn Error>> {
+        Ok(serde_cbor::from_slice(bytes)?)
+    }
+
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    pub fn nodes(&self) -> &BTreeMap<Actor, String> {
+        &self.nodes
+    }
+
+    pub fn ranges(&self) -> &[SlotRange] {
+        &self.ranges
+    }
+
+    pub fn migrations(&self) -> &[Migration] {
+        &self.migrations
+    }
+
+    /// Returns the migration of `slot`, if it is moving
+    pub fn migration(&self, slot: u16) -> Option<&Migration> {
+        self.migrations.iter().find(|migration| migration.slot == slot)
+    }
+
+    pub fn address(&self, node: Actor) -> Option<&str> {
+        self.nodes.get(&node).map(|address| address.as_str())
+    }
+
+    /// Returns the node serving `slot`, if any
+    pub fn owner(&self, slot: u16) -> Option<Actor> {
+        let index = self.ranges.partition_point(|range| range.end < slot);
+        self.ranges.get(index)
+            .filter(|range| range.start <= slot)
+            .map(|range| range.node)
+    }
+
+    /// Whether this map replaces `other`
+    pub fn is_newer_than(&self, other: &SlotMap) -> bool {
+        (self.epoch, self.author) > (other.epoch, other.author)
+    }
+
+    fn add_node(&mut self, node: Actor, address: &str) {
+        self.nodes.insert(node, address.to_owned());
+    }
+
+    /// Hands slots `start` to `end` over to `node`, taking them from their previous owners and
+    /// ending their migrations
+    fn assign(&mut self, start: u16, end: u16, node: Actor) -> Result<(), Box<dyn Error>> {
+        if start > end || end >= CLUSTER_SLOTS {
+            Err(format!("Invalid slot range {}-{}", start, end))?
+        }
+        if !self.nodes.contains_key(&node) {
+            Err(format!("Unknown node {:016x}", node))?
+        }
+        self.migrations.retain(|migration| migration.slot < start || migration.slot > end);
+        let mut ranges = Vec::with_capacity(self.ranges.len() + 2);
+        for range in self.ranges.drain(..) {
+            if range.end < start || range.start > end {
+                ranges.push(range);
+                continue;
+            }
+            if range.start < start {
+                ranges.push(SlotRange { start: range.start, end: start - 1, node: range.node });
+            }
+            if range.end > end {
+                ranges.push(SlotRange { start: end + 1, end: range.end, node: range.node });
+            }
+        }
+        ranges.push(SlotRange { start, end, node });
+        ranges.sort_by_key(|range| range.start);
+        // merge the adjacent ranges of a node
+        for range in ranges {
+            match self.ranges.last_mut() {
+                Some(last) if last.node == range.node && last.end + 1 == range.start => last.end = range.end,
+                _ => self.ranges.push(range),
+            }
+        }
+        Ok(())
+    }
+
+    /// Starts moving `slot` from its owner to `target`
+    fn migrate(&mut self, slot: u16, target: Actor, address: &str) -> Result<(), Box<dyn Error>> {
+        let source = match self.owner(slot) {
+            Some(source) => source,
+            None => Err(format!("Slot {} is not assigned", slot))?,
+        };
+        if !self.nodes.contains_key(&target) {
+            Err(format!("Unknown node {:016x}", target))?
+        }
+        if source == target {
+            Err(format!("Slot {} is already served by {:016x}", slot, target))?
+        }
+        if self.migration(slot).is_some() {
+            Err(format!("Slot {} is already migrating", slot))?
+        }
+        self.migrations.push(Migration { slot, source, target, address: address.to_owned() });
+        Ok(())
+    }
+}
+
+/// Why a command on a key can not be served by this node, displayed as the error returned to
+/// the client.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Redirect {
+    /// The slot is served by the node at `address`, clients should update their slot map
+    Moved { slot: u16, address: String },
+    /// The key of the slot is being moved to the node at `address`, clients should retry there
+    /// once, prefixing the command with ASKING
+    Ask { slot: u16, address: String },
+    /// No node serves the slot
+    Unassigned { slot: u16 },
+}
+
+impl fmt::Display for Redirect {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Redirect::Moved { slot, address } => write!(f, "MOVED {} {}", slot, address),
+            Redirect::Ask { slot, address } => write!(f, "ASK {} {}", slot, address),
+            Redirect::Unassigned { slot } => write!(f, "CLUSTERDOWN Hash slot {} not served", slot),
+        }
+    }
+}
+
+impl Error for Redirect {}
+
+/// Gossip callback of a cluster node: decodes the received slot maps and hands them over to
+/// the thread owning the [Cluster], see [Cluster::apply_received].
+pub struct ClusterHandler {
+    sender: Mutex<Sender<SlotMap>>,
+}
+
+impl UpdateHandler for ClusterHandler {
+    fn on_update(&self, update: Update) {
+        match SlotMap::from_bytes(update.content()) {
+            Ok(slot_map) => {
+                if let Err(e) = self.sender.lock().unwrap().send(slot_map) {
+                    log::error!("Cluster is gone, dropping slot map {}: {:?}", update.digest(), e);
+                }
+            }
+            Err(e) => log::warn!("Could not decode slot map {}: {:?}", update.digest(), e),
+        }
+    }
+}
+
+/// The view a node has of the cluster the keyspace is sharded across.
+///
+/// Changes made on this node, see [Cluster::add_node] and [Cluster::assign_slots], return the
+/// encoded slot map to gossip on [CLUSTER_TOPIC]; the maps of other nodes come back through
+/// the [ClusterHandler].
+pub struct Cluster {
+    myself: Actor,
+    slot_map: SlotMap,
+    receiver: Receiver<SlotMap>,
+    /// Whether a node gossiped an older map, e.g. after restarting
+    stale_peer: bool,
+}
+
+impl Cluster {
+    /// Creates the cluster view of node `myself`, reachable by clients at `address`, and the
+    /// gossip handler feeding it.
+    pub fn new(myself: Actor, address: &str) -> (Cluster, ClusterHandler) {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let mut slot_map = SlotMap::default();
+        slot_map.add_node(myself, address);
+        let cluster = Cluster { myself, slot_map, receiver, stale_peer: false };
+        (cluster, ClusterHandler { sender: Mutex::new(sender) })
+    }
+
+    pub fn myself(&self) -> Actor {
+        self.myself
+    }
+
+    pub fn slot_map(&self) -> &SlotMap {
+        &self.slot_map
+    }
+
+    /// Encodes the current slot map to be gossiped again, so that peers that missed it or
+    /// restarted since catch up
+    pub fn announce(&mut self) -> Result<Vec<u8>, Box<dyn Error>> {
+        self.stale_peer = false;
+        self.slot_map.announcement += 1;
+        self.slot_map.to_bytes()
+    }
+
+    /// Whether a peer gossiped an older map since the last announcement
+    pub fn has_stale_peer(&self) -> bool {
+        self.stale_peer
+    }
+
+    /// Adds a node to the cluster, or changes its address, and returns the map to gossip
+    pub fn add_node(&mut self, node: Actor, address: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+        self.change(|slot_map| {
+            slot_map.add_node(node, address);
+            Ok(())
+        })
+    }
+
+    /// Assigns slots `start` to `end`, both included, to `node` and returns the map to gossip
+    pub fn assign_slots(&mut self, start: u16, end: u16, node: Actor) -> Result<Vec<u8>, Box<dyn Error>> {
+        self.change(|slot_map| slot_map.assign(start, end, node))
+    }
+
+    /// Marks `slot` as migrating from its owner to `target`, which receives the keys at
+    /// `address`, and returns the map to gossip
+    pub fn begin_migration(&mut self, slot: u16, target: Actor, address: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+        self.change(|slot_map| slot_map.migrate(slot, target, address))
+    }
+
+    /// Hands a migrating slot over to its target and returns the map to gossip. Ownership
+    /// changes in a single epoch, nodes either see the migration or the new owner.
+    pub fn finish_migration(&mut self, slot: u16) -> Result<Vec<u8>, Box<dyn Error>> {
+        let target = self.slot_map.migration(slot).ok_or(format!("Slot {} is not migrating", slot))?.target;
+        self.change(|slot_map| slot_map.assign(slot, slot, target))
+    }
+
+    /// Returns the state of `slot` on this node
+    pub fn slot_state(&self, slot: u16) -> SlotState {
+        match self.slot_map.migration(slot) {
+            Some(migration) if migration.source == self.myself => SlotState::Migrating { target: migration.target },
+            Some(migration) if migration.target == self.myself => SlotState::Importing { source: migration.source },
+            _ => SlotState::Stable,
+        }
+    }
+
+    /// The migrations of the slots this node moves away
+    pub fn outgoing_migrations(&self) -> Vec<Migration> {
+        self.slot_map.migrations.iter()
+            .filter(|migration| migration.source == self.myself)
+            .cloned()
+            .collect()
+    }
+
+    fn change<F>(&mut self, change: F) -> Result<Vec<u8>, Box<dyn Error>>
+    where
+        F: FnOnce(&mut SlotMap) -> Result<(), Box<dyn Error>>,
+    {
+        let mut slot_map = self.slot_map.clone();
+        change(&mut slot_map)?;
+        slot_map.epoch += 1;
+        slot_map.author = self.myself;
+        let bytes = slot_map.to_bytes()?;
+        self.slot_map = slot_map;
+        Ok(bytes)
+    }
+
+    /// Adopts the newest of the slot maps received so far and returns whether the map changed.
+    /// Nodes only known locally are kept, so that nodes added concurrently are not forgotten.
+    pub fn apply_received(&mut self) -> bool {
+        let mut changed = false;
+        while let Ok(mut slot_map) = self.receiver.try_recv() {
+            if !slot_map.is_newer_than(&self.slot_map) {
+                self.stale_peer |= self.slot_map.is_newer_than(&slot_map);
+                continue;
+            }
+            for (node, address) in &self.slot_map.nodes {
+                slot_map.nodes.entry(*node).or_insert_with(|| address.clone());
+            }
+            self.slot_map = slot_map;
+            changed = true;
+        }
+        changed
+    }
+
+    /// Whether this node keeps the keys of the slot of `key`: it serves or imports the slot
+    pub fn holds(&self, key: &[u8]) -> bool {
+        let slot = key_hash_slot(key);
+        self.slot_map.owner(slot) == Some(self.myself)
+            || matches!(self.slot_state(slot), SlotState::Importing { .. })
+    }
+
+    /// Checks that this node serves `key`, otherwise tells where the client should go. While
+    /// the slot migrates, the source serves the keys it still holds and sends the client to the
+    /// target for the others, which serves them to clients that sent ASKING.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The key of the command
+    /// * `asking` - Whether the client sent ASKING before the command
+    /// * `present` - Whether the key is still held by this node, only called on migrating slots
+    pub fn route<F>(&self, key: &[u8], asking: bool, present: F) -> Result<(), Redirect>
+    where
+        F: FnOnce() -> bool,
+    {
+        let slot = key_hash_slot(key);
+        match self.slot_state(slot) {
+            SlotState::Migrating { target } if !present() => {
+                return match self.slot_map.address(target) {
+                    Some(address) => Err(Redirect::Ask { slot, address: address.to_owned() }),
+                    None => Err(Redirect::Unassigned { slot }),
+                };
+            }
+            SlotState::Importing { .. } if asking => return Ok(()),
+            _ => {}
+        }
+        match self.slot_map.owner(slot) {
+            Some(node) if node == self.myself => Ok(()),
+            Some(node) => match self.slot_map.address(node) {
+                Some(address) => Err(Redirect::Moved { slot, address: address.to_owned() }),
+                None => Err(Redirect::Unassigned { slot }),
+            },
+            None => Err(Redirect::Unassigned { slot }),
+        }
+    }
+
+    /// The assigned slot ranges with the id and address of their node, as CLUSTER SLOTS
+    /// returns them
+    pub fn slots(&self) -> Vec<SlotInfo> {
+        self.slot_map.ranges.iter()
+            .map(|range| {
+                let address = self.slot_map.address(range.node).unwrap_or_default().to_owned();
+                (range.start, range.end, address, node_id(range.node))
+            })
+            .collect()
+    }
+
+    /// One line per node in the format of CLUSTER NODES: id, address, flags, master, ping
+    /// sent, pong received, epoch, link state and slot ranges, followed on the line of this
+    /// node by the slots it migrates, `[slot->-target]`, and imports, `[slot-<-source]`
+    pub fn nodes(&self) -> String {
+        let mut lines = String::new();
+        for (node, address) in &self.slot_map.nodes {
+            let flags = if *node == self.myself { "myself,master" } else { "master" };
+            lines.push_str(&format!("{} {} {} - 0 0 {} connected", node_id(*node), address, flags, self.slot_map.epoch));
+            for range in self.slot_map.ranges.iter().filter(|range| range.node == *node) {
+                if range.start == range.end {
+                    lines.push_str(&format!(" {}", range.start));
+                }
+                else {
+                    lines.push_str(&format!(" {}-{}", range.start, range.end));
+                }
+            }
+            if *node == self.myself {
+                for migration in &self.slot_map.migrations {
+                    if migration.source == self.myself {
+                        lines.push_str(&format!(" [{}->-{}]", migration.slot, node_id(migration.target)));
+                    }
+                    else if migration.target == self.myself {
+                        lines.push_str(&format!(" [{}-<-{}]", migration.slot, node_id(migration.source)));
+                    }
+                }
+            }
+            lines.push('\n');
+        }
+        lines
+    }
+}
+
+/// Id of a node as shown to clients
+pub fn node_id(node: Actor) -> String {
+    format!("{:016x}", node)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_hash_to_redis_slots() {
+        assert_eq!(crc16(b"123456789"), 0x31C3);
+        assert_eq!(key_hash_slot(b"foo"), 12182);
+        assert_eq!(key_hash_slot(b"{user1000}.following"), key_hash_slot(b"user1000"));
+        assert_eq!(key_hash_slot(b"{user1000}.followers"), key_hash_slot(b"user1000"));
+        assert_eq!(key_hash_slot(b"foo{}{bar}"), crc16(b"foo{}{bar}") & (CLUSTER_SLOTS - 1));
+        assert_eq!(key_hash_slot(b"foo{{bar}}zap"), key_hash_slot(b"{bar"));
+    }
+
+    #[test]
+    fn newest_slot_map_routes_keys() {
+        let (mut a, ha) = Cluster::new(1, "127.0.0.1:6380");
+        let (mut b, hb) = Cluster::new(2, "127.0.0.1:6381");
+        assert_eq!(a.route(b"foo", false, || true), Err(Redirect::Unassigned { slot: 12182 }));
+
+        a.add_node(2, "127.0.0.1:6381").unwrap();
+        a.assign_slots(0, CLUSTER_SLOTS - 1, 1).unwrap();
+        let update = a.assign_slots(10000, 12999, 2).unwrap();
+        assert_eq!(a.slot_map().ranges().len(), 3);
+        assert!(a.route(b"bar", false, || true).is_ok());
+        assert_eq!(a.route(b"foo", false, || true).unwrap_err().to_string(), "MOVED 12182 127.0.0.1:6381");
+
+        hb.on_update(Update::new_with_topic(CLUSTER_TOPIC, update));
+        assert!(b.apply_received());
+        assert!(b.route(b"foo", false, || true).is_ok());
+        assert_eq!(b.slots(), a.slots());
+
+        // a stale map is ignored
+        ha.on_update(Update::new_with_topic(CLUSTER_TOPIC, b.assign_slots(0, 0, 2).unwrap()));
+        a.assign_slots(0, 0, 1).unwrap();
+        a.assign_slots(1, 1, 1).unwrap();
+        assert!(!a.apply_received());
+        assert_eq!(a.slot_map().owner(0), Some(1));
+    }
+}
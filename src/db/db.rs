@@ -1,4 +1,4 @@
-use std::{hash::Hasher, marker::PhantomData, ops::DerefMut, rc::Rc, string, time::{Duration, SystemTime}};
+use std::{hash::Hasher, marker::PhantomData, ops::DerefMut, rc::Rc, string, time::{Duration, SystemTime, UNIX_EPOCH}};
 
 use cache::{Cache, OnEvict};
 use lazy_static::__Deref;
@@ -7,6 +7,12 @@ use rand::Rng;
 use crate::{lcache::{cache::{self, VoidEvict}, tiny_lfu::MAX_WINDOW_SIZE}, svalue::{dict::Dict, object::{Robj, RobjPointer}}};
 use crate::svalue::object::RobjPtr;
 use crate::svalue::hash::string_object_hash;
+use crate::memory::{MemoryUsage, MEMORY_USAGE_SAMPLES};
+use crate::svalue::object::RobjType;
+use crate::svalue::replicated::{Actor, ReplicatedOp};
+use crate::crdts::vclock::VClock;
+use crate::crdts::hlc::{ClockDrift, Hlc, HlcClock};
+use num::BigInt;
 
 pub struct DBCache {
     id: usize,
@@ -83,15 +89,32 @@ pub struct DB {
     pub id: usize,
     pub dict: Dict<RobjPtr, RobjPtr>,
     pub expires: Dict<RobjPtr, SystemTime>,
+    /// Actor this node writes replicated values as.
+    pub actor: Actor,
+    /// Timestamp of the last write to each plain key, anti-entropy keeps the most recent value.
+    pub mtimes: Dict<RobjPtr, Hlc>,
+    /// Clock timestamping writes to plain keys.
+    pub clock: HlcClock,
+    /// When plain keys were deleted or expired, so that anti-entropy does not bring them back.
+    pub tombstones: Dict<RobjPtr, Hlc>,
 }
 
 impl DB {
     pub fn new(id: usize) -> DB {
+        let actor = rand::thread_rng().gen();
+        Self::with_actor(id, actor)
+    }
+
+    pub fn with_actor(id: usize, actor: Actor) -> DB {
         let mut rng = rand::thread_rng();
         DB {
             id,
             dict: Dict::new(string_object_hash, rng.gen()),
             expires: Dict::new(string_object_hash, rng.gen()),
+            actor,
+            mtimes: Dict::new(string_object_hash, rng.gen()),
+            clock: HlcClock::new(actor),
+            tombstones: Dict::new(string_object_hash, rng.gen()),
         }
     }
 
@@ -118,13 +141,17 @@ impl DB {
             return Err(())
         }
 
-        let when = r.unwrap().1;
-        if SystemTime::now() < *when {
+        let when = *r.unwrap().1;
+        if SystemTime::now() < when {
             return Ok(false);
         }
         self.expires.delete(key).unwrap();
+        let _ = self.mtimes.delete(key);
 
-        let _ = self.dict.delete(key)?;
+        let (key, value) = self.dict.delete(key)?;
+        // the key is gone since it expired, whenever it is noticed
+        let expired = when.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64);
+        self.bury(key, &value, Hlc::new(expired, 0, self.actor));
         Ok(true)
     }
 
@@ -134,7 +161,10 @@ impl DB {
         }
 
         let _ = self.expires.delete(key)?;
-        let _ = self.dict.delete(key)?;
+        let _ = self.mtimes.delete(key);
+        let (key, value) = self.dict.delete(key)?;
+        let when = self.clock.now();
+        self.bury(key, &value, when);
         Ok(())
     }
 
@@ -142,10 +172,78 @@ impl DB {
         if self.expires.len() != 0 {
             let _ = self.expires.delete(key);
         }
-        self.dict.delete(key)?;
+        let _ = self.mtimes.delete(key);
+        let (key, value) = self.dict.delete(key)?;
+        let when = self.clock.now();
+        self.bury(key, &value, when);
+        Ok(())
+    }
+
+    /// Removes a key without leaving a tombstone, for the keys another node now serves.
+    pub fn evict_key(&mut self, key: &RobjPtr) -> Result<(), ()> {
+        self.delete_key(key)?;
+        let _ = self.tombstones.delete(key);
         Ok(())
     }
 
+    /// Records that a plain key was deleted at `when`. Replicated values carry their removes
+    /// in their own state and get no tombstone.
+    fn bury(&mut self, key: RobjPtr, value: &RobjPtr, when: Hlc) {
+        if !value.borrow().is_replicated() {
+            self.bury_at(key, when);
+        }
+    }
+
+    /// Records a delete of `key` performed at `when`, unless a later one is already known.
+    pub fn bury_at(&mut self, key: RobjPtr, when: Hlc) {
+        if self.tombstone(&key).is_none_or(|known| known < when) {
+            let _ = self.tombstones.replace(key, when);
+        }
+    }
+
+    /// When `key` was deleted, if it was and was not written since.
+    pub fn tombstone(&self, key: &RobjPtr) -> Option<Hlc> {
+        self.tombstones.find(key).map(|p| *p.1)
+    }
+
+    /// Forgets the deletes performed before `physical` milliseconds since the unix epoch and
+    /// returns how many there were. A replica missing a delete for longer may bring its key
+    /// back.
+    pub fn purge_tombstones(&mut self, physical: u64) -> usize {
+        let old: Vec<RobjPtr> = self.tombstones.iter()
+            .filter(|(_, when)| when.physical < physical)
+            .map(|(key, _)| Rc::clone(key))
+            .collect();
+        for key in &old {
+            let _ = self.tombstones.delete(key);
+        }
+        old.len()
+    }
+
+    /// Stores `value` at `key`, replacing any previous value, and records the write timestamp.
+    pub fn set_key(&mut self, key: RobjPtr, value: RobjPtr) {
+        let when = self.clock.now();
+        self.set_key_at(key, value, when);
+    }
+
+    /// Same as `set_key`, for a write timestamped `when`.
+    pub fn set_key_at(&mut self, key: RobjPtr, value: RobjPtr, when: Hlc) {
+        let _ = self.tombstones.delete(&key);
+        let _ = self.mtimes.replace(Rc::clone(&key), when);
+        let _ = self.dict.replace(key, value);
+    }
+
+    /// Moves the write clock past a timestamp received from another node, so later local
+    /// writes win over it. Fails on timestamps too far ahead of the local clock.
+    pub fn observe_write_time(&mut self, when: &Hlc) -> Result<Hlc, ClockDrift> {
+        self.clock.update(when)
+    }
+
+    /// Timestamp of the last write to a plain key stored with `set_key`.
+    pub fn write_time(&self, key: &RobjPtr) -> Option<Hlc> {
+        self.mtimes.find(key).map(|p| *p.1)
+    }
+
     pub fn look_up_key_read(&mut self, key: &RobjPtr) -> Option<RobjPtr> {
         let _ = self.expire_if_needed(key);
         self.look_up_key(key)
@@ -159,4 +257,159 @@ impl DB {
         }
     }
 
-}
\ No newline at end of file
+    /// MEMORY USAGE key [SAMPLES count]: bytes taken by the key, its value and the dict entry
+    /// holding them. Aggregate values are sampled, `Some(0)` inspects every element.
+    pub fn memory_usage(&mut self, key: &RobjPtr, samples: Option<usize>) -> Option<usize> {
+        let samples = samples.unwrap_or(MEMORY_USAGE_SAMPLES);
+        let value = self.look_up_key_read(key)?;
+        let mut usage = Dict::<RobjPtr, RobjPtr>::entry_size()
+            + key.heap_usage(samples)
+            + value.heap_usage(samples);
+        if self.expires.find(key).is_some() {
+            usage += Dict::<RobjPtr, SystemTime>::entry_size();
+        }
+        Some(usage)
+    }
+
+    /// Replicated value of type `obj_type` stored at `key`, `None` if the key does not exist.
+    /// Fails if the key holds a value of another type.
+    fn read_replicated(&mut self, key: &RobjPtr, obj_type: RobjType) -> Result<Option<RobjPtr>, ()> {
+        match self.look_up_key_read(key) {
+            Some(o) if o.borrow().object_type() != obj_type => Err(()),
+            o => Ok(o),
+        }
+    }
+
+    /// Same as `read_replicated`, but a missing key is created with an empty value.
+    fn write_replicated(&mut self, key: &RobjPtr, obj_type: RobjType) -> Result<RobjPtr, ()> {
+        if let Some(o) = self.read_replicated(key, obj_type)? {
+            return Ok(o);
+        }
+        let o = Robj::create_replicated_object(obj_type);
+        self.dict.add(Rc::clone(key), Rc::clone(&o))?;
+        Ok(o)
+    }
+
+    /// Applies a write another node performed on `key`, creating the key if needed.
+    pub fn apply_replicated(&mut self, key: &RobjPtr, op: ReplicatedOp) -> Result<(), ()> {
+        let o = self.write_replicated(key, op.object_type())?;
+        let r = o.borrow_mut().replicated_apply(op);
+        r
+    }
+
+    /// Causal context of the replicated set or map at `key`, reported to the other replicas to
+    /// find out which of its dots are stable.
+    pub fn replicated_clock(&mut self, key: &RobjPtr) -> Option<VClock<Actor>> {
+        let o = self.look_up_key_read(key)?;
+        let o = o.borrow();
+        match o.object_type() {
+            RobjType::ReplicatedSet | RobjType::ReplicatedMap => Some(o.replicated_clock()),
+            _ => None,
+        }
+    }
+
+    /// Prunes the deferred removes of the replicated value at `key` given the causally stable
+    /// cut of its replicas, returns how many were dropped.
+    pub fn replicated_gc(&mut self, key: &RobjPtr, stable: &VClock<Actor>) -> usize {
+        match self.look_up_key_read(key) {
+            Some(o) if o.borrow().is_replicated() => o.borrow_mut().replicated_gc(stable),
+            _ => 0,
+        }
+    }
+
+    /// Removes waiting for causal context over all the replicated values of the database.
+    pub fn deferred_removes(&self) -> usize {
+        self.dict.iter()
+            .filter(|(_, o)| o.borrow().is_replicated())
+            .map(|(_, o)| o.borrow().replicated_deferred_len())
+            .sum()
+    }
+
+    /// CINCRBY key increment: returns the new value and the op to ship to the other nodes.
+    pub fn counter_incr_by(&mut self, key: &RobjPtr, by: i64) -> Result<(BigInt, ReplicatedOp), ()> {
+        let o = self.write_replicated(key, RobjType::Counter)?;
+        let mut o = o.borrow_mut();
+        let op = o.counter_incr_by(self.actor, by);
+        Ok((o.counter_read(), op))
+    }
+
+    /// CGET key
+    pub fn counter_get(&mut self, key: &RobjPtr) -> Result<Option<BigInt>, ()> {
+        let o = self.read_replicated(key, RobjType::Counter)?;
+        Ok(o.map(|o| o.borrow().counter_read()))
+    }
+
+    /// RSADD key member [member ...]
+    pub fn replicated_set_add(&mut self, key: &RobjPtr, members: Vec<Vec<u8>>) -> Result<ReplicatedOp, ()> {
+        let o = self.write_replicated(key, RobjType::ReplicatedSet)?;
+        let op = o.borrow_mut().replicated_set_add(self.actor, members);
+        Ok(op)
+    }
+
+    /// RSREM key member [member ...]: concurrent additions on other nodes are kept.
+    pub fn replicated_set_rem(&mut self, key: &RobjPtr, members: Vec<Vec<u8>>) -> Result<ReplicatedOp, ()> {
+        let o = self.write_replicated(key, RobjType::ReplicatedSet)?;
+        let op = o.borrow_mut().replicated_set_rem(members);
+        Ok(op)
+    }
+
+    /// RSMEMBERS key
+    pub fn replicated_set_members(&mut self, key: &RobjPtr) -> Result<Vec<Vec<u8>>, ()> {
+        let o = self.read_replicated(key, RobjType::ReplicatedSet)?;
+        Ok(o.map(|o| o.borrow().replicated_set_members()).unwrap_or_default())
+    }
+
+    /// RSISMEMBER key member
+    pub fn replicated_set_is_member(&mut self, key: &RobjPtr, member: &Vec<u8>) -> Result<bool, ()> {
+        let o = self.read_replicated(key, RobjType::ReplicatedSet)?;
+        Ok(o.is_some_and(|o| o.borrow().replicated_set_contains(member)))
+    }
+
+    /// RSET key value
+    pub fn register_set(&mut self, key: &RobjPtr, value: Vec<u8>) -> Result<ReplicatedOp, ()> {
+        let o = self.write_replicated(key, RobjType::Register)?;
+        let op = o.borrow_mut().register_set(self.actor, value);
+        Ok(op)
+    }
+
+    /// RGET key: all values written concurrently, a single one once the writes are ordered.
+    pub fn register_get(&mut self, key: &RobjPtr) -> Result<Vec<Vec<u8>>, ()> {
+        let o = self.read_replicated(key, RobjType::Register)?;
+        Ok(o.map(|o| o.borrow().register_get()).unwrap_or_default())
+    }
+
+    /// RMSET key field value
+    pub fn replicated_map_set(&mut self, key: &RobjPtr, field: Vec<u8>, value: Vec<u8>) -> Result<ReplicatedOp, ()> {
+        let o = self.write_replicated(key, RobjType::ReplicatedMap)?;
+        let op = o.borrow_mut().replicated_map_set(self.actor, field, value);
+        Ok(op)
+    }
+
+    /// RMDEL key field: concurrent writes to the field on other nodes are kept.
+    pub fn replicated_map_del(&mut self, key: &RobjPtr, field: Vec<u8>) -> Result<ReplicatedOp, ()> {
+        let o = self.write_replicated(key, RobjType::ReplicatedMap)?;
+        let op = o.borrow_mut().replicated_map_del(field);
+        Ok(op)
+    }
+
+    /// RMGET key field
+    pub fn replicated_map_get(&mut self, key: &RobjPtr, field: &[u8]) -> Result<Vec<Vec<u8>>, ()> {
+        let o = self.read_replicated(key, RobjType::ReplicatedMap)?;
+        Ok(o.map(|o| o.borrow().replicated_map_get(field)).unwrap_or_default())
+    }
+
+    /// RMFIELDS key
+    pub fn replicated_map_fields(&mut self, key: &RobjPtr) -> Result<Vec<Vec<u8>>, ()> {
+        let o = self.read_replicated(key, RobjType::ReplicatedMap)?;
+        Ok(o.map(|o| o.borrow().replicated_map_fields()).unwrap_or_default())
+    }
+}
+
+impl MemoryUsage for DB {
+    fn heap_usage(&self, samples: usize) -> usize {
+        self.dict.heap_usage(samples)
+            + self.expires.heap_usage(samples)
+            + self.mtimes.heap_usage(samples)
+            + self.tombstones.heap_usage(samples)
+    }
+}
@@ -0,0 +1,539 @@
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::io::Write;
+use std::net::{SocketAddr, TcpStream};
+use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, Sender};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime};
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::svalue::object::{Robj, RobjPtr};
+
+use super::anti_entropy::{is_sent_from, object_bytes, start_message_listener, KeyEntry};
+use super::cluster::{key_hash_slot, Cluster, SlotState};
+use super::db::DB;
+
+/// Milliseconds a source waits for the acknowledgement of a batch before sending it again
+pub const MIGRATION_TIMEOUT: u64 = 1000;
+
+/// A key moved to another node, with its value and the milliseconds it has left to live
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MigratedKey {
+    db: usize,
+    entry: KeyEntry,
+    ttl: Option<u64>,
+}
+
+impl MigratedKey {
+    /// `None` for the keys that already expired
+    pub fn from_db(index: usize, db: &DB, key: &RobjPtr, value: &RobjPtr) -> Option<MigratedKey> {
+        let ttl = match db.expires.find(key) {
+            Some((_, when)) => Some(when.duration_since(SystemTime::now()).ok()?.as_millis() as u64),
+            None => None,
+        };
+        Some(MigratedKey {
+            db: index,
+            entry: KeyEntry::from_db(db, key, value)?,
+            ttl,
+        })
+    }
+
+    pub fn key(&self) -> &[u8] {
+        self.entry.key()
+    }
+
+    /// Stores the key in its database, merged with the value it already has there
+    pub fn restore(self, dbs: &mut [DB]) -> Result<(), ()> {
+        let db = dbs.get_mut(self.db).ok_or(())?;
+        let key = Robj::from_bytes(self.entry.key().to_vec());
+        self.entry.merge_into(db)?;
+        let _ = db.remove_expire(&key);
+        if let Some(ttl) = self.ttl {
+            db.set_expire(key, SystemTime::now() + Duration::from_millis(ttl))?;
+        }
+        Ok(())
+    }
+}
+
+/// Messages exchanged by the source and the target of a migrating slot.
+///
+/// The source sends the keys of the slot in batches and the target acknowledges the keys it
+/// restored. Once every key is acknowledged, an empty batch checks that the target did not
+/// restart since, losing keys, before the source hands the slot over.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum MigrationMessage {
+    Keys {
+        /// Where the acknowledgement goes
+        sender: String,
+        slot: u16,
+        keys: Vec<MigratedKey>,
+    },
+    Ack {
+        /// The target, which must be the one the slot moves to
+        sender: String,
+        slot: u16,
+        /// Identifies the run of the target, it changes when the target restarts
+        incarnation: u64,
+        /// Database and name of the restored keys
+        keys: Vec<(usize, Vec<u8>)>,
+    },
+}
+
+impl MigrationMessage {
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+        Ok(serde_cbor::to_vec(self)?)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Box<dyn Error>> {
+        Ok(serde_cbor::from_slice(bytes)?)
+    }
+
+    pub fn sender(&self) -> &str {
+        match self {
+            MigrationMessage::Keys { sender, .. } => sender,
+            MigrationMessage::Ack { sender, .. } => sender,
+        }
+    }
+}
+
+/// Host part of a `host:port` address
+fn host(address: &str) -> &str {
+    address.rsplit_once(':').map_or(address, |(host, _)| host)
+}
+
+/// Progress of a slot this node moves away
+#[derive(Default)]
+struct Outgoing {
+    /// Run of the target that acknowledged the moved keys
+    incarnation: Option<u64>,
+    /// Keys the target acknowledged, kept until the slot is handed over
+    moved: HashSet<(usize, Vec<u8>)>,
+    /// When the batch waiting for its acknowledgement was sent
+    sent: Option<Instant>,
+    /// Whether that batch is the empty one checking the target
+    probing: bool,
+    /// Whether the target acknowledged the empty batch sent once every key moved
+    confirmed: bool,
+}
+
+/// What a migration round did, see [Migrator::step]
+#[derive(Default)]
+pub struct MigrationRound {
+    /// Messages to send, with the address to send them to
+    pub messages: Vec<(String, MigrationMessage)>,
+    /// Slot maps to gossip, one per slot handed over
+    pub slot_maps: Vec<Vec<u8>>,
+}
+
+/// Moves the keys of migrating slots between nodes.
+///
+/// Nothing is persisted: a source restarting sends every key it still holds again, which the
+/// target merges, and a target restarting answers with a new incarnation, upon which the
+/// source sends the keys it kept again. The source only drops the keys of a slot once the
+/// ownership flipped.
+pub struct Migrator {
+    address: String,
+    incarnation: u64,
+    outgoing: HashMap<u16, Outgoing>,
+}
+
+impl Migrator {
+    /// Creates the migrator of a node receiving migration messages at `address`
+    pub fn new(address: &str) -> Migrator {
+        Migrator {
+            address: address.to_owned(),
+            incarnation: rand::thread_rng().gen(),
+            outgoing: HashMap::new(),
+        }
+    }
+
+    pub fn incarnation(&self) -> u64 {
+        self.incarnation
+    }
+
+    /// Whether the key of database `db` was moved to the target of its migrating slot
+    pub fn is_moved(&self, db: usize, key: &[u8]) -> bool {
+        self.outgoing.get(&key_hash_slot(key))
+            .is_some_and(|outgoing| outgoing.moved.contains(&(db, key.to_vec())))
+    }
+
+    /// Handles a message of a peer and returns the answer to send back, if any. Keys are only
+    /// restored once this node knows it imports their slot, the source sends them again
+    /// otherwise.
+    pub fn handle(&mut self, message: MigrationMessage, cluster: &Cluster, dbs: &mut [DB]) -> Option<(String, MigrationMessage)> {
+        match message {
+            MigrationMessage::Keys { sender, slot, keys } => {
+                let owned = cluster.slot_map().owner(slot) == Some(cluster.myself());
+                // the keys come from the node the slot is imported from, or once the slot is
+                // handed over, late from one of the nodes of the cluster
+                let sources: Vec<&str> = match cluster.slot_state(slot) {
+                    SlotState::Importing { source } => cluster.slot_map().address(source).into_iter().collect(),
+                    _ if owned => cluster.slot_map().nodes().values().map(String::as_str).collect(),
+                    _ => {
+                        log::warn!("Received keys of slot {} which is not imported", slot);
+                        return None;
+                    }
+                };
+                if !sources.iter().any(|source| host(source) == host(&sender)) {
+                    log::warn!("Rejected keys of slot {} sent by {} which does not move it", slot, sender);
+                    return None;
+                }
+                let mut restored = Vec::with_capacity(keys.len());
+                for key in keys {
+                    let name = (key.db, key.key().to_vec());
+                    match key.restore(dbs) {
+                        Ok(()) => restored.push(name),
+                        Err(()) => log::warn!("Could not restore migrated key {:?}", name.1),
+                    }
+                }
+                let ack = MigrationMessage::Ack { sender: self.address.clone(), slot, incarnation: self.incarnation, keys: restored };
+                Some((sender, ack))
+            }
+            MigrationMessage::Ack { sender, slot, incarnation, keys } => {
+                let target = cluster.slot_map().migration(slot)
+                    .filter(|migration| migration.source == cluster.myself())
+                    .map(|migration| migration.address.as_str());
+                if target != Some(sender.as_str()) {
+                    log::warn!("Rejected acknowledgement for slot {} sent by {} which it does not move to", slot, sender);
+                    return None;
+                }
+                let outgoing = self.outgoing.entry(slot).or_default();
+                if outgoing.incarnation != Some(incarnation) {
+                    // the target restarted and lost the keys moved so far
+                    outgoing.moved.clear();
+                    outgoing.confirmed = false;
+                    outgoing.incarnation = Some(incarnation);
+                }
+                else if outgoing.probing && outgoing.sent.is_some() {
+                    outgoing.confirmed = true;
+                }
+                outgoing.moved.extend(keys);
+                outgoing.sent = None;
+                None
+            }
+        }
+    }
+
+    /// Sends the next batch of every slot this node migrates, at most `batch` keys each, and
+    /// hands over the slots whose keys all moved, dropping them from `dbs`
+    pub fn step(&mut self, cluster: &mut Cluster, dbs: &mut [DB], batch: usize) -> MigrationRound {
+        let batch = std::cmp::max(batch, 1);
+        let mut round = MigrationRound::default();
+        let migrations = cluster.outgoing_migrations();
+        self.outgoing.retain(|slot, _| migrations.iter().any(|migration| migration.slot == *slot));
+        for migration in migrations {
+            let outgoing = self.outgoing.entry(migration.slot).or_default();
+            if outgoing.sent.is_some_and(|sent| sent.elapsed() < Duration::from_millis(MIGRATION_TIMEOUT)) {
+                continue;
+            }
+            if outgoing.confirmed {
+                match cluster.finish_migration(migration.slot) {
+                    Ok(slot_map) => round.slot_maps.push(slot_map),
+                    Err(e) => {
+                        log::error!("Could not hand slot {} over: {:?}", migration.slot, e);
+                        continue;
+                    }
+                }
+                let moved = self.outgoing.remove(&migration.slot).unwrap().moved;
+                drop_slot(dbs, migration.slot, &moved);
+                continue;
+            }
+            let keys = slot_keys(dbs, migration.slot, &outgoing.moved, batch);
+            outgoing.sent = Some(Instant::now());
+            outgoing.probing = keys.is_empty();
+            round.messages.push((migration.address, MigrationMessage::Keys {
+                sender: self.address.clone(),
+                slot: migration.slot,
+                keys,
+            }));
+        }
+        round
+    }
+}
+
+/// Up to `batch` keys of `slot` that are not in `moved`. Every key of the slot was scanned when
+/// fewer than `batch` are returned.
+fn slot_keys(dbs: &[DB], slot: u16, moved: &HashSet<(usize, Vec<u8>)>, batch: usize) -> Vec<MigratedKey> {
+    let now = SystemTime::now();
+    let mut keys = Vec::new();
+    for (index, db) in dbs.iter().enumerate() {
+        for (key, value) in db.dict.iter() {
+            let name = object_bytes(key);
+            if key_hash_slot(&name) != slot || moved.contains(&(index, name)) {
+                continue;
+            }
+            if db.expires.find(key).is_some_and(|(_, when)| *when <= now) {
+                continue;
+            }
+            if let Some(key) = MigratedKey::from_db(index, db, key, value) {
+                keys.push(key);
+            }
+            if keys.len() == batch {
+                return keys;
+            }
+        }
+    }
+    keys
+}
+
+/// Deletes the keys of a slot handed over. Keys not moved were created by replicated writes
+/// since the last batch, which the target applied as well.
+fn drop_slot(dbs: &mut [DB], slot: u16, moved: &HashSet<(usize, Vec<u8>)>) {
+    for (index, db) in dbs.iter_mut().enumerate() {
+        let keys: Vec<RobjPtr> = db.dict.iter()
+            .filter(|(key, _)| key_hash_slot(&object_bytes(key)) == slot)
+            .map(|(key, _)| Rc::clone(key))
+            .collect();
+        for key in keys {
+            let name = object_bytes(&key);
+            if !moved.contains(&(index, name.clone())) {
+                log::debug!("Dropping key {:?} of slot {} which was not migrated", name, slot);
+            }
+            let _ = db.evict_key(&key);
+        }
+    }
+}
+
+/// Receives the migration messages of other nodes, handed over to the thread owning the
+/// databases through [MigrationService::try_recv], see [super::server::Server::poll_migration].
+pub struct MigrationService {
+    address: SocketAddr,
+    shutdown: Arc<AtomicBool>,
+    listener: Option<JoinHandle<()>>,
+    receiver: Receiver<MigrationMessage>,
+}
+
+impl MigrationService {
+    /// Starts listening for migration messages at `address`
+    pub fn start(address: SocketAddr) -> Result<MigrationService, Box<dyn Error>> {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let listener = Self::start_listener(address, Arc::clone(&shutdown), sender)?;
+        Ok(MigrationService {
+            address,
+            shutdown,
+            listener: Some(listener),
+            receiver,
+        })
+    }
+
+    fn start_listener(address: SocketAddr, shutdown: Arc<AtomicBool>, sender: Sender<MigrationMessage>) -> Result<JoinHandle<()>, Box<dyn Error>> {
+        start_message_listener("migration", address, shutdown, move |peer, bytes| {
+            match MigrationMessage::from_bytes(&bytes) {
+                Ok(message) if is_sent_from(message.sender(), &peer) => sender.send(message).is_ok(),
+                Ok(message) => {
+                    log::warn!("Rejected migration message claiming to come from {} sent from {}", message.sender(), peer);
+                    true
+                }
+                Err(e) => {
+                    log::error!("Could not decode migration message: {:?}", e);
+                    true
+                }
+            }
+        })
+    }
+
+    pub fn address(&self) -> &SocketAddr {
+        &self.address
+    }
+
+    /// Next pending message, without blocking.
+    pub fn try_recv(&self) -> Option<MigrationMessage> {
+        self.receiver.try_recv().ok()
+    }
+
+    pub fn send(&self, peer: &str, message: &MigrationMessage) -> Result<usize, Box<dyn Error>> {
+        let bytes = message.to_bytes()?;
+        TcpStream::connect(peer)?.write_all(&bytes)?;
+        Ok(bytes.len())
+    }
+
+    pub fn shutdown(&mut self) -> Result<(), Box<dyn Error>> {
+        self.shutdown.store(true, Ordering::SeqCst);
+        // wake up the listener
+        let _ = TcpStream::connect(self.address);
+        match self.listener.take().map(|handle| handle.join()) {
+            Some(Err(e)) => {
+                log::error!("Error during thread join: {:?}", e);
+                Err("Error occurred during shutdown")?
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gossip::update::{Update, UpdateHandler};
+    use crate::svalue::object::{RobjEncoding, RobjType};
+    use crate::svalue::replicated::Actor;
+    use crate::svalue::zip_list::ZipList;
+    use super::super::cluster::{ClusterHandler, Redirect, CLUSTER_SLOTS, CLUSTER_TOPIC};
+
+    struct Node {
+        cluster: Cluster,
+        handler: ClusterHandler,
+        migrator: Migrator,
+        dbs: Vec<DB>,
+    }
+
+    impl Node {
+        fn new(actor: Actor, address: &str) -> Node {
+            let (cluster, handler) = Cluster::new(actor, address);
+            Node { cluster, handler, migrator: Migrator::new(address), dbs: vec![DB::with_actor(0, actor)] }
+        }
+
+        fn learn(&mut self, slot_map: &[u8]) {
+            self.handler.on_update(Update::new_with_topic(CLUSTER_TOPIC, slot_map.to_vec()));
+            self.cluster.apply_received();
+        }
+
+        fn has(&mut self, key: &str) -> bool {
+            self.dbs[0].look_up_key_read(&Robj::create_string_object(key)).is_some()
+        }
+    }
+
+    /// One round of `source`, the answers of `target` delivered back right away
+    fn round(source: &mut Node, target: &mut Node, batch: usize) -> Vec<Vec<u8>> {
+        let round = source.migrator.step(&mut source.cluster, &mut source.dbs, batch);
+        for (_, message) in round.messages {
+            if let Some((_, answer)) = target.migrator.handle(message, &target.cluster, &mut target.dbs) {
+                source.migrator.handle(answer, &source.cluster, &mut source.dbs);
+            }
+        }
+        round.slot_maps
+    }
+
+    #[test]
+    fn slot_moves_with_its_ttls_across_a_target_restart() {
+        let mut a = Node::new(1, "a");
+        let mut b = Node::new(2, "b");
+        let slot = key_hash_slot(b"user");
+        a.cluster.add_node(2, "b").unwrap();
+        a.cluster.assign_slots(0, CLUSTER_SLOTS - 1, 1).unwrap();
+        let slot_map = a.cluster.begin_migration(slot, 2, "b").unwrap();
+        b.learn(&slot_map);
+        assert_eq!(a.cluster.slot_state(slot), SlotState::Migrating { target: 2 });
+        assert_eq!(b.cluster.slot_state(slot), SlotState::Importing { source: 1 });
+
+        let keys: Vec<String> = (0..5).map(|i| format!("{{user}}:{}", i)).collect();
+        for key in &keys {
+            a.dbs[0].set_key(Robj::create_string_object(key), Robj::create_string_object("v"));
+        }
+        a.dbs[0].set_expire(Robj::create_string_object(&keys[0]), SystemTime::now() + Duration::from_secs(60)).unwrap();
+        a.dbs[0].set_key(Robj::create_string_object("other"), Robj::create_string_object("v"));
+
+        assert!(round(&mut a, &mut b, 2).is_empty());
+        let moved: Vec<&String> = keys.iter().filter(|key| a.migrator.is_moved(0, key.as_bytes())).collect();
+        assert_eq!(moved.len(), 2);
+        // the source sends clients to the target for the keys it moved, the target only serves
+        // them after ASKING
+        let key = moved[0].as_bytes();
+        assert_eq!(a.cluster.route(key, false, || false), Err(Redirect::Ask { slot, address: "b".to_owned() }));
+        assert!(a.cluster.route(key, false, || true).is_ok());
+        assert!(b.cluster.route(key, true, || true).is_ok());
+        assert_eq!(b.cluster.route(key, false, || true), Err(Redirect::Moved { slot, address: "a".to_owned() }));
+
+        // the target restarts and loses the keys it restored
+        b = Node::new(2, "b");
+        b.learn(&slot_map);
+        let mut handed_over = Vec::new();
+        for _ in 0..10 {
+            handed_over = round(&mut a, &mut b, 2);
+            if !handed_over.is_empty() {
+                break;
+            }
+        }
+        assert_eq!(handed_over.len(), 1);
+        b.learn(&handed_over[0]);
+        assert_eq!(b.cluster.slot_map().owner(slot), Some(2));
+        assert_eq!(b.cluster.slot_state(slot), SlotState::Stable);
+        for key in &keys {
+            assert!(b.has(key));
+            assert!(!a.has(key));
+        }
+        assert!(a.has("other"));
+        let expire = *b.dbs[0].get_expire(&Robj::create_string_object(&keys[0])).unwrap();
+        assert!(expire > SystemTime::now() + Duration::from_secs(50));
+    }
+
+    #[test]
+    fn slot_moves_with_sorted_sets_and_zip_list_hashes() {
+        let mut a = Node::new(1, "a");
+        let mut b = Node::new(2, "b");
+        let slot = key_hash_slot(b"user");
+        a.cluster.add_node(2, "b").unwrap();
+        a.cluster.assign_slots(0, CLUSTER_SLOTS - 1, 1).unwrap();
+        let slot_map = a.cluster.begin_migration(slot, 2, "b").unwrap();
+        b.learn(&slot_map);
+
+        let counter = Robj::create_string_object("{user}:visits");
+        a.dbs[0].counter_incr_by(&counter, 3).unwrap();
+        let ranking = Robj::create_zset_object();
+        ranking.borrow_mut().zset_add(Robj::create_string_object("alice"), 12.5).unwrap();
+        a.dbs[0].set_key(Robj::create_string_object("{user}:ranking"), ranking);
+        let mut profile = ZipList::new();
+        for entry in ["name", "alice", "age", "30"] {
+            profile.push(entry.as_bytes());
+        }
+        let profile = Robj::create_object(RobjType::Hash, RobjEncoding::ZipList, Box::new(profile));
+        a.dbs[0].set_key(Robj::create_string_object("{user}:profile"), profile);
+
+        let mut handed_over = Vec::new();
+        for _ in 0..10 {
+            handed_over = round(&mut a, &mut b, 10);
+            if !handed_over.is_empty() {
+                break;
+            }
+        }
+        assert_eq!(handed_over.len(), 1);
+        assert_eq!(b.dbs[0].counter_get(&counter).unwrap(), Some(3.into()));
+        let ranking = b.dbs[0].look_up_key_read(&Robj::create_string_object("{user}:ranking")).unwrap();
+        let members: Vec<(Vec<u8>, f64)> = ranking.borrow().zset_iter()
+            .map(|(m, score)| (m.borrow().string().to_vec(), score))
+            .collect();
+        assert_eq!(members, vec![(b"alice".to_vec(), 12.5)]);
+        let profile = b.dbs[0].look_up_key_read(&Robj::create_string_object("{user}:profile")).unwrap();
+        assert_eq!(profile.borrow().hash_iter().count(), 2);
+        for key in ["{user}:visits", "{user}:ranking", "{user}:profile"] {
+            assert!(!a.has(key));
+        }
+    }
+
+    #[test]
+    fn messages_of_other_nodes_are_rejected() {
+        let mut a = Node::new(1, "a:7000");
+        let mut b = Node::new(2, "b:7000");
+        let slot = key_hash_slot(b"user");
+        a.cluster.add_node(2, "b:7000").unwrap();
+        a.cluster.assign_slots(0, CLUSTER_SLOTS - 1, 1).unwrap();
+        let slot_map = a.cluster.begin_migration(slot, 2, "b:7000").unwrap();
+        b.learn(&slot_map);
+        a.dbs[0].set_key(Robj::create_string_object("{user}:name"), Robj::create_string_object("v"));
+
+        // a forged acknowledgement would have the key dropped once the slot is handed over
+        let forged = MigrationMessage::Ack { sender: "c:7001".to_owned(), slot, incarnation: 1, keys: vec![(0, b"{user}:name".to_vec())] };
+        assert!(a.migrator.handle(forged, &a.cluster, &mut a.dbs).is_none());
+        assert!(!a.migrator.is_moved(0, b"{user}:name"));
+
+        let keys = |sender: &str| MigrationMessage::Keys {
+            sender: sender.to_owned(),
+            slot,
+            keys: vec![MigratedKey::from_db(0, &a.dbs[0], &Robj::create_string_object("{user}:name"), &Robj::create_string_object("v")).unwrap()],
+        };
+        assert!(b.migrator.handle(keys("c:7001"), &b.cluster, &mut b.dbs).is_none());
+        assert!(!b.has("{user}:name"));
+        // the migration address of the source differs from its client address by the port
+        let (to, ack) = b.migrator.handle(keys("a:7001"), &b.cluster, &mut b.dbs).unwrap();
+        assert_eq!(to, "a:7001");
+        assert!(b.has("{user}:name"));
+        assert!(a.migrator.handle(ack, &a.cluster, &mut a.dbs).is_none());
+        assert!(a.migrator.is_moved(0, b"{user}:name"));
+    }
+}
@@ -1,3 +1,7 @@
 pub mod server;
 pub mod db;
-pub mod client;
\ No newline at end of file
+pub mod client;
+pub mod replication;
+pub mod anti_entropy;
+pub mod cluster;
+pub mod migration;
\ No newline at end of file
@@ -0,0 +1,435 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::Mutex;
+use std::sync::mpsc::{Receiver, Sender};
+use std::time::{Duration, Instant};
+
+use rand::Rng;
+
+use serde::{Deserialize, Serialize};
+
+use crate::crdts::dot::Dot;
+use crate::crdts::stability::StableCut;
+use crate::crdts::vclock::VClock;
+use crate::crdts::vvwe::{CausalOp, CausalityBarrier};
+use crate::gossip::update::{Update, UpdateHandler};
+use crate::svalue::object::Robj;
+use crate::svalue::replicated::{Actor, ReplicatedOp};
+
+use super::db::DB;
+
+/// How long a received write waits for the earlier writes of its origin before they are given up
+/// on, see [Replicator::set_gap_timeout].
+pub const GAP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A write on a replicated key as it travels through gossip.
+///
+/// Every run of a node numbers the writes it originates, `seq` starting at 0, and each write
+/// happens after the previous one of the same run. Delivering them in that order is enough for
+/// the CRDT ops to converge, since an op only depends on earlier writes of its own actor.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReplicationMessage {
+    /// Node that performed the write
+    origin: Actor,
+    /// Run of `origin` the write was performed in, picked at random when it starts replicating
+    incarnation: u64,
+    /// Position of the write among the ones of `origin` in this run
+    seq: u64,
+    /// Index of the database holding the key
+    db: usize,
+    key: Vec<u8>,
+    op: ReplicatedOp,
+    /// Causal context of the key at `origin` once the write was performed, empty for the types
+    /// without deferred removes
+    clock: VClock<Actor>,
+}
+
+impl ReplicationMessage {
+    pub fn origin(&self) -> Actor {
+        self.origin
+    }
+
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+}
+
+/// What nodes gossip to each other about the replicated keys.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ReplicationUpdate {
+    Write(ReplicationMessage),
+    /// Causal context a node reached on a replicated set or map after applying writes of the
+    /// others, which lets every node find out which removes are causally stable.
+    Clock {
+        origin: Actor,
+        db: usize,
+        key: Vec<u8>,
+        clock: VClock<Actor>,
+    },
+}
+
+impl ReplicationUpdate {
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+        Ok(serde_cbor::to_vec(self)?)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Box<dyn Error>> {
+        Ok(serde_cbor::from_slice(bytes)?)
+    }
+
+    pub fn origin(&self) -> Actor {
+        match self {
+            ReplicationUpdate::Write(message) => message.origin,
+            ReplicationUpdate::Clock { origin, .. } => *origin,
+        }
+    }
+}
+
+/// Run of a node the writes are numbered within
+type Origin = (Actor, u64);
+
+impl CausalOp<Origin> for ReplicationMessage {
+    fn happens_after(&self) -> Option<Dot<Origin>> {
+        match self.seq {
+            0 => None,
+            seq => Some(Dot::new((self.origin, self.incarnation), seq - 1)),
+        }
+    }
+
+    fn dot(&self) -> Dot<Origin> {
+        Dot::new((self.origin, self.incarnation), self.seq)
+    }
+}
+
+/// Gossip callback of a replicating node: decodes the received updates and hands them over to
+/// the thread owning the databases, see [Replicator::apply_received].
+pub struct ReplicationHandler {
+    sender: Mutex<Sender<ReplicationUpdate>>,
+}
+
+impl UpdateHandler for ReplicationHandler {
+    fn on_update(&self, update: Update) {
+        match ReplicationUpdate::from_bytes(update.content()) {
+            Ok(message) => {
+                if let Err(e) = self.sender.lock().unwrap().send(message) {
+                    log::error!("Replicator is gone, dropping update {}: {:?}", update.digest(), e);
+                }
+            }
+            Err(e) => log::warn!("Could not decode replicated update {}: {:?}", update.digest(), e),
+        }
+    }
+}
+
+/// Multi-master replication of the CRDT-typed keys of a node.
+///
+/// Local writes are turned into messages by [Replicator::prepare] and submitted to gossip;
+/// messages of other nodes come back through the [ReplicationHandler] and are held by a
+/// causality barrier until every earlier write of their origin has been applied. Writes lost on
+/// the way are given up on after a while, see [Replicator::set_gap_timeout], the state they
+/// carried reaches the node through anti-entropy instead.
+///
+/// Once the nodes of the cluster are known, see [Replicator::set_replicas], the replicator
+/// also tracks the clocks each of them reached on every replicated set and map and drops the
+/// deferred removes of a key as soon as their context is stable at all of them.
+pub struct Replicator {
+    actor: Actor,
+    incarnation: u64,
+    seq: u64,
+    barrier: CausalityBarrier<Origin, ReplicationMessage>,
+    /// When the writes buffered by the barrier started waiting, by the write they wait on
+    waiting: HashMap<Dot<Origin>, Instant>,
+    gap_timeout: Duration,
+    receiver: Receiver<ReplicationUpdate>,
+    replicas: Vec<Actor>,
+    cuts: HashMap<(usize, Vec<u8>), StableCut<Actor, Actor>>,
+    reports: HashMap<(usize, Vec<u8>), VClock<Actor>>,
+    pruned: usize,
+}
+
+impl Replicator {
+    /// Creates the replicator of node `actor` and the gossip handler feeding it.
+    pub fn new(actor: Actor) -> (Replicator, ReplicationHandler) {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let replicator = Replicator {
+            actor,
+            incarnation: rand::thread_rng().gen(),
+            seq: 0,
+            barrier: CausalityBarrier::new(),
+            waiting: HashMap::new(),
+            gap_timeout: GAP_TIMEOUT,
+            receiver,
+            replicas: vec![],
+            cuts: HashMap::new(),
+            reports: HashMap::new(),
+            pruned: 0,
+        };
+        (replicator, ReplicationHandler { sender: Mutex::new(sender) })
+    }
+
+    pub fn actor(&self) -> Actor {
+        self.actor
+    }
+
+    /// Sets how long received writes wait for the earlier writes of their origin. Past it the
+    /// missing writes are skipped and the waiting ones applied, anti-entropy repairing the keys
+    /// the skipped writes touched.
+    pub fn set_gap_timeout(&mut self, timeout: Duration) {
+        self.gap_timeout = timeout;
+    }
+
+    /// Sets the actors of every node of the cluster, this one included. Deferred removes are only
+    /// collected once all of them reported a clock, an empty list disables collection.
+    pub fn set_replicas(&mut self, replicas: Vec<Actor>) {
+        self.cuts.clear();
+        self.replicas = replicas;
+    }
+
+    pub fn replicas(&self) -> &[Actor] {
+        &self.replicas
+    }
+
+    /// Number of deferred removes dropped since the replicator was created.
+    pub fn pruned(&self) -> usize {
+        self.pruned
+    }
+
+    /// Encodes `op`, just performed on `key` of database `db` whose causal context is now
+    /// `clock`, as the next write of this node.
+    pub fn prepare(&mut self, db: usize, key: &[u8], op: ReplicatedOp, clock: VClock<Actor>) -> Result<Vec<u8>, Box<dyn Error>> {
+        let message = ReplicationMessage {
+            origin: self.actor,
+            incarnation: self.incarnation,
+            seq: self.seq,
+            db,
+            key: key.to_vec(),
+            op,
+            clock,
+        };
+        let bytes = ReplicationUpdate::Write(message).to_bytes()?;
+        self.seq += 1;
+        Ok(bytes)
+    }
+
+    /// Encodes the clocks this node reached on the keys it applied writes of others to since the
+    /// last call, to be gossiped so that the other nodes can collect their deferred removes.
+    pub fn take_reports(&mut self) -> Vec<Vec<u8>> {
+        let origin = self.actor;
+        self.reports.drain()
+            .filter_map(|((db, key), clock)| {
+                ReplicationUpdate::Clock { origin, db, key, clock }.to_bytes()
+                    .map_err(|e| log::error!("Could not encode clock report: {:?}", e))
+                    .ok()
+            })
+            .collect()
+    }
+
+    /// Applies the writes received so far whose dependencies have landed, or waited on them for
+    /// longer than the gap timeout, and returns how many were applied. Writes for a missing
+    /// database or a key holding another type are dropped.
+    pub fn apply_received(&mut self, dbs: &mut [DB]) -> usize {
+        self.apply_received_where(dbs, |_| true)
+    }
+
+    /// Same as [Replicator::apply_received], dropping the writes on the keys `keep` refuses,
+    /// such as the keys of the slots another node of the cluster serves.
+    pub fn apply_received_where<F>(&mut self, dbs: &mut [DB], keep: F) -> usize
+    where
+        F: Fn(&[u8]) -> bool,
+    {
+        let mut ready = Vec::new();
+        while let Ok(update) = self.receiver.try_recv() {
+            if update.origin() == self.actor {
+                continue;
+            }
+            let message = match update {
+                ReplicationUpdate::Write(message) => message,
+                ReplicationUpdate::Clock { origin, db, key, clock } => {
+                    if let Some(target) = dbs.get_mut(db) {
+                        self.collect(target, db, key, origin, &clock);
+                    }
+                    continue;
+                }
+            };
+            let awaited = message.happens_after();
+            let released = self.barrier.ingest_all(message);
+            match awaited {
+                Some(dot) if released.is_empty() && self.barrier.buffer.contains_key(&dot) => {
+                    self.waiting.entry(dot).or_insert_with(Instant::now);
+                }
+                _ => {}
+            }
+            ready.extend(released);
+        }
+        ready.extend(self.skip_gaps());
+
+        let mut applied = 0;
+        for message in ready {
+            self.waiting.remove(&message.dot());
+            if !keep(&message.key) {
+                continue;
+            }
+            let db = match dbs.get_mut(message.db) {
+                Some(db) => db,
+                None => {
+                    log::warn!("Replicated write for unknown db {}", message.db);
+                    continue;
+                }
+            };
+            let key = Robj::from_bytes(message.key.clone());
+            match db.apply_replicated(&key, message.op) {
+                Ok(()) => applied += 1,
+                Err(()) => {
+                    log::warn!("Replicated write does not match the type of its key");
+                    continue;
+                }
+            }
+            if let Some(clock) = db.replicated_clock(&key) {
+                if !self.replicas.is_empty() {
+                    self.reports.insert((message.db, message.key.clone()), clock);
+                }
+                self.collect(db, message.db, message.key, message.origin, &message.clock);
+            }
+        }
+        applied
+    }
+
+    /// Gives up on the writes that buffered writes waited on for longer than the gap timeout,
+    /// and returns the buffered writes this releases.
+    fn skip_gaps(&mut self) -> Vec<ReplicationMessage> {
+        let timeout = self.gap_timeout;
+        let expired: Vec<Dot<Origin>> = self.waiting.iter()
+            .filter(|(_, since)| since.elapsed() >= timeout)
+            .map(|(dot, _)| *dot)
+            .collect();
+        let mut released = Vec::new();
+        for dot in expired {
+            self.waiting.remove(&dot);
+            let (origin, _) = dot.actor;
+            log::warn!("Gave up on the writes of node {} up to {}, leaving them to anti-entropy", origin, dot.counter);
+            released.extend(self.barrier.skip_to(&dot));
+        }
+        released
+    }
+
+    /// Records the clock `origin` reached on `key` and drops the deferred removes of the key
+    /// that are stable at every replica.
+    fn collect(&mut self, db: &mut DB, index: usize, key: Vec<u8>, origin: Actor, clock: &VClock<Actor>) {
+        if self.replicas.is_empty() {
+            return;
+        }
+        let robj = Robj::from_bytes(key.clone());
+        let local = match db.replicated_clock(&robj) {
+            Some(local) => local,
+            None => return,
+        };
+        let replicas = &self.replicas;
+        let cut = self.cuts.entry((index, key))
+            .or_insert_with(|| StableCut::new(replicas.iter().cloned()));
+        cut.observe(&origin, clock);
+        cut.observe(&self.actor, &local);
+        self.pruned += db.replicated_gc(&robj, &cut.cut());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn replicate(from: &mut Replicator, key: &[u8], op: ReplicatedOp) -> Update {
+        Update::new(from.prepare(0, key, op, VClock::new()).unwrap())
+    }
+
+    #[test]
+    fn out_of_order_writes_converge() {
+        let (mut ra, ha) = Replicator::new(1);
+        let (mut rb, hb) = Replicator::new(2);
+        let mut a = vec![DB::with_actor(0, 1)];
+        let mut b = vec![DB::with_actor(0, 2)];
+        let key = Robj::create_string_object("s");
+
+        let op = a[0].replicated_set_add(&key, vec![b"x".to_vec()]).unwrap();
+        let first = replicate(&mut ra, b"s", op);
+        let op = a[0].replicated_set_rem(&key, vec![b"x".to_vec()]).unwrap();
+        let second = replicate(&mut ra, b"s", op);
+        let op = b[0].replicated_set_add(&key, vec![b"y".to_vec()]).unwrap();
+        let concurrent = replicate(&mut rb, b"s", op);
+
+        hb.on_update(second);
+        assert_eq!(rb.apply_received(&mut b), 0);
+        hb.on_update(first);
+        assert_eq!(rb.apply_received(&mut b), 2);
+        ha.on_update(concurrent);
+        assert_eq!(ra.apply_received(&mut a), 1);
+
+        let members = vec![b"y".to_vec()];
+        assert_eq!(a[0].replicated_set_members(&key).unwrap(), members);
+        assert_eq!(b[0].replicated_set_members(&key).unwrap(), members);
+    }
+
+    #[test]
+    fn writes_of_a_restarted_node_are_applied() {
+        let (mut ra, _) = Replicator::new(1);
+        let (mut rb, hb) = Replicator::new(2);
+        let mut a = [DB::with_actor(0, 1)];
+        let mut b = vec![DB::with_actor(0, 2)];
+        let key = Robj::create_string_object("c");
+
+        let (_, op) = a[0].counter_incr_by(&key, 1).unwrap();
+        hb.on_update(replicate(&mut ra, b"c", op));
+        assert_eq!(rb.apply_received(&mut b), 1);
+
+        // the restarted node numbers its writes from 0 again
+        let (mut ra, _) = Replicator::new(1);
+        let (_, op) = a[0].counter_incr_by(&key, 1).unwrap();
+        hb.on_update(replicate(&mut ra, b"c", op));
+        assert_eq!(rb.apply_received(&mut b), 1);
+        assert_eq!(b[0].counter_get(&key).unwrap(), Some(2.into()));
+    }
+
+    #[test]
+    fn lost_writes_are_given_up_on() {
+        let (mut ra, _) = Replicator::new(1);
+        let (mut rb, hb) = Replicator::new(2);
+        let mut a = [DB::with_actor(0, 1)];
+        let mut b = vec![DB::with_actor(0, 2)];
+        let key = Robj::create_string_object("s");
+
+        let op = a[0].replicated_set_add(&key, vec![b"x".to_vec()]).unwrap();
+        let _lost = replicate(&mut ra, b"s", op);
+        let op = a[0].replicated_set_add(&key, vec![b"y".to_vec()]).unwrap();
+        hb.on_update(replicate(&mut ra, b"s", op));
+        assert_eq!(rb.apply_received(&mut b), 0);
+
+        rb.set_gap_timeout(Duration::from_secs(0));
+        assert_eq!(rb.apply_received(&mut b), 1);
+        assert_eq!(b[0].replicated_set_members(&key).unwrap(), vec![b"y".to_vec()]);
+        assert!(rb.waiting.is_empty());
+    }
+
+    #[test]
+    fn applied_writes_are_reported_to_the_replicas() {
+        let (mut ra, _) = Replicator::new(1);
+        let (mut rb, hb) = Replicator::new(2);
+        rb.set_replicas(vec![1, 2]);
+        let mut a = [DB::with_actor(0, 1)];
+        let mut b = vec![DB::with_actor(0, 2)];
+        let key = Robj::create_string_object("s");
+
+        let op = a[0].replicated_set_add(&key, vec![b"x".to_vec()]).unwrap();
+        let clock = a[0].replicated_clock(&key).unwrap();
+        hb.on_update(Update::new(ra.prepare(0, b"s", op, clock.clone()).unwrap()));
+        assert_eq!(rb.apply_received(&mut b), 1);
+
+        let reports = rb.take_reports();
+        assert_eq!(reports.len(), 1);
+        match ReplicationUpdate::from_bytes(&reports[0]).unwrap() {
+            ReplicationUpdate::Clock { origin, key, clock: reported, .. } => {
+                assert_eq!(origin, 2);
+                assert_eq!(key, b"s".to_vec());
+                assert_eq!(reported, clock);
+            }
+            update => panic!("unexpected update {:?}", update),
+        }
+        assert!(rb.take_reports().is_empty());
+    }
+}
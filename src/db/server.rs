@@ -1,13 +1,456 @@
-
+use std::error::Error;
 use std::net::SocketAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use crate::crdts;
+use crate::gossip::config::TopicConfig;
+use crate::gossip::gossip::GossipService;
+use crate::gossip::peer::Peer;
+use crate::svalue::object::{Robj, RobjPtr};
+use crate::svalue::replicated::{Actor, ReplicatedOp};
 
 use super::db::DB;
+use super::replication::{ReplicationHandler, Replicator};
+use super::cluster::{key_hash_slot, Cluster, Redirect, SlotInfo, CLUSTER_TOPIC};
+use super::migration::{MigrationService, Migrator};
+use super::anti_entropy::{AntiEntropyEvent, AntiEntropyMessage, AntiEntropyService, RoundTrees, TOMBSTONE_RETENTION};
 
 pub struct Server {
     pub port: u16,
     pub db: Vec<DB>,  //TODO: change to hashmap
+    replicator: Option<Replicator>,
+    gossip: Option<GossipService<ReplicationHandler>>,
+    anti_entropy: Option<AntiEntropyService>,
+    /// Trees of the anti-entropy rounds in progress
+    round_trees: RoundTrees,
+    cluster: Option<Cluster>,
+    migration: Option<MigrationService>,
+    migrator: Option<Migrator>,
 }
 
+impl Server {
+    pub fn new(port: u16, db: Vec<DB>) -> Server {
+        Server {
+            port,
+            db,
+            replicator: None,
+            gossip: None,
+            anti_entropy: None,
+            round_trees: RoundTrees::new(),
+            cluster: None,
+            migration: None,
+            migrator: None,
+        }
+    }
+
+    /// Starts active-active replication of the CRDT-typed keys over `gossip`. The node is
+    /// identified in the cluster by the actor of its first database.
+    pub fn start_replication(
+        &mut self,
+        mut gossip: GossipService<ReplicationHandler>,
+        peer_sampling_init: Box<dyn FnOnce() -> Option<Vec<Peer>>>,
+    ) -> Result<(), Box<dyn Error>> {
+        let actor = match self.db.first() {
+            Some(db) => db.actor,
+            None => Err("No database to replicate")?,
+        };
+        let (replicator, handler) = Replicator::new(actor);
+        gossip.start(peer_sampling_init, Box::new(handler))?;
+        self.replicator = Some(replicator);
+        self.gossip = Some(gossip);
+        Ok(())
+    }
+
+    pub fn stop_replication(&mut self) -> Result<(), Box<dyn Error>> {
+        self.replicator = None;
+        self.cluster = None;
+        match self.gossip.take() {
+            Some(mut gossip) => gossip.shutdown(),
+            None => Ok(()),
+        }
+    }
+
+    pub fn is_replicating(&self) -> bool {
+        self.gossip.is_some()
+    }
+
+    /// Changes a gossip or peer sampling parameter of the running replication, as CONFIG SET
+    /// does. The gossip threads pick the change up on their next cycle.
+    ///
+    /// # Arguments
+    ///
+    /// * `parameter` - Name of the parameter, see [Server::config_get]
+    /// * `value` - New value, `yes` or `no` for flags
+    pub fn config_set(&mut self, parameter: &str, value: &str) -> Result<(), Box<dyn Error>> {
+        let gossip = self.gossip.as_ref().ok_or("Replication not started")?;
+        let parameter = parameter.to_ascii_lowercase();
+        if parameter.starts_with("gossip-") {
+            let mut config = gossip.gossip_config();
+            match parameter.as_str() {
+                "gossip-period" => config.set_gossip_period(value.parse()?),
+                "gossip-deviation" => config.set_gossip_deviation(value.parse()?),
+                "gossip-push" => config.set_push(parse_flag(value)?),
+                "gossip-pull" => config.set_pull(parse_flag(value)?),
+                _ => Err(format!("Unknown configuration parameter: {}", parameter))?,
+            }
+            gossip.set_gossip_config(config)
+        }
+        else {
+            let mut config = gossip.peer_sampling_config();
+            match parameter.as_str() {
+                "sampling-period" => config.set_sampling_period(value.parse()?),
+                "sampling-deviation" => config.set_sampling_deviation(value.parse()?),
+                "sampling-push" => config.set_push(parse_flag(value)?),
+                "sampling-pull" => config.set_pull(parse_flag(value)?),
+                "sampling-view-size" => config.set_view_size(value.parse()?),
+                "sampling-healing" => config.set_healing_factor(value.parse()?),
+                "sampling-swapping" => config.set_swapping_factor(value.parse()?),
+                _ => Err(format!("Unknown configuration parameter: {}", parameter))?,
+            }
+            gossip.set_peer_sampling_config(config)
+        }
+    }
+
+    /// Returns a gossip or peer sampling parameter of the running replication, as CONFIG GET
+    /// does. The parameters are `gossip-period`, `gossip-deviation`, `gossip-push`,
+    /// `gossip-pull`, `sampling-period`, `sampling-deviation`, `sampling-push`,
+    /// `sampling-pull`, `sampling-view-size`, `sampling-healing` and `sampling-swapping`.
+    pub fn config_get(&self, parameter: &str) -> Result<String, Box<dyn Error>> {
+        let gossip = self.gossip.as_ref().ok_or("Replication not started")?;
+        let gossip_config = gossip.gossip_config();
+        let sampling_config = gossip.peer_sampling_config();
+        let value = match parameter.to_ascii_lowercase().as_str() {
+            "gossip-period" => gossip_config.gossip_period().to_string(),
+            "gossip-deviation" => gossip_config.gossip_deviation().to_string(),
+            "gossip-push" => format_flag(gossip_config.is_push()),
+            "gossip-pull" => format_flag(gossip_config.is_pull()),
+            "sampling-period" => sampling_config.sampling_period().to_string(),
+            "sampling-deviation" => sampling_config.sampling_deviation().to_string(),
+            "sampling-push" => format_flag(sampling_config.is_push()),
+            "sampling-pull" => format_flag(sampling_config.is_pull()),
+            "sampling-view-size" => sampling_config.view_size().to_string(),
+            "sampling-healing" => sampling_config.healing_factor().to_string(),
+            "sampling-swapping" => sampling_config.swapping_factor().to_string(),
+            _ => Err(format!("Unknown configuration parameter: {}", parameter))?,
+        };
+        Ok(value)
+    }
+
+    /// Runs `write` against database `db` and submits the op it returns to the other nodes.
+    /// Without replication the write only happens locally. `asking` tells whether the client
+    /// sent ASKING before the command, as it does after an ASK redirect, see [Server::check_slot].
+    pub fn replicated_write<F>(&mut self, db: usize, key: &RobjPtr, asking: bool, write: F) -> Result<(), Box<dyn Error>>
+    where
+        F: FnOnce(&mut DB, &RobjPtr) -> Result<ReplicatedOp, ()>,
+    {
+        self.check_slot(db, key.borrow().string(), asking)?;
+        let target = self.db.get_mut(db).ok_or("No such database")?;
+        let op = write(target, key).map_err(|_| "Operation against a key holding the wrong kind of value")?;
+        if let (Some(replicator), Some(gossip)) = (self.replicator.as_mut(), self.gossip.as_ref()) {
+            let clock = target.replicated_clock(key).unwrap_or_default();
+            let bytes = replicator.prepare(db, key.borrow().string(), op, clock)?;
+            gossip.submit(bytes)?;
+        }
+        Ok(())
+    }
+
+    /// Sets the actors of the nodes of the cluster, enabling the collection of the deferred
+    /// removes every node has the context of.
+    pub fn set_replicas(&mut self, replicas: Vec<Actor>) -> Result<(), Box<dyn Error>> {
+        let replicator = self.replicator.as_mut().ok_or("Replication not started")?;
+        replicator.set_replicas(replicas);
+        Ok(())
+    }
+
+    /// Applies the writes other nodes replicated to this one since the last call, then gossips
+    /// the clocks they brought the written keys to.
+    pub fn apply_replicated(&mut self) -> usize {
+        let (replicator, gossip) = match (self.replicator.as_mut(), self.gossip.as_ref()) {
+            (Some(replicator), Some(gossip)) => (replicator, gossip),
+            _ => return 0,
+        };
+        let applied = match self.cluster.as_ref() {
+            Some(cluster) => replicator.apply_received_where(&mut self.db, |key| cluster.holds(key)),
+            None => replicator.apply_received(&mut self.db),
+        };
+        for report in replicator.take_reports() {
+            if let Err(e) = gossip.submit(report) {
+                log::warn!("Could not gossip clock report: {:?}", e);
+            }
+        }
+        applied
+    }
+
+    /// Enables cluster mode: keys are sharded across the nodes by hash slot and the slot map is
+    /// gossiped on its own topic, ahead of the replicated writes. Requires replication to be
+    /// started, the node starts without any slot.
+    ///
+    /// # Arguments
+    ///
+    /// * `address` - Address clients reach this node at, sent along redirects
+    pub fn start_cluster(&mut self, address: &str) -> Result<(), Box<dyn Error>> {
+        let (replicator, gossip) = match (self.replicator.as_ref(), self.gossip.as_ref()) {
+            (Some(replicator), Some(gossip)) => (replicator, gossip),
+            _ => Err("Replication not started")?,
+        };
+        if self.cluster.is_some() {
+            Err("Cluster already started")?
+        }
+        let config = gossip.gossip_config();
+        let topic = TopicConfig::new(CLUSTER_TOPIC, u8::MAX, config.update_expiration().clone());
+        gossip.set_gossip_config(config.with_topic(topic))?;
+        let (mut cluster, handler) = Cluster::new(replicator.actor(), address);
+        gossip.subscribe(CLUSTER_TOPIC, Box::new(handler));
+        gossip.submit_to_topic(CLUSTER_TOPIC, cluster.announce()?)?;
+        self.cluster = Some(cluster);
+        Ok(())
+    }
+
+    pub fn is_cluster_enabled(&self) -> bool {
+        self.cluster.is_some()
+    }
+
+    /// Adds a node to the cluster, as CLUSTER MEET does
+    pub fn cluster_meet(&mut self, node: Actor, address: &str) -> Result<(), Box<dyn Error>> {
+        let cluster = self.cluster.as_mut().ok_or("Cluster mode not enabled")?;
+        let bytes = cluster.add_node(node, address)?;
+        self.submit_slot_map(bytes)
+    }
+
+    /// Assigns slots `start` to `end`, both included, to `node`, as CLUSTER ADDSLOTSRANGE does
+    pub fn cluster_add_slots(&mut self, start: u16, end: u16, node: Actor) -> Result<(), Box<dyn Error>> {
+        let cluster = self.cluster.as_mut().ok_or("Cluster mode not enabled")?;
+        let bytes = cluster.assign_slots(start, end, node)?;
+        self.submit_slot_map(bytes)
+    }
+
+    fn submit_slot_map(&self, bytes: Vec<u8>) -> Result<(), Box<dyn Error>> {
+        let gossip = self.gossip.as_ref().ok_or("Replication not started")?;
+        gossip.submit_to_topic(CLUSTER_TOPIC, bytes)
+    }
+
+    /// Adopts the newest slot map other nodes gossiped, returns whether it changed. The map is
+    /// announced again when a peer gossiped an older one, as a restarted node does.
+    pub fn poll_cluster(&mut self) -> bool {
+        let cluster = match self.cluster.as_mut() {
+            Some(cluster) => cluster,
+            None => return false,
+        };
+        let changed = cluster.apply_received();
+        if cluster.has_stale_peer() {
+            match cluster.announce() {
+                Ok(bytes) => {
+                    if let Err(e) = self.submit_slot_map(bytes) {
+                        log::warn!("Could not announce slot map: {:?}", e);
+                    }
+                }
+                Err(e) => log::error!("Could not encode slot map: {:?}", e),
+            }
+        }
+        changed
+    }
+
+    /// Checks that this node serves `key` of database `db`, always the case outside of cluster
+    /// mode. The error is the redirect to send to the client.
+    ///
+    /// # Arguments
+    ///
+    /// * `db` - Database of the key
+    /// * `key` - The key of the command
+    /// * `asking` - Whether the client sent ASKING before the command
+    pub fn check_slot(&mut self, db: usize, key: &[u8], asking: bool) -> Result<(), Redirect> {
+        let cluster = match self.cluster.as_ref() {
+            Some(cluster) => cluster,
+            None => return Ok(()),
+        };
+        let (dbs, migrator) = (&mut self.db, self.migrator.as_ref());
+        cluster.route(key, asking, || {
+            !migrator.is_some_and(|migrator| migrator.is_moved(db, key))
+                && dbs.get_mut(db).is_some_and(|target| target.look_up_key_read(&Robj::from_bytes(key.to_vec())).is_some())
+        })
+    }
 
+    /// Starts receiving the keys of the slots migrated to this node. Required on both ends of
+    /// a migration.
+    ///
+    /// # Arguments
+    ///
+    /// * `address` - Address to listen on for migration messages
+    pub fn start_migration(&mut self, address: SocketAddr) -> Result<(), Box<dyn Error>> {
+        if self.migration.is_some() {
+            Err("Migration already started")?
+        }
+        self.migration = Some(MigrationService::start(address)?);
+        self.migrator = Some(Migrator::new(&address.to_string()));
+        Ok(())
+    }
+
+    pub fn stop_migration(&mut self) -> Result<(), Box<dyn Error>> {
+        self.migrator = None;
+        match self.migration.take() {
+            Some(mut service) => service.shutdown(),
+            None => Ok(()),
+        }
+    }
+
+    /// Starts moving `slot` to `target`, as CLUSTER SETSLOT MIGRATING and IMPORTING do on
+    /// both nodes at once. The owner of the slot moves the keys on its next calls to
+    /// [Server::poll_migration].
+    ///
+    /// # Arguments
+    ///
+    /// * `slot` - The slot to move
+    /// * `target` - Node receiving the slot
+    /// * `address` - Migration address of the target, see [Server::start_migration]
+    pub fn cluster_migrate(&mut self, slot: u16, target: Actor, address: &str) -> Result<(), Box<dyn Error>> {
+        let cluster = self.cluster.as_mut().ok_or("Cluster mode not enabled")?;
+        let bytes = cluster.begin_migration(slot, target, address)?;
+        self.submit_slot_map(bytes)
+    }
+
+    /// Restores the keys migrated to this node and sends the next batch, at most `batch` keys,
+    /// of every slot this node migrates away. Slots whose keys all moved are handed over to
+    /// their target. Returns how many messages were handled and sent.
+    pub fn poll_migration(&mut self, batch: usize) -> usize {
+        let (cluster, service, migrator) = match (self.cluster.as_mut(), self.migration.as_ref(), self.migrator.as_mut()) {
+            (Some(cluster), Some(service), Some(migrator)) => (cluster, service, migrator),
+            _ => return 0,
+        };
+        let mut handled = 0;
+        let mut answers = Vec::new();
+        while let Some(message) = service.try_recv() {
+            handled += 1;
+            answers.extend(migrator.handle(message, cluster, &mut self.db));
+        }
+        let round = migrator.step(cluster, &mut self.db, batch);
+        for (peer, message) in answers.into_iter().chain(round.messages) {
+            handled += 1;
+            if let Err(e) = service.send(&peer, &message) {
+                log::error!("Error sending migration message to {}: {:?}", peer, e);
+            }
+        }
+        for bytes in round.slot_maps {
+            if let Err(e) = self.submit_slot_map(bytes) {
+                log::error!("Could not gossip slot hand over: {:?}", e);
+            }
+        }
+        handled
+    }
+
+    /// Slot of `key`, as CLUSTER KEYSLOT returns it
+    pub fn cluster_keyslot(&self, key: &[u8]) -> u16 {
+        key_hash_slot(key)
+    }
+
+    /// Assigned slot ranges with the address and id of their node, as CLUSTER SLOTS returns them
+    pub fn cluster_slots(&self) -> Result<Vec<SlotInfo>, Box<dyn Error>> {
+        let cluster = self.cluster.as_ref().ok_or("Cluster mode not enabled")?;
+        Ok(cluster.slots())
+    }
+
+    /// Nodes of the cluster and their slots, as CLUSTER NODES returns them
+    pub fn cluster_nodes(&self) -> Result<String, Box<dyn Error>> {
+        let cluster = self.cluster.as_ref().ok_or("Cluster mode not enabled")?;
+        Ok(cluster.nodes())
+    }
+
+    /// Removes still waiting for causal context across all databases.
+    pub fn deferred_removes(&self) -> usize {
+        self.db.iter().map(|db| db.deferred_removes()).sum()
+    }
+
+    /// Starts repairing the keyspace with a random peer every `period` milliseconds, which
+    /// catches up nodes that missed updates gossip no longer spreads.
+    ///
+    /// # Arguments
+    ///
+    /// * `address` - Address to listen on for anti-entropy messages
+    /// * `period` - Milliseconds between two repair rounds
+    /// * `peers` - Anti-entropy addresses of the other replicas
+    pub fn start_anti_entropy(&mut self, address: SocketAddr, period: u64, peers: Vec<SocketAddr>) -> Result<(), Box<dyn Error>> {
+        if self.anti_entropy.is_some() {
+            Err("Anti-entropy already started")?
+        }
+        self.anti_entropy = Some(AntiEntropyService::start(address, period, peers)?);
+        Ok(())
+    }
+
+    pub fn stop_anti_entropy(&mut self) -> Result<(), Box<dyn Error>> {
+        match self.anti_entropy.take() {
+            Some(mut service) => service.shutdown(),
+            None => Ok(()),
+        }
+    }
+
+    /// Handles the pending anti-entropy rounds and peer messages, returns how many there were.
+    pub fn poll_anti_entropy(&mut self) -> usize {
+        let service = match self.anti_entropy.as_ref() {
+            Some(service) => service,
+            None => return 0,
+        };
+        let address = service.address().to_string();
+        let mut handled = 0;
+        while let Some(event) = service.try_recv() {
+            handled += 1;
+            match event {
+                AntiEntropyEvent::Round(peer) => {
+                    let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64);
+                    for db in self.db.iter_mut() {
+                        db.purge_tombstones(now.saturating_sub(TOMBSTONE_RETENTION));
+                    }
+                    for (i, db) in self.db.iter().enumerate() {
+                        // the round compares a fresh tree, reused for its next levels
+                        self.round_trees.invalidate(i);
+                        let tree = match self.cluster.as_ref() {
+                            Some(cluster) => self.round_trees.get_or_build(i, db, |key| cluster.holds(key)),
+                            None => self.round_trees.get_or_build(i, db, |_| true),
+                        };
+                        let message = AntiEntropyMessage::start(address.clone(), i, tree);
+                        if let Err(e) = service.send(&peer, &message) {
+                            log::error!("Error sending anti-entropy round to {}: {:?}", peer, e);
+                        }
+                    }
+                }
+                AntiEntropyEvent::Message(message) => {
+                    let peer = match message.sender().parse::<SocketAddr>() {
+                        Ok(peer) => peer,
+                        Err(_) => {
+                            log::error!("Could not parse sender address {}", message.sender());
+                            continue;
+                        }
+                    };
+                    let db = match self.db.get_mut(message.db()) {
+                        Some(db) => db,
+                        None => {
+                            log::warn!("Anti-entropy message for unknown db {}", message.db());
+                            continue;
+                        }
+                    };
+                    let trees = &mut self.round_trees;
+                    let answer = match self.cluster.as_ref() {
+                        Some(cluster) => message.handle_where(db, &address, |key| cluster.holds(key), trees),
+                        None => message.handle_where(db, &address, |_| true, trees),
+                    };
+                    if let Some(answer) = answer {
+                        if let Err(e) = service.send(&peer, &answer) {
+                            log::error!("Error answering anti-entropy message of {}: {:?}", peer, e);
+                        }
+                    }
+                }
+            }
+        }
+        handled
+    }
+}
+
+fn parse_flag(value: &str) -> Result<bool, Box<dyn Error>> {
+    match value.to_ascii_lowercase().as_str() {
+        "yes" => Ok(true),
+        "no" => Ok(false),
+        _ => Err(format!("Expected yes or no, got {}", value))?,
+    }
+}
+
+fn format_flag(flag: bool) -> String {
+    if flag { "yes".to_owned() } else { "no".to_owned() }
+}
@@ -0,0 +1,128 @@
+use std::error::Error;
+
+// A byte oriented LZ77 codec for batched updates. The compressed block is the uncompressed
+// length (4 bytes, big endian) followed by tokens: a tag below 0x80 is followed by `tag + 1`
+// literal bytes, a tag from 0x80 copies `(tag & 0x7F) + MIN_MATCH` bytes starting `offset`
+// bytes back, the offset following the tag (2 bytes, big endian).
+const MIN_MATCH: usize = 4;
+const MAX_MATCH: usize = 0x7F + MIN_MATCH;
+const MAX_LITERALS: usize = 0x80;
+const MAX_OFFSET: usize = u16::MAX as usize;
+const HASH_BITS: u32 = 12;
+/// Largest ratio between the decompressed and compressed sizes of a valid block
+pub const MAX_EXPANSION: usize = MAX_MATCH / 3 + 1;
+
+fn hash(bytes: &[u8]) -> usize {
+    let value = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    (value.wrapping_mul(0x9E37_79B1) >> (32 - HASH_BITS)) as usize
+}
+
+fn flush_literals(output: &mut Vec<u8>, literals: &[u8]) {
+    for chunk in literals.chunks(MAX_LITERALS) {
+        output.push((chunk.len() - 1) as u8);
+        output.extend_from_slice(chunk);
+    }
+}
+
+/// Compresses `input`
+pub fn compress(input: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(input.len() / 2 + 8);
+    output.extend_from_slice(&(input.len() as u32).to_be_bytes());
+    // last position of each hashed 4 byte sequence, shifted by one so that zero means none
+    let mut table = vec![0usize; 1 << HASH_BITS];
+    let mut literal_start = 0;
+    let mut position = 0;
+    while position + MIN_MATCH <= input.len() {
+        let slot = hash(&input[position..]);
+        let candidate = table[slot];
+        table[slot] = position + 1;
+        if candidate > 0 && position - (candidate - 1) <= MAX_OFFSET && input[candidate - 1..candidate - 1 + MIN_MATCH] == input[position..position + MIN_MATCH] {
+            let start = candidate - 1;
+            let mut length = MIN_MATCH;
+            while length < MAX_MATCH && position + length < input.len() && input[start + length] == input[position + length] {
+                length += 1;
+            }
+            flush_literals(&mut output, &input[literal_start..position]);
+            output.push(0x80 | (length - MIN_MATCH) as u8);
+            output.extend_from_slice(&((position - start) as u16).to_be_bytes());
+            position += length;
+            literal_start = position;
+        }
+        else {
+            position += 1;
+        }
+    }
+    flush_literals(&mut output, &input[literal_start..]);
+    output
+}
+
+/// Decompresses a block produced by [compress], refusing blocks expanding beyond `max_size` bytes
+///
+/// # Arguments
+///
+/// * `input` - The compressed block
+/// * `max_size` - Maximum size of the decompressed content
+pub fn decompress(input: &[u8], max_size: usize) -> Result<Vec<u8>, Box<dyn Error>> {
+    if input.len() < 4 {
+        Err("Truncated compressed block")?
+    }
+    let size = u32::from_be_bytes([input[0], input[1], input[2], input[3]]) as usize;
+    if size > max_size {
+        Err(format!("Compressed block too large: {} bytes", size))?
+    }
+    let mut output = Vec::with_capacity(size);
+    let mut position = 4;
+    while position < input.len() {
+        let tag = input[position] as usize;
+        position += 1;
+        if tag < 0x80 {
+            let end = position + tag + 1;
+            if end > input.len() {
+                Err("Truncated literals")?
+            }
+            output.extend_from_slice(&input[position..end]);
+            position = end;
+        }
+        else {
+            if position + 2 > input.len() {
+                Err("Truncated match")?
+            }
+            let offset = u16::from_be_bytes([input[position], input[position + 1]]) as usize;
+            position += 2;
+            if offset == 0 || offset > output.len() {
+                Err("Invalid match offset")?
+            }
+            // copied one byte at a time, a match may overlap the bytes it produces
+            let start = output.len() - offset;
+            for index in 0..(tag & 0x7F) + MIN_MATCH {
+                let byte = output[start + index];
+                output.push(byte);
+            }
+        }
+        if output.len() > size {
+            Err("Compressed block longer than announced")?
+        }
+    }
+    if output.len() != size {
+        Err("Compressed block shorter than announced")?
+    }
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compressed_blocks_round_trip() {
+        let repetitive: Vec<u8> = b"key=value;".iter().cycle().take(10_000).copied().collect();
+        let random: Vec<u8> = (0..5_000u32).map(|index| (index.wrapping_mul(2_654_435_761) >> 13) as u8).collect();
+        for input in [Vec::new(), b"abc".to_vec(), b"aaaaaaaaaaaaaaaaaaaaa".to_vec(), repetitive.clone(), random].iter() {
+            let compressed = compress(input);
+            assert_eq!(&decompress(&compressed, input.len()).unwrap(), input);
+        }
+        assert!(compress(&repetitive).len() < repetitive.len() / 10);
+        assert!(decompress(&compress(&repetitive), 100).is_err());
+        assert!(decompress(&[0, 0, 0, 8, 0x80, 0, 1], 100).is_err());
+    }
+}
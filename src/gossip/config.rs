@@ -1,4 +1,12 @@
 #![allow(dead_code)]
+use std::error::Error;
+use super::peer::PeerLabels;
+
+/// Largest period, and deviation added to it, in milliseconds: a day
+pub const MAX_PERIOD: u64 = 24 * 60 * 60 * 1000;
+/// Largest view, peers exchanged at each cycle scaling with it
+pub const MAX_VIEW_SIZE: usize = 10_000;
+
 /// The peer sampling parameters
 ///
 /// See: [Gossip-based Peer Sampling](https://infoscience.epfl.ch/record/109297/files/all.pdf)
@@ -11,6 +19,7 @@ pub struct PeerSamplingConfig {
     view_size: usize,
     healing_factor: usize,
     swapping_factor: usize,
+    topology: TopologyConfig,
 }
 
 impl PeerSamplingConfig {
@@ -33,6 +42,7 @@ impl PeerSamplingConfig {
             view_size,
             healing_factor,
             swapping_factor,
+            topology: TopologyConfig::default(),
         }
     }
 
@@ -49,6 +59,7 @@ impl PeerSamplingConfig {
             view_size,
             healing_factor,
             swapping_factor,
+            topology: TopologyConfig::default(),
         }
     }
 
@@ -79,6 +90,69 @@ impl PeerSamplingConfig {
     pub fn is_push(&self) -> bool {
         self.push
     }
+
+    pub fn set_push(&mut self, push: bool) {
+        self.push = push
+    }
+
+    pub fn set_pull(&mut self, pull: bool) {
+        self.pull = pull
+    }
+
+    pub fn set_sampling_period(&mut self, sampling_period: u64) {
+        self.sampling_period = sampling_period
+    }
+
+    pub fn set_sampling_deviation(&mut self, sampling_deviation: u64) {
+        self.sampling_deviation = sampling_deviation
+    }
+
+    pub fn set_view_size(&mut self, view_size: usize) {
+        self.view_size = view_size
+    }
+
+    pub fn set_healing_factor(&mut self, healing_factor: usize) {
+        self.healing_factor = healing_factor
+    }
+
+    pub fn set_swapping_factor(&mut self, swapping_factor: usize) {
+        self.swapping_factor = swapping_factor
+    }
+
+    /// Checks that the parameters make sense together: the node must push or pull, and the
+    /// healed and swapped peers must fit in the half of the view exchanged at each cycle
+    pub fn validate(&self) -> Result<(), Box<dyn Error>> {
+        if !self.push && !self.pull {
+            Err("Peer sampling must push or pull")?
+        }
+        if self.sampling_period == 0 || self.sampling_period > MAX_PERIOD {
+            Err(format!("Sampling period must be between 1 and {} ms", MAX_PERIOD))?
+        }
+        if self.sampling_deviation > MAX_PERIOD {
+            Err(format!("Sampling deviation must not exceed {} ms", MAX_PERIOD))?
+        }
+        if self.view_size < 2 || self.view_size > MAX_VIEW_SIZE {
+            Err(format!("View size must be between 2 and {}", MAX_VIEW_SIZE))?
+        }
+        if self.healing_factor + self.swapping_factor > self.view_size / 2 {
+            Err(format!("Healing ({}) and swapping ({}) factors must not exceed half the view size ({})", self.healing_factor, self.swapping_factor, self.view_size))?
+        }
+        Ok(())
+    }
+
+    pub fn topology(&self) -> &TopologyConfig {
+        &self.topology
+    }
+
+    /// Makes peer selection and view maintenance aware of where nodes run, see [TopologyConfig]
+    ///
+    /// # Arguments
+    ///
+    /// * `topology` - Location of the node and selection policy
+    pub fn with_topology(mut self, topology: TopologyConfig) -> Self {
+        self.topology = topology;
+        self
+    }
 }
 
 impl Default for PeerSamplingConfig {
@@ -90,18 +164,72 @@ impl Default for PeerSamplingConfig {
             sampling_deviation: 0,
             view_size: 30,
             healing_factor: 3,
-            swapping_factor: 12
+            swapping_factor: 12,
+            topology: TopologyConfig::default(),
         }
     }
 }
 
+/// The location of a node and how it selects peers accordingly. A node with a zone exchanges
+/// with peers of its own zone, except for a minimum share of exchanges with other zones, and
+/// keeps every zone it hears of represented in its view.
+#[derive(Debug, Clone)]
+pub struct TopologyConfig {
+    labels: PeerLabels,
+    remote_ratio: f64,
+}
+
+impl TopologyConfig {
+    /// Creates a topology configuration
+    ///
+    /// # Arguments
+    ///
+    /// * `labels` - Location of the node, peer selection is uniform when it has no zone
+    /// * `remote_ratio` - Minimum share of the exchanges with peers of other zones, between 0 and 1
+    pub fn new(labels: PeerLabels, remote_ratio: f64) -> Self {
+        TopologyConfig {
+            labels,
+            remote_ratio: remote_ratio.clamp(0.0, 1.0),
+        }
+    }
+    pub fn labels(&self) -> &PeerLabels {
+        &self.labels
+    }
+    pub fn remote_ratio(&self) -> f64 {
+        self.remote_ratio
+    }
+    /// Returns whether selection and view maintenance depend on zones
+    pub fn is_zone_aware(&self) -> bool {
+        self.labels.zone().is_some()
+    }
+}
+
+impl Default for TopologyConfig {
+    fn default() -> Self {
+        TopologyConfig {
+            labels: PeerLabels::default(),
+            remote_ratio: 0.1,
+        }
+    }
+}
+
+/// Default maximum size of the update contents sent in one message
+const DEFAULT_BATCH_SIZE: usize = 64 * 1024;
+/// Default maximum size of the update contents sent in one exchange, the largest TCP frame
+const DEFAULT_ROUND_BUDGET: usize = 16 * 1024 * 1024;
+
 /// The gossip parameters
+#[derive(Clone)]
 pub struct GossipConfig {
     push: bool,
     pull: bool,
     gossip_period: u64,
     gossip_deviation: u64,
     update_expiration: UpdateExpirationMode,
+    batch_size: usize,
+    compression: bool,
+    round_budget: usize,
+    topics: Vec<TopicConfig>,
 }
 
 impl GossipConfig {
@@ -120,6 +248,10 @@ impl GossipConfig {
             gossip_period,
             gossip_deviation: 0,
             update_expiration,
+            batch_size: DEFAULT_BATCH_SIZE,
+            compression: true,
+            round_budget: DEFAULT_ROUND_BUDGET,
+            topics: Vec::new(),
         }
     }
 
@@ -134,6 +266,29 @@ impl GossipConfig {
             gossip_period,
             gossip_deviation,
             update_expiration,
+            batch_size: DEFAULT_BATCH_SIZE,
+            compression: true,
+            round_budget: DEFAULT_ROUND_BUDGET,
+            topics: Vec::new(),
+        }
+    }
+
+    /// Creates a new configuration setting how updates are batched with peers supporting digest summaries
+    /// # Arguments
+    ///
+    /// * `batch_size` - Maximum size of the update contents sent in one message, a larger update is sent alone
+    /// * `compression` - If batches are compressed for peers supporting compression
+    pub fn new_with_batching(push: bool, pull: bool, gossip_period: u64, gossip_deviation: u64, update_expiration: UpdateExpirationMode, batch_size: usize, compression: bool) -> Self {
+        GossipConfig {
+            push,
+            pull,
+            gossip_period,
+            gossip_deviation,
+            update_expiration,
+            batch_size,
+            compression,
+            round_budget: DEFAULT_ROUND_BUDGET,
+            topics: Vec::new(),
         }
     }
     pub fn is_push(&self) -> bool {
@@ -151,6 +306,103 @@ impl GossipConfig {
     pub fn update_expiration(&self) -> &UpdateExpirationMode {
         &self.update_expiration
     }
+    pub fn batch_size(&self) -> usize {
+        self.batch_size
+    }
+    pub fn is_compression(&self) -> bool {
+        self.compression
+    }
+    pub fn round_budget(&self) -> usize {
+        self.round_budget
+    }
+    pub fn set_push(&mut self, push: bool) {
+        self.push = push
+    }
+    pub fn set_pull(&mut self, pull: bool) {
+        self.pull = pull
+    }
+    pub fn set_gossip_period(&mut self, gossip_period: u64) {
+        self.gossip_period = gossip_period
+    }
+    pub fn set_gossip_deviation(&mut self, gossip_deviation: u64) {
+        self.gossip_deviation = gossip_deviation
+    }
+
+    /// Checks that the parameters make sense together
+    pub fn validate(&self) -> Result<(), Box<dyn Error>> {
+        if !self.push && !self.pull {
+            Err("Gossip must push or pull")?
+        }
+        if self.gossip_period == 0 || self.gossip_period > MAX_PERIOD {
+            Err(format!("Gossip period must be between 1 and {} ms", MAX_PERIOD))?
+        }
+        if self.gossip_deviation > MAX_PERIOD {
+            Err(format!("Gossip deviation must not exceed {} ms", MAX_PERIOD))?
+        }
+        if self.batch_size == 0 || self.round_budget == 0 {
+            Err("Batch size and round budget must be positive")?
+        }
+        Ok(())
+    }
+    pub fn topics(&self) -> &[TopicConfig] {
+        &self.topics
+    }
+
+    /// Limits the size of the update contents sent to a peer in one exchange, the updates of the
+    /// topics with the highest priority being sent first. At least one update is always sent.
+    ///
+    /// # Arguments
+    ///
+    /// * `round_budget` - Maximum size of the update contents sent in one exchange
+    pub fn with_round_budget(mut self, round_budget: usize) -> Self {
+        self.round_budget = round_budget;
+        self
+    }
+
+    /// Configures a topic, updates of topics not configured have the default priority and expiration
+    ///
+    /// # Arguments
+    ///
+    /// * `topic` - Name, priority and expiration of the topic, see [TopicConfig]
+    pub fn with_topic(mut self, topic: TopicConfig) -> Self {
+        self.topics.retain(|configured| configured.name() != topic.name());
+        self.topics.push(topic);
+        self
+    }
+}
+
+/// The parameters of a topic, a named stream of updates
+#[derive(Debug, Clone)]
+pub struct TopicConfig {
+    name: String,
+    priority: u8,
+    update_expiration: UpdateExpirationMode,
+}
+
+impl TopicConfig {
+    /// Creates a topic configuration
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Name of the topic
+    /// * `priority` - Updates of topics with a higher priority are sent first, the default topic has priority 0
+    /// * `update_expiration` - Strategy for expiring the updates of the topic, see [UpdateExpirationMode]
+    pub fn new(name: &str, priority: u8, update_expiration: UpdateExpirationMode) -> Self {
+        TopicConfig {
+            name: name.to_owned(),
+            priority,
+            update_expiration,
+        }
+    }
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    pub fn priority(&self) -> u8 {
+        self.priority
+    }
+    pub fn update_expiration(&self) -> &UpdateExpirationMode {
+        &self.update_expiration
+    }
 }
 
 impl Default for GossipConfig {
@@ -160,7 +412,150 @@ impl Default for GossipConfig {
             pull: true,
             gossip_period: 1000,
             gossip_deviation: 0,
-            update_expiration: UpdateExpirationMode::None
+            update_expiration: UpdateExpirationMode::None,
+            batch_size: DEFAULT_BATCH_SIZE,
+            compression: true,
+            round_budget: DEFAULT_ROUND_BUDGET,
+            topics: Vec::new(),
+        }
+    }
+}
+
+/// The SWIM failure detection parameters, durations are in milliseconds
+///
+/// See: [SWIM: Scalable Weakly-consistent Infection-style Process Group Membership Protocol](https://www.cs.cornell.edu/projects/Quicksilver/public_pdfs/SWIM.pdf)
+#[derive(Debug, Clone)]
+pub struct SwimConfig {
+    protocol_period: u64,
+    ping_timeout: u64,
+    indirect_checks: usize,
+    suspicion_timeout: u64,
+    retransmit_multiplier: usize,
+    max_piggyback: usize,
+}
+
+impl SwimConfig {
+    /// Creates a new failure detection configuration
+    ///
+    /// # Arguments
+    ///
+    /// * `protocol_period` - Interval between two probes, a probe not acknowledged by then makes its target suspected
+    /// * `ping_timeout` - Time waited for a direct ack before asking other members to ping the target
+    /// * `indirect_checks` - Number of members asked to ping an unresponsive target
+    /// * `suspicion_timeout` - Time a suspected member has to refute the suspicion before being declared failed
+    /// * `retransmit_multiplier` - A membership update is piggybacked `retransmit_multiplier * log10(members + 1)` times
+    /// * `max_piggyback` - Maximum number of membership updates piggybacked on a message
+    pub fn new(protocol_period: u64, ping_timeout: u64, indirect_checks: usize, suspicion_timeout: u64, retransmit_multiplier: usize, max_piggyback: usize) -> Self {
+        SwimConfig {
+            protocol_period,
+            ping_timeout,
+            indirect_checks,
+            suspicion_timeout,
+            retransmit_multiplier,
+            max_piggyback,
+        }
+    }
+    pub fn protocol_period(&self) -> u64 {
+        self.protocol_period
+    }
+    pub fn ping_timeout(&self) -> u64 {
+        self.ping_timeout
+    }
+    pub fn indirect_checks(&self) -> usize {
+        self.indirect_checks
+    }
+    pub fn suspicion_timeout(&self) -> u64 {
+        self.suspicion_timeout
+    }
+    pub fn retransmit_multiplier(&self) -> usize {
+        self.retransmit_multiplier
+    }
+    pub fn max_piggyback(&self) -> usize {
+        self.max_piggyback
+    }
+}
+
+impl Default for SwimConfig {
+    fn default() -> Self {
+        SwimConfig {
+            protocol_period: 1000,
+            ping_timeout: 300,
+            indirect_checks: 3,
+            suspicion_timeout: 5000,
+            retransmit_multiplier: 4,
+            max_piggyback: 8,
+        }
+    }
+}
+
+/// The TCP transport parameters, durations are in milliseconds
+#[derive(Debug, Clone)]
+pub struct TcpConfig {
+    connect_timeout: u64,
+    read_timeout: u64,
+    write_timeout: u64,
+    idle_timeout: u64,
+    max_frame_size: usize,
+    workers: usize,
+    queue_size: usize,
+}
+
+impl TcpConfig {
+    /// Creates a new TCP transport configuration
+    ///
+    /// # Arguments
+    ///
+    /// * `connect_timeout` - Time waited for a connection to a peer to be established
+    /// * `read_timeout` - Time waited for the rest of a frame once its first byte arrived, also how often idle connections check for shutdown
+    /// * `write_timeout` - Time waited for a frame to be written
+    /// * `idle_timeout` - Inbound connections without any frame for that long are closed
+    /// * `max_frame_size` - Maximum size of a frame payload (bytes), larger frames are refused and close the connection
+    /// * `workers` - Number of threads handling inbound frames
+    /// * `queue_size` - Number of inbound frames waiting for a worker before connections stop being read
+    pub fn new(connect_timeout: u64, read_timeout: u64, write_timeout: u64, idle_timeout: u64, max_frame_size: usize, workers: usize, queue_size: usize) -> Self {
+        TcpConfig {
+            connect_timeout,
+            read_timeout,
+            write_timeout,
+            idle_timeout,
+            max_frame_size,
+            workers,
+            queue_size,
+        }
+    }
+    pub fn connect_timeout(&self) -> u64 {
+        self.connect_timeout
+    }
+    pub fn read_timeout(&self) -> u64 {
+        self.read_timeout
+    }
+    pub fn write_timeout(&self) -> u64 {
+        self.write_timeout
+    }
+    pub fn idle_timeout(&self) -> u64 {
+        self.idle_timeout
+    }
+    pub fn max_frame_size(&self) -> usize {
+        self.max_frame_size
+    }
+    pub fn workers(&self) -> usize {
+        self.workers
+    }
+    pub fn queue_size(&self) -> usize {
+        self.queue_size
+    }
+}
+
+impl Default for TcpConfig {
+    fn default() -> Self {
+        TcpConfig {
+            connect_timeout: 1000,
+            read_timeout: 1000,
+            write_timeout: 1000,
+            idle_timeout: 30000,
+            max_frame_size: 16 * 1024 * 1024,
+            workers: 4,
+            queue_size: 1024,
         }
     }
 }
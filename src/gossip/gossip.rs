@@ -6,18 +6,27 @@ use std::thread::JoinHandle;
 use std::sync::atomic::AtomicBool;
 use std::sync::{Arc, Mutex, RwLock};
 use std::net::SocketAddr;
-use std::sync::mpsc::{Sender, Receiver};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
 use std::collections::HashMap;
 use std::error::Error;
-use rand::Rng;
+use std::time::{Duration, Instant};
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
 use super::config::GossipConfig;
 use super::config::PeerSamplingConfig;
+use super::config::SwimConfig;
+use super::membership::{Member, Membership, MembershipEvent, MembershipListener, SwimMessage};
 use super::sampling::PeerSamplingService;
-use super::update::{Update, UpdateHandler, UpdateDecorator};
-use super::message::{HeaderMessage, ContentMessage};
+use super::update::{Update, UpdateHandler, UpdateDecorator, DEFAULT_TOPIC};
+use super::message::{HeaderMessage, ContentMessage, SummaryMessage, BatchMessage};
 use super::message::{NoopMessage, MessageType};
 use super::peer::Peer;
 use super::sampling::PeerSamplingMessage;
+use super::version::{VersionedTransport, CAPABILITY_COMPRESSION, CAPABILITY_DELTA_SYNC};
+use super::transport::{self, Dispatcher, TcpTransport, Transport};
+
+/// Application callbacks receiving the updates of a topic, by topic
+type TopicHandlers = Mutex<HashMap<String, Box<dyn UpdateHandler + Send>>>;
 
 /// The gossip service
 pub struct GossipService<T> {
@@ -26,7 +35,7 @@ pub struct GossipService<T> {
     /// Peer sampling service
     peer_sampling_service: Arc<Mutex<PeerSamplingService>>,
     /// Configuration for gossip
-    gossip_config: Arc<GossipConfig>,
+    gossip_config: Arc<RwLock<GossipConfig>>,
     /// Shutdown requested flag
     shutdown: Arc<AtomicBool>,
     /// Thread handles
@@ -35,6 +44,52 @@ pub struct GossipService<T> {
     updates: Arc<RwLock<UpdateDecorator>>,
     /// Application callback for receiving new updates
     update_handler: Arc<Mutex<Option<Box<T>>>>,
+    /// Application callbacks for receiving the new updates of a topic
+    topic_handlers: Arc<TopicHandlers>,
+    /// Failure detector and membership list
+    membership: Arc<Mutex<Membership>>,
+    /// Application callback for receiving membership changes
+    membership_listener: Arc<Mutex<Option<Box<dyn MembershipListener + Send>>>>,
+    /// Transport used to exchange messages with other nodes
+    transport: Arc<dyn Transport>,
+    /// Source of the random deviation of the gossip period
+    rng: Arc<Mutex<StdRng>>,
+    /// Received messages and schedule, when the service is polled instead of running its own threads
+    manual: Mutex<Option<ManualState>>,
+}
+
+/// Messages waiting to be handled and next activities, for a polled service
+struct ManualState {
+    header_receiver: Receiver<HeaderMessage>,
+    content_receiver: Receiver<ContentMessage>,
+    summary_receiver: Receiver<SummaryMessage>,
+    batch_receiver: Receiver<BatchMessage>,
+    membership_receiver: Receiver<SwimMessage>,
+    next_gossip: Option<Instant>,
+    join: JoinSchedule,
+}
+
+/// Paces how often peers found by peer sampling are asked to join the membership list
+struct JoinSchedule {
+    period: Duration,
+    last: Option<Instant>,
+}
+
+impl JoinSchedule {
+    fn new(period: Duration) -> Self {
+        JoinSchedule { period, last: None }
+    }
+
+    /// Returns whether peers should be asked to join at `now`, and if so records it
+    fn is_due(&mut self, now: Instant) -> bool {
+        match self.last {
+            Some(last) if now.duration_since(last) < self.period => false,
+            _ => {
+                self.last = Some(now);
+                true
+            }
+        }
+    }
 }
 
 impl<T> GossipService<T>
@@ -48,14 +103,49 @@ where T: UpdateHandler + 'static + Send
     /// * `peer_sampling_config` - Configuration for peer sampling, see [PeerSamplingConfig]
     /// * `gossip_config` - Configuration for gossiping, see [GossipConfig]
     pub fn new(address: SocketAddr, peer_sampling_config: PeerSamplingConfig, gossip_config: GossipConfig) -> GossipService<T> {
+        Self::new_with_membership(address, peer_sampling_config, gossip_config, SwimConfig::default())
+    }
+
+    /// Creates a new gossiping service with the given failure detection parameters
+    ///
+    /// # Arguments
+    ///
+    /// * `address` - Socket address of the node
+    /// * `peer_sampling_config` - Configuration for peer sampling, see [PeerSamplingConfig]
+    /// * `gossip_config` - Configuration for gossiping, see [GossipConfig]
+    /// * `swim_config` - Configuration for failure detection, see [SwimConfig]
+    pub fn new_with_membership(address: SocketAddr, peer_sampling_config: PeerSamplingConfig, gossip_config: GossipConfig, swim_config: SwimConfig) -> GossipService<T> {
+        Self::new_with_transport(address, peer_sampling_config, gossip_config, swim_config, Arc::new(VersionedTransport::new(address, Arc::new(TcpTransport::default()))), rand::random())
+    }
+
+    /// Creates a new gossiping service exchanging messages over `transport`
+    ///
+    /// # Arguments
+    ///
+    /// * `address` - Socket address of the node
+    /// * `peer_sampling_config` - Configuration for peer sampling, see [PeerSamplingConfig]
+    /// * `gossip_config` - Configuration for gossiping, see [GossipConfig]
+    /// * `swim_config` - Configuration for failure detection, see [SwimConfig]
+    /// * `transport` - The transport used to send messages, see [Transport]
+    /// * `seed` - Seed of the random choices of the protocols
+    pub fn new_with_transport(address: SocketAddr, peer_sampling_config: PeerSamplingConfig, gossip_config: GossipConfig, swim_config: SwimConfig, transport: Arc<dyn Transport>, seed: u64) -> GossipService<T> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let peer_sampling_service = PeerSamplingService::new_with_transport(address, peer_sampling_config, Arc::clone(&transport), rng.gen());
+        let membership = Membership::new_with_seed(address.to_string(), swim_config, rng.gen());
         GossipService{
             address,
-            peer_sampling_service: Arc::new(Mutex::new(PeerSamplingService::new(address, peer_sampling_config))),
-            updates: Arc::new(RwLock::new(UpdateDecorator::new(gossip_config.update_expiration().clone()))),
-            gossip_config: Arc::new(gossip_config),
+            peer_sampling_service: Arc::new(Mutex::new(peer_sampling_service)),
+            updates: Arc::new(RwLock::new(UpdateDecorator::new_with_topics(gossip_config.update_expiration().clone(), gossip_config.topics()))),
+            gossip_config: Arc::new(RwLock::new(gossip_config)),
             shutdown: Arc::new(AtomicBool::new(false)),
             activities: Vec::new(),
             update_handler: Arc::new(Mutex::new(None)),
+            topic_handlers: Arc::new(Mutex::new(HashMap::new())),
+            membership: Arc::new(Mutex::new(membership)),
+            membership_listener: Arc::new(Mutex::new(None)),
+            transport,
+            rng: Arc::new(Mutex::new(rng)),
+            manual: Mutex::new(None),
         }
     }
 
@@ -78,6 +168,72 @@ where T: UpdateHandler + 'static + Send
         self.peer_sampling_service.lock().unwrap().peers()
     }
 
+    /// Returns the members of the cluster believed alive or suspected
+    pub fn members(&self) -> Vec<Member> {
+        self.membership.lock().unwrap().members()
+    }
+
+    /// Sets the application callback receiving nodes joining, leaving or failing
+    ///
+    /// # Arguments
+    ///
+    /// * `listener` - Application callback for membership changes
+    pub fn set_membership_listener(&self, listener: Box<dyn MembershipListener + Send>) {
+        self.membership_listener.lock().unwrap().replace(listener);
+    }
+
+    /// Returns a copy of the gossip configuration
+    pub fn gossip_config(&self) -> GossipConfig {
+        self.gossip_config.read().unwrap().clone()
+    }
+
+    /// Replaces the gossip configuration, the activities pick it up on their next cycle. New
+    /// expiration strategies only apply to the updates received from then on.
+    ///
+    /// # Arguments
+    ///
+    /// * `gossip_config` - The new configuration, rejected if it does not validate
+    pub fn set_gossip_config(&self, gossip_config: GossipConfig) -> Result<(), Box<dyn Error>> {
+        gossip_config.validate()?;
+        self.updates.write().unwrap().set_topics(gossip_config.update_expiration().clone(), gossip_config.topics());
+        *self.gossip_config.write().unwrap() = gossip_config;
+        Ok(())
+    }
+
+    /// Returns a copy of the peer sampling configuration
+    pub fn peer_sampling_config(&self) -> PeerSamplingConfig {
+        self.peer_sampling_service.lock().unwrap().config()
+    }
+
+    /// Replaces the peer sampling configuration, the activities pick it up on their next cycle
+    ///
+    /// # Arguments
+    ///
+    /// * `peer_sampling_config` - The new configuration, rejected if it does not validate
+    pub fn set_peer_sampling_config(&self, peer_sampling_config: PeerSamplingConfig) -> Result<(), Box<dyn Error>> {
+        self.peer_sampling_service.lock().unwrap().set_config(peer_sampling_config)
+    }
+
+    /// Sets the application callback receiving the updates of a topic, instead of the update
+    /// handler given when starting the service
+    ///
+    /// # Arguments
+    ///
+    /// * `topic` - Name of the topic
+    /// * `handler` - Application callback for the updates of the topic
+    pub fn subscribe(&self, topic: &str, handler: Box<dyn UpdateHandler + Send>) {
+        self.topic_handlers.lock().unwrap().insert(topic.to_owned(), handler);
+    }
+
+    /// Removes the application callback of a topic, its updates then go to the update handler
+    ///
+    /// # Arguments
+    ///
+    /// * `topic` - Name of the topic
+    pub fn unsubscribe(&self, topic: &str) {
+        self.topic_handlers.lock().unwrap().remove(topic);
+    }
+
     /// Starts the gossip protocol and related threads
     ///
     /// # Arguments
@@ -98,140 +254,449 @@ where T: UpdateHandler + 'static + Send
         let (tx_header, rx_header) = std::sync::mpsc::channel::<HeaderMessage>();
         // message receiver for content messages
         let (tx_content, rx_content) = std::sync::mpsc::channel::<ContentMessage>();
+        // message receivers for digest summaries and batched updates
+        let (tx_summary, rx_summary) = std::sync::mpsc::channel::<SummaryMessage>();
+        let (tx_batch, rx_batch) = std::sync::mpsc::channel::<BatchMessage>();
+        // message receiver for failure detection messages
+        let (tx_membership, rx_membership) = std::sync::mpsc::channel::<SwimMessage>();
 
         // start message header handler
         self.start_message_header_handler(rx_header).expect("Error starting message header handler");
         // start message content handler
         self.start_message_content_handler(rx_content).expect("Error starting message content handler");
+        // start digest summary and batch handlers
+        self.start_message_summary_handler(rx_summary).expect("Error starting message summary handler");
+        self.start_message_batch_handler(rx_batch).expect("Error starting message batch handler");
+        // start failure detection
+        self.start_membership_activity(rx_membership).expect("Error starting failure detection");
         // start TCP listener
-        self.start_network_listener(tx_sampling, tx_header, tx_content).expect(&format!("Error setting up listener at {:?}", self.address));
+        self.start_network_listener(Dispatcher::new(tx_sampling, tx_header, tx_content, tx_membership).with_digest_sync(tx_summary, tx_batch)).unwrap_or_else(|_| panic!("Error setting up listener at {:?}", self.address));
         // start gossiping
         self.start_gossip_activity().expect("Error starting gossip activity");
         Ok(())
     }
 
+    /// Starts the gossip protocol without starting its threads, [GossipService::poll] then
+    /// runs the protocols. Meant for transports delivering messages on demand, such as
+    /// [super::simulation::SimNetwork].
+    ///
+    /// # Arguments
+    ///
+    /// * `peer_sampling_init` - Closure for retrieving the address of the first peer to contact
+    /// * `update_handler` - Application callback for receiving new updates
+    pub fn start_manual(&mut self, peer_sampling_init: Box<dyn FnOnce() -> Option<Vec<Peer>>>, update_handler: Box<T>) -> Result<(), Box<dyn Error>> {
+
+        self.update_handler.lock().unwrap().replace(update_handler);
+
+        let (tx_sampling, rx_sampling) = std::sync::mpsc::channel::<PeerSamplingMessage>();
+        self.peer_sampling_service.lock().unwrap().init_manual(peer_sampling_init, rx_sampling);
+        let (tx_header, rx_header) = std::sync::mpsc::channel::<HeaderMessage>();
+        let (tx_content, rx_content) = std::sync::mpsc::channel::<ContentMessage>();
+        let (tx_summary, rx_summary) = std::sync::mpsc::channel::<SummaryMessage>();
+        let (tx_batch, rx_batch) = std::sync::mpsc::channel::<BatchMessage>();
+        let (tx_membership, rx_membership) = std::sync::mpsc::channel::<SwimMessage>();
+        self.start_network_listener(Dispatcher::new(tx_sampling, tx_header, tx_content, tx_membership).with_digest_sync(tx_summary, tx_batch))?;
+
+        let join_period = Duration::from_millis(self.membership.lock().unwrap().config().protocol_period());
+        self.manual.lock().unwrap().replace(ManualState {
+            header_receiver: rx_header,
+            content_receiver: rx_content,
+            summary_receiver: rx_summary,
+            batch_receiver: rx_batch,
+            membership_receiver: rx_membership,
+            next_gossip: None,
+            join: JoinSchedule::new(join_period),
+        });
+        Ok(())
+    }
+
+    /// Handles the received messages and runs the gossip, peer sampling and failure detection
+    /// activities due at `now`. Returns the number of messages handled.
+    pub fn poll(&self, now: Instant) -> Result<usize, Box<dyn Error>> {
+        let mut manual = self.manual.lock().unwrap();
+        let state = match manual.as_mut() {
+            Some(state) => state,
+            None => Err("Gossip service was not started with start_manual")?,
+        };
+        let address = self.address.to_string();
+        // changes to the configuration apply from the next poll
+        let gossip_config = self.gossip_config.read().unwrap().clone();
+        let mut handled = 0;
+
+        while let Ok(message) = state.header_receiver.try_recv() {
+            Self::handle_header(&address, &gossip_config, &self.updates, self.transport.as_ref(), message);
+            handled += 1;
+        }
+        while let Ok(message) = state.content_receiver.try_recv() {
+            Self::handle_content(&address, &gossip_config, &self.updates, &self.update_handler, &self.topic_handlers, self.transport.as_ref(), message);
+            handled += 1;
+        }
+        while let Ok(message) = state.summary_receiver.try_recv() {
+            Self::handle_summary(&address, &gossip_config, &self.updates, &self.rng, self.transport.as_ref(), message);
+            handled += 1;
+        }
+        while let Ok(message) = state.batch_receiver.try_recv() {
+            Self::handle_batch(&self.updates, &self.update_handler, &self.topic_handlers, message);
+            handled += 1;
+        }
+        while let Ok(message) = state.membership_receiver.try_recv() {
+            self.membership_step(Some(message), &mut state.join, now);
+            handled += 1;
+        }
+        self.membership_step(None, &mut state.join, now);
+
+        handled += self.peer_sampling_service.lock().unwrap().poll(now);
+
+        match state.next_gossip {
+            Some(next) if now < next => (),
+            Some(_) => {
+                Self::gossip_round(&address, &gossip_config, &self.peer_sampling_service, &self.updates, &self.rng, self.transport.as_ref());
+                state.next_gossip = now.checked_add(Self::gossip_delay(&gossip_config, &self.rng));
+            }
+            None => state.next_gossip = now.checked_add(Self::gossip_delay(&gossip_config, &self.rng)),
+        }
+        Ok(handled)
+    }
+
     fn start_message_header_handler(&mut self, receiver: Receiver<HeaderMessage>) -> Result<(), Box<dyn Error>> {
         let gossip_config_arc = Arc::clone(&self.gossip_config);
         let address = self.address.to_string();
         let updates_arc = Arc::clone(&self.updates);
+        let transport = Arc::clone(&self.transport);
         let handle = std::thread::Builder::new().name(format!("{} - header receiver", address)).spawn(move|| {
             log::info!("Started message header handling thread");
             while let Ok(message) = receiver.recv() {
+                Self::handle_header(&address, &gossip_config_arc.read().unwrap(), &updates_arc, transport.as_ref(), message);
+            }
+            log::info!("Message header handling thread exiting");
+        }).unwrap();
+        self.activities.push(handle);
+        Ok(())
+    }
 
-                if let Ok(sender_address) = message.sender().parse::<SocketAddr>() {
+    fn handle_header(address: &str, gossip_config: &GossipConfig, updates_lock: &RwLock<UpdateDecorator>, transport: &dyn Transport, message: HeaderMessage) {
+        if let Ok(sender_address) = message.sender().parse::<SocketAddr>() {
 
-                    let updates = updates_arc.read().unwrap();
+            let updates = updates_lock.read().unwrap();
 
-                    // Response with message headers if pull is enabled
-                    if gossip_config_arc.is_pull() && updates.active_count() > 0 && *message.message_type() == MessageType::Request {
-                        let mut response = HeaderMessage::new_response(address.clone());
-                        response.set_headers(updates.active_headers());
-                        match super::network::send(&sender_address, Box::new(response)) {
-                            Ok(written) => log::trace!("Sent header response - {} bytes to {:?}", written, sender_address),
-                            Err(e) => log::error!("Error sending header response: {:?}", e)
-                        }
+            // Response with message headers if pull is enabled
+            if gossip_config.is_pull() && updates.active_count() > 0 && *message.message_type() == MessageType::Request {
+                let mut response = HeaderMessage::new_response(address.to_owned());
+                response.set_headers(updates.active_headers());
+                match transport::send(transport, &sender_address, &response) {
+                    Ok(written) => log::trace!("Sent header response - {} bytes to {:?}", written, sender_address),
+                    Err(e) => log::error!("Error sending header response: {:?}", e)
+                }
+            }
+
+            // Process message if (request and push enabled) or (response and pull enabled)
+            if *message.message_type() == MessageType::Request && gossip_config.is_push() || *message.message_type() == MessageType::Response && gossip_config.is_pull() {
+
+                let mut new_digests = HashMap::new();
+                message.headers().iter().for_each(|digest| {
+                    if updates.is_new(digest) {
+                        log::debug!("New digest: {}", digest);
+                        new_digests.insert(digest.to_owned(), vec![]);
+                    }
+                    else {
+                        log::trace!("Duplicate digest: {}", digest);
                     }
+                });
+                if !new_digests.is_empty() {
+                    let content_request = ContentMessage::new_request(address.to_owned(), new_digests);
+                    match transport::send(transport, &sender_address, &content_request) {
+                        Ok(written) => log::trace!("Sent content request - {} bytes to {:?}", written, sender_address),
+                        Err(e) => log::error!("Error content request response: {:?}", e)
+                    }
+                }
+            }
+        }
+        else {
+            log::error!("Could not parse sender address {}", message.sender());
+        }
+    }
 
-                    // Process message if (request and push enabled) or (response and pull enabled)
-                    if *message.message_type() == MessageType::Request && gossip_config_arc.is_push() || *message.message_type() == MessageType::Response && gossip_config_arc.is_pull() {
+    fn start_message_content_handler(&mut self, receiver: Receiver<ContentMessage>) -> Result<(), Box<dyn Error>> {
+        let gossip_config_arc = Arc::clone(&self.gossip_config);
+        let address = self.address.to_string();
+        let updates_arc = Arc::clone(&self.updates);
+        let update_callback_arc = Arc::clone(&self.update_handler);
+        let topic_handlers_arc = Arc::clone(&self.topic_handlers);
+        let transport = Arc::clone(&self.transport);
+        let handle = std::thread::Builder::new().name(format!("{} - content receiver", address)).spawn(move|| {
+            log::info!("Started message content handling thread");
+            while let Ok(message) = receiver.recv() {
+                Self::handle_content(&address, &gossip_config_arc.read().unwrap(), &updates_arc, &update_callback_arc, &topic_handlers_arc, transport.as_ref(), message);
+            }
+        }).unwrap();
+        self.activities.push(handle);
+        Ok(())
+    }
 
-                        let mut new_digests = HashMap::new();
-                        message.headers().iter().for_each(|digest| {
-                            if updates.is_new(digest) {
-                                log::debug!("New digest: {}", digest);
-                                new_digests.insert(digest.to_owned(), vec![]);
+    fn handle_content(address: &str, gossip_config: &GossipConfig, updates_lock: &RwLock<UpdateDecorator>, update_callback: &Mutex<Option<Box<T>>>, topic_handlers: &TopicHandlers, transport: &dyn Transport, message: ContentMessage) {
+        match message.message_type() {
+            MessageType::Request => {
+                if let Ok(peer_address) = message.sender().parse::<SocketAddr>() {
+                    let updates = updates_lock.read().unwrap();
+                    let found: Vec<&Update> = message.content().keys()
+                        .filter_map(|digest| updates.get_update(digest))
+                        .collect();
+                    if !found.is_empty() {
+                        let mut requested_updates = HashMap::new();
+                        let mut topics = HashMap::new();
+                        // the updates of the topics with the highest priority first, within the budget
+                        for update in updates.prioritize(found, gossip_config.round_budget()) {
+                            requested_updates.insert(update.digest().to_owned(), update.content().to_vec());
+                            if update.topic() != DEFAULT_TOPIC {
+                                topics.insert(update.digest().to_owned(), update.topic().to_owned());
                             }
-                            else {
-                                log::trace!("Duplicate digest: {}", digest);
+                        }
+                        let mut response = ContentMessage::new_response(address.to_owned(), requested_updates);
+                        response.set_topics(topics);
+                        match transport::send(transport, &peer_address, &response) {
+                            Ok(written) => log::trace!("Sent content response - {} bytes to {:?}", written, peer_address),
+                            Err(e) => log::error!("Error content response: {:?}", e)
+                        }
+                    }
+                }
+            }
+            MessageType::Response => {
+                if message.len() > 0 {
+                    let mut updates = updates_lock.write().unwrap();
+                    let topics = message.topics().clone();
+                    for (digest, content) in message.content() {
+                        if updates.is_new(&digest) {
+                            let topic = topics.get(&digest).map_or(DEFAULT_TOPIC, |topic| topic.as_str());
+                            let update = Update::new_with_topic(topic, content.clone());
+                            if digest == *update.digest() {
+                                Self::receive_update(&mut updates, update_callback, topic_handlers, update, content);
                             }
-                        });
-                        if new_digests.len() > 0 {
-                            let content_request = ContentMessage::new_request(address.clone(), new_digests);
-                            match super::network::send(&sender_address, Box::new(content_request)) {
-                                Ok(written) => log::trace!("Sent content request - {} bytes to {:?}", written, sender_address),
-                                Err(e) => log::error!("Error content request response: {:?}", e)
+                            else {
+                                log::warn!("Digests did not match: {} <> {}", digest, update.digest());
                             }
                         }
                     }
+                    updates.clear_expired();
+                }
+            }
+        }
+    }
+
+    /// Stores an update received from a peer and hands it to the handler of its topic, or to
+    /// the update handler if the topic has none
+    fn receive_update(updates: &mut UpdateDecorator, update_callback: &Mutex<Option<Box<T>>>, topic_handlers: &TopicHandlers, update: Update, content: Vec<u8>) {
+        log::info!("New update received: {}", update.digest());
+        let topic = update.topic().to_owned();
+        match updates.insert_update(update) {
+            Ok(()) => {
+                // insert OK, notify update handler
+                let update = Update::new_with_topic(&topic, content);
+                if let Some(handler) = topic_handlers.lock().unwrap().get(&topic) {
+                    handler.on_update(update);
+                    return;
+                }
+                let mutex = update_callback.lock().unwrap();
+                if let Some(callback) = mutex.as_ref() {
+                    callback.on_update(update);
                 }
                 else {
-                    log::error!("Could not parse sender address {}", message.sender());
+                    log::warn!("No update handler found");
                 }
+            },
+            Err(e) => log::error!("Could not add update: {:?}", e),
+        }
+    }
+
+    fn start_message_summary_handler(&mut self, receiver: Receiver<SummaryMessage>) -> Result<(), Box<dyn Error>> {
+        let gossip_config_arc = Arc::clone(&self.gossip_config);
+        let address = self.address.to_string();
+        let updates_arc = Arc::clone(&self.updates);
+        let rng_arc = Arc::clone(&self.rng);
+        let transport = Arc::clone(&self.transport);
+        let handle = std::thread::Builder::new().name(format!("{} - summary receiver", address)).spawn(move|| {
+            log::info!("Started message summary handling thread");
+            while let Ok(message) = receiver.recv() {
+                Self::handle_summary(&address, &gossip_config_arc.read().unwrap(), &updates_arc, &rng_arc, transport.as_ref(), message);
             }
-            log::info!("Message header handling thread exiting");
+            log::info!("Message summary handling thread exiting");
         }).unwrap();
         self.activities.push(handle);
         Ok(())
     }
 
-    fn start_message_content_handler(&mut self, receiver: Receiver<ContentMessage>) -> Result<(), Box<dyn Error>> {
+    fn handle_summary(address: &str, gossip_config: &GossipConfig, updates_lock: &RwLock<UpdateDecorator>, rng: &Mutex<StdRng>, transport: &dyn Transport, message: SummaryMessage) {
+        if let Ok(sender_address) = message.sender().parse::<SocketAddr>() {
+            let updates = updates_lock.read().unwrap();
+            match message.message_type() {
+                MessageType::Request => {
+                    // send the updates the requesting node misses if pull is enabled
+                    if gossip_config.is_pull() {
+                        Self::send_batches(address, gossip_config, transport, &sender_address, &updates.prioritize(updates.missing_from(message.filter()), gossip_config.round_budget()));
+                    }
+                    // ask for the updates it has and this node misses if push is enabled
+                    if gossip_config.is_push() {
+                        let seed = rng.lock().unwrap().gen();
+                        let response = SummaryMessage::new_response(address.to_owned(), updates.summary(seed));
+                        match transport::send(transport, &sender_address, &response) {
+                            Ok(written) => log::trace!("Sent summary response - {} bytes to {:?}", written, sender_address),
+                            Err(e) => log::error!("Error sending summary response: {:?}", e)
+                        }
+                    }
+                }
+                MessageType::Response => {
+                    if gossip_config.is_push() {
+                        Self::send_batches(address, gossip_config, transport, &sender_address, &updates.prioritize(updates.missing_from(message.filter()), gossip_config.round_budget()));
+                    }
+                }
+            }
+        }
+        else {
+            log::error!("Could not parse sender address {}", message.sender());
+        }
+    }
+
+    /// Sends the contents of `updates` to a peer, in batches no larger than the configured size
+    fn send_batches(address: &str, gossip_config: &GossipConfig, transport: &dyn Transport, peer_address: &SocketAddr, updates: &[&Update]) {
+        if updates.is_empty() {
+            return;
+        }
+        let compress = gossip_config.is_compression() && transport.peer_protocol(peer_address).is_some_and(|protocol| protocol.supports(CAPABILITY_COMPRESSION));
+        let mut batch: Vec<(&str, &[u8])> = Vec::new();
+        let mut batch_size = 0;
+        for (index, update) in updates.iter().enumerate() {
+            batch.push((update.topic(), update.content()));
+            batch_size += update.content().len();
+            let next_size = updates.get(index + 1).map_or(0, |next| next.content().len());
+            if index + 1 == updates.len() || batch_size + next_size > gossip_config.batch_size() {
+                let message = BatchMessage::new(address.to_owned(), &batch, compress);
+                match transport::send(transport, peer_address, &message) {
+                    Ok(written) => log::trace!("Sent batch of {} updates - {} bytes to {:?}", batch.len(), written, peer_address),
+                    Err(e) => log::error!("Error sending batch: {:?}", e)
+                }
+                batch.clear();
+                batch_size = 0;
+            }
+        }
+    }
+
+    fn start_message_batch_handler(&mut self, receiver: Receiver<BatchMessage>) -> Result<(), Box<dyn Error>> {
         let address = self.address.to_string();
         let updates_arc = Arc::clone(&self.updates);
         let update_callback_arc = Arc::clone(&self.update_handler);
-        let handle = std::thread::Builder::new().name(format!("{} - content receiver", address)).spawn(move|| {
-            log::info!("Started message content handling thread");
+        let topic_handlers_arc = Arc::clone(&self.topic_handlers);
+        let handle = std::thread::Builder::new().name(format!("{} - batch receiver", address)).spawn(move|| {
+            log::info!("Started message batch handling thread");
             while let Ok(message) = receiver.recv() {
+                Self::handle_batch(&updates_arc, &update_callback_arc, &topic_handlers_arc, message);
+            }
+            log::info!("Message batch handling thread exiting");
+        }).unwrap();
+        self.activities.push(handle);
+        Ok(())
+    }
 
-                match message.message_type() {
-                    MessageType::Request => {
-                        if let Ok(peer_address) = message.sender().parse::<SocketAddr>() {
-                            let updates = updates_arc.read().unwrap();
-                            let mut requested_updates = HashMap::new();
-                            for (digest, _) in message.content() {
-                                if let Some(update) = updates.get_update(&digest) {
-                                    requested_updates.insert(digest.to_owned(), update.content().to_vec());
-                                }
-                            }
-                            if requested_updates.len() > 0{
-                                let response = ContentMessage::new_response(address.clone(), requested_updates);
-                                match super::network::send(&peer_address, Box::new(response)) {
-                                    Ok(written) => log::trace!("Sent content response - {} bytes to {:?}", written, peer_address),
-                                    Err(e) => log::error!("Error content response: {:?}", e)
-                                }
-                            }
-                        }
-                    }
-                    MessageType::Response => {
-                        if message.len() > 0 {
-                            let mut updates = updates_arc.write().unwrap();
-                            for (digest, content) in message.content() {
-                                if updates.is_new(&digest) {
-                                    let update = Update::new(content.clone());
-                                    if digest == *update.digest() {
-                                        log::info!("New update received: {}", update.digest());
-                                        match updates.insert_update(update) {
-                                            Ok(()) => {
-                                                // insert OK, notify update handler
-                                                let mutex = update_callback_arc.lock().unwrap();
-                                                if let Some(callback) = mutex.as_ref() {
-                                                    let update = Update::new(content);
-                                                    callback.on_update(update);
-                                                }
-                                                else {
-                                                    log::warn!("No update handler found");
-                                                }
-                                            },
-                                            Err(e) => log::error!("Could not add update: {:?}", e),
-                                        }
-                                    }
-                                    else {
-                                        log::warn!("Digests did not match: {} <> {}", digest, update.digest());
-                                    }
-                                }
-                            }
-                            updates.clear_expired();
-                        }
+    fn handle_batch(updates_lock: &RwLock<UpdateDecorator>, update_callback: &Mutex<Option<Box<T>>>, topic_handlers: &TopicHandlers, message: BatchMessage) {
+        match message.contents() {
+            Ok(contents) => {
+                let mut updates = updates_lock.write().unwrap();
+                for (topic, content) in contents {
+                    let update = Update::new_with_topic(&topic, content.clone());
+                    if updates.is_new(update.digest()) {
+                        Self::receive_update(&mut updates, update_callback, topic_handlers, update, content);
                     }
                 }
+                updates.clear_expired();
             }
+            Err(e) => log::error!("Could not read batch from {}: {:?}", message.sender(), e),
+        }
+    }
+
+    fn start_membership_activity(&mut self, receiver: Receiver<SwimMessage>) -> Result<(), Box<dyn Error>> {
+        let shutdown_requested = Arc::clone(&self.shutdown);
+        let membership_arc = Arc::clone(&self.membership);
+        let listener_arc = Arc::clone(&self.membership_listener);
+        let peer_sampling_arc = Arc::clone(&self.peer_sampling_service);
+        let transport = Arc::clone(&self.transport);
+        let (tick, join_period) = {
+            let membership = membership_arc.lock().unwrap();
+            let config = membership.config();
+            (Duration::from_millis(std::cmp::max(1, config.ping_timeout() / 2)), Duration::from_millis(config.protocol_period()))
+        };
+        let handle = std::thread::Builder::new().name(format!("{} - failure detection", self.address)).spawn(move|| {
+            log::info!("Started failure detection thread");
+            let mut join = JoinSchedule::new(join_period);
+            loop {
+                let message = match receiver.recv_timeout(tick) {
+                    Ok(message) => Some(message),
+                    Err(RecvTimeoutError::Timeout) => None,
+                    Err(RecvTimeoutError::Disconnected) => break,
+                };
+                if shutdown_requested.load(std::sync::atomic::Ordering::SeqCst) {
+                    break;
+                }
+                Self::run_membership(&membership_arc, &listener_arc, &peer_sampling_arc, transport.as_ref(), message, &mut join, Instant::now());
+            }
+            log::info!("Failure detection thread exiting");
         }).unwrap();
         self.activities.push(handle);
         Ok(())
     }
 
-    fn start_network_listener(&mut self, peer_sampling_sender: Sender<PeerSamplingMessage>, header_sender: Sender<HeaderMessage>, content_sender: Sender<ContentMessage>) -> Result<(), Box<dyn Error>> {
-        let handle = super::network::listen(self.address(), Arc::clone(&self.shutdown), peer_sampling_sender, header_sender, content_sender)?;
-        self.activities.push(handle);
+    fn membership_step(&self, message: Option<SwimMessage>, join: &mut JoinSchedule, now: Instant) {
+        Self::run_membership(&self.membership, &self.membership_listener, &self.peer_sampling_service, self.transport.as_ref(), message, join, now);
+    }
+
+    /// Handles a failure detection message, if any, and runs the failure detector
+    ///
+    /// # Arguments
+    ///
+    /// * `message` - The received message
+    /// * `join` - When peers found by peer sampling are asked to join
+    /// * `now` - The current time
+    fn run_membership(membership_lock: &Mutex<Membership>, listener_lock: &Mutex<Option<Box<dyn MembershipListener + Send>>>, peer_sampling: &Mutex<PeerSamplingService>, transport: &dyn Transport, message: Option<SwimMessage>, join: &mut JoinSchedule, now: Instant) {
+        // peers found by peer sampling join once they answer a ping
+        let seeds: Vec<String> =
+            if join.is_due(now) { peer_sampling.lock().unwrap().peers().iter().map(|peer| peer.address().to_owned()).collect() }
+            else { Vec::new() };
+        let (outgoing, events) = {
+            let mut membership = membership_lock.lock().unwrap();
+            let mut outgoing = membership.join(&seeds);
+            if let Some(message) = message {
+                outgoing.extend(membership.handle(message, now));
+            }
+            outgoing.extend(membership.tick(now));
+            (outgoing, membership.take_events())
+        };
+
+        for (recipient, message) in outgoing {
+            Self::send_membership_message(transport, &recipient, message);
+        }
+        for event in events {
+            if let MembershipEvent::Failed(address) | MembershipEvent::Left(address) = &event {
+                peer_sampling.lock().unwrap().remove_peer(address);
+            }
+            match listener_lock.lock().unwrap().as_ref() {
+                Some(listener) => listener.on_membership_event(event),
+                None => log::debug!("No membership listener for {:?}", event),
+            }
+        }
+    }
+
+    fn send_membership_message(transport: &dyn Transport, recipient: &str, message: SwimMessage) {
+        match recipient.parse::<SocketAddr>() {
+            // unreachable members are expected, the failure detector deals with them
+            Ok(address) => match transport::send(transport, &address, &message) {
+                Ok(written) => log::trace!("Sent failure detection message - {} bytes to {:?}", written, address),
+                Err(e) => log::debug!("Error sending failure detection message to {:?}: {:?}", address, e),
+            },
+            Err(_) => log::error!("Could not parse member address {}", recipient),
+        }
+    }
+
+    fn start_network_listener(&mut self, dispatcher: Dispatcher) -> Result<(), Box<dyn Error>> {
+        if let Some(handle) = self.transport.listen(self.address(), Arc::clone(&self.shutdown), dispatcher)? {
+            self.activities.push(handle);
+        }
         Ok(())
     }
 
@@ -241,6 +706,8 @@ where T: UpdateHandler + 'static + Send
         let shutdown_requested = Arc::clone(&self.shutdown);
         let peer_sampling_arc = Arc::clone(&self.peer_sampling_service);
         let updates_arc = Arc::clone(&self.updates);
+        let transport = Arc::clone(&self.transport);
+        let rng_arc = Arc::clone(&self.rng);
         let handle = std::thread::Builder::new().name(format!("{} - gossip activity", self.address().to_string())).spawn(move ||{
             log::info!("Gossip thread started");
             loop {
@@ -248,43 +715,12 @@ where T: UpdateHandler + 'static + Send
                     break;
                 }
 
-                let deviation =
-                    if gossip_config_arc.gossip_deviation() == 0 { 0 }
-                    else { rand::thread_rng().gen_range(0..gossip_config_arc.gossip_deviation()) };
-                let sleep = gossip_config_arc.gossip_period() + deviation;
-                std::thread::sleep(std::time::Duration::from_millis(sleep));
-
-                let mut peer_sampling_service = peer_sampling_arc.lock().unwrap();
-                if let Some(peer) = peer_sampling_service.get_peer() {
-                    if let Ok(peer_address) = peer.address().parse::<SocketAddr>() {
-                        drop(peer_sampling_service);
-                        let mut message = HeaderMessage::new_request(node_address.to_string());
-                        if gossip_config_arc.is_push() {
-                            // send active headers
-                            let mut updates = updates_arc.write().unwrap();
-
-                            if updates.active_count() > 0 {
-                                let active_headers = updates.active_headers_for_push();
-                                message.set_headers(active_headers);
-                                updates.clear_expired();
-                            }
-                        }
-                        else {
-                            // will send empty headers to trigger response
-                        }
-
-                        log::debug!("Will send header request with {:?}", message.headers());
+                // the configuration is read again each round to pick up changes
+                let delay = Self::gossip_delay(&gossip_config_arc.read().unwrap(), &rng_arc);
+                std::thread::sleep(delay);
 
-                        // TODO: check expiration after sending
-                        match super::network::send(&peer_address, Box::new(message)) {
-                            Ok(written) => log::trace!("Sent header request - {} bytes to {:?}", written, peer_address),
-                            Err(e) => log::error!("Error sending header request: {:?}", e)
-                        }
-                    }
-                }
-                else {
-                    log::warn!("No peer found for gossiping");
-                }
+                let gossip_config = gossip_config_arc.read().unwrap().clone();
+                Self::gossip_round(&node_address, &gossip_config, &peer_sampling_arc, &updates_arc, &rng_arc, transport.as_ref());
             }
             log::info!("Gossip thread exiting");
         }).unwrap();
@@ -294,13 +730,82 @@ where T: UpdateHandler + 'static + Send
         Ok(())
     }
 
+    /// Time until the next gossip round, the gossip period plus a random deviation
+    fn gossip_delay(gossip_config: &GossipConfig, rng: &Mutex<StdRng>) -> Duration {
+        let deviation =
+            if gossip_config.gossip_deviation() == 0 { 0 }
+            else { rng.lock().unwrap().gen_range(0..gossip_config.gossip_deviation()) };
+        Duration::from_millis(gossip_config.gossip_period().saturating_add(deviation))
+    }
+
+    /// Sends the active headers, or a summary of the known updates to peers supporting it, to a random peer
+    fn gossip_round(node_address: &str, gossip_config: &GossipConfig, peer_sampling: &Mutex<PeerSamplingService>, updates_lock: &RwLock<UpdateDecorator>, rng: &Mutex<StdRng>, transport: &dyn Transport) {
+        let mut peer_sampling_service = peer_sampling.lock().unwrap();
+        if let Some(peer) = peer_sampling_service.get_peer() {
+            if let Ok(peer_address) = peer.address().parse::<SocketAddr>() {
+                drop(peer_sampling_service);
+                if transport.peer_protocol(&peer_address).is_some_and(|protocol| protocol.supports(CAPABILITY_DELTA_SYNC)) {
+                    let mut updates = updates_lock.write().unwrap();
+                    if gossip_config.is_push() && updates.active_count() > 0 {
+                        // updates expire as when their headers are pushed
+                        updates.active_headers_for_push();
+                        updates.clear_expired();
+                    }
+                    let seed = rng.lock().unwrap().gen();
+                    let message = SummaryMessage::new_request(node_address.to_string(), updates.summary(seed));
+                    drop(updates);
+                    match transport::send(transport, &peer_address, &message) {
+                        Ok(written) => log::trace!("Sent summary request - {} bytes to {:?}", written, peer_address),
+                        Err(e) => log::error!("Error sending summary request: {:?}", e)
+                    }
+                    return;
+                }
+                let mut message = HeaderMessage::new_request(node_address.to_string());
+                if gossip_config.is_push() {
+                    // send active headers
+                    let mut updates = updates_lock.write().unwrap();
+
+                    if updates.active_count() > 0 {
+                        let active_headers = updates.active_headers_for_push();
+                        message.set_headers(active_headers);
+                        updates.clear_expired();
+                    }
+                }
+                else {
+                    // will send empty headers to trigger response
+                }
+
+                log::debug!("Will send header request with {:?}", message.headers());
+
+                // TODO: check expiration after sending
+                match transport::send(transport, &peer_address, &message) {
+                    Ok(written) => log::trace!("Sent header request - {} bytes to {:?}", written, peer_address),
+                    Err(e) => log::error!("Error sending header request: {:?}", e)
+                }
+            }
+        }
+        else {
+            log::warn!("No peer found for gossiping");
+        }
+    }
+
     /// Submits a message for broadcast by the gossip protocol
     ///
     /// # Arguments
     ///
     /// * `bytes` - Content of the message
     pub fn submit(&self, bytes: Vec<u8>) -> Result<(), Box<dyn Error>> {
-        let update = Update::new(bytes);
+        self.submit_to_topic(DEFAULT_TOPIC, bytes)
+    }
+
+    /// Submits a message of a topic for broadcast by the gossip protocol
+    ///
+    /// # Arguments
+    ///
+    /// * `topic` - Topic of the message
+    /// * `bytes` - Content of the message
+    pub fn submit_to_topic(&self, topic: &str, bytes: Vec<u8>) -> Result<(), Box<dyn Error>> {
+        let update = Update::new_with_topic(topic, bytes);
         let mut updates = self.updates.write().unwrap();
         if updates.is_new(update.digest()) {
             log::info!("New update for submission: {}", update.digest());
@@ -322,10 +827,17 @@ where T: UpdateHandler + 'static + Send
 
     /// Terminates the gossip protocol and related threads
     pub fn shutdown(&mut self) -> Result<(), Box<dyn Error>> {
+        // tell the other members we leave rather than have them detect a failure
+        let leave = self.membership.lock().unwrap().leave();
+        for (recipient, message) in leave {
+            Self::send_membership_message(self.transport.as_ref(), &recipient, message);
+        }
         self.update_handler.lock().unwrap().take();
+        self.topic_handlers.lock().unwrap().clear();
+        self.membership_listener.lock().unwrap().take();
         self.shutdown.store(true, std::sync::atomic::Ordering::SeqCst);
         log::info!("Shutdown requested");
-        if let Ok(_) = super::network::send(self.address(), Box::new(NoopMessage)) {
+        if transport::send(self.transport.as_ref(), self.address(), &NoopMessage).is_ok() {
             // shutdown request sent
         }
         let mut error = false;
@@ -352,3 +864,210 @@ where T: UpdateHandler + 'static + Send
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::config::{TopicConfig, UpdateExpirationMode};
+    use super::super::simulation::{SimConfig, SimNetwork};
+
+    struct Received(Arc<Mutex<Vec<Vec<u8>>>>);
+
+    impl UpdateHandler for Received {
+        fn on_update(&self, update: Update) {
+            self.0.lock().unwrap().push(update.content().to_vec());
+        }
+    }
+
+    type Node = (GossipService<Received>, Arc<Mutex<Vec<Vec<u8>>>>);
+
+    fn address(index: usize) -> SocketAddr {
+        format!("127.0.0.1:{}", 9300 + index).parse().unwrap()
+    }
+
+    #[test]
+    fn summaries_and_batches_spread_updates() {
+        let network = SimNetwork::new(SimConfig::new(11, 1, 20, 0.05, 0.05));
+        let nodes: Vec<Node> = (0..5).map(|index| {
+            let mut node = GossipService::new_with_transport(
+                address(index),
+                PeerSamplingConfig::new(true, true, 100, 4, 1, 1),
+                // small batches, several are needed to send all the updates
+                GossipConfig::new_with_batching(true, true, 100, 0, UpdateExpirationMode::None, 256, true),
+                SwimConfig::default(),
+                Arc::new(VersionedTransport::new(address(index), network.transport(address(index)))),
+                index as u64,
+            );
+            let received = Arc::new(Mutex::new(Vec::new()));
+            let init: Box<dyn FnOnce() -> Option<Vec<Peer>>> =
+                if index == 0 { Box::new(|| None) }
+                else { Box::new(move || Some(vec![Peer::new(address(index - 1).to_string())])) };
+            node.start_manual(init, Box::new(Received(Arc::clone(&received)))).unwrap();
+            (node, received)
+        }).collect();
+
+        let step = |steps: usize| for _ in 0..steps {
+            network.advance(10);
+            for (node, _) in nodes.iter() {
+                node.poll(network.now()).unwrap();
+            }
+        };
+        step(200);
+        for index in 0..40 {
+            nodes[index % 2].0.submit(format!("update {} {}", index, "payload ".repeat(index)).into_bytes()).unwrap();
+        }
+        step(600);
+
+        for (index, (node, received)) in nodes.iter().enumerate() {
+            assert_eq!(node.updates.read().unwrap().active_count(), 40, "node {}", index);
+            let expected = if index < 2 { 20 } else { 40 };
+            assert_eq!(received.lock().unwrap().len(), expected, "node {}", index);
+        }
+    }
+
+    #[test]
+    fn priority_topics_overtake_bulk_updates() {
+        let network = SimNetwork::new(SimConfig::new(13, 1, 20, 0.0, 0.0));
+        let invalidations = Arc::new(Mutex::new(Vec::new()));
+        let nodes: Vec<Node> = (0..4).map(|index| {
+            // odd nodes exchange headers and contents, even ones summaries and batches
+            let transport: Arc<dyn Transport> =
+                if index % 2 == 0 { Arc::new(VersionedTransport::new(address(index), network.transport(address(index)))) }
+                else { network.transport(address(index)) };
+            let gossip_config = GossipConfig::new_with_batching(true, true, 100, 0, UpdateExpirationMode::None, 256, false)
+                .with_round_budget(256)
+                .with_topic(TopicConfig::new("invalidation", 10, UpdateExpirationMode::None))
+                .with_topic(TopicConfig::new("presence", 0, UpdateExpirationMode::MostRecent(2, 0.0)));
+            let mut node = GossipService::new_with_transport(
+                address(index),
+                PeerSamplingConfig::new(true, true, 100, 4, 1, 1),
+                gossip_config,
+                SwimConfig::default(),
+                transport,
+                index as u64,
+            );
+            let received = Arc::new(Mutex::new(Vec::new()));
+            let init: Box<dyn FnOnce() -> Option<Vec<Peer>>> =
+                if index == 0 { Box::new(|| None) }
+                else { Box::new(move || Some(vec![Peer::new(address(index - 1).to_string())])) };
+            node.start_manual(init, Box::new(Received(Arc::clone(&received)))).unwrap();
+            node.subscribe("invalidation", Box::new(Received(Arc::clone(&invalidations))));
+            (node, received)
+        }).collect();
+
+        let step = |steps: usize| for _ in 0..steps {
+            network.advance(10);
+            for (node, _) in nodes.iter() {
+                node.poll(network.now()).unwrap();
+            }
+        };
+        step(200);
+        for index in 0..30 {
+            nodes[0].0.submit(format!("bulk {:02} {}", index, "x".repeat(100)).into_bytes()).unwrap();
+        }
+        for index in 0..5 {
+            nodes[0].0.submit_to_topic("presence", format!("presence {}", index).into_bytes()).unwrap();
+        }
+        nodes[0].0.submit_to_topic("invalidation", b"invalidate".to_vec()).unwrap();
+        // the same content in another topic is another update
+        nodes[0].0.submit_to_topic("invalidation", format!("bulk {:02} {}", 0, "x".repeat(100)).into_bytes()).unwrap();
+
+        let mut steps = 0;
+        while invalidations.lock().unwrap().len() < 6 && steps < 300 {
+            step(1);
+            steps += 1;
+        }
+        assert_eq!(invalidations.lock().unwrap().len(), 6, "invalidations did not spread");
+        for (index, (_, received)) in nodes.iter().enumerate().skip(1) {
+            assert!(received.lock().unwrap().len() < 30, "node {} received the bulk updates first", index);
+        }
+
+        step(1000);
+        for (index, (node, received)) in nodes.iter().enumerate().skip(1) {
+            let received = received.lock().unwrap();
+            let bulk = received.iter().filter(|content| content.starts_with(b"bulk")).count();
+            assert_eq!(bulk, 30, "node {}", index);
+            // invalidations only went to the topic handler
+            assert!(!received.iter().any(|content| content == b"invalidate"));
+            // presence keeps only its two most recent updates active
+            assert_eq!(node.updates.read().unwrap().active_count(), 30 + 2 + 2, "node {}", index);
+        }
+    }
+
+    #[test]
+    fn configuration_changes_apply_at_runtime() {
+        let network = SimNetwork::new(SimConfig::new(17, 1, 20, 0.0, 0.0));
+        let nodes: Vec<Node> = (0..5).map(|index| {
+            let mut node = GossipService::new_with_transport(
+                address(index),
+                PeerSamplingConfig::new(true, true, 100, 4, 1, 1),
+                GossipConfig::new(true, true, 100, UpdateExpirationMode::None),
+                SwimConfig::default(),
+                network.transport(address(index)),
+                index as u64,
+            );
+            let received = Arc::new(Mutex::new(Vec::new()));
+            let init: Box<dyn FnOnce() -> Option<Vec<Peer>>> =
+                if index == 0 { Box::new(|| None) }
+                else { Box::new(move || Some(vec![Peer::new(address(index - 1).to_string())])) };
+            node.start_manual(init, Box::new(Received(Arc::clone(&received)))).unwrap();
+            (node, received)
+        }).collect();
+
+        let step = |steps: usize| for _ in 0..steps {
+            network.advance(10);
+            for (node, _) in nodes.iter() {
+                node.poll(network.now()).unwrap();
+            }
+        };
+        step(200);
+        assert!(nodes.iter().all(|(node, _)| node.peers().len() > 2));
+
+        // nonsensical combinations are rejected and leave the configuration unchanged
+        let mut gossip_config = nodes[0].0.gossip_config();
+        gossip_config.set_push(false);
+        gossip_config.set_pull(false);
+        assert!(nodes[0].0.set_gossip_config(gossip_config).is_err());
+        let mut sampling_config = nodes[0].0.peer_sampling_config();
+        sampling_config.set_healing_factor(2);
+        assert!(nodes[0].0.set_peer_sampling_config(sampling_config).is_err());
+        // as are periods overflowing the deadlines they schedule
+        let mut gossip_config = nodes[0].0.gossip_config();
+        gossip_config.set_gossip_deviation(u64::MAX);
+        assert!(nodes[0].0.set_gossip_config(gossip_config).is_err());
+        let mut sampling_config = nodes[0].0.peer_sampling_config();
+        sampling_config.set_sampling_period(u64::MAX);
+        assert!(nodes[0].0.set_peer_sampling_config(sampling_config).is_err());
+        let mut sampling_config = nodes[0].0.peer_sampling_config();
+        sampling_config.set_view_size(usize::MAX);
+        assert!(nodes[0].0.set_peer_sampling_config(sampling_config).is_err());
+        assert!(nodes[0].0.gossip_config().is_push());
+        assert_eq!(nodes[0].0.peer_sampling_config().healing_factor(), 1);
+
+        let sent = network.stats().sent;
+        step(200);
+        let sent_before = network.stats().sent - sent;
+
+        for (node, _) in nodes.iter() {
+            let mut gossip_config = node.gossip_config();
+            gossip_config.set_gossip_period(1000);
+            node.set_gossip_config(gossip_config).unwrap();
+            let mut sampling_config = node.peer_sampling_config();
+            sampling_config.set_sampling_period(1000);
+            sampling_config.set_view_size(2);
+            sampling_config.set_healing_factor(0);
+            node.set_peer_sampling_config(sampling_config).unwrap();
+        }
+        step(20);
+        let sent = network.stats().sent;
+        step(200);
+        let sent_after = network.stats().sent - sent;
+        assert!(sent_after * 2 < sent_before, "{} messages sent before, {} after", sent_before, sent_after);
+        assert!(nodes.iter().all(|(node, _)| node.peers().len() <= 2));
+
+        // updates still spread at the slower pace
+        nodes[0].0.submit(b"slow".to_vec()).unwrap();
+        step(1000);
+        assert!(nodes.iter().skip(1).all(|(_, received)| received.lock().unwrap().contains(&b"slow".to_vec())));
+    }
+}
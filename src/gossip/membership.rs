@@ -0,0 +1,643 @@
+#![allow(dead_code)]
+
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use rand::SeedableRng;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use serde::{Serialize, Deserialize};
+use super::config::SwimConfig;
+use super::message::{Message, MESSAGE_PROTOCOL_MEMBERSHIP_MESSAGE};
+
+/// State of a member as seen by the local node
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MemberState {
+    /// The member answers probes
+    Alive,
+    /// The member missed a probe and has until the suspicion timeout to refute it
+    Suspect,
+    /// The member stayed suspected past the suspicion timeout
+    Dead,
+    /// The member announced it left the cluster
+    Left,
+}
+
+/// A membership change disseminated by piggybacking it on protocol messages
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MemberUpdate {
+    /// Address of the member the update is about
+    address: String,
+    /// Incarnation of the member the update applies to
+    incarnation: u64,
+    /// New state of the member
+    state: MemberState,
+}
+
+impl MemberUpdate {
+    pub fn new(address: String, incarnation: u64, state: MemberState) -> Self {
+        MemberUpdate { address, incarnation, state }
+    }
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+    pub fn incarnation(&self) -> u64 {
+        self.incarnation
+    }
+    pub fn state(&self) -> MemberState {
+        self.state
+    }
+}
+
+/// The kind of a failure detection message
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SwimMessageKind {
+    /// Direct probe, answered with an [SwimMessageKind::Ack]
+    Ping { seq: u64 },
+    /// Asks the recipient to probe `target` and forward its ack
+    PingReq { seq: u64, target: String },
+    /// Answer to a probe of `target`, possibly forwarded by another member
+    Ack { seq: u64, target: String },
+    /// Carries the updates announcing the sender leaves, not answered
+    Leave,
+}
+
+/// A failure detection protocol message
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SwimMessage {
+    /// Address of the sender
+    sender: String,
+    /// Incarnation of the sender, a message proves the sender alive in that incarnation
+    incarnation: u64,
+    /// Kind of the message
+    kind: SwimMessageKind,
+    /// Piggybacked membership updates
+    updates: Vec<MemberUpdate>,
+}
+
+impl SwimMessage {
+    pub fn sender(&self) -> &str {
+        &self.sender
+    }
+    pub fn kind(&self) -> &SwimMessageKind {
+        &self.kind
+    }
+    pub fn updates(&self) -> &Vec<MemberUpdate> {
+        &self.updates
+    }
+}
+
+impl Message for SwimMessage {
+    fn protocol(&self) -> u8 {
+        MESSAGE_PROTOCOL_MEMBERSHIP_MESSAGE
+    }
+}
+
+/// Membership changes surfaced to the application
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MembershipEvent {
+    /// A member joined the cluster, or came back under a new incarnation
+    Joined(String),
+    /// A member announced it left the cluster
+    Left(String),
+    /// A member was declared failed after being suspected for too long
+    Failed(String),
+}
+
+/// Trait for receiving membership changes from the failure detector.
+///
+/// See: [MembershipEvent]
+pub trait MembershipListener {
+    /// Method called for every membership change
+    ///
+    /// # Arguments
+    ///
+    /// * `event` - The membership change
+    fn on_membership_event(&self, event: MembershipEvent);
+}
+
+/// A member of the cluster
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Member {
+    address: String,
+    incarnation: u64,
+    state: MemberState,
+    /// When the member entered its state
+    since: Instant,
+}
+
+impl Member {
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+    pub fn incarnation(&self) -> u64 {
+        self.incarnation
+    }
+    pub fn state(&self) -> MemberState {
+        self.state
+    }
+    fn is_reachable(&self) -> bool {
+        self.state == MemberState::Alive || self.state == MemberState::Suspect
+    }
+}
+
+/// Probe of a member waiting for its ack
+struct Probe {
+    target: String,
+    seq: u64,
+    sent: Instant,
+    indirect: bool,
+}
+
+/// Probe sent on behalf of another member, whose ack is forwarded to it
+struct Relay {
+    origin: String,
+    seq: u64,
+    sent: Instant,
+}
+
+/// SWIM failure detector and membership list of a node.
+///
+/// The detector does no I/O: [Membership::tick] and [Membership::handle] return the messages
+/// to send and the caller delivers them, which keeps the protocol independent of the network.
+pub struct Membership {
+    /// Address of the node
+    address: String,
+    /// Incarnation of the node, raised to refute suspicions. It starts from the wall clock so
+    /// that a restarted node comes back above the incarnation it was declared dead in.
+    incarnation: u64,
+    /// Protocol parameters
+    config: SwimConfig,
+    /// Known members, dead and left ones included so stale updates cannot revive them
+    members: BTreeMap<String, Member>,
+    /// Probe order, a shuffled round of the members
+    probe_order: Vec<String>,
+    probe_index: usize,
+    /// Probe in progress
+    probe: Option<Probe>,
+    /// When the next probe starts
+    next_probe: Option<Instant>,
+    /// Probes sent for other members, by sequence number
+    relays: BTreeMap<u64, Relay>,
+    next_seq: u64,
+    /// Updates to piggyback, with the number of times they were sent
+    broadcasts: Vec<(MemberUpdate, usize)>,
+    /// Membership changes not yet taken by the application
+    events: Vec<MembershipEvent>,
+    /// The node left the cluster
+    left: bool,
+    /// Source of the random choices of the protocol
+    rng: StdRng,
+}
+
+impl Membership {
+    /// Creates the membership list of the node at `address`, which knows no member yet
+    pub fn new(address: String, config: SwimConfig) -> Self {
+        Self::new_with_seed(address, config, rand::random())
+    }
+
+    /// Creates the membership list of the node at `address`, making its random choices
+    /// from `seed`
+    pub fn new_with_seed(address: String, config: SwimConfig, seed: u64) -> Self {
+        let incarnation = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64);
+        Membership {
+            address,
+            incarnation,
+            config,
+            members: BTreeMap::new(),
+            probe_order: Vec::new(),
+            probe_index: 0,
+            probe: None,
+            next_probe: None,
+            relays: BTreeMap::new(),
+            next_seq: 0,
+            broadcasts: Vec::new(),
+            events: Vec::new(),
+            left: false,
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
+
+    /// Returns the node address
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    /// Returns the protocol parameters
+    pub fn config(&self) -> &SwimConfig {
+        &self.config
+    }
+
+    /// Returns the incarnation of the node
+    pub fn incarnation(&self) -> u64 {
+        self.incarnation
+    }
+
+    /// Returns the members believed alive or suspected
+    pub fn members(&self) -> Vec<Member> {
+        self.members.values().filter(|m| m.is_reachable()).cloned().collect()
+    }
+
+    /// Returns what the node knows about a member
+    pub fn member(&self, address: &str) -> Option<&Member> {
+        self.members.get(address)
+    }
+
+    /// Returns the membership changes since the last call
+    pub fn take_events(&mut self) -> Vec<MembershipEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    /// Pings addresses that are not members yet; they become members once they answer.
+    ///
+    /// # Arguments
+    ///
+    /// * `seeds` - Addresses of nodes of the cluster
+    pub fn join(&mut self, seeds: &[String]) -> Vec<(String, SwimMessage)> {
+        let unknown: Vec<String> = seeds.iter()
+            .filter(|seed| **seed != self.address && !self.members.contains_key(*seed))
+            .cloned()
+            .collect();
+        unknown.into_iter()
+            .map(|seed| {
+                let seq = self.next_seq();
+                let message = self.message(SwimMessageKind::Ping { seq });
+                (seed, message)
+            })
+            .collect()
+    }
+
+    /// Announces the node leaves the cluster to the members, after which it ignores messages
+    pub fn leave(&mut self) -> Vec<(String, SwimMessage)> {
+        if self.left {
+            return Vec::new();
+        }
+        self.left = true;
+        self.broadcasts.clear();
+        self.enqueue(MemberUpdate::new(self.address.clone(), self.incarnation, MemberState::Left));
+        let targets: Vec<String> = self.members.values()
+            .filter(|m| m.is_reachable())
+            .map(|m| m.address.clone())
+            .collect();
+        targets.into_iter()
+            .map(|target| (target, self.message(SwimMessageKind::Leave)))
+            .collect()
+    }
+
+    /// Advances the protocol to `now`: expires suspicions, follows up on the probe in progress
+    /// and starts the next one when the protocol period is over.
+    pub fn tick(&mut self, now: Instant) -> Vec<(String, SwimMessage)> {
+        let mut outgoing = Vec::new();
+        if self.left {
+            return outgoing;
+        }
+
+        // suspected members that did not refute in time are declared failed
+        let suspicion_timeout = Duration::from_millis(self.config.suspicion_timeout());
+        let failed: Vec<(String, u64)> = self.members.values()
+            .filter(|m| m.state == MemberState::Suspect && now.duration_since(m.since) >= suspicion_timeout)
+            .map(|m| (m.address.clone(), m.incarnation))
+            .collect();
+        for (address, incarnation) in failed {
+            log::info!("Member {} failed", address);
+            self.apply(MemberUpdate::new(address, incarnation, MemberState::Dead), now);
+        }
+
+        let period = Duration::from_millis(self.config.protocol_period());
+        self.relays.retain(|_, relay| now.duration_since(relay.sent) < period);
+
+        if let Some(probe) = self.probe.as_mut() {
+            let elapsed = now.duration_since(probe.sent);
+            if elapsed >= period {
+                // no ack, direct or indirect, within the protocol period
+                let target = probe.target.clone();
+                self.probe = None;
+                if let Some(member) = self.members.get(&target) {
+                    if member.state == MemberState::Alive {
+                        log::debug!("Member {} suspected", target);
+                        let update = MemberUpdate::new(target, member.incarnation, MemberState::Suspect);
+                        self.apply(update, now);
+                    }
+                }
+            }
+            else if elapsed >= Duration::from_millis(self.config.ping_timeout()) && !probe.indirect {
+                probe.indirect = true;
+                let (seq, target) = (probe.seq, probe.target.clone());
+                let mut helpers: Vec<String> = self.members.values()
+                    .filter(|m| m.state == MemberState::Alive && m.address != target)
+                    .map(|m| m.address.clone())
+                    .collect();
+                helpers.shuffle(&mut self.rng);
+                helpers.truncate(self.config.indirect_checks());
+                for helper in helpers {
+                    let message = self.message(SwimMessageKind::PingReq { seq, target: target.clone() });
+                    outgoing.push((helper, message));
+                }
+            }
+        }
+
+        if self.probe.is_none() && !matches!(self.next_probe, Some(next) if now < next) {
+            self.next_probe = Some(now + period);
+            if let Some(target) = self.next_target() {
+                let seq = self.next_seq();
+                self.probe = Some(Probe { target: target.clone(), seq, sent: now, indirect: false });
+                outgoing.push((target, self.message(SwimMessageKind::Ping { seq })));
+            }
+        }
+        outgoing
+    }
+
+    /// Handles a message received from another node and returns the answers to send
+    pub fn handle(&mut self, message: SwimMessage, now: Instant) -> Vec<(String, SwimMessage)> {
+        let mut outgoing = Vec::new();
+        if self.left {
+            return outgoing;
+        }
+
+        let SwimMessage { sender, incarnation, kind, updates } = message;
+        if kind != SwimMessageKind::Leave {
+            // a member declared dead or left that talks again, such as a restarted node, is told
+            // so on the answer in order to refute it with a higher incarnation
+            if let Some(member) = self.members.get(&sender) {
+                if !member.is_reachable() && incarnation <= member.incarnation {
+                    self.enqueue(MemberUpdate::new(sender.clone(), member.incarnation, member.state));
+                }
+            }
+            self.apply(MemberUpdate::new(sender.clone(), incarnation, MemberState::Alive), now);
+        }
+        for update in updates {
+            self.apply(update, now);
+        }
+
+        match kind {
+            SwimMessageKind::Ping { seq } => {
+                let ack = self.message(SwimMessageKind::Ack { seq, target: self.address.clone() });
+                outgoing.push((sender, ack));
+            }
+            SwimMessageKind::PingReq { seq, target } => {
+                let relay_seq = self.next_seq();
+                self.relays.insert(relay_seq, Relay { origin: sender, seq, sent: now });
+                outgoing.push((target, self.message(SwimMessageKind::Ping { seq: relay_seq })));
+            }
+            SwimMessageKind::Ack { seq, target } => {
+                match self.probe.as_ref() {
+                    Some(probe) if probe.seq == seq && probe.target == target => self.probe = None,
+                    _ => {
+                        if let Some(relay) = self.relays.remove(&seq) {
+                            let ack = self.message(SwimMessageKind::Ack { seq: relay.seq, target });
+                            outgoing.push((relay.origin, ack));
+                        }
+                    }
+                }
+            }
+            SwimMessageKind::Leave => (),
+        }
+        outgoing
+    }
+
+    /// Applies a membership update if it supersedes what the node knows of the member
+    fn apply(&mut self, update: MemberUpdate, now: Instant) {
+        if update.address == self.address {
+            // refute suspicions about ourselves with a new incarnation, as well as the news that
+            // we failed or left from before a restart
+            let accused = update.state != MemberState::Alive;
+            if accused && update.incarnation >= self.incarnation {
+                self.incarnation = update.incarnation + 1;
+                log::info!("Refuting suspicion with incarnation {}", self.incarnation);
+                self.enqueue(MemberUpdate::new(self.address.clone(), self.incarnation, MemberState::Alive));
+            }
+            return;
+        }
+
+        let event = match self.members.get_mut(&update.address) {
+            None => {
+                if update.state == MemberState::Dead || update.state == MemberState::Left {
+                    return;
+                }
+                self.members.insert(update.address.clone(), Member {
+                    address: update.address.clone(),
+                    incarnation: update.incarnation,
+                    state: update.state,
+                    since: now,
+                });
+                Some(MembershipEvent::Joined(update.address.clone()))
+            }
+            Some(member) => {
+                let supersedes = match (update.state, member.state) {
+                    (MemberState::Alive, _) => update.incarnation > member.incarnation,
+                    (MemberState::Suspect, MemberState::Alive) => update.incarnation >= member.incarnation,
+                    (MemberState::Suspect, MemberState::Suspect) => update.incarnation > member.incarnation,
+                    (MemberState::Dead, MemberState::Alive) | (MemberState::Dead, MemberState::Suspect)
+                    | (MemberState::Left, MemberState::Alive) | (MemberState::Left, MemberState::Suspect) => {
+                        update.incarnation >= member.incarnation
+                    }
+                    _ => false,
+                };
+                if !supersedes {
+                    return;
+                }
+                let event = match update.state {
+                    MemberState::Alive if !member.is_reachable() => Some(MembershipEvent::Joined(update.address.clone())),
+                    MemberState::Dead => Some(MembershipEvent::Failed(update.address.clone())),
+                    MemberState::Left => Some(MembershipEvent::Left(update.address.clone())),
+                    _ => None,
+                };
+                member.incarnation = update.incarnation;
+                member.state = update.state;
+                member.since = now;
+                event
+            }
+        };
+        if let Some(event) = event {
+            log::info!("Membership change: {:?}", event);
+            self.events.push(event);
+        }
+        self.enqueue(update);
+    }
+
+    /// Queues an update for dissemination, replacing older updates about the same member
+    fn enqueue(&mut self, update: MemberUpdate) {
+        self.broadcasts.retain(|(queued, _)| queued.address != update.address);
+        self.broadcasts.push((update, 0));
+    }
+
+    /// Builds a message piggybacking the least sent updates
+    fn message(&mut self, kind: SwimMessageKind) -> SwimMessage {
+        let reachable = self.members.values().filter(|m| m.is_reachable()).count();
+        let limit = self.config.retransmit_multiplier() * ((reachable + 1) as f64).log10().ceil().max(1.0) as usize;
+
+        self.broadcasts.sort_by_key(|(_, sent)| *sent);
+        let mut updates = Vec::new();
+        for (update, sent) in self.broadcasts.iter_mut().take(self.config.max_piggyback()) {
+            *sent += 1;
+            updates.push(update.clone());
+        }
+        self.broadcasts.retain(|(_, sent)| *sent < limit);
+
+        SwimMessage {
+            sender: self.address.clone(),
+            incarnation: self.incarnation,
+            kind,
+            updates,
+        }
+    }
+
+    /// Picks the next member to probe, going through the members in a random order
+    fn next_target(&mut self) -> Option<String> {
+        loop {
+            if self.probe_index >= self.probe_order.len() {
+                self.probe_order = self.members.values()
+                    .filter(|m| m.is_reachable())
+                    .map(|m| m.address.clone())
+                    .collect();
+                self.probe_order.shuffle(&mut self.rng);
+                self.probe_index = 0;
+                if self.probe_order.is_empty() {
+                    return None;
+                }
+            }
+            let candidate = &self.probe_order[self.probe_index];
+            self.probe_index += 1;
+            if matches!(self.members.get(candidate), Some(m) if m.is_reachable()) {
+                return Some(candidate.clone());
+            }
+        }
+    }
+
+    fn next_seq(&mut self) -> u64 {
+        self.next_seq += 1;
+        self.next_seq
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Delivers messages until none is left, dropping the ones to or from `down`
+    fn deliver(nodes: &mut [Membership], mut pending: Vec<(String, SwimMessage)>, down: &[&str], now: Instant) {
+        while let Some((to, message)) = pending.pop() {
+            if down.contains(&to.as_str()) || down.contains(&message.sender()) {
+                continue;
+            }
+            if let Some(node) = nodes.iter_mut().find(|n| n.address() == to) {
+                pending.extend(node.handle(message, now));
+            }
+        }
+    }
+
+    fn cluster(size: usize, now: Instant) -> Vec<Membership> {
+        let addresses: Vec<String> = (0..size).map(|i| format!("node-{}", i)).collect();
+        let mut nodes: Vec<Membership> = addresses.iter()
+            .map(|a| Membership::new(a.clone(), SwimConfig::default()))
+            .collect();
+        for i in 0..size {
+            let joins = nodes[i].join(&addresses);
+            deliver(&mut nodes, joins, &[], now);
+        }
+        nodes
+    }
+
+    /// Runs the protocol for `millis`, in steps of 100ms
+    fn run(nodes: &mut [Membership], start: Instant, millis: u64, down: &[&str]) -> Instant {
+        let mut now = start;
+        for _ in 0..millis / 100 {
+            now += Duration::from_millis(100);
+            for i in 0..nodes.len() {
+                if down.contains(&nodes[i].address()) {
+                    continue;
+                }
+                let outgoing = nodes[i].tick(now);
+                deliver(nodes, outgoing, down, now);
+            }
+        }
+        now
+    }
+
+    #[test]
+    fn failed_member_is_detected_by_every_node() {
+        let now = Instant::now();
+        let mut nodes = cluster(5, now);
+        for node in nodes.iter_mut() {
+            assert_eq!(node.members().len(), 4);
+            assert_eq!(node.take_events().len(), 4);
+        }
+
+        let now = run(&mut nodes, now, 3000, &[]);
+        assert!(nodes.iter_mut().all(|n| n.take_events().is_empty()));
+
+        run(&mut nodes, now, 15000, &["node-3"]);
+        for node in nodes.iter_mut().filter(|n| n.address() != "node-3") {
+            assert_eq!(node.member("node-3").unwrap().state(), MemberState::Dead);
+            assert_eq!(node.take_events(), vec![MembershipEvent::Failed("node-3".to_string())]);
+            assert_eq!(node.members().len(), 3);
+        }
+    }
+
+    #[test]
+    fn suspected_member_refutes() {
+        let now = Instant::now();
+        let mut nodes = cluster(3, now);
+        let incarnation = nodes[1].incarnation();
+        let suspicion = MemberUpdate::new("node-1".to_string(), incarnation, MemberState::Suspect);
+        nodes[0].apply(suspicion, now);
+        assert_eq!(nodes[0].member("node-1").unwrap().state(), MemberState::Suspect);
+
+        // the suspicion reaches node-1 piggybacked on a probe, which refutes it
+        let now = run(&mut nodes, now, 3000, &[]);
+        assert_eq!(nodes[1].incarnation(), incarnation + 1);
+        for node in [&nodes[0], &nodes[2]] {
+            let member = node.member("node-1").unwrap();
+            assert_eq!((member.state(), member.incarnation()), (MemberState::Alive, incarnation + 1));
+        }
+        run(&mut nodes, now, 10000, &[]);
+        assert!(nodes.iter_mut().all(|n| n.take_events().len() == 2));
+    }
+
+    #[test]
+    fn failed_member_rejoins_after_a_restart() {
+        let now = Instant::now();
+        let mut nodes = cluster(5, now);
+        let now = run(&mut nodes, now, 15000, &["node-3"]);
+        let dead = nodes[0].member("node-3").unwrap().incarnation();
+        assert_eq!(nodes[0].member("node-3").unwrap().state(), MemberState::Dead);
+        for node in nodes.iter_mut() {
+            node.take_events();
+        }
+
+        // the restarted node comes back in the same incarnation, as if its clock went back,
+        // and learns it was declared dead from the answers to its join
+        let mut restarted = Membership::new("node-3".to_string(), SwimConfig::default());
+        restarted.incarnation = dead;
+        nodes[3] = restarted;
+        let seeds: Vec<String> = (0..5).map(|i| format!("node-{}", i)).collect();
+        let joins = nodes[3].join(&seeds);
+        deliver(&mut nodes, joins, &[], now);
+        run(&mut nodes, now, 30000, &[]);
+
+        let incarnation = nodes[3].incarnation();
+        assert!(incarnation > dead);
+        assert_eq!(nodes[3].members().len(), 4);
+        for node in nodes.iter_mut().filter(|n| n.address() != "node-3") {
+            let member = node.member("node-3").unwrap();
+            assert_eq!((member.state(), member.incarnation()), (MemberState::Alive, incarnation));
+            assert_eq!(node.members().len(), 4);
+            assert_eq!(node.take_events(), vec![MembershipEvent::Joined("node-3".to_string())]);
+        }
+    }
+
+    #[test]
+    fn leaving_member_is_not_reported_failed() {
+        let now = Instant::now();
+        let mut nodes = cluster(3, now);
+        for node in nodes.iter_mut() {
+            node.take_events();
+        }
+        let leave = nodes[2].leave();
+        deliver(&mut nodes, leave, &[], now);
+        run(&mut nodes, now, 10000, &["node-2"]);
+        for node in &mut nodes[..2] {
+            assert_eq!(node.take_events(), vec![MembershipEvent::Left("node-2".to_string())]);
+            assert_eq!(node.members().len(), 1);
+        }
+    }
+}
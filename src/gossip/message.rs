@@ -1,13 +1,26 @@
 use std::error::Error;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use super::compression;
+use super::update::DEFAULT_TOPIC;
 
 // Protocol is the first four bits
 pub const MASK_MESSAGE_PROTOCOL: u8             = 0xF0; // 0b11110000
 pub const MESSAGE_PROTOCOL_SAMPLING_MESSAGE: u8 = 0x10; // 0b00010000
 pub const MESSAGE_PROTOCOL_HEADER_MESSAGE: u8   = 0x20; // 0b00100000
+pub const MESSAGE_PROTOCOL_MEMBERSHIP_MESSAGE: u8 = 0x30; // 0b00110000
 pub const MESSAGE_PROTOCOL_CONTENT_MESSAGE: u8  = 0x40; // 0b01000000
+pub const MESSAGE_PROTOCOL_HANDSHAKE_MESSAGE: u8 = 0x50; // 0b01010000
+pub const MESSAGE_PROTOCOL_SECURE_MESSAGE: u8    = 0x60; // 0b01100000
+pub const MESSAGE_PROTOCOL_SUMMARY_MESSAGE: u8   = 0x70; // 0b01110000
 pub const MESSAGE_PROTOCOL_NOOP_MESSAGE: u8     = 0x80; // 0b10000000
+pub const MESSAGE_PROTOCOL_BATCH_MESSAGE: u8     = 0x90; // 0b10010000
+
+// Envelope is the last four bits: legacy messages follow the protocol byte directly, versioned
+// ones are preceded by the protocol version (2 bytes, big endian)
+pub const MASK_MESSAGE_ENVELOPE: u8             = 0x0F; // 0b00001111
+pub const MESSAGE_ENVELOPE_LEGACY: u8           = 0x00; // 0b00000000
+pub const MESSAGE_ENVELOPE_VERSIONED: u8        = 0x01; // 0b00000001
 
 /// The message type. [MessageType::Request] is used to advertise the node data or request advertised data;
 /// [MessageType::Response] is used to advertise back in response to a request, or provide the requested data.
@@ -17,7 +30,10 @@ pub enum MessageType {
     Response = 2,
 }
 
-/// Message trait with generic implementation for serialization and deserialization
+/// Message trait with generic implementation for serialization and deserialization.
+///
+/// Nodes of different versions exchange the same messages: unknown fields are ignored, so
+/// fields added later must be `#[serde(default)]` for messages of older nodes to decode.
 pub trait Message {
 
     /// The message protocol, used for serialization/deserialization
@@ -101,6 +117,9 @@ pub struct ContentMessage {
     sender: String,
     message_type: MessageType,
     content: HashMap<String, Vec<u8>>,
+    /// Topics of the updates not in the default topic, by digest
+    #[serde(default)]
+    topics: HashMap<String, String>,
 }
 impl ContentMessage {
     pub fn new_request(sender: String, content: HashMap<String, Vec<u8>>) -> Self {
@@ -114,8 +133,16 @@ impl ContentMessage {
             sender,
             message_type,
             content,
+            topics: HashMap::new(),
         }
     }
+    pub fn set_topics(&mut self, topics: HashMap<String, String>) {
+        self.topics = topics
+    }
+    /// Returns the topics of the updates not in the default topic, by digest
+    pub fn topics(&self) -> &HashMap<String, String> {
+        &self.topics
+    }
     pub fn sender(&self) -> &str {
         &self.sender
     }
@@ -136,3 +163,267 @@ impl Message for ContentMessage {
         MESSAGE_PROTOCOL_CONTENT_MESSAGE
     }
 }
+
+/// Serializes a `Vec<u8>` as a byte string instead of a sequence of integers
+mod wire_bytes {
+    use serde::{Deserializer, Serializer};
+    use serde::de::{SeqAccess, Visitor};
+
+    pub fn serialize<S>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error>
+    where S: Serializer
+    {
+        serializer.serialize_bytes(bytes)
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Vec<u8>, D::Error>
+    where D: Deserializer<'de>
+    {
+        deserializer.deserialize_byte_buf(BytesVisitor)
+    }
+
+    struct BytesVisitor;
+
+    impl<'de> Visitor<'de> for BytesVisitor {
+        type Value = Vec<u8>;
+
+        fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+            formatter.write_str("a byte string")
+        }
+
+        fn visit_bytes<E>(self, bytes: &[u8]) -> Result<Self::Value, E> {
+            Ok(bytes.to_vec())
+        }
+
+        fn visit_byte_buf<E>(self, bytes: Vec<u8>) -> Result<Self::Value, E> {
+            Ok(bytes)
+        }
+
+        fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+        where A: SeqAccess<'de>
+        {
+            let mut bytes = Vec::new();
+            while let Some(byte) = seq.next_element()? {
+                bytes.push(byte);
+            }
+            Ok(bytes)
+        }
+    }
+}
+
+/// A Bloom filter over 32 byte update digests. The digests being uniformly distributed, their
+/// bytes are used as hashes, mixed with a seed drawn for each filter so that a digest wrongly
+/// reported as present is not missed on every round.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DigestFilter {
+    seed: u64,
+    hashes: u32,
+    #[serde(with = "wire_bytes")]
+    bits: Vec<u8>,
+}
+impl DigestFilter {
+    /// Bits per digest, for a false positive rate around 1%
+    const BITS_PER_DIGEST: usize = 10;
+    const HASHES: u32 = 7;
+
+    /// Creates an empty filter sized for `expected` digests
+    ///
+    /// # Arguments
+    ///
+    /// * `expected` - Number of digests that will be inserted
+    /// * `seed` - Mixed with the digests to choose their bits
+    pub fn new(expected: usize, seed: u64) -> Self {
+        let bytes = std::cmp::max(8, (expected * Self::BITS_PER_DIGEST).div_ceil(8));
+        DigestFilter {
+            seed,
+            hashes: Self::HASHES,
+            bits: vec![0; bytes],
+        }
+    }
+
+    fn positions(&self, digest: &[u8; 32]) -> impl Iterator<Item = usize> {
+        let mut first = [0; 8];
+        let mut second = [0; 8];
+        first.copy_from_slice(&digest[0..8]);
+        second.copy_from_slice(&digest[8..16]);
+        let first = u64::from_le_bytes(first) ^ self.seed;
+        let second = (u64::from_le_bytes(second) ^ self.seed.rotate_left(32)) | 1;
+        let size = self.bits.len() as u64 * 8;
+        // a received filter may announce any number of hashes
+        (0..std::cmp::min(self.hashes, 32) as u64).map(move |index| (first.wrapping_add(index.wrapping_mul(second)) % size) as usize)
+    }
+
+    pub fn insert(&mut self, digest: &[u8; 32]) {
+        for position in self.positions(digest).collect::<Vec<usize>>() {
+            self.bits[position / 8] |= 1 << (position % 8);
+        }
+    }
+
+    /// Returns whether the filter may contain `digest`; it certainly does not when `false`
+    pub fn contains(&self, digest: &[u8; 32]) -> bool {
+        !self.bits.is_empty() && self.positions(digest).all(|position| self.bits[position / 8] & (1 << (position % 8)) != 0)
+    }
+}
+
+/// A compact summary of the updates known to a node, active or expired, replacing the list of
+/// headers with peers supporting [super::version::CAPABILITY_DELTA_SYNC]. A request is answered
+/// with the updates missing from the summary if pull is enabled, and with a response summary if
+/// push is enabled so that the requesting node sends the updates the responder misses.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SummaryMessage {
+    sender: String,
+    message_type: MessageType,
+    filter: DigestFilter,
+}
+impl SummaryMessage {
+    pub fn new_request(sender: String, filter: DigestFilter) -> Self {
+        Self::new(sender, MessageType::Request, filter)
+    }
+    pub fn new_response(sender: String, filter: DigestFilter) -> Self {
+        Self::new(sender, MessageType::Response, filter)
+    }
+    fn new(sender: String, message_type: MessageType, filter: DigestFilter) -> Self {
+        SummaryMessage {
+            sender,
+            message_type,
+            filter,
+        }
+    }
+    pub fn sender(&self) -> &str {
+        &self.sender
+    }
+    pub fn message_type(&self) -> &MessageType {
+        &self.message_type
+    }
+    pub fn filter(&self) -> &DigestFilter {
+        &self.filter
+    }
+}
+impl Message for SummaryMessage {
+    fn protocol(&self) -> u8 {
+        MESSAGE_PROTOCOL_SUMMARY_MESSAGE
+    }
+}
+
+/// Topic and content of an update received in a batch
+pub type BatchedUpdate = (String, Vec<u8>);
+
+/// Update contents sent in one message, each preceded by its length (4 bytes, big endian), the
+/// whole optionally compressed. Digests are not sent, receivers compute them from the contents
+/// and topics.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BatchMessage {
+    sender: String,
+    compressed: bool,
+    #[serde(with = "wire_bytes")]
+    payload: Vec<u8>,
+    /// Topic of each update, empty when all of them are in the default topic
+    #[serde(default)]
+    topics: Vec<String>,
+}
+impl BatchMessage {
+    /// Creates a batch of update contents
+    ///
+    /// # Arguments
+    ///
+    /// * `sender` - Address of the sending node
+    /// * `updates` - Topics and contents of the updates
+    /// * `compress` - Whether the contents are compressed, done only when it makes them smaller
+    pub fn new(sender: String, updates: &[(&str, &[u8])], compress: bool) -> Self {
+        let mut payload = Vec::with_capacity(updates.iter().map(|(_, content)| content.len() + 4).sum());
+        for (_, content) in updates {
+            payload.extend_from_slice(&(content.len() as u32).to_be_bytes());
+            payload.extend_from_slice(content);
+        }
+        let topics =
+            if updates.iter().all(|(topic, _)| *topic == DEFAULT_TOPIC) { Vec::new() }
+            else { updates.iter().map(|(topic, _)| topic.to_string()).collect() };
+        let compressed = if compress { Some(compression::compress(&payload)) } else { None };
+        match compressed {
+            Some(compressed) if compressed.len() < payload.len() => BatchMessage { sender, compressed: true, payload: compressed, topics },
+            _ => BatchMessage { sender, compressed: false, payload, topics },
+        }
+    }
+    pub fn sender(&self) -> &str {
+        &self.sender
+    }
+    pub fn is_compressed(&self) -> bool {
+        self.compressed
+    }
+    /// Returns the topics and contents of the updates in the batch
+    pub fn contents(&self) -> Result<Vec<BatchedUpdate>, Box<dyn Error>> {
+        let decompressed;
+        let mut payload = if self.compressed {
+            decompressed = compression::decompress(&self.payload, self.payload.len() * compression::MAX_EXPANSION)?;
+            &decompressed[..]
+        }
+        else {
+            &self.payload[..]
+        };
+        let mut contents = Vec::new();
+        while !payload.is_empty() {
+            if payload.len() < 4 {
+                Err("Truncated batch")?
+            }
+            let length = u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]) as usize;
+            if payload.len() - 4 < length {
+                Err("Truncated batch")?
+            }
+            let topic = match self.topics.get(contents.len()) {
+                Some(topic) => topic.to_owned(),
+                None if self.topics.is_empty() => DEFAULT_TOPIC.to_owned(),
+                None => Err("Missing topic in batch")?,
+            };
+            contents.push((topic, payload[4..4 + length].to_vec()));
+            payload = &payload[4 + length..];
+        }
+        Ok(contents)
+    }
+}
+impl Message for BatchMessage {
+    fn protocol(&self) -> u8 {
+        MESSAGE_PROTOCOL_BATCH_MESSAGE
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn digest(index: u32) -> [u8; 32] {
+        *blake3::hash(&index.to_be_bytes()).as_bytes()
+    }
+
+    #[test]
+    fn digest_filter_survives_serialization() {
+        let mut filter = DigestFilter::new(1000, 42);
+        (0..1000).for_each(|index| filter.insert(&digest(index)));
+        let message = SummaryMessage::new_request("127.0.0.1:9000".to_owned(), filter);
+        let bytes = message.as_bytes().unwrap();
+        // bits are sent as a byte string, around 10 bits per digest
+        assert!(bytes.len() < 1400);
+
+        let message = SummaryMessage::from_bytes(&bytes).unwrap();
+        assert!((0..1000).all(|index| message.filter().contains(&digest(index))));
+        let false_positives = (1000..11_000).filter(|index| message.filter().contains(&digest(*index))).count();
+        assert!(false_positives < 300, "{} false positives", false_positives);
+    }
+
+    #[test]
+    fn batches_round_trip() {
+        let contents: Vec<(String, Vec<u8>)> = vec![
+            (DEFAULT_TOPIC.to_owned(), b"first".to_vec()),
+            ("invalidation".to_owned(), Vec::new()),
+            (DEFAULT_TOPIC.to_owned(), b"second second second second".to_vec()),
+        ];
+        let updates: Vec<(&str, &[u8])> = contents.iter().map(|(topic, content)| (&topic[..], &content[..])).collect();
+        for compress in [false, true].iter() {
+            let batch = BatchMessage::new("127.0.0.1:9000".to_owned(), &updates, *compress);
+            let batch = BatchMessage::from_bytes(&batch.as_bytes().unwrap()).unwrap();
+            assert_eq!(batch.contents().unwrap(), contents);
+        }
+        let repetitive = vec![7u8; 4096];
+        let batch = BatchMessage::new("127.0.0.1:9000".to_owned(), &[(DEFAULT_TOPIC, &repetitive[..])], true);
+        assert!(batch.is_compressed());
+        assert_eq!(batch.contents().unwrap(), vec![(DEFAULT_TOPIC.to_owned(), repetitive)]);
+    }
+}
@@ -4,4 +4,10 @@ pub mod peer;
 pub mod sampling;
 pub mod config;
 pub mod network;
-pub mod update;
\ No newline at end of file
+pub mod update;
+pub mod membership;
+pub mod transport;
+pub mod simulation;
+pub mod version;
+pub mod security;
+pub mod compression;
\ No newline at end of file
@@ -1,15 +1,28 @@
-use std::net::{SocketAddr, TcpStream};
-use std::io::{Write, Read};
+use std::net::{SocketAddr, TcpStream, TcpListener};
+use std::io::{self, Write, Read};
 use std::thread::JoinHandle;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::AtomicBool;
+use std::sync::mpsc::SyncSender;
+use std::collections::HashMap;
 use std::error::Error;
+use std::time::{Duration, Instant};
 use serde::Serialize;
-use std::sync::mpsc::Sender;
-use super::message::{Message, MASK_MESSAGE_PROTOCOL, MESSAGE_PROTOCOL_SAMPLING_MESSAGE, MESSAGE_PROTOCOL_HEADER_MESSAGE, MESSAGE_PROTOCOL_CONTENT_MESSAGE, MESSAGE_PROTOCOL_NOOP_MESSAGE};
-use super::sampling::PeerSamplingMessage;
-use super::message::{HeaderMessage, ContentMessage};
+use super::config::TcpConfig;
+use super::message::Message;
+use super::transport::Dispatcher;
 
-/// Sends a message to the specified address
+// Frame layout: magic (2 bytes), version, protocol, payload length (4 bytes, big endian),
+// payload, checksum (4 bytes)
+pub const FRAME_MAGIC: [u8; 2] = [0x47, 0x53]; // "GS"
+pub const FRAME_VERSION: u8 = 1;
+const FRAME_HEADER_SIZE: usize = 8;
+const FRAME_CHECKSUM_SIZE: usize = 4;
+
+/// How often the listener checks for a shutdown request when no connection comes in
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Sends a message to the specified address, over a new connection
 ///
 /// # Arguments
 ///
@@ -22,8 +35,7 @@ where M: Message + Serialize
         Ok(mut bytes) => {
             // insert protocol byte for deserialization
             bytes.insert(0, message.protocol());
-            let written = TcpStream::connect(address)?.write(&bytes)?;
-            Ok(written)
+            send_frame(address, &bytes)
         }
         Err(e) => {
             log::error!("Could not serialize message");
@@ -32,73 +44,353 @@ where M: Message + Serialize
     }
 }
 
-/// Starts listening to TCP connections
+/// Sends a message (protocol byte followed by the message) to the specified address, over a new connection
+///
+/// # Arguments
+///
+/// * `address` - Address of the recipient
+/// * `frame` - The protocol byte followed by the message
+pub fn send_frame(address: &SocketAddr, frame: &[u8]) -> Result<usize, Box<dyn Error>> {
+    let config = TcpConfig::default();
+    let bytes = encode_frame(frame, config.max_frame_size())?;
+    connect(address, &config)?.write_all(&bytes)?;
+    Ok(bytes.len())
+}
+
+/// Wraps a message (protocol byte followed by the message) in a frame
+///
+/// # Arguments
+///
+/// * `frame` - The protocol byte followed by the message
+/// * `max_frame_size` - Maximum size of the message
+pub fn encode_frame(frame: &[u8], max_frame_size: usize) -> Result<Vec<u8>, Box<dyn Error>> {
+    let (protocol, payload) = match frame.split_first() {
+        Some(split) => split,
+        None => Err("Empty frame")?,
+    };
+    if payload.len() > max_frame_size {
+        Err(format!("Frame of {} bytes exceeds the maximum of {} bytes", payload.len(), max_frame_size))?
+    }
+    let mut bytes = Vec::with_capacity(FRAME_HEADER_SIZE + payload.len() + FRAME_CHECKSUM_SIZE);
+    bytes.extend_from_slice(&FRAME_MAGIC);
+    bytes.push(FRAME_VERSION);
+    bytes.push(*protocol);
+    bytes.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    bytes.extend_from_slice(payload);
+    let checksum = checksum(&bytes);
+    bytes.extend_from_slice(&checksum);
+    Ok(bytes)
+}
+
+/// What reading a connection produced
+#[derive(Debug, PartialEq, Eq)]
+pub enum Incoming {
+    /// A complete frame, the protocol byte followed by the message
+    Frame(Vec<u8>),
+    /// Nothing arrived before the read timeout
+    Idle,
+    /// The peer closed the connection
+    Closed,
+}
+
+/// Reads a frame, timing out mid-frame or a malformed frame are errors
+///
+/// # Arguments
+///
+/// * `reader` - The connection
+/// * `max_frame_size` - Maximum size of the message
+pub fn read_frame<R: Read>(reader: &mut R, max_frame_size: usize) -> io::Result<Incoming> {
+    let mut header = [0u8; FRAME_HEADER_SIZE];
+    match reader.read(&mut header[..1]) {
+        Ok(0) => return Ok(Incoming::Closed),
+        Ok(_) => (),
+        Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut | io::ErrorKind::Interrupted) => return Ok(Incoming::Idle),
+        Err(e) => return Err(e),
+    }
+    reader.read_exact(&mut header[1..])?;
+    if header[..2] != FRAME_MAGIC {
+        return Err(invalid_data("Bad frame magic".to_owned()));
+    }
+    if header[2] != FRAME_VERSION {
+        return Err(invalid_data(format!("Unsupported frame version {}", header[2])));
+    }
+    let length = u32::from_be_bytes([header[4], header[5], header[6], header[7]]) as usize;
+    if length > max_frame_size {
+        return Err(invalid_data(format!("Frame of {} bytes exceeds the maximum of {} bytes", length, max_frame_size)));
+    }
+
+    let mut bytes = Vec::with_capacity(FRAME_HEADER_SIZE + length + FRAME_CHECKSUM_SIZE);
+    bytes.extend_from_slice(&header);
+    bytes.resize(FRAME_HEADER_SIZE + length + FRAME_CHECKSUM_SIZE, 0);
+    reader.read_exact(&mut bytes[FRAME_HEADER_SIZE..])?;
+    let (content, received) = bytes.split_at(FRAME_HEADER_SIZE + length);
+    if checksum(content) != received {
+        return Err(invalid_data("Frame checksum mismatch".to_owned()));
+    }
+
+    let mut frame = Vec::with_capacity(1 + length);
+    frame.push(header[3]);
+    frame.extend_from_slice(&content[FRAME_HEADER_SIZE..]);
+    Ok(Incoming::Frame(frame))
+}
+
+fn checksum(bytes: &[u8]) -> [u8; FRAME_CHECKSUM_SIZE] {
+    let hash = blake3::hash(bytes);
+    let mut checksum = [0u8; FRAME_CHECKSUM_SIZE];
+    checksum.copy_from_slice(&hash.as_bytes()[..FRAME_CHECKSUM_SIZE]);
+    checksum
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn connect(address: &SocketAddr, config: &TcpConfig) -> io::Result<TcpStream> {
+    let stream = TcpStream::connect_timeout(address, Duration::from_millis(config.connect_timeout()))?;
+    stream.set_write_timeout(Some(Duration::from_millis(config.write_timeout())))?;
+    stream.set_nodelay(true)?;
+    Ok(stream)
+}
+
+/// Outbound connections, kept open and reused for every frame sent to the same peer
+pub struct ConnectionPool {
+    config: TcpConfig,
+    connections: Mutex<HashMap<SocketAddr, Arc<Mutex<TcpStream>>>>,
+}
+
+impl ConnectionPool {
+    /// Creates an empty pool
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The TCP parameters, see [TcpConfig]
+    pub fn new(config: TcpConfig) -> Self {
+        ConnectionPool {
+            config,
+            connections: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Sends a message (protocol byte followed by the message) to the specified address.
+    /// A pooled connection found broken is replaced once.
+    ///
+    /// # Arguments
+    ///
+    /// * `address` - Address of the recipient
+    /// * `frame` - The protocol byte followed by the message
+    pub fn send(&self, address: &SocketAddr, frame: &[u8]) -> Result<usize, Box<dyn Error>> {
+        let bytes = encode_frame(frame, self.config.max_frame_size())?;
+        let connection = self.connection(address)?;
+        let written = connection.lock().unwrap().write_all(&bytes);
+        if let Err(e) = written {
+            log::debug!("Connection to {} broken, reconnecting: {}", address, e);
+            self.close(address);
+            let connection = self.connection(address)?;
+            let written = connection.lock().unwrap().write_all(&bytes);
+            if written.is_err() {
+                self.close(address);
+            }
+            written?;
+        }
+        Ok(bytes.len())
+    }
+
+    /// Returns the number of open connections
+    pub fn len(&self) -> usize {
+        self.connections.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Closes the connection to the specified address
+    pub fn close(&self, address: &SocketAddr) {
+        self.connections.lock().unwrap().remove(address);
+    }
+
+    fn connection(&self, address: &SocketAddr) -> io::Result<Arc<Mutex<TcpStream>>> {
+        {
+            let mut connections = self.connections.lock().unwrap();
+            if let Some(connection) = connections.get(address) {
+                if is_open(&connection.lock().unwrap()) {
+                    return Ok(Arc::clone(connection));
+                }
+                log::debug!("Connection to {} closed by peer", address);
+                connections.remove(address);
+            }
+        }
+        // connect without holding the pool, other peers remain reachable meanwhile
+        let connection = Arc::new(Mutex::new(connect(address, &self.config)?));
+        Ok(Arc::clone(self.connections.lock().unwrap().entry(*address).or_insert(connection)))
+    }
+}
+
+/// Checks whether the peer closed the connection, since writing to it would seem to succeed
+/// and lose the frame
+fn is_open(stream: &TcpStream) -> bool {
+    if stream.set_nonblocking(true).is_err() {
+        return false;
+    }
+    let open = match stream.peek(&mut [0u8; 1]) {
+        Ok(0) => false,
+        Ok(_) => true,
+        Err(e) => e.kind() == io::ErrorKind::WouldBlock,
+    };
+    stream.set_nonblocking(false).is_ok() && open
+}
+
+/// Starts listening to TCP connections. Each connection is read by its own thread and the
+/// received frames are dispatched by a pool of workers.
 ///
 /// # Arguments
 ///
 /// * `address` - Bind address
+/// * `config` - The TCP parameters, see [TcpConfig]
 /// * `shutdown` - Flag used to check for a shutdown request
-/// * `peer_sampling_sender` - Used to dispatch peer sampling messages
-/// * `header_sender` - Used to dispatch gossip header messages
-/// * `content_sender` - Used to dispatch gossip content messages
-pub fn listen(address: &SocketAddr, shutdown: Arc<std::sync::atomic::AtomicBool>, peer_sampling_sender: Sender<PeerSamplingMessage>, header_sender: Sender<HeaderMessage>, content_sender: Sender<ContentMessage>) -> std::io::Result<JoinHandle<()>> {
+/// * `dispatcher` - Used to dispatch received messages to their protocol
+pub fn listen(address: &SocketAddr, config: &TcpConfig, shutdown: Arc<AtomicBool>, dispatcher: Dispatcher) -> io::Result<JoinHandle<()>> {
 
-    let listener = std::net::TcpListener::bind(address)?;
+    let listener = TcpListener::bind(address)?;
+    listener.set_nonblocking(true)?;
     log::info!("Listener started at {}", address);
+    let config = config.clone();
+    let address = *address;
     Ok(std::thread::Builder::new().name(format!("{} - gossip listener", address)).spawn(move || {
         log::info!("Started listener thread");
-        // TODO: handle hanging connections where peer connect but does not write
-        for incoming_stream in listener.incoming() {
+        let (frame_sender, frame_receiver) = std::sync::mpsc::sync_channel::<Vec<u8>>(config.queue_size());
+        let frame_receiver = Arc::new(Mutex::new(frame_receiver));
+        let workers: Vec<JoinHandle<()>> = (0..std::cmp::max(1, config.workers())).map(|index| {
+            let frame_receiver = Arc::clone(&frame_receiver);
+            let dispatcher = dispatcher.clone();
+            std::thread::Builder::new().name(format!("{} - gossip worker {}", address, index)).spawn(move || {
+                loop {
+                    let frame = frame_receiver.lock().unwrap().recv();
+                    match frame {
+                        Ok(frame) => match dispatcher.dispatch(&frame) {
+                            Ok(()) => log::trace!("Message parsed successfully"),
+                            Err(e) => log::error!("{:?}", e),
+                        },
+                        Err(_) => break,
+                    }
+                }
+            }).unwrap()
+        }).collect();
+        drop(dispatcher);
 
+        let mut readers: Vec<JoinHandle<()>> = Vec::new();
+        loop {
             // check for shutdown request
             if shutdown.load(std::sync::atomic::Ordering::SeqCst) {
                 log::info!("Shutdown requested");
                 break;
             }
 
-            // TODO: handle in new thread or worker
-            // handle request
-            match incoming_stream {
-                Ok(mut stream) => {
-                    let mut buf = Vec::new();
-                    match stream.read_to_end(&mut buf) {
-                        Ok(read) => {
-                            if read > 0 {
-                                match handle_message(buf, &peer_sampling_sender, &header_sender, &content_sender) {
-                                    Ok(()) => log::trace!("Message parsed successfully"),
-                                    Err(e) => log::error!("{:?}", e),
-                                }
-                            }
-                        },
-                        Err(e) => log::error!("Error receiving data: {:?}", e),
-                    }
+            match listener.accept() {
+                Ok((stream, peer)) => {
+                    readers.retain(|reader| !reader.is_finished());
+                    let frame_sender = frame_sender.clone();
+                    let shutdown = Arc::clone(&shutdown);
+                    let config = config.clone();
+                    let reader = std::thread::Builder::new().name(format!("{} - connection from {}", address, peer)).spawn(move || {
+                        read_connection(stream, peer, frame_sender, shutdown, config);
+                    }).unwrap();
+                    readers.push(reader);
                 }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => std::thread::sleep(ACCEPT_POLL_INTERVAL),
                 Err(e) => log::warn!("Connection failed: {}", e),
             }
         }
+
+        // readers stop on the shutdown flag, then workers once every frame was handled
+        drop(frame_sender);
+        for handle in readers.into_iter().chain(workers) {
+            if let Err(e) = handle.join() {
+                log::error!("Error joining thread: {:?}", e);
+            }
+        }
         log::info!("Listener thread exiting");
     }).unwrap())
 }
 
-fn handle_message(buffer: Vec<u8>, peer_sampling_sender: &Sender<PeerSamplingMessage>, header_sender: &Sender<HeaderMessage>, content_sender: &Sender<ContentMessage>) -> Result<(), Box<dyn Error>> {
-    let protocol = buffer[0] & MASK_MESSAGE_PROTOCOL;
-    match protocol {
-        MESSAGE_PROTOCOL_NOOP_MESSAGE => Ok(()),
-        MESSAGE_PROTOCOL_SAMPLING_MESSAGE => {
-            let message = PeerSamplingMessage::from_bytes(&buffer[1..])?;
-            peer_sampling_sender.send(message)?;
-            Ok(())
-        }
-        MESSAGE_PROTOCOL_CONTENT_MESSAGE => {
-            let message = ContentMessage::from_bytes(&buffer[1..])?;
-            content_sender.send(message)?;
-            Ok(())
+/// Reads frames from an inbound connection until the peer closes it, it stays idle for too
+/// long, a malformed frame arrives or shutdown is requested
+fn read_connection(mut stream: TcpStream, peer: SocketAddr, frame_sender: SyncSender<Vec<u8>>, shutdown: Arc<AtomicBool>, config: TcpConfig) {
+    let setup = stream.set_nonblocking(false)
+        .and_then(|_| stream.set_read_timeout(Some(Duration::from_millis(std::cmp::max(1, config.read_timeout())))));
+    if let Err(e) = setup {
+        log::error!("Could not set up connection from {}: {}", peer, e);
+        return;
+    }
+    let idle_timeout = Duration::from_millis(config.idle_timeout());
+    let mut last_frame = Instant::now();
+    while !shutdown.load(std::sync::atomic::Ordering::SeqCst) {
+        match read_frame(&mut stream, config.max_frame_size()) {
+            Ok(Incoming::Frame(frame)) => {
+                last_frame = Instant::now();
+                if frame_sender.send(frame).is_err() {
+                    break;
+                }
+            }
+            Ok(Incoming::Idle) => {
+                if last_frame.elapsed() >= idle_timeout {
+                    log::debug!("Closing idle connection from {}", peer);
+                    break;
+                }
+            }
+            Ok(Incoming::Closed) => break,
+            Err(e) => {
+                log::warn!("Dropping connection from {}: {}", peer, e);
+                break;
+            }
         }
-        MESSAGE_PROTOCOL_HEADER_MESSAGE => {
-            let message = HeaderMessage::from_bytes(&buffer[1..])?;
-            header_sender.send(message)?;
-            Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_round_trip_and_are_checked() {
+        let message = vec![0x20, 1, 2, 3];
+        let bytes = encode_frame(&message, 16).unwrap();
+        assert_eq!(read_frame(&mut bytes.as_slice(), 16).unwrap(), Incoming::Frame(message.clone()));
+        assert_eq!(read_frame(&mut &[][..], 16).unwrap(), Incoming::Closed);
+
+        let mut corrupted = bytes.clone();
+        corrupted[FRAME_HEADER_SIZE] ^= 1;
+        assert_eq!(read_frame(&mut corrupted.as_slice(), 16).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(read_frame(&mut bytes.as_slice(), 2).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert!(encode_frame(&message, 2).is_err());
+        // truncated frame
+        assert!(read_frame(&mut &bytes[..bytes.len() - 1], 16).is_err());
+    }
+
+    #[test]
+    fn pooled_connection_carries_consecutive_frames() {
+        let address: SocketAddr = "127.0.0.1:47210".parse().unwrap();
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let (tx_sampling, _rx_sampling) = std::sync::mpsc::channel();
+        let (tx_header, rx_header) = std::sync::mpsc::channel();
+        let dispatcher = Dispatcher::new(tx_sampling, tx_header, std::sync::mpsc::channel().0, std::sync::mpsc::channel().0);
+        let listener = listen(&address, &TcpConfig::default(), Arc::clone(&shutdown), dispatcher).unwrap();
+
+        let pool = ConnectionPool::new(TcpConfig::default());
+        for index in 0..3 {
+            let mut message = super::super::message::HeaderMessage::new_request(format!("peer {}", index));
+            message.set_headers(vec![index.to_string()]);
+            let mut frame = message.as_bytes().unwrap();
+            frame.insert(0, message.protocol());
+            pool.send(&address, &frame).unwrap();
         }
-        _ => Err(format!("Unknown protocol: {}", protocol))?
+        // workers may dispatch frames of one connection out of order
+        let mut senders: Vec<String> = (0..3).map(|_| rx_header.recv_timeout(Duration::from_secs(5)).unwrap().sender().to_owned()).collect();
+        senders.sort();
+        assert_eq!(senders, vec!["peer 0", "peer 1", "peer 2"]);
+        assert_eq!(pool.len(), 1);
+
+        shutdown.store(true, std::sync::atomic::Ordering::SeqCst);
+        listener.join().unwrap();
     }
 }
@@ -1,6 +1,46 @@
 use std::hash::{Hash, Hasher};
 use serde::{Serialize, Deserialize};
 
+/// Where a node runs, used to prefer nearby peers. Nodes without a zone are treated as
+/// remote by every other node.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeerLabels {
+    /// Availability zone of the node
+    #[serde(default)]
+    zone: Option<String>,
+    /// Rack of the node within its zone
+    #[serde(default)]
+    rack: Option<String>,
+    /// Free-form tags
+    #[serde(default)]
+    tags: Vec<String>,
+}
+
+impl PeerLabels {
+    /// Creates labels
+    ///
+    /// # Arguments
+    ///
+    /// * `zone` - Availability zone of the node
+    /// * `rack` - Rack of the node within its zone
+    /// * `tags` - Free-form tags
+    pub fn new(zone: Option<String>, rack: Option<String>, tags: Vec<String>) -> Self {
+        PeerLabels { zone, rack, tags }
+    }
+
+    pub fn zone(&self) -> Option<&str> {
+        self.zone.as_deref()
+    }
+
+    pub fn rack(&self) -> Option<&str> {
+        self.rack.as_deref()
+    }
+
+    pub fn tags(&self) -> &[String] {
+        &self.tags
+    }
+}
+
 /// Information about a peer
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Peer {
@@ -8,6 +48,9 @@ pub struct Peer {
     address: String,
     /// Age of the peer
     age: u16,
+    /// Location of the peer, absent from the views of nodes predating labels
+    #[serde(default)]
+    labels: PeerLabels,
 }
 
 impl Peer {
@@ -17,7 +60,17 @@ impl Peer {
     ///
     /// * `address` - Network address of peer
     pub fn new(address: String) -> Peer {
-        Peer {address, age: 0}
+        Self::new_with_labels(address, PeerLabels::default())
+    }
+
+    /// Creates a new peer with the specified address and labels, and age 0
+    ///
+    /// # Arguments
+    ///
+    /// * `address` - Network address of peer
+    /// * `labels` - Location of the peer
+    pub fn new_with_labels(address: String, labels: PeerLabels) -> Peer {
+        Peer {address, age: 0, labels}
     }
 
     /// Increments the age of peer by one
@@ -35,6 +88,9 @@ impl Peer {
     /// Returns the address of peer
     pub fn address(&self) -> &str { &self.address }
 
+    /// Returns the location of peer
+    pub fn labels(&self) -> &PeerLabels { &self.labels }
+
 }
 impl Eq for Peer {}
 impl PartialEq for Peer {
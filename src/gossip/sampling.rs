@@ -2,22 +2,25 @@
 #![allow(unused_must_use)]
 
 use std::fmt::Debug;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, RwLock};
 use std::thread::JoinHandle;
 use std::sync::atomic::AtomicBool;
 use std::net::SocketAddr;
-use rand::Rng;
+use std::time::{Duration, Instant};
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
 use rand::seq::SliceRandom;
 use std::error::Error;
 use std::sync::mpsc::Receiver;
-use std::collections::{HashSet, VecDeque};
-use std::iter::FromIterator;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
 use serde::{Serialize, Deserialize};
-use super::config::PeerSamplingConfig;
+use super::config::{PeerSamplingConfig, TopologyConfig};
 use super::peer::Peer;
 use super::message::{self, Message, MESSAGE_PROTOCOL_SAMPLING_MESSAGE};
 use super::message::NoopMessage;
 use super::message::MessageType;
+use super::version::VersionedTransport;
+use super::transport::{self, TcpTransport, Transport};
 
 /// A peer sampling protocol message
 #[derive(Debug, Serialize, Deserialize)]
@@ -77,14 +80,20 @@ impl Message for PeerSamplingMessage {
 pub struct PeerSamplingService {
     /// Peer address
     address: SocketAddr,
-    /// Protocol parameters
-    config: PeerSamplingConfig,
+    /// Protocol parameters, read again at each cycle
+    config: Arc<RwLock<PeerSamplingConfig>>,
     /// View containing a list of other peers
     view: Arc<Mutex<View>>,
     // Handles for activity threads
     thread_handles: Vec<JoinHandle<()>>,
     /// Handle for shutting down threads
     shutdown: Arc<AtomicBool>,
+    /// Transport used to exchange views
+    transport: Arc<dyn Transport>,
+    /// Received messages, when the service is polled instead of running its own threads
+    receiver: Option<Receiver<PeerSamplingMessage>>,
+    /// When the next sampling cycle starts, when the service is polled
+    next_sampling: Option<Instant>,
 }
 
 impl PeerSamplingService {
@@ -94,12 +103,26 @@ impl PeerSamplingService {
     ///
     /// * `config` - The parameters for the peer sampling protocol [PeerSamplingConfig]
     pub fn new(address: SocketAddr, config: PeerSamplingConfig) -> PeerSamplingService {
+        Self::new_with_transport(address, config, Arc::new(VersionedTransport::new(address, Arc::new(TcpTransport::default()))), rand::random())
+    }
+
+    /// Create a new peer sampling service exchanging views over `transport`
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The parameters for the peer sampling protocol [PeerSamplingConfig]
+    /// * `transport` - The transport used to send views, see [Transport]
+    /// * `seed` - Seed of the random choices of the protocol
+    pub fn new_with_transport(address: SocketAddr, config: PeerSamplingConfig, transport: Arc<dyn Transport>, seed: u64) -> PeerSamplingService {
         PeerSamplingService {
             address,
-            view: Arc::new(Mutex::new(View::new(address.to_string()))),
-            config,
+            view: Arc::new(Mutex::new(View::new(address.to_string(), config.topology().clone(), seed))),
+            config: Arc::new(RwLock::new(config)),
             thread_handles: Vec::new(),
             shutdown: Arc::new(AtomicBool::new(false)),
+            transport,
+            receiver: None,
+            next_sampling: None,
         }
     }
 
@@ -109,15 +132,7 @@ impl PeerSamplingService {
     ///
     /// * `initial_peer` - A closure returning the initial peer for starting the protocol
     pub fn init(&mut self, initial_peer: Box<dyn FnOnce() -> Option<Vec<Peer>>>, receiver: Receiver<PeerSamplingMessage>) {
-        // get address of initial peer
-        if let Some(initial_peers) = initial_peer() {
-            let mut view = self.view.lock().unwrap();
-            for peer in initial_peers {
-                if peer.address() != &self.address.to_string() {
-                    view.peers.push(peer);
-                }
-            }
-        }
+        self.add_initial_peers(initial_peer);
 
         // handle received messages
         let receiver_handle = self.start_receiver(receiver);
@@ -130,6 +145,53 @@ impl PeerSamplingService {
         log::info!("All activity threads were started");
     }
 
+    /// Initializes service without starting its threads, [PeerSamplingService::poll] then
+    /// runs the protocol.
+    ///
+    /// # Arguments
+    ///
+    /// * `initial_peer` - A closure returning the initial peer for starting the protocol
+    pub fn init_manual(&mut self, initial_peer: Box<dyn FnOnce() -> Option<Vec<Peer>>>, receiver: Receiver<PeerSamplingMessage>) {
+        self.add_initial_peers(initial_peer);
+        self.receiver = Some(receiver);
+    }
+
+    /// Handles the received messages and runs the sampling cycle if it is due at `now`.
+    /// Returns the number of messages handled.
+    pub fn poll(&mut self, now: Instant) -> usize {
+        let address = self.address.to_string();
+        let config = self.config.read().unwrap().clone();
+        let mut view = self.view.lock().unwrap();
+        let mut handled = 0;
+        if let Some(receiver) = self.receiver.as_ref() {
+            while let Ok(message) = receiver.try_recv() {
+                Self::handle_message(&address, &config, &mut view, self.transport.as_ref(), message);
+                handled += 1;
+            }
+        }
+        match self.next_sampling {
+            Some(next) if now < next => (),
+            Some(_) => {
+                Self::sampling_cycle(&address, &config, &mut view, self.transport.as_ref());
+                self.next_sampling = now.checked_add(Self::sampling_delay(&config, &mut view));
+            }
+            None => self.next_sampling = now.checked_add(Self::sampling_delay(&config, &mut view)),
+        }
+        handled
+    }
+
+    fn add_initial_peers(&mut self, initial_peer: Box<dyn FnOnce() -> Option<Vec<Peer>>>) {
+        // get address of initial peer
+        if let Some(initial_peers) = initial_peer() {
+            let mut view = self.view.lock().unwrap();
+            for peer in initial_peers {
+                if *peer.address() != self.address.to_string() {
+                    view.peers.push(peer);
+                }
+            }
+        }
+    }
+
     /// Returns a random peer for the client application.
     /// The peer is pseudo-random peer from the set of all peers.
     /// The local view is built using [Gossip-Based Peer Sampling].
@@ -144,6 +206,35 @@ impl PeerSamplingService {
             .collect()
     }
 
+    /// Returns a copy of the protocol parameters
+    pub fn config(&self) -> PeerSamplingConfig {
+        self.config.read().unwrap().clone()
+    }
+
+    /// Replaces the protocol parameters, picked up by the next cycle
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The new parameters, rejected if they do not validate
+    pub fn set_config(&self, config: PeerSamplingConfig) -> Result<(), Box<dyn Error>> {
+        config.validate()?;
+        let topology = config.topology().clone();
+        *self.config.write().unwrap() = config;
+        self.view.lock().unwrap().topology = topology;
+        Ok(())
+    }
+
+    /// Removes a peer from the view, e.g. once it is known to have failed or left
+    ///
+    /// # Arguments
+    ///
+    /// * `address` - Address of the peer
+    pub fn remove_peer(&mut self, address: &str) {
+        let mut view = self.view.lock().unwrap();
+        view.peers.retain(|peer| peer.address() != address);
+        view.queue.retain(|peer| peer.address() != address);
+    }
+
     /// Stops the threads related to peer sampling activity
     pub fn shutdown(&mut self) -> Result<(), Box<dyn Error>> {
         // request shutdown
@@ -152,7 +243,7 @@ impl PeerSamplingService {
             let mut view = self.view.lock().unwrap();
             view.peers.clear();
             view.queue.clear();
-            super::network::send(&view.host_address.parse()?, Box::new(NoopMessage))?;
+            transport::send(self.transport.as_ref(), &view.host_address.parse()?, &NoopMessage)?;
         }
         // wait for termination
         let mut join_error = false;
@@ -178,7 +269,7 @@ impl PeerSamplingService {
     /// * `config` - The configuration parameters
     /// * `view` - The current view
     fn build_buffer(address: String, config: &PeerSamplingConfig, view: &mut View) -> Vec<Peer> {
-        let mut buffer = vec![ Peer::new(address) ];
+        let mut buffer = vec![ Peer::new_with_labels(address, config.topology().labels().clone()) ];
         view.permute();
         view.move_oldest_to_end(config.healing_factor());
         buffer.append(&mut view.head(config.view_size()));
@@ -192,90 +283,75 @@ impl PeerSamplingService {
     /// * `receiver` - The channel used for receiving incoming messages
     fn start_receiver(&self, receiver: Receiver<PeerSamplingMessage>) -> JoinHandle<()>{
         let address = self.address.to_string();
-        let sampling_config = self.config.clone();
+        let config_arc = Arc::clone(&self.config);
         let view_arc = self.view.clone();
+        let transport = Arc::clone(&self.transport);
         std::thread::Builder::new().name(format!("{} - gbps receiver", &address)).spawn(move|| {
             log::info!("Started message handling thread");
             while let Ok(message) = receiver.recv() {
-                log::debug!("Received: {:?}", message);
+                let sampling_config = config_arc.read().unwrap().clone();
                 let mut view = view_arc.lock().unwrap();
-                if let MessageType::Request = message.message_type() {
-                    if sampling_config.is_pull() {
-                        let buffer = Self::build_buffer(address.clone(), &sampling_config, &mut view);
-                        log::debug!("Built response buffer: {:?}", buffer);
-                        if let Ok(remote_address) = message.sender().parse::<SocketAddr>() {
-                            match super::network::send(&remote_address, Box::new(PeerSamplingMessage::new_response(address.clone(), Some(buffer)))) {
-                                Ok(written) => log::trace!("Buffer sent successfully ({} bytes)", written),
-                                Err(e) => log::error!("Error sending buffer: {}", e),
-                            }
-                        }
-                        else {
-                            log::error!("Could not parse sender address {}", &message.sender());
-                        }
-                    }
-                }
+                Self::handle_message(&address, &sampling_config, &mut view, transport.as_ref(), message);
+            }
+            log::info!("Message handling thread exiting");
+        }).unwrap()
+    }
 
-                if let Some(buffer) = message.view() {
-                    view.select(sampling_config.view_size(), sampling_config.healing_factor(), sampling_config.swapping_factor(), &buffer);
+    /// Handles a message received from another peer
+    ///
+    /// # Arguments
+    ///
+    /// * `address` - Address of the node
+    /// * `sampling_config` - The configuration parameters
+    /// * `view` - The current view
+    /// * `transport` - Transport used to answer
+    /// * `message` - The received message
+    fn handle_message(address: &str, sampling_config: &PeerSamplingConfig, view: &mut View, transport: &dyn Transport, message: PeerSamplingMessage) {
+        log::debug!("Received: {:?}", message);
+        if let MessageType::Request = message.message_type() {
+            if sampling_config.is_pull() {
+                let buffer = Self::build_buffer(address.to_owned(), sampling_config, view);
+                log::debug!("Built response buffer: {:?}", buffer);
+                if let Ok(remote_address) = message.sender().parse::<SocketAddr>() {
+                    match transport::send(transport, &remote_address, &PeerSamplingMessage::new_response(address.to_owned(), Some(buffer))) {
+                        Ok(written) => log::trace!("Buffer sent successfully ({} bytes)", written),
+                        Err(e) => log::error!("Error sending buffer: {}", e),
+                    }
                 }
                 else {
-                    log::warn!("received a response with an empty buffer");
+                    log::error!("Could not parse sender address {}", &message.sender());
                 }
-
-                view.increase_age();
             }
-            log::info!("Message handling thread exiting");
-        }).unwrap()
+        }
+
+        if let Some(buffer) = message.view() {
+            view.select(sampling_config.view_size(), sampling_config.healing_factor(), sampling_config.swapping_factor(), buffer);
+        }
+        else {
+            log::warn!("received a response with an empty buffer");
+        }
+
+        view.increase_age();
     }
 
     /// Creates a thread that periodically executes the peer sampling
     fn start_sampling_activity(&self) -> JoinHandle<()> {
         let address = self.address.to_string();
-        let config = self.config.clone();
+        let config_arc = Arc::clone(&self.config);
         let view_arc = self.view.clone();
         let shutdown_requested = Arc::clone(&self.shutdown);
+        let transport = Arc::clone(&self.transport);
         std::thread::Builder::new().name(format!("{} - gbps sampling", address)).spawn(move || {
             log::info!("Started peer sampling thread");
             loop {
-                // Compute time for sleep cycle
-                let deviation =
-                    if config.sampling_deviation() == 0 { 0 }
-                    else { rand::thread_rng().gen_range(0..config.sampling_deviation()) };
-                let sleep_time = config.sampling_period() + deviation;
-                std::thread::sleep(std::time::Duration::from_millis(sleep_time));
+                // Compute time for sleep cycle, the configuration is read again each cycle to pick up changes
+                let config = config_arc.read().unwrap().clone();
+                let sleep_time = Self::sampling_delay(&config, &mut view_arc.lock().unwrap());
+                std::thread::sleep(sleep_time);
 
+                let config = config_arc.read().unwrap().clone();
                 let mut view = view_arc.lock().unwrap();
-                if let Some(peer) = view.select_peer() {
-                    if config.is_push() {
-                        let buffer = Self::build_buffer(address.clone(), &config, &mut view);
-                        // send local view
-                        if let Ok(remote_address) = &peer.address().parse::<SocketAddr>() {
-                            match super::network::send(remote_address, Box::new(PeerSamplingMessage::new_request(address.clone(), Some(buffer)))) {
-                                Ok(written) => log::trace!("Buffer sent successfully ({} bytes)", written),
-                                Err(e) => log::error!("Error sending buffer: {}", e),
-                            }
-                        }
-                        else {
-                            log::error!("Could not parse sender address {}", &peer.address());
-                        }
-                    }
-                    else {
-                        // send empty view to trigger response
-                        if let Ok(remote_address) = &peer.address().parse::<SocketAddr>() {
-                            match super::network::send(remote_address, Box::new(PeerSamplingMessage::new_request(address.clone(), None))) {
-                                Ok(written) => log::trace!("Empty view sent successfully ({} bytes)", written),
-                                Err(e) => log::error!("Error sending empty view: {}", e),
-                            }
-                        }
-                        else {
-                            log::error!("Could not parse sender address {}", &peer.address());
-                        }
-                    }
-                    view.increase_age();
-                }
-                else {
-                    log::warn!("No peer found for sampling")
-                }
+                Self::sampling_cycle(&address, &config, &mut view, transport.as_ref());
 
                 // check for shutdown request
                 if shutdown_requested.load(std::sync::atomic::Ordering::SeqCst) {
@@ -286,6 +362,56 @@ impl PeerSamplingService {
             log::info!("Peer sampling thread exiting");
         }).unwrap()
     }
+
+    /// Time until the next sampling cycle, the sampling period plus a random deviation
+    fn sampling_delay(config: &PeerSamplingConfig, view: &mut View) -> Duration {
+        let deviation =
+            if config.sampling_deviation() == 0 { 0 }
+            else { view.rng.gen_range(0..config.sampling_deviation()) };
+        Duration::from_millis(config.sampling_period().saturating_add(deviation))
+    }
+
+    /// Exchanges views with a random peer
+    ///
+    /// # Arguments
+    ///
+    /// * `address` - Address of the node
+    /// * `config` - The configuration parameters
+    /// * `view` - The current view
+    /// * `transport` - Transport used to send the view
+    fn sampling_cycle(address: &str, config: &PeerSamplingConfig, view: &mut View, transport: &dyn Transport) {
+        if let Some(peer) = view.select_peer() {
+            if config.is_push() {
+                let buffer = Self::build_buffer(address.to_owned(), config, view);
+                // send local view
+                if let Ok(remote_address) = &peer.address().parse::<SocketAddr>() {
+                    match transport::send(transport, remote_address, &PeerSamplingMessage::new_request(address.to_owned(), Some(buffer))) {
+                        Ok(written) => log::trace!("Buffer sent successfully ({} bytes)", written),
+                        Err(e) => log::error!("Error sending buffer: {}", e),
+                    }
+                }
+                else {
+                    log::error!("Could not parse sender address {}", &peer.address());
+                }
+            }
+            else {
+                // send empty view to trigger response
+                if let Ok(remote_address) = &peer.address().parse::<SocketAddr>() {
+                    match transport::send(transport, remote_address, &PeerSamplingMessage::new_request(address.to_owned(), None)) {
+                        Ok(written) => log::trace!("Empty view sent successfully ({} bytes)", written),
+                        Err(e) => log::error!("Error sending empty view: {}", e),
+                    }
+                }
+                else {
+                    log::error!("Could not parse sender address {}", &peer.address());
+                }
+            }
+            view.increase_age();
+        }
+        else {
+            log::warn!("No peer found for sampling")
+        }
+    }
 }
 
 /// The view at each node
@@ -296,6 +422,13 @@ struct View {
     peers: Vec<Peer>,
     /// The queue from which peer are retrieved for the application layer
     queue: VecDeque<Peer>,
+    /// Source of the random choices of the protocol
+    rng: StdRng,
+    /// Location of the node and selection policy
+    topology: TopologyConfig,
+    /// Number of peers selected, and how many of them were in another zone
+    selections: u64,
+    remote_selections: u64,
 }
 impl View {
     /// Creates a new view with the node's address
@@ -303,21 +436,42 @@ impl View {
     /// # Arguments
     ///
     /// * `address` - Addres of peer
-    fn new(host_address: String) -> View {
+    /// * `topology` - Location of the node and selection policy
+    /// * `seed` - Seed of the random choices
+    fn new(host_address: String, topology: TopologyConfig, seed: u64) -> View {
         View {
             host_address,
             peers: vec![],
             queue: VecDeque::new(),
+            rng: StdRng::seed_from_u64(seed),
+            topology,
+            selections: 0,
+            remote_selections: 0,
         }
     }
 
-    /// Randomly select a peer for exchanging views at each cycle
-    fn select_peer(&self) -> Option<Peer> {
+    /// Randomly select a peer for exchanging views at each cycle. A zone aware node selects a
+    /// peer of its zone, unless exchanges with other zones fell below their minimum share.
+    fn select_peer(&mut self) -> Option<Peer> {
         if self.peers.is_empty() {
             None
         }
+        else if let Some(zone) = self.topology.labels().zone() {
+            let (local, remote): (Vec<usize>, Vec<usize>) = (0..self.peers.len())
+                .partition(|index| self.peers[*index].labels().zone() == Some(zone));
+            self.selections += 1;
+            let remote_due = (self.remote_selections as f64) < self.topology.remote_ratio() * self.selections as f64;
+            let candidates =
+                if !remote.is_empty() && (remote_due || local.is_empty()) {
+                    self.remote_selections += 1;
+                    remote
+                }
+                else { local };
+            let selected_peer = candidates[self.rng.gen_range(0..candidates.len())];
+            Some(self.peers[selected_peer].clone())
+        }
         else {
-            let selected_peer = rand::thread_rng().gen_range(0..self.peers.len());
+            let selected_peer = self.rng.gen_range(0..self.peers.len());
             // let selected_peer = rand::thread_rng();
             // selected_peer.gen_range(0..self.peers.len());
             Some(self.peers[selected_peer].clone())
@@ -326,7 +480,7 @@ impl View {
 
     /// Randomly reorder the current view
     fn permute(&mut self) {
-        self.peers.shuffle(&mut rand::thread_rng());
+        self.peers.shuffle(&mut self.rng);
     }
 
     /// Move the oldest peers to the end of the view if the size
@@ -394,30 +548,37 @@ impl View {
             .for_each(|peer| self.peers.push(peer.clone()));
         // Perform peer selection algorithm
         self.remove_duplicates();
+        let candidates = if self.topology.is_zone_aware() { self.peers.clone() } else { Vec::new() };
         self.remove_old_items(c, h);
         self.remove_head(c, s);
-        self.remove_at_random(c);
+        if self.topology.is_zone_aware() {
+            self.remove_from_largest_groups(c);
+            self.restore_zones(&candidates);
+        }
+        else {
+            self.remove_at_random(c);
+        }
         // Update peer queue for application layer
         self.update_queue();
     }
 
-    /// Removes duplicates peers from the view and keep the most recent one
+    /// Removes duplicates peers from the view and keep the most recent one, at the position
+    /// of the first occurrence so that the order of the view stays deterministic
     fn remove_duplicates(&mut self) {
-        let mut unique_peers: HashSet<Peer> = HashSet::new();
+        let mut unique_peers: Vec<Peer> = Vec::with_capacity(self.peers.len());
         self.peers.iter().for_each(|peer| {
-            if let Some(entry) = unique_peers.get(peer) {
+            if let Some(entry) = unique_peers.iter_mut().find(|entry| *entry == peer) {
                 // duplicate peer, check age
                 if peer.age() < entry.age() {
-                    unique_peers.replace(peer.clone());
+                    *entry = peer.clone();
                 }
             }
             else {
                 // unique peer
-                unique_peers.insert(peer.clone());
+                unique_peers.push(peer.clone());
             }
         });
-        let new_view = Vec::from_iter(unique_peers);
-        std::mem::replace(&mut self.peers, new_view);
+        std::mem::replace(&mut self.peers, unique_peers);
     }
 
     /// Removes the oldest items from the view based on the healing parameter
@@ -463,12 +624,78 @@ impl View {
     fn remove_at_random(&mut self, c: usize) {
         if self.peers.len() > c {
             for _ in 0..(self.peers.len() - c) {
-                let remove_index = rand::thread_rng().gen_range(0..self.peers.len());
+                let remove_index = self.rng.gen_range(0..self.peers.len());
                 self.peers.remove(remove_index);
             }
         }
     }
 
+    /// Removes peers to match the view size parameter, each time at random among the peers of
+    /// the most represented zone, and within it of the most represented rack
+    ///
+    /// # Arguments
+    ///
+    /// * `c` - The size of the view
+    fn remove_from_largest_groups(&mut self, c: usize) {
+        while self.peers.len() > c {
+            let zone = Self::largest_group(self.peers.iter().map(|peer| peer.labels().zone()));
+            let rack = Self::largest_group(self.peers.iter()
+                .filter(|peer| peer.labels().zone() == zone)
+                .map(|peer| peer.labels().rack()));
+            let group: Vec<usize> = (0..self.peers.len())
+                .filter(|index| self.peers[*index].labels().zone() == zone && self.peers[*index].labels().rack() == rack)
+                .collect();
+            let remove_index = group[self.rng.gen_range(0..group.len())];
+            self.peers.remove(remove_index);
+        }
+    }
+
+    /// Returns the most frequent label, the smallest one in case of a tie
+    fn largest_group<'a>(labels: impl Iterator<Item = Option<&'a str>>) -> Option<&'a str> {
+        let mut counts = BTreeMap::new();
+        labels.for_each(|label| *counts.entry(label).or_insert(0) += 1);
+        let mut largest = None;
+        for (label, count) in counts {
+            if largest.is_none_or(|(_, largest_count)| count > largest_count) {
+                largest = Some((label, count));
+            }
+        }
+        largest.and_then(|(label, _)| label)
+    }
+
+    /// Brings back the zones of `candidates` the view lost while being trimmed, replacing the
+    /// oldest peer of the most represented zone by the youngest candidate of the lost zone
+    ///
+    /// # Arguments
+    ///
+    /// * `candidates` - The peers the view was selected from
+    fn restore_zones(&mut self, candidates: &[Peer]) {
+        let kept: BTreeSet<Option<&str>> = self.peers.iter().map(|peer| peer.labels().zone()).collect();
+        let lost: BTreeSet<Option<&str>> = candidates.iter()
+            .map(|peer| peer.labels().zone())
+            .filter(|zone| !kept.contains(zone))
+            .collect();
+        let mut restored = Vec::new();
+        for zone in lost {
+            let largest = Self::largest_group(self.peers.iter().map(|peer| peer.labels().zone()));
+            let replaced = (0..self.peers.len())
+                .filter(|index| self.peers[*index].labels().zone() == largest)
+                .max_by_key(|index| self.peers[*index].age());
+            let size = self.peers.iter().filter(|peer| peer.labels().zone() == largest).count();
+            match replaced {
+                // never empty a zone to restore another one
+                Some(replaced) if size > 1 => {
+                    if let Some(candidate) = candidates.iter().filter(|peer| peer.labels().zone() == zone).min_by_key(|peer| peer.age()) {
+                        restored.push(candidate.clone());
+                        self.peers.remove(replaced);
+                    }
+                }
+                _ => break,
+            }
+        }
+        self.peers.extend(restored);
+    }
+
     /// Update peer queue by adding peers that appeared in the view
     /// and removing those that were removed.
     fn update_queue(&mut self) {
@@ -506,3 +733,71 @@ impl View {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::peer::PeerLabels;
+
+    fn peer(index: usize, zone: &str, rack: &str) -> Peer {
+        Peer::new_with_labels(format!("127.0.0.1:{}", 9400 + index), PeerLabels::new(Some(zone.to_owned()), Some(rack.to_owned()), Vec::new()))
+    }
+
+    fn zone_aware_view(remote_ratio: f64) -> View {
+        View::new("127.0.0.1:9400".to_owned(), TopologyConfig::new(PeerLabels::new(Some("a".to_owned()), Some("1".to_owned()), Vec::new()), remote_ratio), 3)
+    }
+
+    #[test]
+    fn selection_prefers_local_zone_with_minimum_remote_share() {
+        let mut view = zone_aware_view(0.25);
+        view.peers = (1..9).map(|index| peer(index, ["a", "b", "c", "a"][index % 4], "1")).collect();
+        let remote = (0..1000)
+            .filter(|_| view.select_peer().unwrap().labels().zone() != Some("a"))
+            .count();
+        assert_eq!(remote, 250);
+
+        // with no local peer left, remote peers are selected
+        view.peers.retain(|peer| peer.labels().zone() != Some("a"));
+        assert!((0..10).all(|_| view.select_peer().unwrap().labels().zone() != Some("a")));
+    }
+
+    #[test]
+    fn trimmed_view_keeps_every_zone() {
+        let mut view = zone_aware_view(0.1);
+        view.peers = (1..7).map(|index| peer(index, "a", ["1", "2"][index % 2])).collect();
+        // a single peer of zone c, and the oldest of all
+        let mut buffer: Vec<Peer> = (7..13).map(|index| peer(index, "b", "1")).collect();
+        let mut lonely = peer(13, "c", "1");
+        (0..10).for_each(|_| lonely.increment_age());
+        buffer.push(lonely);
+
+        view.select(6, 2, 2, &buffer);
+        assert_eq!(view.peers.len(), 6);
+        for zone in ["a", "b", "c"].iter() {
+            assert!(view.peers.iter().any(|peer| peer.labels().zone() == Some(*zone)), "zone {} was lost", zone);
+        }
+
+        // peers are removed from the most represented rack
+        view.peers = (1..9).map(|index| peer(index, "a", if index < 7 { "1" } else { "2" })).collect();
+        view.remove_from_largest_groups(4);
+        assert_eq!(view.peers.iter().filter(|peer| peer.labels().rack() == Some("2")).count(), 2);
+    }
+
+    #[test]
+    fn labels_are_optional_on_the_wire() {
+        #[derive(Serialize)]
+        struct LegacyPeer {
+            address: String,
+            age: u16,
+        }
+        let bytes = serde_cbor::to_vec(&LegacyPeer { address: "127.0.0.1:9401".to_owned(), age: 2 }).unwrap();
+        let decoded: Peer = serde_cbor::from_slice(&bytes).unwrap();
+        assert_eq!(decoded.age(), 2);
+        assert_eq!(decoded.labels(), &PeerLabels::default());
+
+        let labelled = peer(1, "b", "2");
+        let decoded: Peer = serde_cbor::from_slice(&serde_cbor::to_vec(&labelled).unwrap()).unwrap();
+        assert_eq!(decoded.labels().zone(), Some("b"));
+        assert_eq!(decoded.labels().rack(), Some("2"));
+    }
+}
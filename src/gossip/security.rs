@@ -0,0 +1,483 @@
+use std::collections::{HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::thread::JoinHandle;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::error::Error;
+use super::message::{MASK_MESSAGE_PROTOCOL, MESSAGE_PROTOCOL_SECURE_MESSAGE};
+use super::transport::{Dispatcher, Transport};
+use super::version::PeerProtocol;
+
+// Layout of a secured message: protocol byte (the mode in its last four bits), key id,
+// nonce, milliseconds since the unix epoch when it was sealed, the message (protocol byte
+// followed by the message, encrypted or not), MAC of everything before it
+const MASK_SECURE_MODE: u8 = 0x0F;
+pub const SECURE_MODE_AUTHENTICATED: u8 = 0x00;
+pub const SECURE_MODE_ENCRYPTED: u8 = 0x01;
+const NONCE_SIZE: usize = 16;
+const TIMESTAMP_SIZE: usize = 8;
+const MAC_SIZE: usize = blake3::OUT_LEN;
+const SECURE_HEADER_SIZE: usize = 2 + NONCE_SIZE + TIMESTAMP_SIZE;
+
+/// Milliseconds the clock of a sender may be ahead or behind the one of the receiver. Older
+/// messages are rejected, and the nonces of the messages accepted are remembered for as long
+/// to reject them when they are replayed.
+pub const MAX_CLOCK_SKEW: u64 = 30_000;
+
+const MAC_KEY_CONTEXT: &str = "crate gossip 2021-03 frame authentication";
+const CIPHER_KEY_CONTEXT: &str = "crate gossip 2021-03 frame encryption";
+
+/// A secret shared by the nodes of a cluster. Its id tells receivers which key to verify
+/// a message with, so that two keys can be active while rotating.
+#[derive(Clone)]
+pub struct ClusterKey {
+    id: u8,
+    secret: [u8; blake3::KEY_LEN],
+}
+
+impl ClusterKey {
+    pub fn new(id: u8, secret: [u8; blake3::KEY_LEN]) -> Self {
+        ClusterKey { id, secret }
+    }
+    pub fn id(&self) -> u8 {
+        self.id
+    }
+}
+
+impl std::fmt::Debug for ClusterKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ClusterKey").field("id", &self.id).finish()
+    }
+}
+
+/// The keys derived from a [ClusterKey]
+struct ActiveKey {
+    id: u8,
+    mac_key: [u8; blake3::KEY_LEN],
+    cipher_key: [u8; blake3::KEY_LEN],
+}
+
+impl ActiveKey {
+    fn derive(key: &ClusterKey) -> Self {
+        let mut mac_key = [0u8; blake3::KEY_LEN];
+        let mut cipher_key = [0u8; blake3::KEY_LEN];
+        blake3::derive_key(MAC_KEY_CONTEXT, &key.secret, &mut mac_key);
+        blake3::derive_key(CIPHER_KEY_CONTEXT, &key.secret, &mut cipher_key);
+        ActiveKey { id: key.id, mac_key, cipher_key }
+    }
+
+    /// XORs `bytes` with the keystream of `nonce`.
+    ///
+    /// The keystream is the extendable output of BLAKE3 keyed with the cipher key, the MAC
+    /// being computed on the encrypted message with another key (encrypt-then-MAC). Blake3 is
+    /// already needed for the MACs and makes a sound stream cipher in this mode, which spares
+    /// a second cryptographic dependency for an AEAD such as ChaCha20-Poly1305. The 128-bit
+    /// random nonces make keystreams repeating unlikely within the life of a key.
+    fn apply_keystream(&self, nonce: &[u8], bytes: &mut [u8]) {
+        let mut keystream = vec![0u8; bytes.len()];
+        blake3::Hasher::new_keyed(&self.cipher_key).update(nonce).finalize_xof().fill(&mut keystream);
+        bytes.iter_mut().zip(keystream).for_each(|(byte, key)| *byte ^= key);
+    }
+}
+
+/// Counters of the secured messages received
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SecurityStats {
+    /// Messages verified
+    pub accepted: u64,
+    /// Messages sent without authentication
+    pub unauthenticated: u64,
+    /// Messages authenticated with a key that is not active
+    pub unknown_key: u64,
+    /// Messages whose MAC did not verify
+    pub bad_mac: u64,
+    /// Messages too short or of an unknown mode
+    pub malformed: u64,
+    /// Messages sealed too long ago, or already received
+    pub replayed: u64,
+}
+
+#[derive(Default)]
+struct Counters {
+    accepted: AtomicU64,
+    unauthenticated: AtomicU64,
+    unknown_key: AtomicU64,
+    bad_mac: AtomicU64,
+    malformed: AtomicU64,
+    replayed: AtomicU64,
+}
+
+/// Nonces of the messages accepted within the last [MAX_CLOCK_SKEW]
+#[derive(Default)]
+struct SeenNonces {
+    nonces: HashSet<[u8; NONCE_SIZE]>,
+    /// The nonces with the timestamp of their message, in the order they were accepted
+    accepted: VecDeque<(u64, [u8; NONCE_SIZE])>,
+}
+
+impl SeenNonces {
+    /// Remembers `nonce` and returns whether it is new, forgetting the nonces of the messages
+    /// too old to be accepted anymore
+    fn insert(&mut self, nonce: [u8; NONCE_SIZE], timestamp: u64, now: u64) -> bool {
+        while let Some((oldest, old)) = self.accepted.front() {
+            if oldest.saturating_add(MAX_CLOCK_SKEW) >= now {
+                break;
+            }
+            self.nonces.remove(old);
+            self.accepted.pop_front();
+        }
+        if !self.nonces.insert(nonce) {
+            return false;
+        }
+        self.accepted.push_back((timestamp, nonce));
+        true
+    }
+}
+
+/// Milliseconds since the unix epoch
+fn unix_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64)
+}
+
+/// The active keys of a node: messages are sent with the primary key and accepted with the
+/// primary or the secondary one.
+///
+/// To rotate keys without rejecting messages, [Keyring::install] the new key on every node,
+/// then [Keyring::promote] it on every node, then [Keyring::retire] the previous one.
+pub struct Keyring {
+    /// Primary key first
+    keys: RwLock<Vec<ActiveKey>>,
+    encrypt: bool,
+    counters: Counters,
+    seen: Mutex<SeenNonces>,
+}
+
+impl Keyring {
+    /// Creates a keyring with a single key
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The primary key
+    /// * `encrypt` - Whether sent messages are encrypted besides being authenticated
+    pub fn new(key: &ClusterKey, encrypt: bool) -> Self {
+        Keyring {
+            keys: RwLock::new(vec![ActiveKey::derive(key)]),
+            encrypt,
+            counters: Counters::default(),
+            seen: Mutex::new(SeenNonces::default()),
+        }
+    }
+
+    /// Returns the id of the key messages are sent with
+    pub fn primary(&self) -> u8 {
+        self.keys.read().unwrap()[0].id
+    }
+
+    /// Returns the ids of the keys messages are accepted with, primary first
+    pub fn active(&self) -> Vec<u8> {
+        self.keys.read().unwrap().iter().map(|key| key.id).collect()
+    }
+
+    /// Accepts messages authenticated with `key`, replacing the secondary key
+    pub fn install(&self, key: &ClusterKey) -> Result<(), Box<dyn Error>> {
+        let mut keys = self.keys.write().unwrap();
+        if keys[0].id == key.id {
+            Err(format!("Key {} is the primary key", key.id))?
+        }
+        keys.truncate(1);
+        keys.push(ActiveKey::derive(key));
+        Ok(())
+    }
+
+    /// Sends messages with the secondary key `id`, the primary key becoming secondary
+    pub fn promote(&self, id: u8) -> Result<(), Box<dyn Error>> {
+        let mut keys = self.keys.write().unwrap();
+        match keys.iter().position(|key| key.id == id) {
+            Some(0) => Ok(()),
+            Some(index) => {
+                keys.swap(0, index);
+                Ok(())
+            }
+            None => Err(format!("Key {} is not installed", id))?
+        }
+    }
+
+    /// Stops accepting messages authenticated with the secondary key `id`
+    pub fn retire(&self, id: u8) -> Result<(), Box<dyn Error>> {
+        let mut keys = self.keys.write().unwrap();
+        match keys.iter().position(|key| key.id == id) {
+            Some(0) => Err(format!("Key {} is the primary key", id))?,
+            Some(index) => {
+                keys.remove(index);
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Returns the counters of the messages received
+    pub fn stats(&self) -> SecurityStats {
+        SecurityStats {
+            accepted: self.counters.accepted.load(Ordering::Relaxed),
+            unauthenticated: self.counters.unauthenticated.load(Ordering::Relaxed),
+            unknown_key: self.counters.unknown_key.load(Ordering::Relaxed),
+            bad_mac: self.counters.bad_mac.load(Ordering::Relaxed),
+            malformed: self.counters.malformed.load(Ordering::Relaxed),
+            replayed: self.counters.replayed.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Authenticates, and encrypts if enabled, a message (protocol byte followed by the message)
+    pub fn seal(&self, frame: &[u8]) -> Vec<u8> {
+        self.seal_at(frame, unix_millis())
+    }
+
+    fn seal_at(&self, frame: &[u8], timestamp: u64) -> Vec<u8> {
+        let keys = self.keys.read().unwrap();
+        let key = &keys[0];
+        let mode = if self.encrypt { SECURE_MODE_ENCRYPTED } else { SECURE_MODE_AUTHENTICATED };
+        let nonce: [u8; NONCE_SIZE] = rand::random();
+
+        let mut sealed = Vec::with_capacity(SECURE_HEADER_SIZE + frame.len() + MAC_SIZE);
+        sealed.push(MESSAGE_PROTOCOL_SECURE_MESSAGE | mode);
+        sealed.push(key.id);
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&timestamp.to_be_bytes());
+        sealed.extend_from_slice(frame);
+        if self.encrypt {
+            key.apply_keystream(&nonce, &mut sealed[SECURE_HEADER_SIZE..]);
+        }
+        let mac = blake3::keyed_hash(&key.mac_key, &sealed);
+        sealed.extend_from_slice(mac.as_bytes());
+        sealed
+    }
+
+    /// Verifies, and decrypts if needed, a secured message and returns the message it carries.
+    /// Messages failing verification, sealed more than [MAX_CLOCK_SKEW] away from now or
+    /// already received are counted by reason.
+    pub fn open(&self, sealed: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        match self.verify(sealed) {
+            Ok(frame) => {
+                self.counters.accepted.fetch_add(1, Ordering::Relaxed);
+                Ok(frame)
+            }
+            Err((counter, reason)) => {
+                counter.fetch_add(1, Ordering::Relaxed);
+                Err(reason)?
+            }
+        }
+    }
+
+    fn verify(&self, sealed: &[u8]) -> Result<Vec<u8>, (&AtomicU64, &'static str)> {
+        match sealed.first() {
+            Some(protocol) if protocol & MASK_MESSAGE_PROTOCOL == MESSAGE_PROTOCOL_SECURE_MESSAGE => (),
+            _ => return Err((&self.counters.unauthenticated, "Rejected unauthenticated message")),
+        }
+        let mode = sealed[0] & MASK_SECURE_MODE;
+        if sealed.len() < SECURE_HEADER_SIZE + 1 + MAC_SIZE || (mode != SECURE_MODE_AUTHENTICATED && mode != SECURE_MODE_ENCRYPTED) {
+            return Err((&self.counters.malformed, "Rejected malformed secured message"));
+        }
+
+        let keys = self.keys.read().unwrap();
+        let key = match keys.iter().find(|key| key.id == sealed[1]) {
+            Some(key) => key,
+            None => return Err((&self.counters.unknown_key, "Rejected message secured with an inactive key")),
+        };
+        let (content, mac) = sealed.split_at(sealed.len() - MAC_SIZE);
+        let mut expected = [0u8; MAC_SIZE];
+        expected.copy_from_slice(mac);
+        // comparing hashes takes constant time
+        if blake3::keyed_hash(&key.mac_key, content) != expected {
+            return Err((&self.counters.bad_mac, "Rejected message failing authentication"));
+        }
+
+        let mut nonce = [0u8; NONCE_SIZE];
+        nonce.copy_from_slice(&content[2..2 + NONCE_SIZE]);
+        let mut timestamp = [0u8; TIMESTAMP_SIZE];
+        timestamp.copy_from_slice(&content[2 + NONCE_SIZE..SECURE_HEADER_SIZE]);
+        let timestamp = u64::from_be_bytes(timestamp);
+        let now = unix_millis();
+        if now.abs_diff(timestamp) > MAX_CLOCK_SKEW || !self.seen.lock().unwrap().insert(nonce, timestamp, now) {
+            return Err((&self.counters.replayed, "Rejected stale or replayed message"));
+        }
+
+        let mut frame = content[SECURE_HEADER_SIZE..].to_vec();
+        if mode == SECURE_MODE_ENCRYPTED {
+            key.apply_keystream(&nonce, &mut frame);
+        }
+        Ok(frame)
+    }
+}
+
+/// A transport authenticating every message with a cluster key: messages from nodes without
+/// the key are rejected before being decoded
+pub struct SecureTransport {
+    keyring: Arc<Keyring>,
+    transport: Arc<dyn Transport>,
+}
+
+impl SecureTransport {
+    /// Wraps a transport
+    ///
+    /// # Arguments
+    ///
+    /// * `transport` - The transport carrying the messages
+    /// * `key` - The cluster key
+    /// * `encrypt` - Whether messages are encrypted besides being authenticated
+    pub fn new(transport: Arc<dyn Transport>, key: &ClusterKey, encrypt: bool) -> Self {
+        SecureTransport {
+            keyring: Arc::new(Keyring::new(key, encrypt)),
+            transport,
+        }
+    }
+
+    /// Returns the keys of the node, for rotating them and reading rejection counters
+    pub fn keyring(&self) -> &Arc<Keyring> {
+        &self.keyring
+    }
+}
+
+impl Transport for SecureTransport {
+    fn send(&self, address: &SocketAddr, frame: Vec<u8>) -> Result<usize, Box<dyn Error>> {
+        self.transport.send(address, self.keyring.seal(&frame))
+    }
+
+    fn listen(&self, address: &SocketAddr, shutdown: Arc<AtomicBool>, dispatcher: Dispatcher) -> Result<Option<JoinHandle<()>>, Box<dyn Error>> {
+        self.transport.listen(address, shutdown, dispatcher.with_keyring(Arc::clone(&self.keyring)))
+    }
+
+    fn peer_protocol(&self, address: &SocketAddr) -> Option<PeerProtocol> {
+        self.transport.peer_protocol(address)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use super::super::config::{GossipConfig, PeerSamplingConfig, SwimConfig, UpdateExpirationMode};
+    use super::super::gossip::GossipService;
+    use super::super::peer::Peer;
+    use super::super::simulation::{SimConfig, SimNetwork};
+    use super::super::update::{Update, UpdateHandler};
+
+    fn key(id: u8) -> ClusterKey {
+        ClusterKey::new(id, [id; blake3::KEY_LEN])
+    }
+
+    #[test]
+    fn sealed_messages_verify_and_tampering_is_counted() {
+        for encrypt in [false, true].iter() {
+            let keyring = Keyring::new(&key(1), *encrypt);
+            let frame = vec![0x20, 1, 2, 3, 4];
+            let sealed = keyring.seal(&frame);
+            assert_eq!(sealed[SECURE_HEADER_SIZE..SECURE_HEADER_SIZE + frame.len()] == frame[..], !*encrypt);
+            assert_eq!(keyring.open(&sealed).unwrap(), frame);
+
+            let mut tampered = sealed.clone();
+            tampered[SECURE_HEADER_SIZE] ^= 1;
+            assert!(keyring.open(&tampered).is_err());
+            assert!(keyring.open(&frame).is_err());
+            assert!(keyring.open(&sealed[..MAC_SIZE]).is_err());
+            assert!(Keyring::new(&key(2), *encrypt).open(&sealed).is_err());
+            let mut forged = sealed.clone();
+            forged[1] = 2;
+            assert!(Keyring::new(&ClusterKey::new(2, [9; blake3::KEY_LEN]), *encrypt).open(&forged).is_err());
+
+            assert_eq!(keyring.stats(), SecurityStats { accepted: 1, unauthenticated: 1, unknown_key: 0, bad_mac: 1, malformed: 1, replayed: 0 });
+        }
+    }
+
+    #[test]
+    fn replayed_and_stale_messages_are_rejected() {
+        let keyring = Keyring::new(&key(1), true);
+        let sealed = keyring.seal(&[0x20, 1]);
+        assert_eq!(keyring.open(&sealed).unwrap(), vec![0x20, 1]);
+        assert!(keyring.open(&sealed).is_err());
+        let now = unix_millis();
+        assert!(keyring.open(&keyring.seal_at(&[0x20], now - 2 * MAX_CLOCK_SKEW)).is_err());
+        assert!(keyring.open(&keyring.seal_at(&[0x20], now + 2 * MAX_CLOCK_SKEW)).is_err());
+        assert_eq!(keyring.open(&keyring.seal_at(&[0x20], now - MAX_CLOCK_SKEW / 2)).unwrap(), vec![0x20]);
+        assert_eq!(keyring.stats().replayed, 3);
+
+        // nonces are forgotten once their messages would be too old anyway
+        let mut seen = SeenNonces::default();
+        assert!(seen.insert([1; NONCE_SIZE], now, now));
+        assert!(!seen.insert([1; NONCE_SIZE], now, now + MAX_CLOCK_SKEW));
+        assert!(seen.insert([2; NONCE_SIZE], now + MAX_CLOCK_SKEW + 1, now + MAX_CLOCK_SKEW + 1));
+        assert_eq!(seen.accepted.len(), 1);
+    }
+
+    #[test]
+    fn keys_rotate_without_rejecting_messages() {
+        let (a, b) = (Keyring::new(&key(1), true), Keyring::new(&key(1), true));
+        a.install(&key(2)).unwrap();
+        b.install(&key(2)).unwrap();
+        a.promote(2).unwrap();
+        // b has not promoted the new key yet, both accept both keys
+        assert_eq!(b.open(&a.seal(&[0x20])).unwrap(), vec![0x20]);
+        assert_eq!(a.open(&b.seal(&[0x20])).unwrap(), vec![0x20]);
+        b.promote(2).unwrap();
+        let old = Keyring::new(&key(1), true).seal(&[0x20]);
+        a.retire(1).unwrap();
+        assert_eq!(a.active(), vec![2]);
+        assert!(a.open(&old).is_err());
+        assert_eq!(a.stats().unknown_key, 1);
+        assert!(a.retire(2).is_err());
+    }
+
+    struct Received(Arc<Mutex<Vec<Vec<u8>>>>);
+
+    type Node = (GossipService<Received>, Arc<Mutex<Vec<Vec<u8>>>>);
+
+    impl UpdateHandler for Received {
+        fn on_update(&self, update: Update) {
+            self.0.lock().unwrap().push(update.content().to_vec());
+        }
+    }
+
+    #[test]
+    fn nodes_without_the_key_cannot_inject_updates() {
+        let network = SimNetwork::new(SimConfig::new(1, 1, 20, 0.0, 0.0));
+        let address = |index: usize| -> SocketAddr { format!("127.0.0.1:{}", 9200 + index).parse().unwrap() };
+        let mut keyrings = Vec::new();
+        let nodes: Vec<Node> = (0..4).map(|index| {
+            // the last node does not know the cluster key
+            let transport: Arc<dyn Transport> =
+                if index < 3 {
+                    let transport = SecureTransport::new(network.transport(address(index)), &key(1), true);
+                    keyrings.push(Arc::clone(transport.keyring()));
+                    Arc::new(transport)
+                }
+                else { network.transport(address(index)) };
+            let mut node = GossipService::new_with_transport(
+                address(index),
+                PeerSamplingConfig::new(true, true, 100, 3, 1, 1),
+                GossipConfig::new(true, true, 100, UpdateExpirationMode::None),
+                SwimConfig::default(),
+                transport,
+                index as u64,
+            );
+            let received = Arc::new(Mutex::new(Vec::new()));
+            let peers: Vec<Peer> = (0..4).filter(|peer| *peer != index).map(|peer| Peer::new(address(peer).to_string())).collect();
+            node.start_manual(Box::new(move || Some(peers)), Box::new(Received(Arc::clone(&received)))).unwrap();
+            (node, received)
+        }).collect();
+
+        nodes[0].0.submit(b"genuine".to_vec()).unwrap();
+        nodes[3].0.submit(b"forged".to_vec()).unwrap();
+        for _ in 0..300 {
+            network.advance(10);
+            for (node, _) in nodes.iter() {
+                node.poll(network.now()).unwrap();
+            }
+        }
+
+        for (_, received) in nodes[1..3].iter() {
+            assert_eq!(*received.lock().unwrap(), vec![b"genuine".to_vec()]);
+        }
+        assert!(nodes[3].1.lock().unwrap().is_empty());
+        assert!(keyrings.iter().all(|keyring| keyring.stats().unauthenticated > 0));
+    }
+}
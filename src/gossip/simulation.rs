@@ -0,0 +1,457 @@
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BinaryHeap, HashMap};
+use std::net::SocketAddr;
+use std::thread::JoinHandle;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::AtomicBool;
+use std::error::Error;
+use std::time::{Duration, Instant};
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+use super::transport::{Dispatcher, Transport};
+
+/// The behaviour of a simulated network
+#[derive(Clone, Debug)]
+pub struct SimConfig {
+    seed: u64,
+    min_delay: u64,
+    max_delay: u64,
+    loss: f64,
+    duplication: f64,
+}
+
+impl SimConfig {
+    /// Creates a new simulated network configuration
+    ///
+    /// # Arguments
+    ///
+    /// * `seed` - Seed of the delays, losses and duplications
+    /// * `min_delay` - Minimum delivery delay of a message (milliseconds)
+    /// * `max_delay` - Maximum delivery delay of a message (milliseconds)
+    /// * `loss` - Probability for a message to be lost
+    /// * `duplication` - Probability for a message to be delivered twice
+    pub fn new(seed: u64, min_delay: u64, max_delay: u64, loss: f64, duplication: f64) -> Self {
+        SimConfig {
+            seed,
+            min_delay,
+            max_delay: std::cmp::max(min_delay, max_delay),
+            loss,
+            duplication,
+        }
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+    pub fn min_delay(&self) -> u64 {
+        self.min_delay
+    }
+    pub fn max_delay(&self) -> u64 {
+        self.max_delay
+    }
+    pub fn loss(&self) -> f64 {
+        self.loss
+    }
+    pub fn duplication(&self) -> f64 {
+        self.duplication
+    }
+}
+
+impl Default for SimConfig {
+    fn default() -> Self {
+        SimConfig {
+            seed: 0,
+            min_delay: 1,
+            max_delay: 10,
+            loss: 0.0,
+            duplication: 0.0,
+        }
+    }
+}
+
+/// Counters of a simulated network
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SimStats {
+    /// Messages handed to the network
+    pub sent: usize,
+    /// Messages handed to their recipient
+    pub delivered: usize,
+    /// Messages lost
+    pub lost: usize,
+    /// Extra copies of messages
+    pub duplicated: usize,
+    /// Messages dropped because sender and recipient were partitioned at delivery time
+    pub partitioned: usize,
+    /// Messages whose recipient shut down before delivery
+    pub undeliverable: usize,
+}
+
+/// A deterministic in-memory network.
+///
+/// Nothing moves until [SimNetwork::advance] is called: messages are delivered in the order of
+/// their delivery time, which is drawn with the delays, losses and duplications from the seeded
+/// random generator, so that a run only depends on the seed and on the order nodes are polled in.
+/// Nodes are driven with [super::gossip::GossipService::poll] at [SimNetwork::now].
+#[derive(Clone)]
+pub struct SimNetwork {
+    state: Arc<Mutex<SimState>>,
+}
+
+struct SimState {
+    config: SimConfig,
+    rng: StdRng,
+    /// Instant of virtual time zero
+    start: Instant,
+    /// Virtual time (milliseconds)
+    time: u64,
+    in_flight: BinaryHeap<InFlight>,
+    next_seq: u64,
+    nodes: BTreeMap<SocketAddr, SimNode>,
+    /// Group of each node, nodes not listed all belong to the same group
+    partitions: HashMap<SocketAddr, usize>,
+    stats: SimStats,
+}
+
+struct SimNode {
+    dispatcher: Dispatcher,
+    shutdown: Arc<AtomicBool>,
+}
+
+/// A message on its way, ordered for a min-heap on delivery time then send order
+struct InFlight {
+    deliver_at: u64,
+    seq: u64,
+    from: SocketAddr,
+    to: SocketAddr,
+    frame: Vec<u8>,
+}
+
+impl PartialEq for InFlight {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for InFlight {}
+
+impl PartialOrd for InFlight {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for InFlight {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (other.deliver_at, other.seq).cmp(&(self.deliver_at, self.seq))
+    }
+}
+
+impl SimNetwork {
+    /// Creates a simulated network
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The behaviour of the network, see [SimConfig]
+    pub fn new(config: SimConfig) -> Self {
+        SimNetwork {
+            state: Arc::new(Mutex::new(SimState {
+                rng: StdRng::seed_from_u64(config.seed()),
+                config,
+                start: Instant::now(),
+                time: 0,
+                in_flight: BinaryHeap::new(),
+                next_seq: 0,
+                nodes: BTreeMap::new(),
+                partitions: HashMap::new(),
+                stats: SimStats::default(),
+            })),
+        }
+    }
+
+    /// Returns the transport of the node at `address`
+    ///
+    /// # Arguments
+    ///
+    /// * `address` - Address of the node, used as the sender of its messages
+    pub fn transport(&self, address: SocketAddr) -> Arc<dyn Transport> {
+        Arc::new(SimTransport {
+            address,
+            network: self.clone(),
+        })
+    }
+
+    /// Returns the current virtual time
+    pub fn now(&self) -> Instant {
+        let state = self.state.lock().unwrap();
+        state.start + Duration::from_millis(state.time)
+    }
+
+    /// Returns the counters of the network
+    pub fn stats(&self) -> SimStats {
+        self.state.lock().unwrap().stats
+    }
+
+    /// Returns the number of messages on their way
+    pub fn in_flight(&self) -> usize {
+        self.state.lock().unwrap().in_flight.len()
+    }
+
+    /// Moves the virtual time forward, delivering the messages due in the meantime.
+    /// Returns the number of messages delivered.
+    ///
+    /// # Arguments
+    ///
+    /// * `millis` - Milliseconds to move forward
+    pub fn advance(&self, millis: u64) -> usize {
+        let until = self.state.lock().unwrap().time + millis;
+        let mut delivered = 0;
+        // the lock is released while dispatching, recipients may answer right away
+        while let Some((frame, dispatcher)) = self.next_delivery(until) {
+            match dispatcher.dispatch(&frame) {
+                Ok(()) => delivered += 1,
+                Err(e) => log::error!("{:?}", e),
+            }
+        }
+        self.state.lock().unwrap().time = until;
+        delivered
+    }
+
+    /// Pops the next message due by `until`, skipping those dropped, and moves the virtual
+    /// time to its delivery
+    fn next_delivery(&self, until: u64) -> Option<(Vec<u8>, Dispatcher)> {
+        let mut state = self.state.lock().unwrap();
+        while state.in_flight.peek().is_some_and(|message| message.deliver_at <= until) {
+            let message = state.in_flight.pop().unwrap();
+            state.time = message.deliver_at;
+            if state.group(&message.from) != state.group(&message.to) {
+                state.stats.partitioned += 1;
+                continue;
+            }
+            let shut_down = match state.nodes.get(&message.to) {
+                Some(node) => node.shutdown.load(std::sync::atomic::Ordering::SeqCst),
+                None => true,
+            };
+            if shut_down {
+                state.nodes.remove(&message.to);
+                state.stats.undeliverable += 1;
+                continue;
+            }
+            state.stats.delivered += 1;
+            let dispatcher = state.nodes[&message.to].dispatcher.clone();
+            return Some((message.frame, dispatcher));
+        }
+        None
+    }
+
+    /// Splits the network, nodes of different groups no longer exchange messages.
+    /// Nodes in no group form a group of their own.
+    ///
+    /// # Arguments
+    ///
+    /// * `groups` - The nodes of each side of the partition
+    pub fn partition(&self, groups: &[Vec<SocketAddr>]) {
+        let mut state = self.state.lock().unwrap();
+        state.partitions.clear();
+        for (index, group) in groups.iter().enumerate() {
+            for address in group {
+                state.partitions.insert(*address, index + 1);
+            }
+        }
+    }
+
+    /// Removes all partitions
+    pub fn heal(&self) {
+        self.state.lock().unwrap().partitions.clear();
+    }
+}
+
+impl SimState {
+    fn group(&self, address: &SocketAddr) -> usize {
+        self.partitions.get(address).copied().unwrap_or(0)
+    }
+
+    fn enqueue(&mut self, from: SocketAddr, to: SocketAddr, frame: Vec<u8>) {
+        let delay = self.rng.gen_range(self.config.min_delay()..=self.config.max_delay());
+        let message = InFlight {
+            deliver_at: self.time + delay,
+            seq: self.next_seq,
+            from,
+            to,
+            frame,
+        };
+        self.next_seq += 1;
+        self.in_flight.push(message);
+    }
+}
+
+/// The transport of a node on a [SimNetwork]
+struct SimTransport {
+    address: SocketAddr,
+    network: SimNetwork,
+}
+
+impl Transport for SimTransport {
+    fn send(&self, address: &SocketAddr, frame: Vec<u8>) -> Result<usize, Box<dyn Error>> {
+        let mut state = self.network.state.lock().unwrap();
+        if !state.nodes.contains_key(address) {
+            Err(format!("Connection refused by {}", address))?
+        }
+        let written = frame.len();
+        state.stats.sent += 1;
+        let (loss, duplication) = (state.config.loss(), state.config.duplication());
+        if state.rng.gen_bool(loss) {
+            state.stats.lost += 1;
+            return Ok(written);
+        }
+        if state.rng.gen_bool(duplication) {
+            state.stats.duplicated += 1;
+            state.enqueue(self.address, *address, frame.clone());
+        }
+        state.enqueue(self.address, *address, frame);
+        Ok(written)
+    }
+
+    fn listen(&self, address: &SocketAddr, shutdown: Arc<AtomicBool>, dispatcher: Dispatcher) -> Result<Option<JoinHandle<()>>, Box<dyn Error>> {
+        let mut state = self.network.state.lock().unwrap();
+        if state.nodes.contains_key(address) {
+            Err(format!("Address already in use: {}", address))?
+        }
+        state.nodes.insert(*address, SimNode { dispatcher, shutdown });
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::config::{GossipConfig, PeerSamplingConfig, SwimConfig, UpdateExpirationMode};
+    use super::super::gossip::GossipService;
+    use super::super::peer::Peer;
+    use super::super::sampling::PeerSamplingService;
+    use super::super::update::{Update, UpdateHandler};
+
+    const NODES: usize = 8;
+
+    struct Received(Arc<Mutex<Vec<Vec<u8>>>>);
+
+    impl UpdateHandler for Received {
+        fn on_update(&self, update: Update) {
+            self.0.lock().unwrap().push(update.content().to_vec());
+        }
+    }
+
+    type Node = (GossipService<Received>, Arc<Mutex<Vec<Vec<u8>>>>);
+
+    fn address(index: usize) -> SocketAddr {
+        format!("127.0.0.1:{}", 9000 + index).parse().unwrap()
+    }
+
+    fn start_nodes(network: &SimNetwork, seed: u64, expiration: UpdateExpirationMode) -> Vec<Node> {
+        (0..NODES).map(|index| {
+            let mut node = GossipService::new_with_transport(
+                address(index),
+                PeerSamplingConfig::new_with_deviation(true, true, 100, 20, 4, 1, 1),
+                GossipConfig::new_with_deviation(true, true, 100, 20, expiration.clone()),
+                SwimConfig::default(),
+                network.transport(address(index)),
+                seed + index as u64,
+            );
+            let received = Arc::new(Mutex::new(Vec::new()));
+            let init: Box<dyn FnOnce() -> Option<Vec<Peer>>> =
+                if index == 0 { Box::new(|| None) }
+                else { Box::new(|| Some(vec![Peer::new(address(0).to_string())])) };
+            node.start_manual(init, Box::new(Received(Arc::clone(&received)))).unwrap();
+            (node, received)
+        }).collect()
+    }
+
+    fn step(network: &SimNetwork, nodes: &[Node], steps: usize) {
+        for _ in 0..steps {
+            network.advance(10);
+            for (node, _) in nodes {
+                node.poll(network.now()).unwrap();
+            }
+        }
+    }
+
+    fn has_update(nodes: &[Node], index: usize, update: &[u8]) -> bool {
+        index == 0 || nodes[index].1.lock().unwrap().iter().any(|content| content == update)
+    }
+
+    /// Runs a cluster until an update submitted by the first node reached every node,
+    /// returns the number of steps it took and the network counters
+    fn spread_update(seed: u64) -> (usize, SimStats) {
+        let network = SimNetwork::new(SimConfig::new(seed, 1, 30, 0.1, 0.05));
+        let nodes = start_nodes(&network, seed, UpdateExpirationMode::PushCount(10));
+        step(&network, &nodes, 200);
+
+        let update = b"update".to_vec();
+        nodes[0].0.submit(update.clone()).unwrap();
+        for steps in 1..=1000 {
+            step(&network, &nodes, 1);
+            if (0..NODES).all(|index| has_update(&nodes, index, &update)) {
+                return (steps, network.stats());
+            }
+        }
+        panic!("update did not reach every node");
+    }
+
+    #[test]
+    fn gossip_converges_reproducibly() {
+        let (steps, stats) = spread_update(7);
+        assert!(stats.lost > 0 && stats.duplicated > 0);
+        assert_eq!(spread_update(7), (steps, stats));
+    }
+
+    #[test]
+    fn update_crosses_healed_partition() {
+        let network = SimNetwork::new(SimConfig::new(3, 1, 20, 0.0, 0.0));
+        // updates must outlive the partition
+        let nodes = start_nodes(&network, 3, UpdateExpirationMode::None);
+        step(&network, &nodes, 200);
+
+        let (left, right): (Vec<usize>, Vec<usize>) = (0..NODES).partition(|index| index % 2 == 0);
+        network.partition(&[left.iter().map(|i| address(*i)).collect(), right.iter().map(|i| address(*i)).collect()]);
+        let update = b"partitioned".to_vec();
+        nodes[0].0.submit(update.clone()).unwrap();
+        step(&network, &nodes, 150);
+        assert!(network.stats().partitioned > 0);
+        assert!(right.iter().all(|index| !has_update(&nodes, *index, &update)));
+
+        network.heal();
+        step(&network, &nodes, 500);
+        assert!((0..NODES).all(|index| has_update(&nodes, index, &update)));
+    }
+
+    #[test]
+    fn peer_sampling_fills_views() {
+        let network = SimNetwork::new(SimConfig::new(11, 1, 20, 0.05, 0.0));
+        let mut services: Vec<PeerSamplingService> = (0..NODES).map(|index| {
+            let mut service = PeerSamplingService::new_with_transport(address(index), PeerSamplingConfig::new(true, true, 100, 4, 1, 1), network.transport(address(index)), index as u64);
+            let (tx_sampling, rx_sampling) = std::sync::mpsc::channel();
+            let dispatcher = Dispatcher::new(tx_sampling, std::sync::mpsc::channel().0, std::sync::mpsc::channel().0, std::sync::mpsc::channel().0);
+            network.transport(address(index)).listen(&address(index), Arc::new(AtomicBool::new(false)), dispatcher).unwrap();
+            let init: Box<dyn FnOnce() -> Option<Vec<Peer>>> =
+                if index == 0 { Box::new(|| None) }
+                else { Box::new(move || Some(vec![Peer::new(address(index - 1).to_string())])) };
+            service.init_manual(init, rx_sampling);
+            service
+        }).collect();
+
+        for _ in 0..300 {
+            network.advance(10);
+            let now = network.now();
+            for service in services.iter_mut() {
+                service.poll(now);
+            }
+        }
+
+        let mut known = std::collections::BTreeSet::new();
+        for service in services.iter() {
+            let peers = service.peers();
+            assert_eq!(peers.len(), 4);
+            known.extend(peers.iter().map(|peer| peer.address().to_owned()));
+        }
+        assert_eq!(known.len(), NODES);
+    }
+}
@@ -0,0 +1,238 @@
+use std::net::SocketAddr;
+use std::thread::JoinHandle;
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use std::sync::mpsc::Sender;
+use std::error::Error;
+use serde::Serialize;
+use super::config::TcpConfig;
+use super::network::ConnectionPool;
+use super::message::{Message, MASK_MESSAGE_PROTOCOL, MESSAGE_PROTOCOL_SAMPLING_MESSAGE, MESSAGE_PROTOCOL_HEADER_MESSAGE, MESSAGE_PROTOCOL_MEMBERSHIP_MESSAGE, MESSAGE_PROTOCOL_CONTENT_MESSAGE, MESSAGE_PROTOCOL_NOOP_MESSAGE, MESSAGE_PROTOCOL_HANDSHAKE_MESSAGE};
+use super::message::{MASK_MESSAGE_ENVELOPE, MESSAGE_ENVELOPE_LEGACY, MESSAGE_ENVELOPE_VERSIONED};
+use super::message::{MESSAGE_PROTOCOL_SUMMARY_MESSAGE, MESSAGE_PROTOCOL_BATCH_MESSAGE};
+use super::message::{HeaderMessage, ContentMessage, SummaryMessage, BatchMessage};
+use super::membership::SwimMessage;
+use super::sampling::PeerSamplingMessage;
+use super::version::{self, HandshakeMessage, Negotiation, PeerProtocol};
+use super::security::Keyring;
+
+/// Moves framed messages, a protocol byte followed by the serialized message, between nodes
+pub trait Transport: Send + Sync {
+    /// Sends a frame to the node at `address`, returns the number of bytes written
+    ///
+    /// # Arguments
+    ///
+    /// * `address` - Address of the recipient
+    /// * `frame` - The framed message
+    fn send(&self, address: &SocketAddr, frame: Vec<u8>) -> Result<usize, Box<dyn Error>>;
+
+    /// Starts handing the frames sent to `address` to `dispatcher`, until `shutdown` is set.
+    /// Returns the thread doing so, for transports that need one.
+    ///
+    /// # Arguments
+    ///
+    /// * `address` - Address of the node
+    /// * `shutdown` - Flag used to check for a shutdown request
+    /// * `dispatcher` - Used to dispatch received messages to their protocol
+    fn listen(&self, address: &SocketAddr, shutdown: Arc<AtomicBool>, dispatcher: Dispatcher) -> Result<Option<JoinHandle<()>>, Box<dyn Error>>;
+
+    /// Returns what was negotiated with the node at the given address, `None` if the transport
+    /// does not negotiate or the node only understands legacy messages
+    fn peer_protocol(&self, _address: &SocketAddr) -> Option<PeerProtocol> {
+        None
+    }
+}
+
+/// Frames a message and sends it over `transport`
+///
+/// # Arguments
+///
+/// * `transport` - The transport to send with
+/// * `address` - Address of the recipient
+/// * `message` - Message implementing the [Message] trait
+pub fn send<M>(transport: &dyn Transport, address: &SocketAddr, message: &M) -> Result<usize, Box<dyn Error>>
+where M: Message + Serialize
+{
+    match message.as_bytes() {
+        Ok(mut bytes) => {
+            // insert protocol byte for deserialization
+            bytes.insert(0, message.protocol());
+            transport.send(address, bytes)
+        }
+        Err(e) => {
+            log::error!("Could not serialize message");
+            Err(e)?
+        }
+    }
+}
+
+/// The TCP transport, sending length-prefixed frames over persistent connections
+pub struct TcpTransport {
+    config: TcpConfig,
+    connections: ConnectionPool,
+}
+
+impl TcpTransport {
+    /// Creates a TCP transport
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The TCP parameters, see [TcpConfig]
+    pub fn new(config: TcpConfig) -> Self {
+        TcpTransport {
+            connections: ConnectionPool::new(config.clone()),
+            config,
+        }
+    }
+}
+
+impl Default for TcpTransport {
+    fn default() -> Self {
+        Self::new(TcpConfig::default())
+    }
+}
+
+impl Transport for TcpTransport {
+    fn send(&self, address: &SocketAddr, frame: Vec<u8>) -> Result<usize, Box<dyn Error>> {
+        self.connections.send(address, &frame)
+    }
+
+    fn listen(&self, address: &SocketAddr, shutdown: Arc<AtomicBool>, dispatcher: Dispatcher) -> Result<Option<JoinHandle<()>>, Box<dyn Error>> {
+        Ok(Some(super::network::listen(address, &self.config, shutdown, dispatcher)?))
+    }
+}
+
+/// Decodes received frames and hands the messages to the protocol they belong to
+#[derive(Clone)]
+pub struct Dispatcher {
+    peer_sampling_sender: Sender<PeerSamplingMessage>,
+    header_sender: Sender<HeaderMessage>,
+    content_sender: Sender<ContentMessage>,
+    membership_sender: Sender<SwimMessage>,
+    /// Receive digest summaries and batched updates, absent on nodes predating them
+    summary_sender: Option<Sender<SummaryMessage>>,
+    batch_sender: Option<Sender<BatchMessage>>,
+    /// Handles handshakes and versioned messages, absent on nodes predating them
+    negotiation: Option<Arc<Negotiation>>,
+    /// Verifies messages before they are decoded, when the cluster is secured
+    keyring: Option<Arc<Keyring>>,
+}
+
+impl Dispatcher {
+    /// Creates a dispatcher
+    ///
+    /// # Arguments
+    ///
+    /// * `peer_sampling_sender` - Used to dispatch peer sampling messages
+    /// * `header_sender` - Used to dispatch gossip header messages
+    /// * `content_sender` - Used to dispatch gossip content messages
+    /// * `membership_sender` - Used to dispatch failure detection messages
+    pub fn new(peer_sampling_sender: Sender<PeerSamplingMessage>, header_sender: Sender<HeaderMessage>, content_sender: Sender<ContentMessage>, membership_sender: Sender<SwimMessage>) -> Self {
+        Dispatcher {
+            peer_sampling_sender,
+            header_sender,
+            content_sender,
+            membership_sender,
+            summary_sender: None,
+            batch_sender: None,
+            negotiation: None,
+            keyring: None,
+        }
+    }
+
+    /// Makes the dispatcher accept digest summaries and batched updates
+    ///
+    /// # Arguments
+    ///
+    /// * `summary_sender` - Used to dispatch digest summary messages
+    /// * `batch_sender` - Used to dispatch batched updates
+    pub fn with_digest_sync(mut self, summary_sender: Sender<SummaryMessage>, batch_sender: Sender<BatchMessage>) -> Self {
+        self.summary_sender = Some(summary_sender);
+        self.batch_sender = Some(batch_sender);
+        self
+    }
+
+    /// Makes the dispatcher answer handshakes and accept versioned messages
+    ///
+    /// # Arguments
+    ///
+    /// * `negotiation` - Protocol versions of the node and its peers
+    pub fn with_negotiation(mut self, negotiation: Arc<Negotiation>) -> Self {
+        self.negotiation = Some(negotiation);
+        self
+    }
+
+    /// Makes the dispatcher reject messages not secured with one of the keys of `keyring`
+    ///
+    /// # Arguments
+    ///
+    /// * `keyring` - The active cluster keys
+    pub fn with_keyring(mut self, keyring: Arc<Keyring>) -> Self {
+        self.keyring = Some(keyring);
+        self
+    }
+
+    /// Decodes a frame and dispatches its message
+    pub fn dispatch(&self, frame: &[u8]) -> Result<(), Box<dyn Error>> {
+        let opened;
+        let frame = match &self.keyring {
+            Some(keyring) => {
+                opened = keyring.open(frame)?;
+                &opened[..]
+            }
+            None => frame,
+        };
+        if frame.is_empty() {
+            Err("Empty frame")?
+        }
+        let protocol = frame[0] & MASK_MESSAGE_PROTOCOL;
+        let body = match (frame[0] & MASK_MESSAGE_ENVELOPE, &self.negotiation) {
+            (MESSAGE_ENVELOPE_LEGACY, _) => &frame[1..],
+            // messages decode whatever the version of their sender, which only tells which fields it knows
+            (MESSAGE_ENVELOPE_VERSIONED, Some(_)) => version::open_envelope(&frame[1..])?.1,
+            (envelope, _) => Err(format!("Unknown envelope: {}", envelope))?
+        };
+        match protocol {
+            MESSAGE_PROTOCOL_NOOP_MESSAGE => Ok(()),
+            MESSAGE_PROTOCOL_HANDSHAKE_MESSAGE => match &self.negotiation {
+                Some(negotiation) => negotiation.handle(HandshakeMessage::from_bytes(body)?),
+                None => Err(format!("Unknown protocol: {}", protocol))?
+            }
+            MESSAGE_PROTOCOL_SAMPLING_MESSAGE => {
+                let message = PeerSamplingMessage::from_bytes(body)?;
+                self.peer_sampling_sender.send(message)?;
+                Ok(())
+            }
+            MESSAGE_PROTOCOL_CONTENT_MESSAGE => {
+                let message = ContentMessage::from_bytes(body)?;
+                self.content_sender.send(message)?;
+                Ok(())
+            }
+            MESSAGE_PROTOCOL_HEADER_MESSAGE => {
+                let message = HeaderMessage::from_bytes(body)?;
+                self.header_sender.send(message)?;
+                Ok(())
+            }
+            MESSAGE_PROTOCOL_SUMMARY_MESSAGE => match &self.summary_sender {
+                Some(sender) => {
+                    sender.send(SummaryMessage::from_bytes(body)?)?;
+                    Ok(())
+                }
+                None => Err(format!("Unknown protocol: {}", protocol))?
+            }
+            MESSAGE_PROTOCOL_BATCH_MESSAGE => match &self.batch_sender {
+                Some(sender) => {
+                    sender.send(BatchMessage::from_bytes(body)?)?;
+                    Ok(())
+                }
+                None => Err(format!("Unknown protocol: {}", protocol))?
+            }
+            MESSAGE_PROTOCOL_MEMBERSHIP_MESSAGE => {
+                let message = SwimMessage::from_bytes(body)?;
+                self.membership_sender.send(message)?;
+                Ok(())
+            }
+            _ => Err(format!("Unknown protocol: {}", protocol))?
+        }
+    }
+}
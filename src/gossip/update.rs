@@ -2,14 +2,22 @@
 use std::collections::HashMap;
 use std::error::Error;
 use super::config::UpdateExpirationValue;
-use super::config::UpdateExpirationMode;
+use super::config::{TopicConfig, UpdateExpirationMode};
+use super::message::DigestFilter;
+
+/// Name of the topic of updates submitted without one
+pub const DEFAULT_TOPIC: &str = "";
 
 /// A generic update for sending data as binary content
 pub struct Update {
+    /// Topic the update belongs to
+    topic: String,
     /// Message content
     content: Vec<u8>,
     /// Content digest
     digest: String,
+    /// Binary content digest
+    digest_bytes: [u8; 32],
 }
 
 impl Update {
@@ -20,13 +28,38 @@ impl Update {
     /// * `content` - Message content
     /// * `digest` - Content digest
     pub fn new(content: Vec<u8>) -> Self {
-        let digest = blake3::hash(&content).to_hex().to_string();
+        Self::new_with_topic(DEFAULT_TOPIC, content)
+    }
+
+    /// Creates a new update of a topic. The topic is part of the digest, except for the default
+    /// topic so that updates keep the digest nodes predating topics compute.
+    ///
+    /// # Arguments
+    ///
+    /// * `topic` - Topic of the update
+    /// * `content` - Message content
+    pub fn new_with_topic(topic: &str, content: Vec<u8>) -> Self {
+        let hash =
+            if topic == DEFAULT_TOPIC { blake3::hash(&content) }
+            else {
+                let mut hasher = blake3::Hasher::new();
+                hasher.update(&(topic.len() as u32).to_be_bytes());
+                hasher.update(topic.as_bytes());
+                hasher.update(&content);
+                hasher.finalize()
+            };
         Update {
+            topic: topic.to_owned(),
             content,
-            digest,
+            digest: hash.to_hex().to_string(),
+            digest_bytes: *hash.as_bytes(),
         }
     }
 
+    pub fn topic(&self) -> &str {
+        &self.topic
+    }
+
     pub fn content(&self) -> &Vec<u8> {
         &self.content
     }
@@ -34,6 +67,22 @@ impl Update {
     pub fn digest(&self) -> &String {
         &self.digest
     }
+
+    pub fn digest_bytes(&self) -> &[u8; 32] {
+        &self.digest_bytes
+    }
+}
+
+/// Parses a hex digest, as returned by [Update::digest], into its binary form
+pub fn digest_from_hex(digest: &str) -> Option<[u8; 32]> {
+    if digest.len() != 64 || !digest.is_ascii() {
+        return None;
+    }
+    let mut bytes = [0; 32];
+    for (index, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&digest[index * 2..index * 2 + 2], 16).ok()?;
+    }
+    Some(bytes)
 }
 
 /// Trait for receiving updates from the gossip protocol.
@@ -56,6 +105,8 @@ pub struct UpdateDecorator {
     removed_updates: Vec<String>,
     /// Strategy for expiring updates
     expiration_mode: UpdateExpirationMode,
+    /// Priority and expiration of the configured topics
+    topics: HashMap<String, TopicConfig>,
     /// Number of digests of expired updates that are kept
     max_expired_size: usize,
     /// Margin for cleanup of expired updates
@@ -63,14 +114,67 @@ pub struct UpdateDecorator {
 }
 impl UpdateDecorator {
     pub fn new(expiration_mode: UpdateExpirationMode) -> Self {
+        Self::new_with_topics(expiration_mode, &[])
+    }
+
+    /// Creates a decorator applying the expiration of each configured topic to its updates
+    ///
+    /// # Arguments
+    ///
+    /// * `expiration_mode` - Strategy for expiring the updates of topics not configured
+    /// * `topics` - The configured topics
+    pub fn new_with_topics(expiration_mode: UpdateExpirationMode, topics: &[TopicConfig]) -> Self {
         Self{
             active_updates: HashMap::new(),
             removed_updates: Vec::new(),
             expiration_mode,
+            topics: topics.iter().map(|topic| (topic.name().to_owned(), topic.clone())).collect(),
             max_expired_size: 10000,
             max_expired_margin: 0.5
         }
     }
+
+    /// Changes the expiration strategies and priorities, existing updates keep expiring as before
+    ///
+    /// # Arguments
+    ///
+    /// * `expiration_mode` - Strategy for expiring the updates of topics not configured
+    /// * `topics` - The configured topics
+    pub fn set_topics(&mut self, expiration_mode: UpdateExpirationMode, topics: &[TopicConfig]) {
+        self.expiration_mode = expiration_mode;
+        self.topics = topics.iter().map(|topic| (topic.name().to_owned(), topic.clone())).collect();
+    }
+
+    /// Returns the expiration strategy of a topic
+    pub fn expiration_mode(&self, topic: &str) -> &UpdateExpirationMode {
+        self.topics.get(topic).map_or(&self.expiration_mode, |topic| topic.update_expiration())
+    }
+
+    /// Returns the priority of a topic
+    pub fn priority(&self, topic: &str) -> u8 {
+        self.topics.get(topic).map_or(0, |topic| topic.priority())
+    }
+
+    /// Orders updates by decreasing priority of their topic and keeps those fitting in `budget`
+    /// bytes of content, at least the first one
+    ///
+    /// # Arguments
+    ///
+    /// * `updates` - The updates to send
+    /// * `budget` - Maximum size of their contents
+    pub fn prioritize<'a>(&self, mut updates: Vec<&'a Update>, budget: usize) -> Vec<&'a Update> {
+        updates.sort_by(|first, second| self.priority(second.topic()).cmp(&self.priority(first.topic()))
+            .then_with(|| first.digest().cmp(second.digest())));
+        let mut size = 0;
+        let count = updates.iter()
+            .take_while(|update| {
+                size += update.content().len();
+                size <= budget
+            })
+            .count();
+        updates.truncate(std::cmp::max(count, 1));
+        updates
+    }
     pub fn active_count(&self) -> usize {
         self.active_updates.len()
     }
@@ -79,6 +183,28 @@ impl UpdateDecorator {
         self.active_updates.iter().map(|(header, _)| header.to_owned()).collect()
     }
 
+    /// Returns a filter of the active and expired updates, see [DigestFilter]
+    ///
+    /// # Arguments
+    ///
+    /// * `seed` - Seed of the filter
+    pub fn summary(&self, seed: u64) -> DigestFilter {
+        let mut filter = DigestFilter::new(self.active_updates.len() + self.removed_updates.len(), seed);
+        self.active_updates.values().for_each(|(update, _)| filter.insert(update.digest_bytes()));
+        self.removed_updates.iter()
+            .filter_map(|digest| digest_from_hex(digest))
+            .for_each(|digest| filter.insert(&digest));
+        filter
+    }
+
+    /// Returns the active updates missing from a summary received from a peer
+    pub fn missing_from(&self, filter: &DigestFilter) -> Vec<&Update> {
+        self.active_updates.values()
+            .map(|(update, _)| update)
+            .filter(|update| !filter.contains(update.digest_bytes()))
+            .collect()
+    }
+
     pub fn is_new(&self, digest: &String) -> bool {
         !self.active_updates.contains_key(digest) && !self.removed_updates.contains(&digest)
     }
@@ -96,7 +222,8 @@ impl UpdateDecorator {
     }
 
     pub fn insert_update(&mut self, update: Update) -> Result<(), Box<dyn Error>> {
-        if let None = self.active_updates.insert(update.digest().to_owned(), (update, UpdateExpirationValue::new(self.expiration_mode.clone()))) {
+        let expiration = UpdateExpirationValue::new(self.expiration_mode(update.topic()).clone());
+        if self.active_updates.insert(update.digest().to_owned(), (update, expiration)).is_none() {
             Ok(())
         }
         else {
@@ -120,40 +247,36 @@ impl UpdateDecorator {
     }
 
     pub fn clear_expired(&mut self) {
-        match self.expiration_mode {
-            UpdateExpirationMode::None => (),
-            UpdateExpirationMode::MostRecent(size, margin) => {
-                let max_size = size + (size as f64 * margin) as usize;
-                if self.active_updates.len() > max_size {
-                    let removal_count = self.active_updates.len() - max_size;
-
-                    let mut removal_keys: Vec<(String, std::time::Instant)> = Vec::new();
-                    for(digest, (_, expiration_value)) in &self.active_updates {
-                        match expiration_value {
-                            UpdateExpirationValue::MostRecent(created) => removal_keys.push((digest.to_owned(), created.clone())),
-                            _ => (),
-                        }
-                    }
+        // updates expiring after a number of pushes or a duration
+        let mut expired_keys: Vec<String> = self.active_updates.iter()
+            .filter(|(_, (_, expiration_value))| expiration_value.has_expired())
+            .map(|(digest, (_, _))| digest.to_owned())
+            .collect();
+
+        // topics keeping only their most recent updates
+        let mut recent_keys: HashMap<&str, Vec<(&String, std::time::Instant)>> = HashMap::new();
+        for (digest, (update, expiration_value)) in &self.active_updates {
+            if let UpdateExpirationValue::MostRecent(created) = expiration_value {
+                recent_keys.entry(update.topic()).or_default().push((digest, *created));
+            }
+        }
+        for (topic, mut removal_keys) in recent_keys {
+            if let UpdateExpirationMode::MostRecent(size, margin) = self.expiration_mode(topic) {
+                let max_size = size + (*size as f64 * margin) as usize;
+                if removal_keys.len() > max_size {
+                    let removal_count = removal_keys.len() - max_size;
                     // sort from oldest to more recent
                     removal_keys.sort_by_key(|(_, created)| *created);
-                    for i in 0..removal_count {
-                        self.active_updates.remove(&removal_keys[i].0);
-                        self.removed_updates.push(removal_keys[i].0.clone());
-                    }
-                }
-            },
-            UpdateExpirationMode::PushCount(_) | UpdateExpirationMode::DurationMillis(_) => {
-                let expired_keys: Vec<String> = self.active_updates.iter()
-                    .filter(|(_, (_, expiration_value))| expiration_value.has_expired())
-                    .map(|(digest, (_, _))| digest.to_owned())
-                    .collect();
-                for key in expired_keys {
-                    self.active_updates.remove(&key);
-                    self.removed_updates.push(key.clone());
+                    expired_keys.extend(removal_keys.drain(..removal_count).map(|(digest, _)| digest.to_owned()));
                 }
             }
         }
 
+        for key in expired_keys {
+            self.active_updates.remove(&key);
+            self.removed_updates.push(key);
+        }
+
         let margin_size = (self.max_expired_size as f64 * self.max_expired_margin) as usize;
         let max_expired = self.max_expired_size + margin_size;
         if self.removed_updates.len() > max_expired && margin_size > 0 {
@@ -0,0 +1,332 @@
+use std::net::SocketAddr;
+use std::thread::JoinHandle;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::AtomicBool;
+use std::collections::HashMap;
+use std::error::Error;
+use serde::{Serialize, Deserialize};
+use super::message::{Message, MessageType, MESSAGE_PROTOCOL_HANDSHAKE_MESSAGE, MASK_MESSAGE_ENVELOPE, MESSAGE_ENVELOPE_VERSIONED};
+use super::transport::{self, Dispatcher, Transport};
+
+/// Version of nodes sending bare messages and ignoring handshakes
+pub const LEGACY_PROTOCOL_VERSION: u16 = 1;
+/// Version of the gossip protocol implemented by this node
+pub const PROTOCOL_VERSION: u16 = 2;
+
+// Capabilities, a node only uses those its peer supports as well
+pub const CAPABILITY_COMPRESSION: u32 = 0x1;
+pub const CAPABILITY_DELTA_SYNC: u32  = 0x2;
+/// Capabilities implemented by this node
+pub const SUPPORTED_CAPABILITIES: u32 = CAPABILITY_COMPRESSION | CAPABILITY_DELTA_SYNC;
+
+/// Announces the protocol version and capabilities of a node. A request is answered with a
+/// response, which nodes predating handshakes never send.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HandshakeMessage {
+    sender: String,
+    message_type: MessageType,
+    version: u16,
+    capabilities: u32,
+}
+
+impl HandshakeMessage {
+    pub fn new_request(sender: String, version: u16, capabilities: u32) -> Self {
+        Self::new(sender, MessageType::Request, version, capabilities)
+    }
+    pub fn new_response(sender: String, version: u16, capabilities: u32) -> Self {
+        Self::new(sender, MessageType::Response, version, capabilities)
+    }
+    fn new(sender: String, message_type: MessageType, version: u16, capabilities: u32) -> Self {
+        HandshakeMessage {
+            sender,
+            message_type,
+            version,
+            capabilities,
+        }
+    }
+    pub fn sender(&self) -> &str {
+        &self.sender
+    }
+    pub fn message_type(&self) -> &MessageType {
+        &self.message_type
+    }
+    pub fn version(&self) -> u16 {
+        self.version
+    }
+    pub fn capabilities(&self) -> u32 {
+        self.capabilities
+    }
+}
+
+impl Message for HandshakeMessage {
+    fn protocol(&self) -> u8 {
+        MESSAGE_PROTOCOL_HANDSHAKE_MESSAGE
+    }
+}
+
+/// What a node and one of its peers agreed on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerProtocol {
+    /// The lowest of both versions
+    pub version: u16,
+    /// The capabilities both support
+    pub capabilities: u32,
+}
+
+impl PeerProtocol {
+    /// Returns whether both nodes support `capability`
+    pub fn supports(&self, capability: u32) -> bool {
+        self.capabilities & capability == capability
+    }
+}
+
+enum PeerState {
+    /// Handshake sent, peers that never answer predate handshakes
+    Pending,
+    Negotiated(PeerProtocol),
+}
+
+/// The protocol version and capabilities of a node and of the peers it exchanged handshakes with
+pub struct Negotiation {
+    address: SocketAddr,
+    version: u16,
+    capabilities: u32,
+    peers: Mutex<HashMap<SocketAddr, PeerState>>,
+    transport: Arc<dyn Transport>,
+}
+
+impl Negotiation {
+    /// Returns the version of the node
+    pub fn version(&self) -> u16 {
+        self.version
+    }
+
+    /// Returns the capabilities of the node
+    pub fn capabilities(&self) -> u32 {
+        self.capabilities
+    }
+
+    /// Returns what the node agreed on with the peer at `address`, or `None` if the peer did
+    /// not answer a handshake (yet), in which case it only gets legacy messages
+    pub fn peer(&self, address: &SocketAddr) -> Option<PeerProtocol> {
+        match self.peers.lock().unwrap().get(address) {
+            Some(PeerState::Negotiated(protocol)) => Some(*protocol),
+            _ => None,
+        }
+    }
+
+    /// Handles a handshake from a peer, answering requests
+    ///
+    /// # Arguments
+    ///
+    /// * `message` - The received handshake
+    pub fn handle(&self, message: HandshakeMessage) -> Result<(), Box<dyn Error>> {
+        let address = message.sender().parse::<SocketAddr>()?;
+        let protocol = PeerProtocol {
+            version: std::cmp::min(self.version, message.version()),
+            capabilities: self.capabilities & message.capabilities(),
+        };
+        log::debug!("Negotiated {:?} with {}", protocol, address);
+        self.peers.lock().unwrap().insert(address, PeerState::Negotiated(protocol));
+        if *message.message_type() == MessageType::Request {
+            let response = HandshakeMessage::new_response(self.address.to_string(), self.version, self.capabilities);
+            transport::send(self.transport.as_ref(), &address, &response)?;
+        }
+        Ok(())
+    }
+
+    /// Sends a message (protocol byte followed by the message) in the envelope the peer
+    /// understands, starting a handshake with peers never contacted
+    fn send(&self, address: &SocketAddr, frame: Vec<u8>) -> Result<usize, Box<dyn Error>> {
+        let negotiated = {
+            let mut peers = self.peers.lock().unwrap();
+            match peers.get(address) {
+                Some(PeerState::Negotiated(protocol)) => Some(*protocol),
+                Some(PeerState::Pending) => None,
+                None => {
+                    peers.insert(*address, PeerState::Pending);
+                    drop(peers);
+                    let request = HandshakeMessage::new_request(self.address.to_string(), self.version, self.capabilities);
+                    if let Err(e) = transport::send(self.transport.as_ref(), address, &request) {
+                        // try again with the next message
+                        self.peers.lock().unwrap().remove(address);
+                        return Err(e);
+                    }
+                    None
+                }
+            }
+        };
+        match negotiated {
+            Some(protocol) if protocol.version > LEGACY_PROTOCOL_VERSION => self.transport.send(address, seal_envelope(frame, protocol.version)),
+            _ => self.transport.send(address, frame),
+        }
+    }
+}
+
+/// Puts a message (protocol byte followed by the message) in a versioned envelope
+///
+/// # Arguments
+///
+/// * `frame` - The protocol byte followed by the message
+/// * `version` - The protocol version the message is written in
+pub fn seal_envelope(mut frame: Vec<u8>, version: u16) -> Vec<u8> {
+    if let Some(protocol) = frame.first_mut() {
+        *protocol = (*protocol & !MASK_MESSAGE_ENVELOPE) | MESSAGE_ENVELOPE_VERSIONED;
+        frame.splice(1..1, version.to_be_bytes().iter().copied());
+    }
+    frame
+}
+
+/// Splits the content of a versioned envelope (what follows the protocol byte) into the
+/// protocol version and the message
+pub fn open_envelope(content: &[u8]) -> Result<(u16, &[u8]), Box<dyn Error>> {
+    if content.len() < 2 {
+        Err("Truncated envelope")?
+    }
+    Ok((u16::from_be_bytes([content[0], content[1]]), &content[2..]))
+}
+
+/// A transport negotiating the protocol version with each peer: peers that answered a
+/// handshake get versioned messages, others legacy ones
+pub struct VersionedTransport {
+    negotiation: Arc<Negotiation>,
+}
+
+impl VersionedTransport {
+    /// Wraps a transport, announcing [PROTOCOL_VERSION] and [SUPPORTED_CAPABILITIES]
+    ///
+    /// # Arguments
+    ///
+    /// * `address` - Address of the node
+    /// * `transport` - The transport carrying the messages
+    pub fn new(address: SocketAddr, transport: Arc<dyn Transport>) -> Self {
+        Self::with_protocol(address, transport, PROTOCOL_VERSION, SUPPORTED_CAPABILITIES)
+    }
+
+    /// Wraps a transport, announcing the given version and capabilities
+    ///
+    /// # Arguments
+    ///
+    /// * `address` - Address of the node
+    /// * `transport` - The transport carrying the messages
+    /// * `version` - Protocol version announced to peers
+    /// * `capabilities` - Capabilities announced to peers
+    pub fn with_protocol(address: SocketAddr, transport: Arc<dyn Transport>, version: u16, capabilities: u32) -> Self {
+        VersionedTransport {
+            negotiation: Arc::new(Negotiation {
+                address,
+                version,
+                capabilities,
+                peers: Mutex::new(HashMap::new()),
+                transport,
+            }),
+        }
+    }
+
+    /// Returns the versions and capabilities negotiated with peers
+    pub fn negotiation(&self) -> &Arc<Negotiation> {
+        &self.negotiation
+    }
+}
+
+impl Transport for VersionedTransport {
+    fn send(&self, address: &SocketAddr, frame: Vec<u8>) -> Result<usize, Box<dyn Error>> {
+        self.negotiation.send(address, frame)
+    }
+
+    fn listen(&self, address: &SocketAddr, shutdown: Arc<AtomicBool>, dispatcher: Dispatcher) -> Result<Option<JoinHandle<()>>, Box<dyn Error>> {
+        self.negotiation.transport.listen(address, shutdown, dispatcher.with_negotiation(Arc::clone(&self.negotiation)))
+    }
+
+    fn peer_protocol(&self, address: &SocketAddr) -> Option<PeerProtocol> {
+        self.negotiation.peer(address)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::config::{GossipConfig, PeerSamplingConfig, SwimConfig, UpdateExpirationMode};
+    use super::super::gossip::GossipService;
+    use super::super::peer::Peer;
+    use super::super::simulation::{SimConfig, SimNetwork};
+    use super::super::update::{Update, UpdateHandler};
+
+    struct Received(Arc<Mutex<Vec<Vec<u8>>>>);
+
+    impl UpdateHandler for Received {
+        fn on_update(&self, update: Update) {
+            self.0.lock().unwrap().push(update.content().to_vec());
+        }
+    }
+
+    type Node = (GossipService<Received>, Arc<Mutex<Vec<Vec<u8>>>>);
+
+    fn address(index: usize) -> SocketAddr {
+        format!("127.0.0.1:{}", 9100 + index).parse().unwrap()
+    }
+
+    #[test]
+    fn envelope_round_trip() {
+        let frame = seal_envelope(vec![0x20, 7, 8], PROTOCOL_VERSION);
+        assert_eq!(frame[0] & MASK_MESSAGE_ENVELOPE, MESSAGE_ENVELOPE_VERSIONED);
+        assert_eq!(frame[0] & !MASK_MESSAGE_ENVELOPE, 0x20);
+        assert_eq!(open_envelope(&frame[1..]).unwrap(), (PROTOCOL_VERSION, &[7u8, 8][..]));
+        assert!(open_envelope(&[0]).is_err());
+    }
+
+    #[test]
+    fn legacy_and_versioned_nodes_share_updates() {
+        let network = SimNetwork::new(SimConfig::new(5, 1, 20, 0.0, 0.0));
+        let mut negotiations = Vec::new();
+        let nodes: Vec<Node> = (0..6).map(|index| {
+            // odd nodes run the legacy protocol
+            let transport: Arc<dyn Transport> =
+                if index % 2 == 0 {
+                    let transport = VersionedTransport::new(address(index), network.transport(address(index)));
+                    negotiations.push(Arc::clone(transport.negotiation()));
+                    Arc::new(transport)
+                }
+                else { network.transport(address(index)) };
+            let mut node = GossipService::new_with_transport(
+                address(index),
+                PeerSamplingConfig::new(true, true, 100, 4, 1, 1),
+                GossipConfig::new(true, true, 100, UpdateExpirationMode::None),
+                SwimConfig::default(),
+                transport,
+                index as u64,
+            );
+            let received = Arc::new(Mutex::new(Vec::new()));
+            let init: Box<dyn FnOnce() -> Option<Vec<Peer>>> =
+                if index == 0 { Box::new(|| None) }
+                else { Box::new(move || Some(vec![Peer::new(address(index - 1).to_string())])) };
+            node.start_manual(init, Box::new(Received(Arc::clone(&received)))).unwrap();
+            (node, received)
+        }).collect();
+
+        let step = |steps: usize| for _ in 0..steps {
+            network.advance(10);
+            for (node, _) in nodes.iter() {
+                node.poll(network.now()).unwrap();
+            }
+        };
+        step(200);
+        nodes[0].0.submit(b"versioned".to_vec()).unwrap();
+        nodes[1].0.submit(b"legacy".to_vec()).unwrap();
+        step(500);
+
+        for (index, (_, received)) in nodes.iter().enumerate() {
+            let received = received.lock().unwrap();
+            let expected: &[&[u8]] = match index { 0 => &[b"legacy"], 1 => &[b"versioned"], _ => &[b"versioned", b"legacy"] };
+            for update in expected {
+                assert!(received.iter().any(|content| content == update), "node {} misses {:?}", index, update);
+            }
+        }
+        for negotiation in negotiations.iter() {
+            assert_eq!(negotiation.peer(&address(1)), None);
+            for peer in [0, 2, 4].iter().filter(|peer| address(**peer) != negotiation.address) {
+                assert_eq!(negotiation.peer(&address(*peer)).map(|protocol| protocol.version), Some(PROTOCOL_VERSION));
+            }
+        }
+    }
+}
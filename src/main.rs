@@ -18,6 +18,7 @@ mod lcache;
 mod svalue;
 mod db;
 mod crdts;
+mod memory;
 
 use std::string;
 
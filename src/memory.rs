@@ -0,0 +1,198 @@
+use std::cell::RefCell;
+use std::mem;
+use std::rc::Rc;
+use std::time::SystemTime;
+
+/// Number of elements inspected in big aggregates when the caller does not ask for a
+/// different sample size, same as the `SAMPLES` default of `MEMORY USAGE`.
+pub const MEMORY_USAGE_SAMPLES: usize = 5;
+
+/// Approximate number of bytes a value occupies.
+///
+/// `samples` bounds how many elements of an aggregate are inspected; the size of the rest is
+/// extrapolated from their average. A `samples` of `0` inspects every element.
+pub trait MemoryUsage {
+    /// Bytes owned by the value outside of its own inline size.
+    fn heap_usage(&self, samples: usize) -> usize;
+
+    /// Inline size plus [`heap_usage`](MemoryUsage::heap_usage).
+    fn memory_usage(&self, samples: usize) -> usize {
+        mem::size_of_val(self) + self.heap_usage(samples)
+    }
+}
+
+/// Sums the first `samples` sizes of `sizes` (all of them if `samples` is `0`) and scales the
+/// result up to `len` elements.
+pub fn sampled_usage<I>(len: usize, samples: usize, sizes: I) -> usize
+where
+    I: IntoIterator<Item = usize>,
+{
+    if len == 0 {
+        return 0;
+    }
+
+    let limit = if samples == 0 { len } else { samples.min(len) };
+    let mut seen = 0;
+    let mut total = 0;
+    for size in sizes.into_iter().take(limit) {
+        seen += 1;
+        total += size;
+    }
+
+    if seen == 0 || seen == len {
+        total
+    } else {
+        total / seen * len
+    }
+}
+
+macro_rules! inline_memory_usage {
+    ($($t:ty),*) => {
+        $(
+            impl MemoryUsage for $t {
+                #[inline]
+                fn heap_usage(&self, _samples: usize) -> usize {
+                    0
+                }
+            }
+        )*
+    };
+}
+
+inline_memory_usage!(
+    (), bool, u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize, f32, f64, SystemTime
+);
+
+impl MemoryUsage for String {
+    fn heap_usage(&self, _samples: usize) -> usize {
+        self.capacity()
+    }
+}
+
+impl<T: MemoryUsage> MemoryUsage for Vec<T> {
+    fn heap_usage(&self, samples: usize) -> usize {
+        self.capacity() * mem::size_of::<T>()
+            + sampled_usage(self.len(), samples, self.iter().map(|e| e.heap_usage(samples)))
+    }
+}
+
+impl<T: MemoryUsage + ?Sized> MemoryUsage for Box<T> {
+    fn heap_usage(&self, samples: usize) -> usize {
+        (**self).memory_usage(samples)
+    }
+}
+
+impl<T: MemoryUsage> MemoryUsage for Option<T> {
+    fn heap_usage(&self, samples: usize) -> usize {
+        self.as_ref().map_or(0, |v| v.heap_usage(samples))
+    }
+}
+
+impl<T: MemoryUsage> MemoryUsage for RefCell<T> {
+    fn heap_usage(&self, samples: usize) -> usize {
+        self.borrow().heap_usage(samples)
+    }
+}
+
+impl<T: MemoryUsage> MemoryUsage for Rc<T> {
+    fn heap_usage(&self, samples: usize) -> usize {
+        // strong and weak counters live next to the value
+        2 * mem::size_of::<usize>() + (**self).memory_usage(samples)
+    }
+}
+
+/// Helpers for the memory usage tests of the containers
+#[cfg(test)]
+pub(crate) mod testing {
+    use super::MemoryUsage;
+    use crate::svalue::object::{Robj, RobjPtr};
+
+    /// Objects of the same size, so that any sample extrapolates to the exact usage
+    pub fn member(i: usize) -> RobjPtr {
+        Robj::create_string_object(&format!("member:{:04}", i))
+    }
+
+    /// Adds 10 then 100 elements of the same size to `container` with `add`, checking that
+    /// the usage grows and that a sample of one element extrapolates to it. Returns the usage
+    /// of the empty container, with 10 and with 100 elements.
+    pub fn usage_growth<T, F>(container: &mut T, mut add: F) -> (usize, usize, usize)
+    where
+        T: MemoryUsage,
+        F: FnMut(&mut T, usize),
+    {
+        let empty = container.heap_usage(0);
+        for i in 0..10 {
+            add(container, i);
+        }
+        let ten = container.heap_usage(0);
+        assert!(ten > empty);
+        for i in 10..100 {
+            add(container, i);
+        }
+        let hundred = container.heap_usage(0);
+        assert!(hundred > ten);
+        assert_eq!(container.heap_usage(1), hundred);
+        (empty, ten, hundred)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::testing::member;
+    use crate::db::db::DB;
+    use crate::svalue::dict::Dict;
+    use crate::svalue::object::{Robj, RobjPtr};
+    use std::time::Duration;
+
+    #[test]
+    fn sampling_extrapolates() {
+        assert_eq!(sampled_usage(10, 2, vec![4, 4, 100, 100]), 40);
+        assert_eq!(sampled_usage(4, 0, vec![4, 4, 100, 100]), 208);
+        assert_eq!(sampled_usage(0, 5, vec![]), 0);
+    }
+
+    #[test]
+    fn nested_usage() {
+        let v: Vec<Vec<u8>> = vec![Vec::with_capacity(16), Vec::with_capacity(16)];
+        let inner = mem::size_of::<Vec<u8>>();
+        assert_eq!(v.heap_usage(0), v.capacity() * inner + 32);
+    }
+
+    #[test]
+    fn db_memory_usage_of_a_key() {
+        let mut db = DB::with_actor(0, 1);
+        let key = Robj::create_string_object("key");
+        assert_eq!(DB::memory_usage(&mut db, &key, None), None);
+
+        db.set_key(key.clone(), Robj::create_string_object("v"));
+        let short = DB::memory_usage(&mut db, &key, None).unwrap();
+        assert!(short >= Dict::<RobjPtr, RobjPtr>::entry_size());
+        db.set_key(key.clone(), Robj::create_string_object(&"v".repeat(100)));
+        let long = DB::memory_usage(&mut db, &key, None).unwrap();
+        assert!(long >= short + 99);
+
+        db.set_expire(key.clone(), SystemTime::now() + Duration::from_secs(60)).unwrap();
+        assert_eq!(DB::memory_usage(&mut db, &key, None), Some(long + Dict::<RobjPtr, SystemTime>::entry_size()));
+
+        let set = Robj::create_string_object("set");
+        db.set_key(set.clone(), Robj::create_set_object());
+        for i in 0..100 {
+            db.look_up_key(&set).unwrap().borrow_mut().set_add(member(i)).unwrap();
+        }
+        assert_eq!(DB::memory_usage(&mut db, &set, Some(1)), DB::memory_usage(&mut db, &set, Some(0)));
+    }
+
+    #[test]
+    fn db_usage_counts_tombstones() {
+        let mut db = DB::with_actor(0, 1);
+        for i in 0..100 {
+            let key = Robj::create_string_object(&format!("key:{}", i));
+            db.set_key(key.clone(), Robj::create_string_object("v"));
+            db.delete_key(&key).unwrap();
+        }
+        let deleted = db.heap_usage(0);
+        assert_eq!(db.purge_tombstones(u64::MAX), 100);
+        assert!(db.heap_usage(0) < deleted);
+    }
+}
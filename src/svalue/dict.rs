@@ -1,5 +1,7 @@
 use std::ops::IndexMut;
 use rand::Rng;
+use std::mem;
+use crate::memory::{sampled_usage, MemoryUsage};
 
 const DICT_HT_INITIAL_SIZE: usize = 4;
 
@@ -592,6 +594,33 @@ impl<K, V> Dict<K, V>
     }
 }
 
+impl<K, V> Dict<K, V>
+    where K: DictPartialEq
+{
+    pub fn entry_size() -> usize {
+        mem::size_of::<DictEntry<K, V>>()
+    }
+}
+
+impl<K, V> MemoryUsage for Dict<K, V>
+    where K: DictPartialEq + MemoryUsage,
+          V: MemoryUsage
+{
+    fn heap_usage(&self, samples: usize) -> usize {
+        let buckets: usize = self.ht
+            .iter()
+            .map(|t| t.table.capacity() * mem::size_of::<Option<Box<DictEntry<K, V>>>>())
+            .sum();
+        let entries = self.len() * Self::entry_size();
+        let contents = sampled_usage(
+            self.len(),
+            samples,
+            self.iter().map(|(k, v)| k.heap_usage(samples) + v.heap_usage(samples)),
+        );
+        buckets + entries + contents
+    }
+}
+
 pub struct Iter<'a, K: DictPartialEq, V> {
     d: &'a Dict<K, V>,
     table: usize,
@@ -661,6 +690,9 @@ impl<'a, K, V> Iterator for Iter<'a, K, V>
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::memory::testing::{member, usage_growth};
+    use super::super::hash::string_object_hash;
+    use super::super::object::RobjPtr;
 
     fn int_hash_func(i: &usize, _seed: u64) -> usize {
         i.clone()
@@ -890,5 +922,11 @@ mod test {
         assert_eq!(next_power(513), 1024);
         assert_eq!(next_power(std::usize::MAX), std::usize::MAX);
     }
-}
 
+    #[test]
+    fn usage_grows_with_entries() {
+        let mut dict: Dict<RobjPtr, RobjPtr> = Dict::new(string_object_hash, 0);
+        let (empty, ten, _) = usage_growth(&mut dict, |dict, i| dict.add(member(i), member(i)).unwrap());
+        assert!(ten >= empty + 10 * Dict::<RobjPtr, RobjPtr>::entry_size());
+    }
+}
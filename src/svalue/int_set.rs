@@ -1,5 +1,6 @@
 use std::mem;
 use std::convert::TryInto;
+use crate::memory::MemoryUsage;
 
 pub struct IntSet(Vec<u8>);
 
@@ -268,9 +269,16 @@ fn _i64_from(b: &[u8]) -> i64 {
 
 
 
+impl MemoryUsage for IntSet {
+    fn heap_usage(&self, _samples: usize) -> usize {
+        self.0.capacity()
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::memory::testing::usage_growth;
     use std::{i16, i32, i64};
 
     #[test]
@@ -418,4 +426,18 @@ mod test {
             set.remove(-i - 1).unwrap_err();
         }
     }
+
+    #[test]
+    fn usage_counts_the_encoded_integers() {
+        let mut set = IntSet::new();
+        let (empty, _, hundred) = usage_growth(&mut set, |set, i| set.add(i as i64).unwrap());
+        // the encoding, then two bytes per integer
+        assert_eq!(empty, 4);
+        assert!(hundred >= 4 + 100 * 2);
+        // the buffer may have room to spare, a copy of its content has none
+        assert_eq!(IntSet::from_bytes(set.raw_slice().to_vec()).heap_usage(0), 4 + 100 * 2);
+        // wider integers take more room each
+        set.add(i64::MAX).unwrap();
+        assert_eq!(IntSet::from_bytes(set.raw_slice().to_vec()).heap_usage(0), 4 + 101 * 8);
+    }
 }
@@ -1,6 +1,7 @@
 use std::mem;
 use std::marker::PhantomData;
 use std::iter::FromIterator;
+use crate::memory::{sampled_usage, MemoryUsage};
 
 pub struct LinkedList<T> {
     head: Option<*mut Node<T>>,
@@ -324,6 +325,13 @@ impl<T> LinkedList<T> {
     }
 }
 
+impl<T: MemoryUsage> MemoryUsage for LinkedList<T> {
+    fn heap_usage(&self, samples: usize) -> usize {
+        self.len * mem::size_of::<Node<T>>()
+            + sampled_usage(self.len, samples, self.iter().map(|e| e.heap_usage(samples)))
+    }
+}
+
 impl<T> Extend<T> for LinkedList<T> {
     fn extend<I: IntoIterator<Item=T>>(&mut self, iter: I) {
         for i in iter {
@@ -8,4 +8,5 @@ pub mod linked_list;
 pub mod util;
 pub mod hash;
 pub mod object;
+pub mod replicated;
 // pub mod skip_list_curr;
\ No newline at end of file
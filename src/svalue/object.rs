@@ -4,12 +4,16 @@ use std::rc::Rc;
 use std::error::Error;
 
 use crate::lcache::OnEvict;
+use crate::memory::MemoryUsage;
 
 use super::{hash, list::List};
 use super::zip_list::ZipList;
 use super::dict::{Dict, DictPartialEq};
 use super::int_set::IntSet;
 use super::zset::Zset;
+use super::replicated::{Actor, Counter, Register, ReplicatedMap, ReplicatedOp, ReplicatedSet, ReplicatedState};
+use crate::crdts::traits::{CmRDT, CvRDT};
+use crate::crdts::vclock::VClock;
 
 use lazy_static::__Deref;
 //use crate::hash;
@@ -18,6 +22,7 @@ use super::zip_list::ZipListValue;
 use super::list::ListWhere;
 use super::util::{bytes_vec, bytes_to_i64, bytes_to_f64};
 use std::cmp::Ordering;
+use num::BigInt;
 
 
 #[derive(Eq, Ord, Copy, Clone, Hash, PartialEq, Debug, PartialOrd)]
@@ -27,6 +32,10 @@ pub enum RobjType {
     Set,
     Zset,
     Hash,
+    Counter,
+    ReplicatedSet,
+    Register,
+    ReplicatedMap,
 }
 
 #[derive(Eq, Ord, Copy, Clone, PartialEq, Debug, PartialOrd)]
@@ -40,10 +49,14 @@ pub enum RobjEncoding {
     IntSet,
     SkipList,
     EmbStr,
+    PNCounter,
+    Orswot,
+    MVReg,
+    ORMap,
 }
 
 
-pub trait ObjectData {
+pub trait ObjectData: MemoryUsage {
     fn bytes_ref(&self) -> &[u8] { panic!("This is not a byte slice") }
     fn sds_ref(&self) -> &str { panic!("This is not an Sds string") }
     fn raw_bytes(&self) -> &[u8] { panic!("This type has no raw bytes") }
@@ -55,11 +68,21 @@ pub trait ObjectData {
     fn zip_list_ref(&self) -> &ZipList { panic!("This is not a ZipList") }
     fn zip_list_mut(&mut self) -> &mut ZipList { panic!("This is not a ZipList") }
     fn hash_table_ref(&self) -> &Dict<RobjPtr, RobjPtr> { panic!("This is not a hash table") }
+    fn hash_table_mut(&mut self) -> &mut Dict<RobjPtr, RobjPtr> { panic!("This is not a hash table") }
     fn int_set_ref(&self) -> &IntSet { panic!("This is not an IntSet") }
     fn int_set_mut(&mut self) -> &mut IntSet { panic!("This is not an IntSet") }
     fn set_wrapper_ref(&self) -> &dyn SetWrapper { panic!("This is not as SetWrapper") }
     fn set_wrapper_mut(&mut self) -> &mut dyn SetWrapper { panic!("This is not as SetWrapper") }
     fn zset_ref(&self) -> &Zset { panic!("This is not a Zset") }
+    fn zset_mut(&mut self) -> &mut Zset { panic!("This is not a Zset") }
+    fn counter_ref(&self) -> &Counter { panic!("This is not a Counter") }
+    fn counter_mut(&mut self) -> &mut Counter { panic!("This is not a Counter") }
+    fn replicated_set_ref(&self) -> &ReplicatedSet { panic!("This is not a ReplicatedSet") }
+    fn replicated_set_mut(&mut self) -> &mut ReplicatedSet { panic!("This is not a ReplicatedSet") }
+    fn register_ref(&self) -> &Register { panic!("This is not a Register") }
+    fn register_mut(&mut self) -> &mut Register { panic!("This is not a Register") }
+    fn replicated_map_ref(&self) -> &ReplicatedMap { panic!("This is not a ReplicatedMap") }
+    fn replicated_map_mut(&mut self) -> &mut ReplicatedMap { panic!("This is not a ReplicatedMap") }
     fn encoding(&self) -> RobjEncoding;
 }
 
@@ -126,6 +149,12 @@ pub struct Robj {
 }
 
 
+impl MemoryUsage for Robj {
+    fn heap_usage(&self, samples: usize) -> usize {
+        self.ptr.heap_usage(samples)
+    }
+}
+
 pub trait SetWrapper {
     fn sw_len(&self) -> usize;
     fn sw_delete(&mut self, o: &RobjPtr) -> Result<(), ()>;
@@ -428,6 +457,62 @@ impl Robj {
         )
     }
 
+    pub fn create_counter_object() -> RobjPtr {
+        Self::create_object(
+            RobjType::Counter,
+            RobjEncoding::PNCounter,
+            Box::new(Counter::new()),
+        )
+    }
+
+    pub fn create_replicated_set_object() -> RobjPtr {
+        Self::create_object(
+            RobjType::ReplicatedSet,
+            RobjEncoding::Orswot,
+            Box::new(ReplicatedSet::new()),
+        )
+    }
+
+    pub fn create_register_object() -> RobjPtr {
+        Self::create_object(
+            RobjType::Register,
+            RobjEncoding::MVReg,
+            Box::new(Register::new()),
+        )
+    }
+
+    pub fn create_replicated_map_object() -> RobjPtr {
+        Self::create_object(
+            RobjType::ReplicatedMap,
+            RobjEncoding::ORMap,
+            Box::new(ReplicatedMap::new()),
+        )
+    }
+
+    /// Empty value of one of the replicated types.
+    pub fn create_replicated_object(obj_type: RobjType) -> RobjPtr {
+        match obj_type {
+            RobjType::Counter => Self::create_counter_object(),
+            RobjType::ReplicatedSet => Self::create_replicated_set_object(),
+            RobjType::Register => Self::create_register_object(),
+            RobjType::ReplicatedMap => Self::create_replicated_map_object(),
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn from_replicated_state(state: ReplicatedState) -> RobjPtr {
+        match state {
+            ReplicatedState::Counter(c) =>
+                Self::create_object(RobjType::Counter, RobjEncoding::PNCounter, Box::new(c)),
+            ReplicatedState::Set(s) =>
+                Self::create_object(RobjType::ReplicatedSet, RobjEncoding::Orswot, Box::new(s)),
+            ReplicatedState::Register(r) =>
+                Self::create_object(RobjType::Register, RobjEncoding::MVReg, Box::new(r)),
+            ReplicatedState::Map(m) =>
+                Self::create_object(RobjType::ReplicatedMap, RobjEncoding::ORMap, Box::new(m)),
+        }
+    }
+
     pub fn is_string(&self) -> bool {
         match self.obj_type {
             RobjType::String => true,
@@ -811,6 +896,224 @@ impl Robj {
             others,
         }
     }
+
+    pub fn hash_table_ref(&self) -> &Dict<RobjPtr, RobjPtr> {
+        self.ptr.hash_table_ref()
+    }
+
+    pub fn hash_table_mut(&mut self) -> &mut Dict<RobjPtr, RobjPtr> {
+        self.ptr.hash_table_mut()
+    }
+
+    /// Fields with their values. A zip list holds each field followed by its value.
+    pub fn hash_iter<'a>(&'a self) -> Box<dyn Iterator<Item=(RobjPtr, RobjPtr)> + 'a> {
+        match self.encoding() {
+            RobjEncoding::Ht => Box::new(self.ptr.hash_table_ref()
+                .iter()
+                .map(|(f, v)| (Rc::clone(f), Rc::clone(v)))),
+            RobjEncoding::ZipList => {
+                let entries: Vec<RobjPtr> = self.list_iter().collect();
+                let pairs: Vec<(RobjPtr, RobjPtr)> = entries
+                    .chunks_exact(2)
+                    .map(|pair| (Rc::clone(&pair[0]), Rc::clone(&pair[1])))
+                    .collect();
+                Box::new(pairs.into_iter())
+            }
+            _ => unreachable!()
+        }
+    }
+
+    pub fn zset_add(&mut self, member: RobjPtr, score: f64) -> Result<(), ()> {
+        self.ptr.zset_mut().add(member, score)
+    }
+
+    /// Members with their scores. A zip list holds each member followed by its score.
+    pub fn zset_iter<'a>(&'a self) -> Box<dyn Iterator<Item=(RobjPtr, f64)> + 'a> {
+        match self.encoding() {
+            RobjEncoding::SkipList => Box::new(self.ptr.zset_ref().iter()),
+            RobjEncoding::ZipList => {
+                let entries: Vec<RobjPtr> = self.list_iter().collect();
+                let pairs: Vec<(RobjPtr, f64)> = entries
+                    .chunks_exact(2)
+                    .map(|pair| (Rc::clone(&pair[0]), pair[1].borrow().float()))
+                    .collect();
+                Box::new(pairs.into_iter())
+            }
+            _ => unreachable!()
+        }
+    }
+
+    pub fn is_replicated(&self) -> bool {
+        matches!(
+            self.obj_type,
+            RobjType::Counter | RobjType::ReplicatedSet | RobjType::Register | RobjType::ReplicatedMap
+        )
+    }
+
+    /// Applies a write performed on another node. Fails if `op` is meant for another type.
+    pub fn replicated_apply(&mut self, op: ReplicatedOp) -> Result<(), ()> {
+        if op.object_type() != self.obj_type {
+            return Err(());
+        }
+        match op {
+            ReplicatedOp::Counter(op) => self.ptr.counter_mut().apply(op),
+            ReplicatedOp::Set(op) => self.ptr.replicated_set_mut().apply(op),
+            ReplicatedOp::Register(op) => self.ptr.register_mut().apply(op),
+            ReplicatedOp::Map(op) => self.ptr.replicated_map_mut().apply(op),
+        }
+        Ok(())
+    }
+
+    pub fn replicated_state(&self) -> ReplicatedState {
+        match self.obj_type {
+            RobjType::Counter => ReplicatedState::Counter(self.ptr.counter_ref().clone()),
+            RobjType::ReplicatedSet => ReplicatedState::Set(self.ptr.replicated_set_ref().clone()),
+            RobjType::Register => ReplicatedState::Register(self.ptr.register_ref().clone()),
+            RobjType::ReplicatedMap => ReplicatedState::Map(self.ptr.replicated_map_ref().clone()),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Merges the state of another replica of this value. Fails if `state` is of another type.
+    pub fn replicated_merge(&mut self, state: ReplicatedState) -> Result<(), ()> {
+        if state.object_type() != self.obj_type {
+            return Err(());
+        }
+        match state {
+            ReplicatedState::Counter(c) => self.ptr.counter_mut().merge(c),
+            ReplicatedState::Set(s) => self.ptr.replicated_set_mut().merge(s),
+            ReplicatedState::Register(r) => self.ptr.register_mut().merge(r),
+            ReplicatedState::Map(m) => self.ptr.replicated_map_mut().merge(m),
+        }
+        Ok(())
+    }
+
+    /// Causal context of a replicated set or map, empty for the other replicated types.
+    pub fn replicated_clock(&self) -> VClock<Actor> {
+        match self.obj_type {
+            RobjType::ReplicatedSet => self.ptr.replicated_set_ref().clock(),
+            RobjType::ReplicatedMap => self.ptr.replicated_map_ref().read_ctx().add_clock,
+            _ => VClock::new(),
+        }
+    }
+
+    /// Removes of a replicated set or map still waiting for causal context.
+    pub fn replicated_deferred_len(&self) -> usize {
+        match self.obj_type {
+            RobjType::ReplicatedSet => self.ptr.replicated_set_ref().deferred_len(),
+            RobjType::ReplicatedMap => self.ptr.replicated_map_ref().deferred_len(),
+            _ => 0,
+        }
+    }
+
+    /// Prunes the deferred removes of a replicated set or map given the causally stable cut
+    /// of its replicas, returns how many were dropped.
+    pub fn replicated_gc(&mut self, stable: &VClock<Actor>) -> usize {
+        match self.obj_type {
+            RobjType::ReplicatedSet => self.ptr.replicated_set_mut().gc(stable),
+            RobjType::ReplicatedMap => self.ptr.replicated_map_mut().gc(stable),
+            _ => 0,
+        }
+    }
+
+    pub fn counter_read(&self) -> BigInt {
+        self.ptr.counter_ref().read()
+    }
+
+    pub fn counter_incr_by(&mut self, actor: Actor, by: i64) -> ReplicatedOp {
+        let c = self.ptr.counter_mut();
+        let op = if by < 0 {
+            c.dec_many(actor, by.unsigned_abs())
+        } else {
+            c.inc_many(actor, by as u64)
+        };
+        c.apply(op.clone());
+        ReplicatedOp::Counter(op)
+    }
+
+    pub fn replicated_set_add(&mut self, actor: Actor, members: Vec<Vec<u8>>) -> ReplicatedOp {
+        let s = self.ptr.replicated_set_mut();
+        let ctx = s.read_ctx().derive_add_ctx(actor);
+        let op = s.add_all(members, ctx);
+        s.apply(op.clone());
+        ReplicatedOp::Set(op)
+    }
+
+    /// Only the additions this node has seen are removed, concurrent ones win.
+    pub fn replicated_set_rem(&mut self, members: Vec<Vec<u8>>) -> ReplicatedOp {
+        let s = self.ptr.replicated_set_mut();
+        let ctx = s.read_ctx().derive_rm_ctx();
+        let op = s.rm_all(members, ctx);
+        s.apply(op.clone());
+        ReplicatedOp::Set(op)
+    }
+
+    pub fn replicated_set_contains(&self, member: &Vec<u8>) -> bool {
+        self.ptr.replicated_set_ref().contains(member).val
+    }
+
+    /// Members in byte order.
+    pub fn replicated_set_members(&self) -> Vec<Vec<u8>> {
+        let mut members: Vec<Vec<u8>> = self.ptr.replicated_set_ref().read().val.into_iter().collect();
+        members.sort();
+        members
+    }
+
+    pub fn replicated_set_len(&self) -> usize {
+        self.ptr.replicated_set_ref().read().val.len()
+    }
+
+    pub fn register_set(&mut self, actor: Actor, value: Vec<u8>) -> ReplicatedOp {
+        let r = self.ptr.register_mut();
+        let ctx = r.read_ctx().derive_add_ctx(actor);
+        let op = r.write(value, ctx);
+        r.apply(op.clone());
+        ReplicatedOp::Register(op)
+    }
+
+    /// Every value written concurrently and not overwritten since, in byte order.
+    pub fn register_get(&self) -> Vec<Vec<u8>> {
+        let mut vals = self.ptr.register_ref().read().val;
+        vals.sort();
+        vals
+    }
+
+    pub fn replicated_map_set(&mut self, actor: Actor, field: Vec<u8>, value: Vec<u8>) -> ReplicatedOp {
+        let m = self.ptr.replicated_map_mut();
+        let ctx = m.read_ctx().derive_add_ctx(actor);
+        let op = m.update(field, ctx, |r, ctx| r.write(value, ctx));
+        m.apply(op.clone());
+        ReplicatedOp::Map(op)
+    }
+
+    /// Only the writes this node has seen are removed, a concurrent write keeps the field.
+    pub fn replicated_map_del(&mut self, field: Vec<u8>) -> ReplicatedOp {
+        let m = self.ptr.replicated_map_mut();
+        let ctx = m.get(&field).derive_rm_ctx();
+        let op = m.rm(field, ctx);
+        m.apply(op.clone());
+        ReplicatedOp::Map(op)
+    }
+
+    /// Same as `register_get` on the register stored under `field`.
+    pub fn replicated_map_get(&self, field: &[u8]) -> Vec<Vec<u8>> {
+        match self.ptr.replicated_map_ref().get(&field.to_vec()).val {
+            Some(r) => {
+                let mut vals = r.read().val;
+                vals.sort();
+                vals
+            }
+            None => vec![],
+        }
+    }
+
+    pub fn replicated_map_fields(&self) -> Vec<Vec<u8>> {
+        self.ptr.replicated_map_ref().keys().map(|k| k.val.clone()).collect()
+    }
+
+    pub fn replicated_map_len(&self) -> usize {
+        self.ptr.replicated_map_ref().len().val
+    }
 }
 
 
@@ -908,6 +1211,9 @@ impl ObjectData for Dict<RobjPtr, RobjPtr> {
     fn hash_table_ref(&self) -> &Dict<RobjPtr, RobjPtr> {
         self
     }
+    fn hash_table_mut(&mut self) -> &mut Dict<RobjPtr, RobjPtr> {
+        self
+    }
 
     fn encoding(&self) -> RobjEncoding {
         RobjEncoding::Ht
@@ -919,8 +1225,12 @@ impl ObjectData for Zset {
         self
     }
 
+    fn zset_mut(&mut self) -> &mut Zset {
+        self
+    }
+
     fn encoding(&self) -> RobjEncoding {
-        unimplemented!()
+        RobjEncoding::SkipList
     }
 }
 
@@ -1015,4 +1325,20 @@ impl<'a> Iterator for SWInterIter<'a> {
         }
         None
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::memory::testing::{member, usage_growth};
+
+    #[test]
+    fn usage_follows_the_value() {
+        let short = Robj::create_string_object("v");
+        let long = Robj::create_string_object(&"v".repeat(100));
+        assert!(long.heap_usage(0) >= short.heap_usage(0) + 99);
+
+        let mut set = Robj::create_set_object();
+        usage_growth(&mut set, |set, i| RefCell::borrow_mut(set).set_add(member(i)).unwrap());
+    }
+}
@@ -0,0 +1,205 @@
+use serde::{Deserialize, Serialize};
+
+use crate::crdts::{map, mvreg, orswot, pncounter};
+use crate::crdts::map::Map;
+use crate::crdts::mvreg::MVReg;
+use crate::crdts::orswot::Orswot;
+use crate::crdts::pncounter::PNCounter;
+
+use super::object::{ObjectData, RobjEncoding, RobjType};
+
+/// Identifies the node that performed a write. Every node of a cluster needs its own actor,
+/// writes of two nodes sharing one are not guaranteed to converge.
+pub type Actor = u64;
+
+pub type Counter = PNCounter<Actor>;
+pub type ReplicatedSet = Orswot<Vec<u8>, Actor>;
+pub type Register = MVReg<Vec<u8>, Actor>;
+pub type ReplicatedMap = Map<Vec<u8>, Register, Actor>;
+
+/// A write on a replicated value, produced by the node that performed it and shipped to the
+/// other nodes, which apply it to their copy of the key.
+///
+/// Ops must be delivered in causal order: an op is applied only after every op its node had
+/// seen when producing it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ReplicatedOp {
+    Counter(pncounter::Op<Actor>),
+    Set(orswot::Op<Vec<u8>, Actor>),
+    Register(mvreg::Op<Vec<u8>, Actor>),
+    Map(map::Op<Vec<u8>, Register, Actor>),
+}
+
+impl ReplicatedOp {
+    /// Type of the value this op applies to.
+    pub fn object_type(&self) -> RobjType {
+        match self {
+            ReplicatedOp::Counter(_) => RobjType::Counter,
+            ReplicatedOp::Set(_) => RobjType::ReplicatedSet,
+            ReplicatedOp::Register(_) => RobjType::Register,
+            ReplicatedOp::Map(_) => RobjType::ReplicatedMap,
+        }
+    }
+}
+
+/// Full state of a replicated value, exchanged when replicas repair each other instead of
+/// replaying ops.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ReplicatedState {
+    Counter(Counter),
+    Set(ReplicatedSet),
+    Register(Register),
+    Map(ReplicatedMap),
+}
+
+impl ReplicatedState {
+    /// Type of the value holding this state.
+    pub fn object_type(&self) -> RobjType {
+        match self {
+            ReplicatedState::Counter(_) => RobjType::Counter,
+            ReplicatedState::Set(_) => RobjType::ReplicatedSet,
+            ReplicatedState::Register(_) => RobjType::Register,
+            ReplicatedState::Map(_) => RobjType::ReplicatedMap,
+        }
+    }
+}
+
+impl ObjectData for Counter {
+    fn counter_ref(&self) -> &Counter {
+        self
+    }
+    fn counter_mut(&mut self) -> &mut Counter {
+        self
+    }
+
+    fn encoding(&self) -> RobjEncoding {
+        RobjEncoding::PNCounter
+    }
+}
+
+impl ObjectData for ReplicatedSet {
+    fn replicated_set_ref(&self) -> &ReplicatedSet {
+        self
+    }
+    fn replicated_set_mut(&mut self) -> &mut ReplicatedSet {
+        self
+    }
+
+    fn encoding(&self) -> RobjEncoding {
+        RobjEncoding::Orswot
+    }
+}
+
+impl ObjectData for Register {
+    fn register_ref(&self) -> &Register {
+        self
+    }
+    fn register_mut(&mut self) -> &mut Register {
+        self
+    }
+
+    fn encoding(&self) -> RobjEncoding {
+        RobjEncoding::MVReg
+    }
+}
+
+impl ObjectData for ReplicatedMap {
+    fn replicated_map_ref(&self) -> &ReplicatedMap {
+        self
+    }
+    fn replicated_map_mut(&mut self) -> &mut ReplicatedMap {
+        self
+    }
+
+    fn encoding(&self) -> RobjEncoding {
+        RobjEncoding::ORMap
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use num::BigInt;
+
+    use super::*;
+    use crate::svalue::object::{Robj, RobjPtr};
+
+    fn exchange(a: &RobjPtr, a_ops: Vec<ReplicatedOp>, b: &RobjPtr, b_ops: Vec<ReplicatedOp>) {
+        for op in b_ops {
+            a.borrow_mut().replicated_apply(op).unwrap();
+        }
+        for op in a_ops {
+            b.borrow_mut().replicated_apply(op).unwrap();
+        }
+    }
+
+    #[test]
+    fn concurrent_counter_writes_converge() {
+        let a = Robj::create_replicated_object(RobjType::Counter);
+        let b = Robj::create_replicated_object(RobjType::Counter);
+        let inc = a.borrow_mut().counter_incr_by(1, 5);
+        let dec = a.borrow_mut().counter_incr_by(1, -2);
+        let a_ops = vec![inc, dec];
+        let b_ops = vec![b.borrow_mut().counter_incr_by(2, 10)];
+        exchange(&a, a_ops, &b, b_ops);
+
+        assert_eq!(a.borrow().counter_read(), BigInt::from(13));
+        assert_eq!(b.borrow().counter_read(), BigInt::from(13));
+    }
+
+    #[test]
+    fn concurrent_add_wins_over_remove() {
+        let a = Robj::create_replicated_object(RobjType::ReplicatedSet);
+        let b = Robj::create_replicated_object(RobjType::ReplicatedSet);
+        let op = a.borrow_mut().replicated_set_add(1, vec![b"x".to_vec(), b"y".to_vec()]);
+        b.borrow_mut().replicated_apply(op).unwrap();
+
+        let a_ops = vec![a.borrow_mut().replicated_set_rem(vec![b"x".to_vec(), b"y".to_vec()])];
+        let b_ops = vec![b.borrow_mut().replicated_set_add(2, vec![b"x".to_vec()])];
+        exchange(&a, a_ops, &b, b_ops);
+
+        assert_eq!(a.borrow().replicated_set_members(), vec![b"x".to_vec()]);
+        assert_eq!(b.borrow().replicated_set_members(), vec![b"x".to_vec()]);
+    }
+
+    #[test]
+    fn concurrent_register_writes_are_kept() {
+        let a = Robj::create_replicated_object(RobjType::Register);
+        let b = Robj::create_replicated_object(RobjType::Register);
+        let a_ops = vec![a.borrow_mut().register_set(1, b"a".to_vec())];
+        let b_ops = vec![b.borrow_mut().register_set(2, b"b".to_vec())];
+        exchange(&a, a_ops, &b, b_ops);
+
+        assert_eq!(a.borrow().register_get(), vec![b"a".to_vec(), b"b".to_vec()]);
+        assert_eq!(b.borrow().register_get(), vec![b"a".to_vec(), b"b".to_vec()]);
+
+        let op = a.borrow_mut().register_set(1, b"c".to_vec());
+        b.borrow_mut().replicated_apply(op).unwrap();
+        assert_eq!(b.borrow().register_get(), vec![b"c".to_vec()]);
+    }
+
+    #[test]
+    fn concurrent_map_writes_converge() {
+        let a = Robj::create_replicated_object(RobjType::ReplicatedMap);
+        let b = Robj::create_replicated_object(RobjType::ReplicatedMap);
+        let op = a.borrow_mut().replicated_map_set(1, b"f".to_vec(), b"1".to_vec());
+        b.borrow_mut().replicated_apply(op).unwrap();
+
+        let a_ops = vec![a.borrow_mut().replicated_map_del(b"f".to_vec())];
+        let b_ops = vec![b.borrow_mut().replicated_map_set(2, b"g".to_vec(), b"2".to_vec())];
+        exchange(&a, a_ops, &b, b_ops);
+
+        for o in [&a, &b] {
+            assert_eq!(o.borrow().replicated_map_fields(), vec![b"g".to_vec()]);
+            assert_eq!(o.borrow().replicated_map_get(b"g"), vec![b"2".to_vec()]);
+            assert!(o.borrow().replicated_map_get(b"f").is_empty());
+        }
+    }
+
+    #[test]
+    fn mismatched_op_is_rejected() {
+        let a = Robj::create_replicated_object(RobjType::Counter);
+        let b = Robj::create_replicated_object(RobjType::Register);
+        let op = b.borrow_mut().register_set(2, b"x".to_vec());
+        assert!(a.borrow_mut().replicated_apply(op).is_err());
+    }
+}
@@ -3,6 +3,8 @@ use super::object::{RobjPtr, Robj};
 use rand::prelude::*;
 use std::cell::RefCell;
 use std::iter::Iterator;
+use std::mem;
+use crate::memory::{sampled_usage, MemoryUsage};
 
 const SKIP_LIST_MAX_LEVEL: usize = 32;
 
@@ -406,6 +408,44 @@ impl SkipList {
     }
 }
 
+impl SkipList {
+    /// Members with their scores, lowest score first.
+    pub fn iter(&self) -> impl Iterator<Item=(RobjPtr, f64)> {
+        self.header
+            .borrow()
+            .iter(0)
+            .map(|node| {
+                let node = node.borrow();
+                (Rc::clone(node.obj_ref()), node.score)
+            })
+    }
+
+    // Nodes and their level arrays only; the member objects are usually shared with a dict.
+    pub(crate) fn node_usage(&self) -> usize {
+        let node_size = 2 * mem::size_of::<usize>() + mem::size_of::<RefCell<SkipListNode>>();
+        let level_size = mem::size_of::<SkipListLevel>();
+        let mut usage = node_size + self.header.borrow().level.capacity() * level_size;
+        for node in self.header.borrow().iter(0) {
+            usage += node_size + node.borrow().level.capacity() * level_size;
+        }
+        usage
+    }
+}
+
+impl MemoryUsage for SkipList {
+    fn heap_usage(&self, samples: usize) -> usize {
+        let objects = sampled_usage(
+            self.length,
+            samples,
+            self.header
+                .borrow()
+                .iter(0)
+                .map(|node| node.borrow().obj_ref().heap_usage(samples)),
+        );
+        self.node_usage() + objects
+    }
+}
+
 pub struct RangeSpec {
     min: f64,
     max: f64,
@@ -454,4 +494,16 @@ impl RangeSpec {
             false => value <= range.max,
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::memory::testing::{member, usage_growth};
+
+    #[test]
+    fn usage_grows_with_nodes() {
+        let mut list = SkipList::new();
+        usage_growth(&mut list, |list, i| list.insert(i as f64, member(i)));
+    }
+}
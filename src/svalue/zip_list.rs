@@ -3,6 +3,7 @@ use std::iter::Chain;
 use std::iter::Cloned;
 use std::slice;
 use super::util::bytes_to_i64;
+use crate::memory::MemoryUsage;
 
 const ZIP_LIST_I16_ENC: u8 = 0b1100_0000;
 const ZIP_LIST_I32_ENC: u8 = 0b1101_0000;
@@ -525,6 +526,12 @@ impl<'a> Iterator for IterRev<'a> {
 // | tail offset: sizeof(usize) | number of nodes: sizeof(u16) | node 1 | node 2 | ... | node N |
 pub struct ZipList(Vec<u8>);
 
+impl MemoryUsage for ZipList {
+    fn heap_usage(&self, _samples: usize) -> usize {
+        self.0.capacity()
+    }
+}
+
 const ZIP_LIST_TAIL_OFF_SIZE: usize = mem::size_of::<usize>();
 const ZIP_LIST_LEN_SIZE: usize = mem::size_of::<u16>();
 const ZIP_LIST_HEADER_SIZE: usize = mem::size_of::<usize>() + mem::size_of::<u16>();
@@ -846,4 +853,24 @@ impl ZipList {
     fn header_len(&self) -> usize {
         ZIP_LIST_HEADER_SIZE
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::memory::testing::usage_growth;
+
+    #[test]
+    fn usage_counts_the_encoded_entries() {
+        let mut list = ZipList::new();
+        let (empty, _, hundred) = usage_growth(&mut list, |list, _| {
+            list.push(&[b'v'; 32]);
+        });
+        // the header, then the length of the previous entry, the encoding and the content
+        let entry = 1 + 1 + 32;
+        assert_eq!(empty, ZIP_LIST_HEADER_SIZE);
+        assert!(hundred >= ZIP_LIST_HEADER_SIZE + 100 * entry);
+        // the buffer may have room to spare, a copy of its content has none
+        assert_eq!(ZipList::from_bytes(list.raw_slice().to_vec()).heap_usage(0), ZIP_LIST_HEADER_SIZE + 100 * entry);
+    }
+}
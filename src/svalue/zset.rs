@@ -1,8 +1,9 @@
-use super::object::RobjPtr;
+use super::object::{Robj, RobjPtr};
 use super::skip_list::SkipList;
 use super::dict::Dict;
 use super::hash;
 use rand::prelude::*;
+use crate::memory::MemoryUsage;
 
 pub struct Zset {
     dict: Dict<RobjPtr, RobjPtr>,
@@ -16,4 +17,47 @@ impl Zset {
             list: SkipList::new(),
         }
     }
-}
\ No newline at end of file
+
+    /// Fails if `member` is already in the set.
+    pub fn add(&mut self, member: RobjPtr, score: f64) -> Result<(), ()> {
+        self.dict.add(member.clone(), Robj::create_string_object_from_double(score))?;
+        self.list.insert(score, member);
+        Ok(())
+    }
+
+    /// Members with their scores, lowest score first.
+    pub fn iter(&self) -> impl Iterator<Item=(RobjPtr, f64)> {
+        self.list.iter()
+    }
+}
+
+impl MemoryUsage for Zset {
+    fn heap_usage(&self, samples: usize) -> usize {
+        self.dict.heap_usage(samples) + self.list.node_usage()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::memory::testing::{member, usage_growth};
+
+    #[test]
+    fn usage_grows_with_members() {
+        let mut zset = Zset::new();
+        // members and scores of the same size, so that any sample extrapolates exactly
+        usage_growth(&mut zset, |zset, i| zset.add(member(i), (1000 + i) as f64).unwrap());
+    }
+
+    #[test]
+    fn iterates_by_score() {
+        let mut zset = Zset::new();
+        zset.add(Robj::create_string_object("b"), 2.0).unwrap();
+        zset.add(Robj::create_string_object("a"), 1.5).unwrap();
+        assert!(zset.add(Robj::create_string_object("a"), 3.0).is_err());
+        let members: Vec<(Vec<u8>, f64)> = zset.iter()
+            .map(|(m, score)| (m.borrow().string().to_vec(), score))
+            .collect();
+        assert_eq!(members, vec![(b"a".to_vec(), 1.5), (b"b".to_vec(), 2.0)]);
+    }
+}